@@ -0,0 +1,56 @@
+// 2 種類の Connection 実装で同じフレーム列を読み書きし、かかった時間を比較する
+//
+// cargo run --release --example connection_bench
+use std::time::Instant;
+
+use bytes::Bytes;
+use tokio::net::{TcpListener, TcpStream};
+
+use my_redis::frame::Frame;
+use my_redis::{connection, connection_without_buf_trait, Result};
+
+const ROUNDS: usize = 100_000;
+
+fn frames() -> Vec<Frame> {
+    vec![
+        Frame::command(["SET", "key", "value"]),
+        Frame::Bulk(Bytes::from(vec![b'x'; 16 * 1024])),
+        Frame::Array((0..64).map(Frame::Integer).collect()),
+    ]
+}
+
+async fn socket_pair() -> Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let client = TcpStream::connect(listener.local_addr()?).await?;
+    let (server, _) = listener.accept().await?;
+    Ok((client, server))
+}
+
+macro_rules! bench {
+    ($name:expr, $conn:ty) => {{
+        let (a, b) = socket_pair().await?;
+        let mut writer = <$conn>::new(a);
+        let mut reader = <$conn>::new(b);
+
+        let start = Instant::now();
+        let write = tokio::spawn(async move {
+            let frames = frames();
+            for i in 0..ROUNDS {
+                writer.write_frame(&frames[i % frames.len()]).await.unwrap();
+            }
+        });
+        for _ in 0..ROUNDS {
+            reader.read_frame().await?;
+        }
+        write.await?;
+
+        println!("{:>28}: {:?}", $name, start.elapsed());
+    }};
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    bench!("BytesMut + Buf", connection::Connection);
+    bench!("Vec<u8> + cursor", connection_without_buf_trait::Connection);
+    Ok(())
+}
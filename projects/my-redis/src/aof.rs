@@ -0,0 +1,558 @@
+// AOF (append-only file) による永続化
+//
+// 書き込みコマンドを実行するたびに、そのコマンドを RESP の配列フレームとしてファイルの末尾に追記し、
+// 起動時にファイルの先頭から実行し直して db を復元する
+// スナップショットと異なり、最後に保存してから落ちるまでの書き込みも失われない
+//
+// ファイルに追記したデータをディスクに書き出す（fsync する）タイミングは Fsync で選ぶ
+//
+// # ファイル形式
+//
+// ```text
+// [スナップショット] コマンド*
+// ```
+//
+// コマンドは RESP の配列フレームで、クライアントから受け取るコマンドと同じ形をしている
+// MULTI と EXEC で囲まれたコマンドは、EXEC まで読み込めた場合にだけまとめて実行する
+//
+// BGREWRITEAOF はその時点の db の内容をスナップショット（rdb モジュールの形式）として
+// 新しいファイルの先頭に書き込み、それ以降のコマンドをその後ろに追記する
+// 読み込むときは、ファイルがスナップショットで始まっていれば先にそれを読み込む
+//
+// 最後のコマンドが途中で切れている場合（書き込みの途中で落ちた場合）は、
+// そのコマンドを捨ててファイルを切り詰め、それより前のコマンドだけを復元する
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+
+use crate::cmd::Registry;
+use crate::db::ShardedDb;
+use crate::frame::{self, encode, Frame, Protocol};
+use crate::rdb;
+
+// everysec で fsync する間隔
+// always で fsync に失敗した場合も、この間隔でやり直す
+const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+// ファイルへの書き込みに失敗したときに、書き込みをやり直すまでの間隔
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// 追記したデータを fsync するタイミング
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Fsync {
+    // コマンドを追記するたびに fsync する（最も安全だが最も遅い）
+    Always,
+    // 1 秒ごとに fsync する（落ちたときに失われるのは最大で約 1 秒分）
+    #[default]
+    EverySec,
+    // fsync せず、OS に任せる
+    No,
+}
+
+impl FromStr for Fsync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(Fsync::Always),
+            "everysec" => Ok(Fsync::EverySec),
+            "no" => Ok(Fsync::No),
+            _ => Err(format!("unknown appendfsync policy '{}'", s)),
+        }
+    }
+}
+
+// AOF のファイルと、追記の状態
+//
+// コマンドを追記するときは、エンコードしたデータを書き込み待ちのキューに積むだけで、
+// ファイルへの書き込み（と always の fsync）は専用のスレッドが行う
+// 書き込みコマンドは db の gate を排他ロックで保持したまま追記するので、
+// その間にディスクを待って tokio のワーカースレッドを止めないようにしている
+//
+// 書き込みか fsync に失敗している間は error が Some を返し、書き込みコマンドは
+// MISCONF エラーで拒否される
+// 書き込めなかったデータはキューに戻し、書き込めるようになるまでやり直す
+#[derive(Debug)]
+pub struct Aof {
+    inner: Arc<Inner>,
+    writer: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    path: PathBuf,
+    fsync: Fsync,
+    log: Mutex<Log>,
+    queue: Mutex<Queue>,
+    // キューにデータを積んだとき、書き込みが進んだとき、閉じるときに通知する
+    changed: Condvar,
+    // 書き込みが進んだときに、応答を待っているクライアントに通知する
+    progress: Notify,
+}
+
+// 以下の位置はいずれも、これまでに追記したデータ全体の先頭からのバイト数で表す
+#[derive(Debug)]
+struct Log {
+    file: File,
+    // ファイルに書き込み終えた位置
+    written: u64,
+    // fsync し終えた位置
+    synced: u64,
+    // BGREWRITEAOF の実行中であれば、その状態
+    rewrite: Option<Rewrite>,
+}
+
+#[derive(Debug)]
+struct Rewrite {
+    // スナップショットを取った時点で追記し終えていた位置
+    start: u64,
+    // start より後に書き込んだコマンド
+    // 書き直したファイルに置き換えるときに、その末尾に追記する
+    buf: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    // まだファイルに書き込んでいないデータ
+    pending: Vec<u8>,
+    // 追記した（キューに積んだ）位置
+    appended: u64,
+    // 書き込みを終えた位置（always では fsync まで終えた位置）
+    written: u64,
+    write_error: Option<String>,
+    sync_error: Option<String>,
+    closed: bool,
+}
+
+impl Queue {
+    fn error(&self) -> Option<&String> {
+        self.write_error.as_ref().or(self.sync_error.as_ref())
+    }
+
+    // target までの書き込みを終えていれば Ok を、その前に失敗していればエラーを返す
+    // まだ書き込んでいる途中であれば None を返す
+    fn reached(&self, target: u64) -> Option<Result<(), String>> {
+        if self.written >= target {
+            Some(Ok(()))
+        } else {
+            self.error().map(|err| Err(err.clone()))
+        }
+    }
+}
+
+impl Aof {
+    // ファイルを追記モードで開き（なければ作成する）、書き込みスレッドを起動する
+    pub fn open(path: impl Into<PathBuf>, fsync: Fsync) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let inner = Arc::new(Inner {
+            path,
+            fsync,
+            log: Mutex::new(Log {
+                file,
+                written: 0,
+                synced: 0,
+                rewrite: None,
+            }),
+            queue: Mutex::new(Queue::default()),
+            changed: Condvar::new(),
+            progress: Notify::new(),
+        });
+        let writer = {
+            let inner = inner.clone();
+            thread::Builder::new()
+                .name("aof-writer".to_string())
+                .spawn(move || inner.run_writer())?
+        };
+        Ok(Self {
+            inner,
+            writer: Some(writer),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    pub fn is_rewriting(&self) -> bool {
+        self.inner.log.lock().unwrap().rewrite.is_some()
+    }
+
+    // 書き込みか fsync に失敗していれば、そのエラーを返す
+    pub fn error(&self) -> Option<String> {
+        self.inner.queue.lock().unwrap().error().cloned()
+    }
+
+    // これまでに追記した位置
+    // wait_written に渡して、それ以降に追記したデータの書き込みを待つのに使う
+    pub fn appended(&self) -> u64 {
+        self.inner.queue.lock().unwrap().appended
+    }
+
+    // コマンドを書き込み待ちのキューに積む
+    pub(crate) fn append(&self, commands: &[Vec<Bytes>]) {
+        let mut buf = Vec::new();
+        for command in commands {
+            let frame = Frame::Array(command.iter().cloned().map(Frame::Bulk).collect());
+            encode(&frame, Protocol::Resp2, &mut buf).unwrap();
+        }
+
+        let mut queue = self.inner.queue.lock().unwrap();
+        queue.pending.extend_from_slice(&buf);
+        queue.appended += buf.len() as u64;
+        drop(queue);
+        self.inner.changed.notify_all();
+    }
+
+    // appendfsync always の場合に、since より後に追記したデータを fsync し終えるまで待つ
+    // その前に書き込みか fsync に失敗すれば、そのエラーを返す
+    // ほかのポリシーではクライアントへの応答をディスクへの書き込みより先に返すので、待たない
+    pub async fn wait_written(&self, since: u64) -> Result<(), String> {
+        if self.inner.fsync != Fsync::Always {
+            return Ok(());
+        }
+        let target = self.appended();
+        if target == since {
+            return Ok(());
+        }
+        loop {
+            // 書き込みの状態を調べる前に登録して、その間の通知を取りこぼさないようにする
+            let notified = self.inner.progress.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if let Some(result) = self.inner.queue.lock().unwrap().reached(target) {
+                return result;
+            }
+            notified.await;
+        }
+    }
+
+    // これまでに追記したデータを書き込み終える（always では fsync し終える）まで待つ
+    // その前に書き込みか fsync に失敗すれば、そのエラーを返す
+    pub fn flush(&self) -> Result<(), String> {
+        let mut queue = self.inner.queue.lock().unwrap();
+        let target = queue.appended;
+        loop {
+            if let Some(result) = queue.reached(target) {
+                return result;
+            }
+            queue = self.inner.changed.wait(queue).unwrap();
+        }
+    }
+}
+
+impl Drop for Aof {
+    // キューに残ったデータを書き込んでから、書き込みスレッドを終了する
+    fn drop(&mut self) {
+        self.inner.queue.lock().unwrap().closed = true;
+        self.inner.changed.notify_all();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Inner {
+    // 書き込みスレッドの本体
+    // キューに積まれたデータをまとめて取り出してファイルに書き込む
+    fn run_writer(&self) {
+        loop {
+            let (start, chunk) = {
+                let mut queue = self.queue.lock().unwrap();
+                while queue.pending.is_empty() && !queue.closed {
+                    queue = self.changed.wait(queue).unwrap();
+                }
+                if queue.pending.is_empty() {
+                    return;
+                }
+                let chunk = mem::take(&mut queue.pending);
+                (queue.appended - chunk.len() as u64, chunk)
+            };
+            let end = start + chunk.len() as u64;
+
+            match self.write(start, &chunk) {
+                Ok(()) => {
+                    let mut queue = self.queue.lock().unwrap();
+                    queue.write_error = None;
+                    if self.fsync != Fsync::Always {
+                        queue.written = end;
+                    }
+                    drop(queue);
+                    self.notify();
+                    if self.fsync == Fsync::Always {
+                        if let Err(err) = self.sync() {
+                            eprintln!("failed to fsync the AOF: {}", err);
+                        }
+                    }
+                }
+                Err(err) => {
+                    eprintln!("failed to write to the AOF: {}", err);
+                    // 書き込めなかったデータは、後から積まれたデータの前に戻してやり直す
+                    let mut queue = self.queue.lock().unwrap();
+                    queue.write_error = Some(err.to_string());
+                    let mut chunk = chunk;
+                    chunk.extend_from_slice(&queue.pending);
+                    queue.pending = chunk;
+                    if queue.closed {
+                        return;
+                    }
+                    drop(queue);
+                    self.notify();
+
+                    let queue = self.queue.lock().unwrap();
+                    let _ = self
+                        .changed
+                        .wait_timeout_while(queue, RETRY_INTERVAL, |queue| !queue.closed);
+                }
+            }
+        }
+    }
+
+    // start の位置から始まるデータをファイルに書き込む
+    fn write(&self, start: u64, chunk: &[u8]) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        let len = log.file.metadata()?.len();
+        if let Err(err) = log.file.write_all(chunk) {
+            // 途中まで書き込んだ部分を取り除き、コマンドが途中で切れたファイルを残さない
+            let _ = log.file.set_len(len);
+            return Err(err);
+        }
+        let end = start + chunk.len() as u64;
+        log.written = end;
+        if let Some(rewrite) = &mut log.rewrite {
+            if rewrite.start < end {
+                let skip = rewrite.start.saturating_sub(start) as usize;
+                rewrite.buf.extend_from_slice(&chunk[skip..]);
+            }
+        }
+        Ok(())
+    }
+
+    // まだ fsync していないデータがあれば fsync する
+    // ファイルのハンドルを複製してからロックを外すので、fsync している間も書き込める
+    fn sync(&self) -> io::Result<()> {
+        let (file, target) = {
+            let log = self.log.lock().unwrap();
+            if log.synced == log.written {
+                return Ok(());
+            }
+            (log.file.try_clone()?, log.written)
+        };
+        let result = file.sync_data();
+
+        match &result {
+            Ok(()) => {
+                let mut log = self.log.lock().unwrap();
+                log.synced = log.synced.max(target);
+                self.synced(target);
+            }
+            Err(err) => {
+                self.queue.lock().unwrap().sync_error = Some(err.to_string());
+                self.notify();
+            }
+        }
+        result
+    }
+
+    // target までの fsync を終えたことを記録する
+    fn synced(&self, target: u64) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.sync_error = None;
+        if self.fsync == Fsync::Always {
+            queue.written = queue.written.max(target);
+        }
+        drop(queue);
+        self.notify();
+    }
+
+    // 書き込みの進み具合が変わったことを、待っているスレッドとタスクに知らせる
+    fn notify(&self) {
+        self.changed.notify_all();
+        self.progress.notify_waiters();
+    }
+}
+
+// 定期的に fsync するタスクを起動する
+// everysec ではこのタスクが fsync し、always では fsync に失敗したときにやり直す
+// タスクは db を弱参照で持ち、db がドロップされたら終了する
+pub fn spawn_fsync_task(db: &ShardedDb) {
+    let db = Arc::downgrade(db);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FSYNC_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let db = match db.upgrade() {
+                Some(db) => db,
+                None => return,
+            };
+            let result = tokio::task::spawn_blocking(move || match db.aof() {
+                Some(aof) => aof.inner.sync(),
+                None => Ok(()),
+            })
+            .await;
+            if let Ok(Err(err)) = result {
+                eprintln!("failed to fsync the AOF: {}", err);
+            }
+        }
+    });
+}
+
+// AOF を読み込んで db を復元し、実行したコマンドの数を返す
+// ファイルがなければ何もしない
+//
+// 最後のコマンド（または最後のトランザクション）が途中で切れていれば、その部分を捨てて
+// ファイルを切り詰める
+pub fn load(db: &ShardedDb, path: &Path) -> crate::Result<usize> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut start = 0;
+    if rdb::is_snapshot(&buf) {
+        let (records, len) = rdb::decode_prefix(&buf)?;
+        rdb::restore(db, records);
+        start = len;
+    }
+
+    let registry = Registry::new();
+    let mut executed = 0;
+    // 最後に読み込みを終えたコマンドの終端
+    let mut valid = start;
+    // MULTI の後に読み込んだコマンド
+    let mut transaction: Option<Vec<Frame>> = None;
+
+    let mut src = Cursor::new(&buf[start..]);
+    loop {
+        let begin = src.position() as usize;
+        if begin == src.get_ref().len() {
+            break;
+        }
+        // Frame::check は配列以外をインラインコマンドとして扱うので、先に形式を確かめる
+        if src.get_ref()[begin] != b'*' {
+            return Err(format!("bad AOF format at byte {}", start + begin).into());
+        }
+        match Frame::check(&mut src) {
+            Ok(()) => {}
+            Err(frame::Error::Incomplete) => break,
+            Err(err) => return Err(err.into()),
+        }
+        src.set_position(begin as u64);
+        let frame = Frame::parse(&mut src)?;
+
+        match (frame.command_name().as_deref(), &mut transaction) {
+            (Some("multi"), None) => transaction = Some(Vec::new()),
+            (Some("exec"), Some(_)) => {
+                for frame in transaction.take().unwrap() {
+                    registry.dispatch(db, frame);
+                    executed += 1;
+                }
+            }
+            (_, Some(queued)) => queued.push(frame),
+            (_, None) => {
+                registry.dispatch(db, frame);
+                executed += 1;
+            }
+        }
+        if transaction.is_none() {
+            valid = start + src.position() as usize;
+        }
+    }
+
+    if valid < buf.len() {
+        eprintln!(
+            "the AOF ends with an incomplete command; truncating {} bytes",
+            buf.len() - valid
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(valid as u64)?;
+    }
+    Ok(executed)
+}
+
+// BGREWRITEAOF
+//
+// その時点の db の内容をスナップショットとして新しいファイルに書き込み、古いファイルと置き換える
+// 書き込んでいる間に実行されたコマンドは、古いファイルと一緒にバッファにも追記しておき、
+// 置き換える直前に新しいファイルの末尾に追記する
+//
+// 呼び出し元は db の gate を（共有ロックでよいので）保持していること
+// AOF に記録している間、書き込みコマンドは排他ロックで実行されるので、
+// バッファを用意してから複製を終えるまでの間に書き込みが割り込むことはない
+pub fn rewrite(db: &ShardedDb) -> io::Result<()> {
+    let aof = db
+        .aof()
+        .ok_or_else(|| io::Error::other("append only file is disabled"))?;
+    {
+        let mut log = aof.inner.log.lock().unwrap();
+        if log.rewrite.is_some() {
+            return Err(io::Error::other(
+                "Background append only file rewriting already in progress",
+            ));
+        }
+        // ここまでに追記したコマンドはスナップショットに含まれる
+        let start = aof.inner.queue.lock().unwrap().appended;
+        log.rewrite = Some(Rewrite {
+            start,
+            buf: Vec::new(),
+        });
+    }
+    let (records, _) = rdb::copy(db);
+
+    let db = db.clone();
+    tokio::task::spawn_blocking(move || {
+        let aof = db.aof().unwrap();
+        if let Err(err) = finish_rewrite(&aof.inner, &records) {
+            eprintln!("background AOF rewrite failed: {}", err);
+            aof.inner.log.lock().unwrap().rewrite = None;
+        }
+    });
+    Ok(())
+}
+
+// 書き直したファイルを書き込み、古いファイルと置き換える
+fn finish_rewrite(aof: &Inner, records: &[rdb::Record]) -> io::Result<()> {
+    let mut tmp = aof.path.as_os_str().to_owned();
+    tmp.push(".rewrite");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&rdb::encode(records))?;
+
+    // 置き換えるまでの間に書き込まれないように、ロックを保持したまま残りのコマンドを書き込む
+    // start より後に追記したコマンドのうち、書き込みスレッドがまだ書き込んでいないものは、
+    // 置き換えた後に新しいファイルに書き込まれる
+    let mut log = aof.log.lock().unwrap();
+    file.write_all(&log.rewrite.as_ref().unwrap().buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp, &aof.path)?;
+    log.file = OpenOptions::new().append(true).open(&aof.path)?;
+    log.rewrite = None;
+    log.synced = log.written;
+    aof.synced(log.written);
+    drop(log);
+
+    // 置き換えたことがディスクに残るように、ディレクトリも fsync する
+    // 失敗しても、古いファイルには書き直す前のコマンドがすべて残っている
+    sync_dir(&aof.path)
+}
+
+// path を含むディレクトリを fsync する
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
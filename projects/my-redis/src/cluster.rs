@@ -0,0 +1,596 @@
+// クラスタモード
+//
+// 複数の my-redis のプロセス（ノード）で、16384 個のハッシュスロットを分担する
+// キーのスロットは CRC16(key) mod 16384 で決まる
+// キーに {...} が含まれていれば、その中身（ハッシュタグ）だけからスロットを計算するので、
+// 同じハッシュタグを持つキーは同じスロットに入り、複数のキーを扱うコマンドでまとめて扱える
+//
+// 担当していないスロットのキーを受け取ったら、担当しているノードを -MOVED で知らせる
+// スロットを移行している間は、移行元にないキーを -ASK で移行先に案内する
+// 案内されたクライアントは、移行先に ASKING を送ってからコマンドを送り直す
+//
+// 各ノードは知っているノードに CLUSTER NODES を定期的に問い合わせ、
+// それぞれのノードが自分で担当していると答えたスロットを覚える（ゴシップ）
+// 問い合わせた結果に知らないノードが含まれていれば、そのノードにも問い合わせる
+// そのため CLUSTER MEET でどれか一つのノードと知り合えば、やがてクラスタ全体を知ることになる
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::net::TcpStream;
+
+use crate::cmd::CommandError;
+use crate::db::{get_db_from_sharded_db, ShardedDb};
+use crate::frame::Frame;
+use crate::{Connection, Result};
+
+// ハッシュスロットの数
+pub const SLOTS: usize = 16384;
+
+// 他のノードに問い合わせる間隔
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(100);
+
+// 問い合わせに応答がなければ、接続を切って次の回に接続し直す
+const GOSSIP_TIMEOUT: Duration = Duration::from_secs(1);
+
+// キーのハッシュスロット
+//
+// キーに { があり、その後に空でない } までの部分があれば、その部分だけからスロットを計算する
+pub fn key_slot(key: &[u8]) -> u16 {
+    let tagged = key.iter().position(|&b| b == b'{').and_then(|start| {
+        let rest = &key[start + 1..];
+        match rest.iter().position(|&b| b == b'}') {
+            Some(0) | None => None,
+            Some(end) => Some(&rest[..end]),
+        }
+    });
+    crc16(tagged.unwrap_or(key)) % SLOTS as u16
+}
+
+// CRC16（XMODEM: 多項式 0x1021、初期値 0）
+fn crc16(buf: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in buf {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// このノードから見たクラスタの状態
+#[derive(Debug)]
+pub struct Cluster {
+    myself: String,
+    addr: SocketAddr,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    // このノード以外の、ID がわかっているノード
+    nodes: HashMap<String, Node>,
+    // CLUSTER MEET などで知ったが、まだ ID を問い合わせていないノード
+    meet: HashSet<SocketAddr>,
+    // スロットを担当しているノードの ID
+    slots: Vec<Option<String>>,
+    // このノードから移行中のスロットと、移行先のノードの ID
+    migrating: HashMap<u16, String>,
+    // このノードへ移行中のスロットと、移行元のノードの ID
+    importing: HashMap<u16, String>,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    addr: SocketAddr,
+    // 最後の問い合わせに応答したか
+    connected: bool,
+}
+
+impl Cluster {
+    // addr は他のノードやクライアントがこのノードに接続するアドレス
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            myself: new_node_id(),
+            addr,
+            state: Mutex::new(State {
+                nodes: HashMap::new(),
+                meet: HashSet::new(),
+                slots: vec![None; SLOTS],
+                migrating: HashMap::new(),
+                importing: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn myself(&self) -> &str {
+        &self.myself
+    }
+
+    // CLUSTER MEET ip port
+    // 次の問い合わせで ID を尋ね、このノードのことも知らせる
+    pub fn meet(&self, addr: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        let known = addr == self.addr || state.nodes.values().any(|node| node.addr == addr);
+        if !known {
+            state.meet.insert(addr);
+        }
+    }
+
+    // 問い合わせる先のノード
+    fn peers(&self) -> Vec<SocketAddr> {
+        let state = self.state.lock().unwrap();
+        let mut peers: Vec<SocketAddr> = state.nodes.values().map(|node| node.addr).collect();
+        peers.extend(state.meet.iter().copied());
+        peers
+    }
+
+    // スロットの担当をこのノードに割り当てる（CLUSTER ADDSLOTS）
+    pub fn add_slots(&self, slots: &[u16]) -> std::result::Result<(), CommandError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(slot) = slots
+            .iter()
+            .find(|&&slot| state.slots[slot as usize].is_some())
+        {
+            return Err(CommandError::new(format!(
+                "ERR Slot {} is already busy",
+                slot
+            )));
+        }
+        for &slot in slots {
+            state.slots[slot as usize] = Some(self.myself.clone());
+        }
+        Ok(())
+    }
+
+    // スロットの担当を取り消す（CLUSTER DELSLOTS）
+    pub fn del_slots(&self, slots: &[u16]) -> std::result::Result<(), CommandError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(slot) = slots
+            .iter()
+            .find(|&&slot| state.slots[slot as usize].is_none())
+        {
+            return Err(CommandError::new(format!(
+                "ERR Slot {} is already unassigned",
+                slot
+            )));
+        }
+        for &slot in slots {
+            state.slots[slot as usize] = None;
+            state.migrating.remove(&slot);
+            state.importing.remove(&slot);
+        }
+        Ok(())
+    }
+
+    // CLUSTER SETSLOT slot MIGRATING node-id
+    pub fn set_migrating(&self, slot: u16, node: &str) -> std::result::Result<(), CommandError> {
+        let mut state = self.state.lock().unwrap();
+        if state.slots[slot as usize].as_deref() != Some(self.myself.as_str()) {
+            return Err(CommandError::new(format!(
+                "ERR I'm not the owner of hash slot {}",
+                slot
+            )));
+        }
+        if !state.nodes.contains_key(node) {
+            return Err(unknown_node(node));
+        }
+        state.migrating.insert(slot, node.to_string());
+        Ok(())
+    }
+
+    // CLUSTER SETSLOT slot IMPORTING node-id
+    pub fn set_importing(&self, slot: u16, node: &str) -> std::result::Result<(), CommandError> {
+        let mut state = self.state.lock().unwrap();
+        if state.slots[slot as usize].as_deref() == Some(self.myself.as_str()) {
+            return Err(CommandError::new(format!(
+                "ERR I'm already the owner of hash slot {}",
+                slot
+            )));
+        }
+        if !state.nodes.contains_key(node) {
+            return Err(unknown_node(node));
+        }
+        state.importing.insert(slot, node.to_string());
+        Ok(())
+    }
+
+    // CLUSTER SETSLOT slot STABLE
+    pub fn set_stable(&self, slot: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.migrating.remove(&slot);
+        state.importing.remove(&slot);
+    }
+
+    // CLUSTER SETSLOT slot NODE node-id
+    //
+    // 移行を終えたスロットの担当を書き換える
+    // 移行元と移行先の両方で実行すれば、他のノードもゴシップで新しい担当を知る
+    pub fn set_node(
+        &self,
+        db: &ShardedDb,
+        slot: u16,
+        node: &str,
+    ) -> std::result::Result<(), CommandError> {
+        let mut state = self.state.lock().unwrap();
+        if node != self.myself && !state.nodes.contains_key(node) {
+            return Err(unknown_node(node));
+        }
+        let owned = state.slots[slot as usize].as_deref() == Some(self.myself.as_str());
+        if owned && node != self.myself && count_keys_in_slot(db, slot) > 0 {
+            return Err(CommandError::new(format!(
+                "ERR Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                slot
+            )));
+        }
+        state.slots[slot as usize] = Some(node.to_string());
+        state.migrating.remove(&slot);
+        if node == self.myself {
+            state.importing.remove(&slot);
+        }
+        Ok(())
+    }
+
+    // CLUSTER NODES
+    //
+    // <id> <ip:port@cport> <flags> <master> <ping-sent> <pong-recv> <config-epoch> <link-state> <slot> ...
+    // このノードの行には、移行中のスロットを [slot->-id] と [slot-<-id] で加える
+    pub fn nodes(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+
+        let mut line = node_line(&self.myself, self.addr, "myself,master", true, &state);
+        let mut migrating: Vec<_> = state.migrating.iter().collect();
+        migrating.sort();
+        for (slot, node) in migrating {
+            let _ = write!(line, " [{}->-{}]", slot, node);
+        }
+        let mut importing: Vec<_> = state.importing.iter().collect();
+        importing.sort();
+        for (slot, node) in importing {
+            let _ = write!(line, " [{}-<-{}]", slot, node);
+        }
+        out.push_str(&line);
+        out.push('\n');
+
+        let mut others: Vec<_> = state.nodes.iter().collect();
+        others.sort_by_key(|(id, _)| *id);
+        for (id, node) in others {
+            out.push_str(&node_line(id, node.addr, "master", node.connected, &state));
+            out.push('\n');
+        }
+        out
+    }
+
+    // CLUSTER SLOTS
+    // 連続したスロットごとに [start, end, [ip, port, id]] を返す
+    pub fn slots(&self) -> Frame {
+        let state = self.state.lock().unwrap();
+        let ranges = slot_ranges(&state.slots)
+            .into_iter()
+            .filter_map(|(start, end, owner)| {
+                let addr = if owner == self.myself {
+                    self.addr
+                } else {
+                    state.nodes.get(owner)?.addr
+                };
+                Some(Frame::Array(vec![
+                    Frame::Integer(start as i64),
+                    Frame::Integer(end as i64),
+                    Frame::Array(vec![
+                        Frame::Bulk(Bytes::from(addr.ip().to_string())),
+                        Frame::Integer(addr.port() as i64),
+                        Frame::Bulk(Bytes::from(owner.to_string())),
+                    ]),
+                ]))
+            })
+            .collect();
+        Frame::Array(ranges)
+    }
+
+    // CLUSTER INFO
+    pub fn info(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let assigned = state.slots.iter().filter(|owner| owner.is_some()).count();
+        let size = state.slots.iter().flatten().collect::<HashSet<_>>().len();
+        format!(
+            "cluster_enabled:1\r\ncluster_state:{}\r\ncluster_slots_assigned:{}\r\ncluster_known_nodes:{}\r\ncluster_size:{}\r\ncluster_my_id:{}\r\n",
+            if assigned == SLOTS { "ok" } else { "fail" },
+            assigned,
+            state.nodes.len() + 1,
+            size,
+            self.myself
+        )
+    }
+
+    // 問い合わせたノードの CLUSTER NODES の結果を取り込む
+    //
+    // 問い合わせたノード自身の行（myself）からは、そのノードが担当しているスロットを知る
+    // 他のノードの行からは、まだ知らないノードを知る
+    fn apply_gossip(&self, from: SocketAddr, nodes: &str) {
+        let mut state = self.state.lock().unwrap();
+        for line in nodes.lines() {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() < 8 || parts[0] == self.myself {
+                continue;
+            }
+            let id = parts[0];
+            let addr = match parts[1].split('@').next().and_then(|a| a.parse().ok()) {
+                Some(addr) => addr,
+                None => continue,
+            };
+
+            if !parts[2].split(',').any(|flag| flag == "myself") {
+                if !state.nodes.contains_key(id) && addr != self.addr {
+                    state.meet.insert(addr);
+                }
+                continue;
+            }
+
+            state.meet.remove(&from);
+            state.meet.remove(&addr);
+            state.nodes.insert(
+                id.to_string(),
+                Node {
+                    addr: from,
+                    connected: true,
+                },
+            );
+            let mut claimed = vec![false; SLOTS];
+            for range in &parts[8..] {
+                if let Some((start, end)) = parse_slot_range(range) {
+                    claimed[start as usize..=end as usize].fill(true);
+                }
+            }
+            for (slot, claimed) in claimed.into_iter().enumerate() {
+                let owner = state.slots[slot].as_deref();
+                if claimed && owner != Some(self.myself.as_str()) {
+                    state.slots[slot] = Some(id.to_string());
+                } else if !claimed && owner == Some(id) {
+                    state.slots[slot] = None;
+                }
+            }
+        }
+    }
+
+    // 問い合わせに応答しなかったノードを記録する
+    fn disconnected(&self, addr: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        for node in state.nodes.values_mut() {
+            if node.addr == addr {
+                node.connected = false;
+            }
+        }
+    }
+}
+
+// キーを担当しているのがこのノードかどうかを調べる
+//
+// キーがすべて同じスロットに入っていなければ -CROSSSLOT、
+// 他のノードが担当していれば -MOVED、移行中で移行元にキーがなければ -ASK のエラーを返す
+// asking は、直前に ASKING を受け取ったか
+pub fn route(
+    db: &ShardedDb,
+    keys: &[Bytes],
+    asking: bool,
+) -> std::result::Result<(), CommandError> {
+    let cluster = match db.cluster() {
+        Some(cluster) => cluster,
+        None => return Ok(()),
+    };
+    let slot = match keys.first() {
+        Some(key) => key_slot(key),
+        None => return Ok(()),
+    };
+    if keys.iter().any(|key| key_slot(key) != slot) {
+        return Err(CommandError::new(
+            "CROSSSLOT Keys in request don't hash to the same slot",
+        ));
+    }
+
+    let state = cluster.state.lock().unwrap();
+    let redirect = |kind: &str, node: &str| match state.nodes.get(node) {
+        Some(node) => CommandError::new(format!("{} {} {}", kind, slot, node.addr)),
+        None => CommandError::new("CLUSTERDOWN Hash slot not served"),
+    };
+    match state.slots[slot as usize].as_deref() {
+        Some(owner) if owner == cluster.myself => match state.migrating.get(&slot) {
+            Some(target) => {
+                let missing = keys.iter().filter(|key| !exists(db, key)).count();
+                if missing == 0 {
+                    Ok(())
+                } else if missing == keys.len() {
+                    Err(redirect("ASK", target))
+                } else {
+                    Err(CommandError::new(
+                        "TRYAGAIN Multiple keys request during rehashing of slot",
+                    ))
+                }
+            }
+            None => Ok(()),
+        },
+        _ if asking && state.importing.contains_key(&slot) => Ok(()),
+        Some(owner) => Err(redirect("MOVED", owner)),
+        None => Err(CommandError::new("CLUSTERDOWN Hash slot not served")),
+    }
+}
+
+fn exists(db: &ShardedDb, key: &Bytes) -> bool {
+    let key = String::from_utf8_lossy(key);
+    let shard = get_db_from_sharded_db(db, &key);
+    let exists = shard.lock().unwrap().peek(&key).is_some();
+    exists
+}
+
+// スロットに入っているキーを count 個まで返す（CLUSTER GETKEYSINSLOT）
+pub fn keys_in_slot(db: &ShardedDb, slot: u16, count: usize) -> Vec<String> {
+    let mut keys = Vec::new();
+    for shard in db.iter() {
+        let shard = shard.lock().unwrap();
+        keys.extend(
+            shard
+                .iter()
+                .map(|(key, _)| key)
+                .filter(|key| key_slot(key.as_bytes()) == slot)
+                .take(count - keys.len())
+                .cloned(),
+        );
+        if keys.len() >= count {
+            break;
+        }
+    }
+    keys
+}
+
+// スロットに入っているキーの数（CLUSTER COUNTKEYSINSLOT）
+pub fn count_keys_in_slot(db: &ShardedDb, slot: u16) -> usize {
+    db.iter()
+        .map(|shard| {
+            let shard = shard.lock().unwrap();
+            let count = shard
+                .iter()
+                .filter(|(key, _)| key_slot(key.as_bytes()) == slot)
+                .count();
+            count
+        })
+        .sum()
+}
+
+// 他のノードに定期的に問い合わせるタスクを起動する
+// タスクは db を弱参照で持ち、db がドロップされたら終了する
+pub fn spawn_gossip_task(db: &ShardedDb) {
+    let db = Arc::downgrade(db);
+    tokio::spawn(async move {
+        // 問い合わせ先ごとの接続は、切れるまで使い回す
+        let mut links: HashMap<SocketAddr, Connection> = HashMap::new();
+        let mut interval = tokio::time::interval(GOSSIP_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let db = match db.upgrade() {
+                Some(db) => db,
+                None => return,
+            };
+            let cluster = match db.cluster() {
+                Some(cluster) => cluster,
+                None => return,
+            };
+            for addr in cluster.peers() {
+                let gossip =
+                    tokio::time::timeout(GOSSIP_TIMEOUT, gossip(&mut links, cluster, addr));
+                if !matches!(gossip.await, Ok(Ok(()))) {
+                    links.remove(&addr);
+                    cluster.disconnected(addr);
+                }
+            }
+        }
+    });
+}
+
+// ノードに CLUSTER NODES を問い合わせる
+// 初めて接続したときは、CLUSTER MEET でこのノードのことも知らせる
+async fn gossip(
+    links: &mut HashMap<SocketAddr, Connection>,
+    cluster: &Cluster,
+    addr: SocketAddr,
+) -> Result<()> {
+    let connection = match links.entry(addr) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let mut connection = Connection::new(TcpStream::connect(addr).await?);
+            let meet = Frame::command([
+                "CLUSTER".to_string(),
+                "MEET".to_string(),
+                cluster.addr.ip().to_string(),
+                cluster.addr.port().to_string(),
+            ]);
+            connection.write_frame(&meet).await?;
+            connection.read_frame().await?;
+            entry.insert(connection)
+        }
+    };
+    connection
+        .write_frame(&Frame::command(["CLUSTER", "NODES"]))
+        .await?;
+    match connection.read_frame().await? {
+        Some(Frame::Bulk(nodes)) => {
+            cluster.apply_gossip(addr, &String::from_utf8_lossy(&nodes));
+            Ok(())
+        }
+        other => Err(format!("unexpected reply to CLUSTER NODES: {:?}", other).into()),
+    }
+}
+
+// CLUSTER NODES の 1 行
+fn node_line(id: &str, addr: SocketAddr, flags: &str, connected: bool, state: &State) -> String {
+    let mut line = format!(
+        "{} {}:{}@{} {} - 0 0 0 {}",
+        id,
+        addr.ip(),
+        addr.port(),
+        addr.port() as u32 + 10000,
+        flags,
+        if connected {
+            "connected"
+        } else {
+            "disconnected"
+        }
+    );
+    for (start, end, owner) in slot_ranges(&state.slots) {
+        if owner != id {
+            continue;
+        }
+        if start == end {
+            let _ = write!(line, " {}", start);
+        } else {
+            let _ = write!(line, " {}-{}", start, end);
+        }
+    }
+    line
+}
+
+// 同じノードが担当している連続したスロットを (start, end, ノードの ID) にまとめる
+fn slot_ranges(slots: &[Option<String>]) -> Vec<(u16, u16, &str)> {
+    let mut ranges: Vec<(u16, u16, &str)> = Vec::new();
+    for (slot, owner) in slots.iter().enumerate() {
+        let owner = match owner {
+            Some(owner) => owner.as_str(),
+            None => continue,
+        };
+        match ranges.last_mut() {
+            Some((_, end, last)) if *last == owner && *end as usize + 1 == slot => {
+                *end = slot as u16;
+            }
+            _ => ranges.push((slot as u16, slot as u16, owner)),
+        }
+    }
+    ranges
+}
+
+// CLUSTER NODES のスロットの指定（"5" や "0-5460"）を解釈する
+// 移行中のスロット（[...]）は無視する
+fn parse_slot_range(range: &str) -> Option<(u16, u16)> {
+    let (start, end) = range.split_once('-').unwrap_or((range, range));
+    let start: u16 = start.parse().ok()?;
+    let end: u16 = end.parse().ok()?;
+    (start <= end && (end as usize) < SLOTS).then_some((start, end))
+}
+
+fn unknown_node(node: &str) -> CommandError {
+    CommandError::new(format!("ERR I don't know about node {}", node))
+}
+
+// 40 文字の 16 進数からなる、ランダムなノード ID を作る
+fn new_node_id() -> String {
+    crate::replication::random_hex_id()
+}
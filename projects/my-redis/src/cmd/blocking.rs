@@ -0,0 +1,414 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::sync::oneshot;
+
+use super::list::{parse_end, pop, push, read_list};
+use super::{key, CommandError, CommandResult, Keys, Registry};
+use crate::db::{
+    get_db_from_sharded_db, lock_shards, Delivery, End, Shard, ShardedDb, Waiter, WaiterSlot,
+};
+use crate::frame::Frame;
+use crate::propagate;
+
+// BLPOP, BRPOP は最後の引数（タイムアウト）以外がキー
+const KEYS_BEFORE_TIMEOUT: Keys = Keys::Range {
+    first: 1,
+    last: -2,
+    step: 1,
+};
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register_blocking("blpop", -3, blpop, |db, args| {
+        Box::pin(blocking_pop(db, args, End::Left))
+    });
+    registry.register_blocking("brpop", -3, brpop, |db, args| {
+        Box::pin(blocking_pop(db, args, End::Right))
+    });
+    registry.register_blocking("blmove", 6, blmove, |db, args| {
+        Box::pin(blocking_move(db, args))
+    });
+    registry.mark_write("blpop");
+    registry.mark_write("brpop");
+    registry.mark_write("blmove");
+    registry.set_keys("blpop", KEYS_BEFORE_TIMEOUT);
+    registry.set_keys("brpop", KEYS_BEFORE_TIMEOUT);
+    registry.set_keys("blmove", Keys::FIRST_TWO);
+}
+
+// BLPOP key [key ...] timeout（待たずに実行する場合）
+fn blpop(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    try_pop(db, args, End::Left)
+}
+
+// BRPOP key [key ...] timeout（待たずに実行する場合）
+fn brpop(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    try_pop(db, args, End::Right)
+}
+
+// BLMOVE source destination LEFT|RIGHT LEFT|RIGHT timeout（待たずに実行する場合）
+fn blmove(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    parse_timeout(&args[4])?;
+    super::list::lmove(db, &args[..4])
+}
+
+// 指定されたキーを順に調べ、最初に見つかった要素を取り出す
+fn try_pop(db: &ShardedDb, args: &[Bytes], end: End) -> CommandResult {
+    let (keys, timeout) = args.split_at(args.len() - 1);
+    parse_timeout(&timeout[0])?;
+
+    for key in keys.iter().map(key) {
+        let shard = get_db_from_sharded_db(db, &key);
+        let mut shard = shard.lock().unwrap();
+        if let Some(value) = pop(&mut shard, &key, end)? {
+            return Ok(pop_reply(Some((key, value))));
+        }
+    }
+    Ok(pop_reply(None))
+}
+
+// BLPOP key [key ...] timeout
+// BRPOP key [key ...] timeout
+async fn blocking_pop(db: ShardedDb, args: Vec<Bytes>, end: End) -> CommandResult {
+    let (keys, timeout) = args.split_at(args.len() - 1);
+    let timeout = parse_timeout(&timeout[0])?;
+    let keys: Vec<String> = keys.iter().map(key).collect();
+
+    let popped = wait_for_element(&db, &keys, end, None, timeout).await?;
+    Ok(pop_reply(popped))
+}
+
+// BLMOVE source destination LEFT|RIGHT LEFT|RIGHT timeout
+//
+// 取り出しと追加は両方のシャードをロックしたまま続けて行い、AOF とフォロワーには LMOVE として伝える
+// 待っている間に要素が追加された場合も、serve_moves が同じように移動してから要素を渡す
+async fn blocking_move(db: ShardedDb, args: Vec<Bytes>) -> CommandResult {
+    let from = parse_end(&args[2])?;
+    let to = parse_end(&args[3])?;
+    let timeout = parse_timeout(&args[4])?;
+    let src = key(&args[0]);
+    let target = (key(&args[1]), to);
+
+    let moved =
+        wait_for_element(&db, std::slice::from_ref(&src), from, Some(target), timeout).await?;
+    Ok(match moved {
+        Some((_, value)) => Frame::Bulk(value),
+        None => Frame::Null,
+    })
+}
+
+// serve_waiters が後回しにした、BLMOVE で待っているクライアントへの移動を行う
+//
+// 書き込みコマンドを実行した後に、シャードのロックを解放してから（gate は保持したまま）呼び出す
+// 移動で記録した副作用は、すべて伝えてから返る
+pub(super) fn serve_moves(db: &ShardedDb) {
+    loop {
+        let keys: Vec<String> = db
+            .iter()
+            .flat_map(|shard| shard.lock().unwrap().take_moves())
+            .collect();
+        if keys.is_empty() {
+            propagate::effects(db);
+            return;
+        }
+        for key in keys {
+            serve_move(db, &key);
+        }
+    }
+}
+
+// キーの待ち行列の先頭から順に、BLMOVE で待っているクライアントの移動先へ要素を移動する
+// 先頭が BLPOP などで待っているクライアントになったら serve_waiters に任せる
+fn serve_move(db: &ShardedDb, src: &str) {
+    loop {
+        // 移動の前に記録された副作用を先に伝え、伝える順序が実行した順序と入れ替わらないようにする
+        propagate::effects(db);
+
+        // 移動先がわかってから、番号の小さい順に両方のシャードをロックし直す
+        let (id, target) = {
+            let shard = get_db_from_sharded_db(db, src);
+            let mut shard = shard.lock().unwrap();
+            match shard.first_waiter(src) {
+                Some(Waiter {
+                    id,
+                    target: Some(target),
+                    ..
+                }) => (*id, target.clone()),
+                Some(_) => {
+                    shard.serve_waiters(src);
+                    return;
+                }
+                None => return,
+            }
+        };
+
+        let mut locked = lock_shards(db, &[src, &target.0]);
+        let (shard, dst_shard) = locked.pair(src, &target.0);
+        // ロックし直す間に、他のコネクションが先に要素を渡していたらやり直す
+        if shard.first_waiter(src).map(|waiter| waiter.id) != Some(id) {
+            continue;
+        }
+        if !matches!(read_list(shard, src), Ok(Some(list)) if !list.is_empty()) {
+            return;
+        }
+        let waiter = shard.pop_waiter(src).unwrap();
+        let tx = match waiter.slot.take() {
+            Some(tx) => tx,
+            None => continue,
+        };
+        // 移動先がリストでなければ、要素を渡さずに受け取り口を閉じる（BLMOVE はエラーを返す）
+        if let Ok(Some(value)) = move_element(shard, dst_shard, src, waiter.end, &target) {
+            // 受け取る側は WaitGuard が Receiver を持っているので、送れなくても要素は移動先に残る
+            let _ = tx.send((src.to_string(), value));
+        }
+    }
+}
+
+// src_shard のリスト src の端から要素を取り出し、target のリストの端に追加する
+// dst_shard が None なら、移動先も src_shard に属する
+//
+// 移動は一つの LMOVE として記録し、移動先で待っているクライアントに渡した分はその後に記録する
+fn move_element(
+    src_shard: &mut Shard,
+    mut dst_shard: Option<&mut Shard>,
+    src: &str,
+    from: End,
+    (dst, to): &(String, End),
+) -> Result<Option<Bytes>, CommandError> {
+    match &mut dst_shard {
+        Some(shard) => read_list(shard, dst)?,
+        None => read_list(src_shard, dst)?,
+    };
+    let value = match pop(src_shard, src, from)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let dst_shard = dst_shard.unwrap_or(src_shard);
+    dst_shard.record_effect(move_command(src, dst, from, *to));
+    push(
+        dst_shard,
+        dst.clone(),
+        std::slice::from_ref(&value),
+        *to,
+        true,
+    )?;
+    Ok(Some(value))
+}
+
+// タイムアウトの秒数を解釈する
+// 0 は無期限に待つことを表すので None を返す
+fn parse_timeout(arg: &[u8]) -> Result<Option<Duration>, CommandError> {
+    let secs: f64 = std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|secs: &f64| secs.is_finite())
+        .ok_or_else(|| CommandError::new("ERR timeout is not a float or out of range"))?;
+    if secs < 0.0 {
+        return Err(CommandError::new("ERR timeout is negative"));
+    }
+    if secs == 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(secs)
+        .map(Some)
+        .map_err(|_| CommandError::new("ERR timeout is out of range"))
+}
+
+// AOF に記録する、要素を端に追加するコマンド
+fn push_command(key: &str, value: &Bytes, end: End) -> Vec<Bytes> {
+    let name = match end {
+        End::Left => "LPUSH",
+        End::Right => "RPUSH",
+    };
+    vec![
+        Bytes::from(name),
+        Bytes::from(key.to_string()),
+        value.clone(),
+    ]
+}
+
+// AOF に記録する、要素を移動するコマンド
+fn move_command(src: &str, dst: &str, from: End, to: End) -> Vec<Bytes> {
+    let name = |end| match end {
+        End::Left => "LEFT",
+        End::Right => "RIGHT",
+    };
+    vec![
+        Bytes::from("LMOVE"),
+        Bytes::from(src.to_string()),
+        Bytes::from(dst.to_string()),
+        Bytes::from(name(from)),
+        Bytes::from(name(to)),
+    ]
+}
+
+// AOF に記録する、要素を端から取り出すコマンド
+fn pop_command(key: &str, end: End) -> Vec<Bytes> {
+    let name = match end {
+        End::Left => "LPOP",
+        End::Right => "RPOP",
+    };
+    vec![Bytes::from(name), Bytes::from(key.to_string())]
+}
+
+// BLPOP, BRPOP の返り値
+fn pop_reply(popped: Option<Delivery>) -> Frame {
+    match popped {
+        Some((key, value)) => Frame::Array(vec![Frame::Bulk(Bytes::from(key)), Frame::Bulk(value)]),
+        None => Frame::Null,
+    }
+}
+
+// キーのいずれかに要素が現れるまで待ち、現れたら端から取り出す
+//
+// すでに要素のあるキーがあれば、待たずにそのキーから取り出す
+// そうでなければ各キーの待ち行列に並び、他のコネクションが要素を追加したときに
+// 並んだ順に一人ずつ要素を受け取る
+// target を指定すると（BLMOVE）、取り出した要素をそのキーの端に追加してから返す
+// タイムアウトしたら None を返す
+async fn wait_for_element(
+    db: &ShardedDb,
+    keys: &[String],
+    end: End,
+    target: Option<(String, End)>,
+    timeout: Option<Duration>,
+) -> Result<Option<Delivery>, CommandError> {
+    let (tx, rx) = oneshot::channel();
+    let slot = Arc::new(WaiterSlot::new(tx));
+    let waiter = Waiter::new(end, target, slot.clone());
+
+    // この Future が途中でドロップされても（タイムアウトやクライアントの切断など）、
+    // ガードがすべての待ち行列から登録を取り除く
+    let mut guard = WaitGuard {
+        db,
+        keys: Vec::new(),
+        waiter: waiter.clone(),
+        rx,
+    };
+
+    // 登録を終えるまでは EXEC の途中の状態を見ないようにする
+    // ゲートを保持したまま .await しないように、ブロックの中で解放する
+    let ready = {
+        let _gate = db.write_gate();
+        let ready = pop_or_register(db, keys, &waiter, &mut guard);
+        propagate::effects(db);
+        serve_moves(db);
+        ready?
+    };
+    if let Some(popped) = ready {
+        return Ok(popped);
+    }
+
+    let rx = &mut guard.rx;
+    let received = match timeout {
+        None => rx.await,
+        Some(timeout) => match tokio::time::timeout(timeout, &mut *rx).await {
+            Ok(received) => received,
+            // タイムアウトと同時に要素が渡されていたら、その要素を返す
+            Err(_) => match slot.take() {
+                Some(_) => return Ok(None),
+                None => rx.await,
+            },
+        },
+    };
+    // 要素を渡さずに受け取り口が閉じられるのは、BLMOVE の移動先がリストでなかったときだけ
+    received.map(Some).map_err(|_| CommandError::wrong_type())
+}
+
+// すでに要素のあるキーがあれば取り出し、なければ各キーの待ち行列に登録する
+// 待たずに結果が決まった場合は Some を返す
+fn pop_or_register(
+    db: &ShardedDb,
+    keys: &[String],
+    waiter: &Waiter,
+    guard: &mut WaitGuard<'_>,
+) -> Result<Option<Option<Delivery>>, CommandError> {
+    for key in keys {
+        let dst = waiter.target.as_ref().map_or(key.as_str(), |(dst, _)| dst);
+        let mut locked = lock_shards(db, &[key.as_str(), dst]);
+        let (shard, mut dst_shard) = locked.pair(key, dst);
+
+        // 待つ前に、移動先がリストでなければエラーにする
+        if let Some((dst, _)) = &waiter.target {
+            match &mut dst_shard {
+                Some(dst_shard) => read_list(dst_shard, dst)?,
+                None => read_list(shard, dst)?,
+            };
+        }
+        let available = match read_list(shard, key) {
+            Ok(list) => matches!(list, Some(list) if !list.is_empty()),
+            // 先に登録したキーですでに要素を受け取っていたら、そちらを返す
+            Err(err) => match waiter.slot.take() {
+                Some(_) => return Err(err),
+                None => return Ok(Some(guard.rx.try_recv().ok())),
+            },
+        };
+        if available {
+            match waiter.slot.take() {
+                Some(_) => {
+                    let popped = match &waiter.target {
+                        Some(target) => move_element(shard, dst_shard, key, waiter.end, target)?,
+                        None => {
+                            let popped = pop(shard, key, waiter.end)?;
+                            if popped.is_some() {
+                                shard.record_effect(pop_command(key, waiter.end));
+                            }
+                            popped
+                        }
+                    };
+                    return Ok(Some(popped.map(|value| (key.clone(), value))));
+                }
+                None => break,
+            }
+        }
+
+        shard.add_waiter(key, waiter.clone());
+        guard.keys.push(key.clone());
+    }
+    Ok(None)
+}
+
+// 待ち行列への登録を、待つのをやめたときに取り除くためのガード
+//
+// 要素を受け取った後、それを返す前に Future がドロップされたら、その要素をキーの元の端に戻す
+// BLMOVE の要素はすでに移動先に追加されているので、そのままにする
+struct WaitGuard<'a> {
+    db: &'a ShardedDb,
+    keys: Vec<String>,
+    waiter: Waiter,
+    rx: oneshot::Receiver<Delivery>,
+}
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        for key in &self.keys {
+            let shard = get_db_from_sharded_db(self.db, key);
+            shard.lock().unwrap().remove_waiter(key, self.waiter.id);
+        }
+        // 待ち行列から取り除いた後は、新しく要素が渡されることはない
+        if self.waiter.slot.take().is_some() || self.waiter.target.is_some() {
+            return;
+        }
+        if let Ok((key, value)) = self.rx.try_recv() {
+            restore(self.db, key, value, self.waiter.end);
+        }
+    }
+}
+
+// 受け取ったが返せなかった要素を、取り出したキーの端に戻す
+fn restore(db: &ShardedDb, key: String, value: Bytes, end: End) {
+    let _gate = db.write_gate();
+    {
+        let shard = get_db_from_sharded_db(db, &key);
+        let mut shard = shard.lock().unwrap();
+        // 取り出した後にリスト以外の値に置き換えられていたら、戻す先がない
+        if read_list(&mut shard, &key).is_err() {
+            return;
+        }
+        shard.record_effect(push_command(&key, &value, end));
+        push(&mut shard, key, std::slice::from_ref(&value), end, true).ok();
+    }
+    propagate::effects(db);
+    serve_moves(db);
+}
@@ -0,0 +1,433 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+use super::{eq_ignore_case, key, parse_int, CommandError, CommandResult, Keys, Registry};
+use crate::cluster::{self, Cluster, SLOTS};
+use crate::db::{get_db_from_sharded_db, ShardedDb, Value};
+use crate::frame::Frame;
+use crate::rdb::{self, Record};
+use crate::{propagate, Connection};
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register("cluster", -2, cluster);
+    registry.register("dump", 2, dump);
+    registry.register_write("restore", -4, restore);
+    registry.mark_denyoom("restore");
+    registry.register_blocking("migrate", -6, migrate_now, |db, args| {
+        Box::pin(migrate(db, args))
+    });
+    registry.mark_write("migrate");
+    registry.set_keys("cluster", Keys::None);
+    // MIGRATE は移行中のスロットのキーを送るためのコマンドなので、リダイレクトの対象にしない
+    registry.set_keys("migrate", Keys::None);
+}
+
+// CLUSTER subcommand [argument ...]
+fn cluster(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let sub = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    let args = &args[1..];
+
+    // KEYSLOT はクラスタモードでなくても使える
+    if sub == "keyslot" {
+        expect_args(&sub, args, 1)?;
+        return Ok(Frame::Integer(cluster::key_slot(&args[0]) as i64));
+    }
+    let cluster = db
+        .cluster()
+        .ok_or_else(|| CommandError::new("ERR This instance has cluster support disabled"))?;
+
+    match sub.as_str() {
+        "myid" => {
+            expect_args(&sub, args, 0)?;
+            Ok(bulk(cluster.myself()))
+        }
+        "info" => {
+            expect_args(&sub, args, 0)?;
+            Ok(bulk(&cluster.info()))
+        }
+        "nodes" => {
+            expect_args(&sub, args, 0)?;
+            Ok(bulk(&cluster.nodes()))
+        }
+        "slots" => {
+            expect_args(&sub, args, 0)?;
+            Ok(cluster.slots())
+        }
+        "meet" => {
+            expect_args(&sub, args, 2)?;
+            cluster.meet(parse_addr(&args[0], &args[1])?);
+            Ok(ok())
+        }
+        "addslots" | "delslots" => {
+            if args.is_empty() {
+                return Err(wrong_subcommand_arity(&sub));
+            }
+            let slots = args
+                .iter()
+                .map(|arg| parse_slot(arg))
+                .collect::<Result<Vec<_>, _>>()?;
+            assign(cluster, &sub, &slots)
+        }
+        "addslotsrange" | "delslotsrange" => {
+            if args.is_empty() || !args.len().is_multiple_of(2) {
+                return Err(wrong_subcommand_arity(&sub));
+            }
+            let mut slots = Vec::new();
+            for range in args.chunks(2) {
+                let (start, end) = (parse_slot(&range[0])?, parse_slot(&range[1])?);
+                if start > end {
+                    return Err(CommandError::new(format!(
+                        "ERR start slot number {} is greater than end slot number {}",
+                        start, end
+                    )));
+                }
+                slots.extend(start..=end);
+            }
+            assign(cluster, &sub, &slots)
+        }
+        "countkeysinslot" => {
+            expect_args(&sub, args, 1)?;
+            let slot = parse_slot(&args[0])?;
+            Ok(Frame::Integer(cluster::count_keys_in_slot(db, slot) as i64))
+        }
+        "getkeysinslot" => {
+            expect_args(&sub, args, 2)?;
+            let slot = parse_slot(&args[0])?;
+            let count = parse_int(&args[1])?;
+            if count < 0 {
+                return Err(CommandError::new("ERR Invalid number of keys"));
+            }
+            let keys = cluster::keys_in_slot(db, slot, count as usize);
+            Ok(Frame::Array(keys.iter().map(|key| bulk(key)).collect()))
+        }
+        "setslot" => setslot(db, cluster, args),
+        _ => Err(CommandError::new(format!(
+            "ERR unknown subcommand '{}'. Try CLUSTER HELP.",
+            sub
+        ))),
+    }
+}
+
+// CLUSTER SETSLOT slot IMPORTING|MIGRATING|NODE node-id
+// CLUSTER SETSLOT slot STABLE
+fn setslot(db: &ShardedDb, cluster: &Cluster, args: &[Bytes]) -> CommandResult {
+    if args.len() < 2 {
+        return Err(wrong_subcommand_arity("setslot"));
+    }
+    let slot = parse_slot(&args[0])?;
+    let action = String::from_utf8_lossy(&args[1]).to_ascii_lowercase();
+    let node = match (action.as_str(), &args[2..]) {
+        ("stable", []) => {
+            cluster.set_stable(slot);
+            return Ok(ok());
+        }
+        ("importing" | "migrating" | "node", [node]) => key(node),
+        _ => return Err(CommandError::syntax()),
+    };
+    match action.as_str() {
+        "importing" => cluster.set_importing(slot, &node)?,
+        "migrating" => cluster.set_migrating(slot, &node)?,
+        _ => cluster.set_node(db, slot, &node)?,
+    }
+    Ok(ok())
+}
+
+fn assign(cluster: &Cluster, sub: &str, slots: &[u16]) -> CommandResult {
+    if sub.starts_with("add") {
+        cluster.add_slots(slots)?;
+    } else {
+        cluster.del_slots(slots)?;
+    }
+    Ok(ok())
+}
+
+// DUMP key
+//
+// 値をスナップショットと同じ形式にした、RESTORE で復元できるバイト列を返す
+// 有効期限は含まない
+fn dump(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+    let record = match shard.get(&key) {
+        Some(entry) => Record {
+            key,
+            value: entry.value.clone(),
+            expires_at_ms: None,
+        },
+        None => return Ok(Frame::Null),
+    };
+    Ok(Frame::Bulk(Bytes::from(rdb::encode(&[record]))))
+}
+
+// RESTORE key ttl serialized-value [REPLACE] [ABSTTL]
+//
+// ttl はミリ秒で、0 なら有効期限なし（ABSTTL なら UNIX 時刻のミリ秒）
+fn restore(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let ttl = parse_int(&args[1])?;
+    if ttl < 0 {
+        return Err(CommandError::new("ERR Invalid TTL value, must be >= 0"));
+    }
+    let mut replace = false;
+    let mut absolute = false;
+    for option in &args[3..] {
+        if eq_ignore_case(option, "replace") {
+            replace = true;
+        } else if eq_ignore_case(option, "absttl") {
+            absolute = true;
+        } else {
+            return Err(CommandError::syntax());
+        }
+    }
+    let value = decode_payload(&args[2])?;
+
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+    if !replace && shard.get(&key).is_some() {
+        return Err(CommandError::new("BUSYKEY Target key name already exists."));
+    }
+    let expires_at = match (ttl, absolute) {
+        (0, _) => None,
+        (at, true) => {
+            let now = super::keys::unix_millis_now();
+            Some(Instant::now() + Duration::from_millis(at.saturating_sub(now).max(0) as u64))
+        }
+        (ttl, false) => Some(Instant::now() + Duration::from_millis(ttl as u64)),
+    };
+    shard.insert(key.clone(), value, expires_at);
+    shard.serve_waiters(&key);
+    Ok(ok())
+}
+
+// DUMP が返したバイト列から値を取り出す
+fn decode_payload(payload: &[u8]) -> Result<Value, CommandError> {
+    match rdb::decode(payload).map(|records| records.into_iter().next()) {
+        Ok(Some(record)) => Ok(record.value),
+        _ => Err(CommandError::new(
+            "ERR DUMP payload version or checksum are wrong",
+        )),
+    }
+}
+
+// MIGRATE の引数
+struct MigrateRequest {
+    addr: SocketAddr,
+    keys: Vec<String>,
+    timeout: Duration,
+    copy: bool,
+    replace: bool,
+}
+
+impl MigrateRequest {
+    // host port key|"" destination-db timeout [COPY] [REPLACE] [KEYS key [key ...]]
+    fn parse(args: &[Bytes]) -> Result<Self, CommandError> {
+        let addr = parse_addr(&args[0], &args[1])?;
+        if parse_int(&args[3])? != 0 {
+            return Err(CommandError::new("ERR DB index is out of range"));
+        }
+        let timeout = parse_int(&args[4])?;
+        if timeout < 0 {
+            return Err(CommandError::new("ERR timeout is negative"));
+        }
+
+        let mut request = MigrateRequest {
+            addr,
+            keys: Vec::new(),
+            timeout: Duration::from_millis(timeout.max(1) as u64),
+            copy: false,
+            replace: false,
+        };
+        let mut options = args[5..].iter();
+        while let Some(option) = options.next() {
+            if eq_ignore_case(option, "copy") {
+                request.copy = true;
+            } else if eq_ignore_case(option, "replace") {
+                request.replace = true;
+            } else if eq_ignore_case(option, "keys") {
+                // KEYS を使うときは、key の位置は空文字列でなければならない
+                if !args[2].is_empty() {
+                    return Err(CommandError::new(
+                        "ERR When using MIGRATE KEYS option, the key argument must be set to the empty string",
+                    ));
+                }
+                request.keys = options.by_ref().map(key).collect();
+            } else {
+                return Err(CommandError::syntax());
+            }
+        }
+        if !args[2].is_empty() {
+            request.keys.push(key(&args[2]));
+        }
+        Ok(request)
+    }
+}
+
+// MIGRATE（待たずに実行する場合）
+//
+// 他のノードとの通信を待つので、MULTI の中などでは実行できない
+fn migrate_now(_db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    MigrateRequest::parse(args)?;
+    Err(CommandError::new(
+        "ERR MIGRATE can't be used inside a transaction",
+    ))
+}
+
+// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE] [KEYS key [key ...]]
+//
+// キーを DUMP と同じ形式で移行先に送り、移行先で RESTORE させてから、このノードから削除する
+// 移行先では、ASKING を送ってから RESTORE することで、移行中のスロットのキーとして受け付けさせる
+//
+// 送っている間も他のコネクションはキーを変更できるので、送る前にキーを WATCH しておき、
+// 送った後で変更されていたキーは削除せずにエラーを返す（もう一度 MIGRATE すれば送り直せる）
+async fn migrate(db: ShardedDb, args: Vec<Bytes>) -> CommandResult {
+    let request = MigrateRequest::parse(&args)?;
+
+    let mut found = Vec::new();
+    {
+        let _gate = db.shared();
+        for key in &request.keys {
+            let shard = get_db_from_sharded_db(&db, key);
+            let mut shard = shard.lock().unwrap();
+            // 送るだけなので、LRU や LFU のアクセスとしては数えない
+            let (value, expires_at) = match shard.peek(key) {
+                Some(entry) => (entry.value.clone(), entry.expires_at()),
+                None => continue,
+            };
+            let version = shard.watch(key);
+            found.push((key.clone(), value, expires_at, version));
+        }
+    }
+    if found.is_empty() {
+        return Ok(Frame::Simple("NOKEY".to_string()));
+    }
+
+    let sent = tokio::time::timeout(request.timeout, send(&request, &found)).await;
+
+    let _gate = db.write_gate();
+    let mut modified = false;
+    let mut deleted = Vec::new();
+    for (key, _, _, version) in &found {
+        let shard = get_db_from_sharded_db(&db, key);
+        let mut shard = shard.lock().unwrap();
+        let unchanged = shard.version(key) == Some(*version);
+        shard.unwatch(key);
+        if !matches!(sent, Ok(Ok(()))) || request.copy {
+            continue;
+        }
+        if unchanged {
+            shard.remove(key);
+            deleted.push(Bytes::from(key.clone()));
+        } else {
+            modified = true;
+        }
+    }
+    if !deleted.is_empty() {
+        deleted.insert(0, Bytes::from("DEL"));
+        propagate::command(&db, deleted);
+    }
+
+    match sent {
+        Err(_) => Err(CommandError::new(
+            "IOERR error or timeout reading to target instance",
+        )),
+        Ok(Err(err)) => Err(err),
+        Ok(Ok(())) if modified => Err(CommandError::new(
+            "TRYAGAIN Some keys were modified during MIGRATE",
+        )),
+        Ok(Ok(())) => Ok(ok()),
+    }
+}
+
+// 移行先に接続し、キーを 1 つずつ RESTORE させる
+async fn send(
+    request: &MigrateRequest,
+    found: &[(String, Value, Option<Instant>, u64)],
+) -> Result<(), CommandError> {
+    let socket = TcpStream::connect(request.addr).await.map_err(io_error)?;
+    let mut connection = Connection::new(socket);
+
+    for (key, value, expires_at, _) in found {
+        let ttl = expires_at.map_or(0, |when| {
+            when.saturating_duration_since(Instant::now())
+                .as_millis()
+                .max(1)
+        });
+        let payload = rdb::encode(&[Record {
+            key: key.clone(),
+            value: value.clone(),
+            expires_at_ms: None,
+        }]);
+        let mut restore = vec![
+            Bytes::from("RESTORE"),
+            Bytes::from(key.clone()),
+            Bytes::from(ttl.to_string()),
+            Bytes::from(payload),
+        ];
+        if request.replace {
+            restore.push(Bytes::from("REPLACE"));
+        }
+
+        for command in [Frame::command(["ASKING"]), Frame::command(restore)] {
+            connection.write_frame(&command).await.map_err(io_error)?;
+            match connection.read_frame().await.map_err(io_error)? {
+                Some(Frame::Error(err)) => {
+                    return Err(CommandError::new(format!(
+                        "ERR Target instance replied with error: {}",
+                        err
+                    )))
+                }
+                Some(_) => {}
+                None => return Err(CommandError::new("IOERR connection closed by target")),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn io_error(err: impl std::fmt::Display) -> CommandError {
+    CommandError::new(format!("IOERR {}", err))
+}
+
+fn parse_addr(host: &Bytes, port: &Bytes) -> Result<SocketAddr, CommandError> {
+    let addr = format!("{}:{}", key(host), key(port));
+    addr.parse()
+        .map_err(|_| CommandError::new(format!("ERR Invalid node address specified: {}", addr)))
+}
+
+fn parse_slot(arg: &[u8]) -> Result<u16, CommandError> {
+    match std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse::<u16>().ok())
+    {
+        Some(slot) if (slot as usize) < SLOTS => Ok(slot),
+        _ => Err(CommandError::new("ERR Invalid or out of range slot")),
+    }
+}
+
+fn expect_args(sub: &str, args: &[Bytes], count: usize) -> Result<(), CommandError> {
+    if args.len() == count {
+        Ok(())
+    } else {
+        Err(wrong_subcommand_arity(sub))
+    }
+}
+
+fn wrong_subcommand_arity(sub: &str) -> CommandError {
+    CommandError::new(format!(
+        "ERR wrong number of arguments for 'cluster|{}' command",
+        sub
+    ))
+}
+
+fn ok() -> Frame {
+    Frame::Simple("OK".to_string())
+}
+
+fn bulk(s: &str) -> Frame {
+    Frame::Bulk(Bytes::from(s.to_string()))
+}
@@ -0,0 +1,26 @@
+use bytes::Bytes;
+
+use super::{CommandError, CommandResult, Keys, Registry};
+use crate::db::ShardedDb;
+use crate::frame::Frame;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register("ping", -1, ping);
+    registry.register("echo", 2, echo);
+    registry.set_keys("ping", Keys::None);
+    registry.set_keys("echo", Keys::None);
+}
+
+// PING [message]
+fn ping(_db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    match args {
+        [] => Ok(Frame::Simple("PONG".to_string())),
+        [msg] => Ok(Frame::Bulk(msg.clone())),
+        _ => Err(CommandError::wrong_arity("ping")),
+    }
+}
+
+// ECHO message
+fn echo(_db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    Ok(Frame::Bulk(args[0].clone()))
+}
@@ -0,0 +1,265 @@
+use bytes::Bytes;
+
+use super::keys::{parse_cursor, ScanOptions};
+use super::{key, parse_int, CommandError, CommandResult, Registry};
+use crate::db::{get_db_from_sharded_db, Dict, Shard, ShardedDb, Value};
+use crate::frame::Frame;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register_write("hset", -4, hset);
+    registry.register_write("hmset", -4, hmset);
+    registry.register_write("hsetnx", 4, hsetnx);
+    registry.register("hget", 3, hget);
+    registry.register("hmget", -3, hmget);
+    registry.register("hgetall", 2, hgetall);
+    registry.register("hkeys", 2, hkeys);
+    registry.register("hvals", 2, hvals);
+    registry.register_write("hdel", -3, hdel);
+    registry.register_write("hincrby", 4, hincrby);
+    registry.register("hexists", 3, hexists);
+    registry.register("hlen", 2, hlen);
+    registry.register("hscan", -3, hscan);
+    for name in ["hset", "hmset", "hsetnx", "hincrby"] {
+        registry.mark_denyoom(name);
+    }
+}
+
+// キーに対応するハッシュを参照する
+// キーが存在しなければ None、ハッシュ以外の値であれば WRONGTYPE エラーを返す
+fn get_hash<'a>(shard: &'a mut Shard, key: &str) -> Result<Option<&'a mut Dict>, CommandError> {
+    match shard.get_mut(key) {
+        None => Ok(None),
+        Some(entry) => match &mut entry.value {
+            Value::Hash(hash) => Ok(Some(hash)),
+            _ => Err(CommandError::wrong_type()),
+        },
+    }
+}
+
+// キーに対応するハッシュを読み取り専用で参照する
+// 変更しないので、WATCH しているトランザクションを失敗させない
+fn read_hash<'a>(shard: &'a mut Shard, key: &str) -> Result<Option<&'a Dict>, CommandError> {
+    match shard.get(key) {
+        None => Ok(None),
+        Some(entry) => match &entry.value {
+            Value::Hash(v) => Ok(Some(v)),
+            _ => Err(CommandError::wrong_type()),
+        },
+    }
+}
+
+// キーに対応するハッシュを参照する
+// キーが存在しなければ空のハッシュを作成する
+fn get_or_create_hash<'a>(shard: &'a mut Shard, key: &str) -> Result<&'a mut Dict, CommandError> {
+    if get_hash(shard, key)?.is_none() {
+        shard.insert(key.to_string(), Value::Hash(Dict::new()), None);
+    }
+    Ok(get_hash(shard, key)?.unwrap())
+}
+
+// HSET key field value [field value ...]
+// 新たに追加したフィールドの数を返す
+fn hset(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let added = set_fields(db, args, "hset")?;
+    Ok(Frame::Integer(added as i64))
+}
+
+// HMSET key field value [field value ...]
+fn hmset(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    set_fields(db, args, "hmset")?;
+    Ok(Frame::Simple("OK".to_string()))
+}
+
+fn set_fields(db: &ShardedDb, args: &[Bytes], command: &str) -> Result<usize, CommandError> {
+    if args.len() % 2 != 1 {
+        return Err(CommandError::wrong_arity(command));
+    }
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+    let hash = get_or_create_hash(&mut shard, &key)?;
+
+    let mut added = 0;
+    for pair in args[1..].chunks(2) {
+        if hash.insert(pair[0].clone(), pair[1].clone()).is_none() {
+            added += 1;
+        }
+    }
+    Ok(added)
+}
+
+// HSETNX key field value
+fn hsetnx(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+    let hash = get_or_create_hash(&mut shard, &key)?;
+
+    if hash.contains_key(&args[1]) {
+        return Ok(Frame::Integer(0));
+    }
+    hash.insert(args[1].clone(), args[2].clone());
+    Ok(Frame::Integer(1))
+}
+
+// HGET key field
+fn hget(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let value = read_hash(&mut shard, &key)?.and_then(|hash| hash.get(&args[1]).cloned());
+    Ok(value.map_or(Frame::Null, Frame::Bulk))
+}
+
+// HMGET key field [field ...]
+fn hmget(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let hash = read_hash(&mut shard, &key)?;
+    let values = args[1..]
+        .iter()
+        .map(|field| {
+            hash.as_ref()
+                .and_then(|hash| hash.get(field).cloned())
+                .map_or(Frame::Null, Frame::Bulk)
+        })
+        .collect();
+    Ok(Frame::Array(values))
+}
+
+// HGETALL key
+// フィールドと値のマップを返す（RESP2 では交互に並べた配列になる）
+fn hgetall(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = super::key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let pairs = match read_hash(&mut shard, &key)? {
+        Some(hash) => hash
+            .iter()
+            .map(|(field, value)| (Frame::Bulk(field.clone()), Frame::Bulk(value.clone())))
+            .collect(),
+        None => vec![],
+    };
+    Ok(Frame::Map(pairs))
+}
+
+// HKEYS key
+fn hkeys(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    collect(db, &args[0], |field, _| vec![Frame::Bulk(field.clone())])
+}
+
+// HVALS key
+fn hvals(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    collect(db, &args[0], |_, value| vec![Frame::Bulk(value.clone())])
+}
+
+// ハッシュのすべてのフィールドを f で変換して一つの配列にまとめる
+fn collect(db: &ShardedDb, key: &Bytes, f: impl Fn(&Bytes, &Bytes) -> Vec<Frame>) -> CommandResult {
+    let key = super::key(key);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let items = match read_hash(&mut shard, &key)? {
+        Some(hash) => hash
+            .iter()
+            .flat_map(|(field, value)| f(field, value))
+            .collect(),
+        None => vec![],
+    };
+    Ok(Frame::Array(items))
+}
+
+// HDEL key field [field ...]
+fn hdel(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let removed = match get_hash(&mut shard, &key)? {
+        Some(hash) => args[1..]
+            .iter()
+            .filter(|field| hash.remove(field).is_some())
+            .count(),
+        None => 0,
+    };
+    shard.remove_if_empty(&key);
+    Ok(Frame::Integer(removed as i64))
+}
+
+// HINCRBY key field increment
+fn hincrby(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let delta = parse_int(&args[2])?;
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+    let hash = get_or_create_hash(&mut shard, &key)?;
+
+    let current = match hash.get(&args[1]) {
+        Some(value) => {
+            parse_int(value).map_err(|_| CommandError::new("ERR hash value is not an integer"))?
+        }
+        None => 0,
+    };
+    let value = current
+        .checked_add(delta)
+        .ok_or_else(|| CommandError::new("ERR increment or decrement would overflow"))?;
+
+    hash.insert(args[1].clone(), Bytes::from(value.to_string()));
+    Ok(Frame::Integer(value))
+}
+
+// HEXISTS key field
+fn hexists(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let exists = read_hash(&mut shard, &key)?.is_some_and(|hash| hash.contains_key(&args[1]));
+    Ok(Frame::Integer(exists as i64))
+}
+
+// HLEN key
+fn hlen(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let len = read_hash(&mut shard, &key)?.map_or(0, |hash| hash.len());
+    Ok(Frame::Integer(len as i64))
+}
+
+// HSCAN key cursor [MATCH pattern] [COUNT count]
+//
+// SCAN と同様に、カーソルはフィールドのハッシュ値から決まる位置を表す
+fn hscan(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let cursor = parse_cursor(&args[1])?;
+    let opts = ScanOptions::parse(&args[2..])?;
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let (items, next) = match read_hash(&mut shard, &key)? {
+        Some(hash) => {
+            let (pairs, next) = hash.scan(cursor, opts.count);
+            let items = pairs
+                .into_iter()
+                .filter(|(field, _)| opts.matches_pattern(field))
+                .flat_map(|(field, value)| [Frame::Bulk(field.clone()), Frame::Bulk(value.clone())])
+                .collect();
+            (items, next.unwrap_or(0))
+        }
+        None => (vec![], 0),
+    };
+
+    Ok(Frame::Array(vec![
+        Frame::Bulk(Bytes::from(next.to_string())),
+        Frame::Array(items),
+    ]))
+}
@@ -0,0 +1,54 @@
+use std::fmt::Write;
+
+use bytes::Bytes;
+
+use super::{eq_ignore_case, replication, CommandResult, Keys, Registry};
+use crate::db::ShardedDb;
+use crate::evict::{self, Policy};
+use crate::frame::Frame;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register("info", -1, info);
+    registry.set_keys("info", Keys::None);
+}
+
+// INFO [section ...]
+//
+// 今のところ memory と replication のセクションを返す
+fn info(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let wanted = |section: &str| {
+        args.is_empty()
+            || args.iter().any(|arg| {
+                ["all", "everything", "default", section]
+                    .iter()
+                    .any(|name| eq_ignore_case(arg, name))
+            })
+    };
+
+    let mut sections = Vec::new();
+    if wanted("memory") {
+        let mut out = String::new();
+        memory_section(db, &mut out);
+        sections.push(out);
+    }
+    if wanted("replication") {
+        let mut out = String::new();
+        replication::info_section(db, &mut out);
+        sections.push(out);
+    }
+    Ok(Frame::Bulk(Bytes::from(sections.join("\r\n"))))
+}
+
+// INFO memory の内容
+// used_memory はキーと値が使うおおよそのバイト数で、プロセス全体の使用量ではない
+fn memory_section(db: &ShardedDb, out: &mut String) {
+    let maxmemory = db.maxmemory();
+    let _ = write!(
+        out,
+        "# Memory\r\nused_memory:{}\r\nmaxmemory:{}\r\nmaxmemory_policy:{}\r\nevicted_keys:{}\r\n",
+        evict::used_memory(db),
+        maxmemory.map_or(0, |maxmemory| maxmemory.bytes()),
+        maxmemory.map_or(Policy::NoEviction, |maxmemory| maxmemory.policy()),
+        maxmemory.map_or(0, |maxmemory| maxmemory.evicted())
+    );
+}
@@ -0,0 +1,427 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use tokio::time::Instant;
+
+use super::{eq_ignore_case, key, parse_int, CommandError, CommandResult, Keys, Registry};
+use crate::db::{get_db_from_sharded_db, lock_shards, Shard, ShardedDb, SCAN_BITS};
+use crate::frame::Frame;
+use crate::glob::glob_match;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register_write("del", -2, del);
+    registry.register("exists", -2, exists);
+    registry.register("type", 2, type_);
+    registry.register_write("rename", 3, rename);
+    registry.register_write("renamenx", 3, renamenx);
+    registry.register("keys", 2, keys);
+    registry.register("scan", -2, scan);
+    registry.register_write("expire", -3, expire);
+    registry.register_write("pexpire", -3, pexpire);
+    registry.register_write("expireat", -3, expireat);
+    registry.register_write("pexpireat", -3, pexpireat);
+    registry.register("ttl", 2, ttl);
+    registry.register("pttl", 2, pttl);
+    registry.register_write("persist", 2, persist);
+    registry.set_keys("del", Keys::ALL);
+    registry.set_keys("exists", Keys::ALL);
+    registry.set_keys("rename", Keys::FIRST_TWO);
+    registry.set_keys("renamenx", Keys::FIRST_TWO);
+    registry.set_keys("keys", Keys::None);
+    registry.set_keys("scan", Keys::None);
+}
+
+// DEL key [key ...]
+fn del(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let mut removed = 0;
+    for arg in args {
+        let key = key(arg);
+        let shard = get_db_from_sharded_db(db, &key);
+        if shard.lock().unwrap().remove(&key).is_some() {
+            removed += 1;
+        }
+    }
+    Ok(Frame::Integer(removed))
+}
+
+// EXISTS key [key ...]
+// 同じキーが複数回指定された場合は、その回数だけ数える
+fn exists(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let mut found = 0;
+    for arg in args {
+        let key = key(arg);
+        let shard = get_db_from_sharded_db(db, &key);
+        if shard.lock().unwrap().get(&key).is_some() {
+            found += 1;
+        }
+    }
+    Ok(Frame::Integer(found))
+}
+
+// TYPE key
+fn type_(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+    let name = shard.get(&key).map_or("none", |entry| entry.type_name());
+    Ok(Frame::Simple(name.to_string()))
+}
+
+// RENAME key newkey
+fn rename(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    rename_key(db, args, false)
+}
+
+// RENAMENX key newkey
+fn renamenx(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    rename_key(db, args, true)
+}
+
+// RENAME と RENAMENX の共通部分
+// 新しいキーは別のシャードに属することがあるので、両方のシャードをロックする
+fn rename_key(db: &ShardedDb, args: &[Bytes], nx: bool) -> CommandResult {
+    let from = key(&args[0]);
+    let to = key(&args[1]);
+
+    let mut locked = lock_shards(db, &[&from, &to]);
+    let (src, dst) = locked.pair(&from, &to);
+    move_entry(src, dst, from, to, nx)
+}
+
+// src のキー from を dst（None なら src 自身）のキー to に移す
+// 有効期限もそのまま引き継ぐ
+fn move_entry(
+    src: &mut Shard,
+    dst: Option<&mut Shard>,
+    from: String,
+    to: String,
+    nx: bool,
+) -> CommandResult {
+    if src.get(&from).is_none() {
+        return Err(CommandError::new("ERR no such key"));
+    }
+
+    let target_exists = match &dst {
+        Some(dst) => dst.peek(&to).is_some(),
+        None => src.peek(&to).is_some(),
+    };
+    if nx && target_exists {
+        return Ok(Frame::Integer(0));
+    }
+
+    if from != to {
+        let entry = src.remove(&from).unwrap();
+        let expires_at = entry.expires_at();
+        let target = dst.unwrap_or(src);
+        target.insert(to.clone(), entry.value, expires_at);
+        // 移動先のキーでブロッキングポップを待っているクライアントがいれば要素を渡す
+        target.serve_waiters(&to);
+    }
+
+    if nx {
+        Ok(Frame::Integer(1))
+    } else {
+        Ok(Frame::Simple("OK".to_string()))
+    }
+}
+
+// KEYS pattern
+// 一度にロックするのは 1 シャードだけにする
+fn keys(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let pattern = &args[0];
+    let mut found = Vec::new();
+    for shard in db.iter() {
+        let shard = shard.lock().unwrap();
+        found.extend(
+            shard
+                .iter()
+                .filter(|(key, _)| glob_match(pattern, key.as_bytes()))
+                .map(|(key, _)| Frame::Bulk(Bytes::from(key.clone()))),
+        );
+    }
+    Ok(Frame::Array(found))
+}
+
+// SCAN のデフォルトの COUNT
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+//
+// カーソルは上位ビットにシャードの番号、下位 SCAN_BITS ビットにシャード内の位置を持つ
+// シャード内の位置はキーのハッシュ値で決まるので、呼び出しの合間にキーが追加・削除されても、
+// その間ずっと存在していたキーは必ず一度は返される
+// 一度の呼び出しでロックするのは 1 シャードずつで、すべてのシャードを同時にロックすることはない
+// シャード内は位置の順に並べた索引を途中からたどるので、調べるのは返すキーの分だけで済む
+fn scan(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let cursor = parse_cursor(&args[0])?;
+    let opts = ScanOptions::parse(&args[1..])?;
+
+    let mask = (1u64 << SCAN_BITS) - 1;
+    let mut index = (cursor >> SCAN_BITS) as usize;
+    let mut position = cursor & mask;
+
+    let mut found = Vec::new();
+    let mut scanned = 0;
+    let mut next = 0;
+    while index < db.len() {
+        let shard = db[index].lock().unwrap();
+        let (keys, resume) = shard.scan(position, opts.count - scanned);
+        scanned += keys.len();
+
+        for key in keys {
+            let entry = shard.peek(key).unwrap();
+            if opts.matches(key.as_bytes(), entry.type_name()) {
+                found.push(Frame::Bulk(Bytes::from(key.to_string())));
+            }
+        }
+
+        match resume {
+            Some(pos) => {
+                next = ((index as u64) << SCAN_BITS) | pos;
+                break;
+            }
+            None => {
+                index += 1;
+                position = 0;
+            }
+        }
+
+        if scanned >= opts.count {
+            if index < db.len() {
+                next = (index as u64) << SCAN_BITS;
+            }
+            break;
+        }
+    }
+
+    Ok(Frame::Array(vec![
+        Frame::Bulk(Bytes::from(next.to_string())),
+        Frame::Array(found),
+    ]))
+}
+
+// SCAN 系コマンドのカーソルを解釈する
+pub(super) fn parse_cursor(arg: &[u8]) -> Result<u64, CommandError> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| CommandError::new("ERR invalid cursor"))
+}
+
+// SCAN 系コマンドに共通のオプション
+pub(super) struct ScanOptions {
+    pub(super) pattern: Option<Bytes>,
+    pub(super) count: usize,
+    type_name: Option<String>,
+}
+
+impl ScanOptions {
+    // [MATCH pattern] [COUNT count] [TYPE type] を解釈する
+    pub(super) fn parse(args: &[Bytes]) -> Result<Self, CommandError> {
+        let mut opts = ScanOptions {
+            pattern: None,
+            count: DEFAULT_SCAN_COUNT,
+            type_name: None,
+        };
+
+        let mut args = args.iter();
+        while let Some(opt) = args.next() {
+            let value = args.next().ok_or_else(CommandError::syntax)?;
+            if eq_ignore_case(opt, "match") {
+                opts.pattern = Some(value.clone());
+            } else if eq_ignore_case(opt, "count") {
+                let count = parse_int(value)?;
+                if count < 1 {
+                    return Err(CommandError::syntax());
+                }
+                opts.count = count as usize;
+            } else if eq_ignore_case(opt, "type") {
+                opts.type_name = Some(String::from_utf8_lossy(value).to_ascii_lowercase());
+            } else {
+                return Err(CommandError::syntax());
+            }
+        }
+        Ok(opts)
+    }
+
+    // MATCH パターンにマッチするか
+    pub(super) fn matches_pattern(&self, item: &[u8]) -> bool {
+        match &self.pattern {
+            Some(pattern) => glob_match(pattern, item),
+            None => true,
+        }
+    }
+
+    fn matches(&self, key: &[u8], type_name: &str) -> bool {
+        self.matches_pattern(key) && self.type_name.as_deref().is_none_or(|t| t == type_name)
+    }
+}
+
+// 現在から ms ミリ秒後の時刻を返す
+// 0 以下の場合は現在時刻（すなわち、すでに期限切れ）を返す
+pub(super) fn deadline_in_millis(ms: i64, command: &str) -> Result<Instant, CommandError> {
+    let now = Instant::now();
+    if ms <= 0 {
+        return Ok(now);
+    }
+    now.checked_add(Duration::from_millis(ms as u64))
+        .ok_or_else(|| invalid_expire_time(command))
+}
+
+// 現在の UNIX 時刻（ミリ秒）
+pub(super) fn unix_millis_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+// UNIX 時刻（ミリ秒）で指定された期限を Instant に変換する
+pub(super) fn deadline_at_unix_millis(at: i64, command: &str) -> Result<Instant, CommandError> {
+    deadline_in_millis(at.saturating_sub(unix_millis_now()), command)
+}
+
+// Instant で表された期限を UNIX 時刻（ミリ秒）に変換する
+pub(super) fn instant_to_unix_millis(when: Instant) -> i64 {
+    let now = Instant::now();
+    if when >= now {
+        unix_millis_now() + (when - now).as_millis() as i64
+    } else {
+        unix_millis_now() - (now - when).as_millis() as i64
+    }
+}
+
+// 秒やミリ秒の引数をミリ秒に変換する
+pub(super) fn parse_millis(arg: &[u8], unit: i64, command: &str) -> Result<i64, CommandError> {
+    parse_int(arg)?
+        .checked_mul(unit)
+        .ok_or_else(|| invalid_expire_time(command))
+}
+
+fn invalid_expire_time(command: &str) -> CommandError {
+    CommandError::new(format!("ERR invalid expire time in '{}' command", command))
+}
+
+// EXPIRE key seconds [NX | XX | GT | LT]
+fn expire(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let ms = parse_millis(&args[1], 1000, "expire")?;
+    set_expiry(db, args, ms, "expire")
+}
+
+// PEXPIRE key milliseconds [NX | XX | GT | LT]
+fn pexpire(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let ms = parse_millis(&args[1], 1, "pexpire")?;
+    set_expiry(db, args, ms, "pexpire")
+}
+
+// EXPIREAT key unix-time-seconds [NX | XX | GT | LT]
+fn expireat(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let at = parse_millis(&args[1], 1000, "expireat")?;
+    set_expiry(db, args, at.saturating_sub(unix_millis_now()), "expireat")
+}
+
+// PEXPIREAT key unix-time-milliseconds [NX | XX | GT | LT]
+fn pexpireat(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let at = parse_millis(&args[1], 1, "pexpireat")?;
+    set_expiry(db, args, at.saturating_sub(unix_millis_now()), "pexpireat")
+}
+
+// EXPIRE, PEXPIRE, EXPIREAT, PEXPIREAT の共通部分
+// ms には現在から期限までのミリ秒数を渡す
+fn set_expiry(db: &ShardedDb, args: &[Bytes], ms: i64, command: &str) -> CommandResult {
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    for opt in &args[2..] {
+        if eq_ignore_case(opt, "nx") {
+            nx = true;
+        } else if eq_ignore_case(opt, "xx") {
+            xx = true;
+        } else if eq_ignore_case(opt, "gt") {
+            gt = true;
+        } else if eq_ignore_case(opt, "lt") {
+            lt = true;
+        } else {
+            return Err(CommandError::new(format!(
+                "ERR Unsupported option {}",
+                String::from_utf8_lossy(opt)
+            )));
+        }
+    }
+    if nx && (xx || gt || lt) {
+        return Err(CommandError::new(
+            "ERR NX and XX, GT or LT options at the same time are not compatible",
+        ));
+    }
+    if gt && lt {
+        return Err(CommandError::new(
+            "ERR GT and LT options at the same time are not compatible",
+        ));
+    }
+
+    let deadline = deadline_in_millis(ms, command)?;
+
+    let key = key(&args[0]);
+    let db = get_db_from_sharded_db(db, &key);
+    let mut db = db.lock().unwrap();
+
+    let current = match db.get(&key) {
+        Some(entry) => entry.expires_at(),
+        None => return Ok(Frame::Integer(0)),
+    };
+
+    // 期限なしは無限大の期限として比較する
+    let allowed = match current {
+        None => !(xx || gt),
+        Some(current) => !nx && (!gt || deadline > current) && (!lt || deadline < current),
+    };
+    if !allowed {
+        return Ok(Frame::Integer(0));
+    }
+
+    if deadline <= Instant::now() {
+        db.remove(&key);
+    } else {
+        db.set_expiry(&key, Some(deadline));
+    }
+    Ok(Frame::Integer(1))
+}
+
+// TTL key
+fn ttl(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    // Redis と同様に、残り時間を秒単位に四捨五入する
+    remaining(db, &args[0], |d| ((d.as_millis() + 500) / 1000) as i64)
+}
+
+// PTTL key
+fn pttl(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    remaining(db, &args[0], |d| d.as_millis() as i64)
+}
+
+// キーの残り有効期間を返す
+// キーが存在しなければ -2、期限が設定されていなければ -1 を返す
+fn remaining(db: &ShardedDb, key: &Bytes, unit: fn(Duration) -> i64) -> CommandResult {
+    let key = super::key(key);
+    let db = get_db_from_sharded_db(db, &key);
+    let mut db = db.lock().unwrap();
+
+    let ttl = match db.get(&key) {
+        None => -2,
+        Some(entry) => match entry.expires_at() {
+            None => -1,
+            Some(when) => unit(when.saturating_duration_since(Instant::now())),
+        },
+    };
+    Ok(Frame::Integer(ttl))
+}
+
+// PERSIST key
+fn persist(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let db = get_db_from_sharded_db(db, &key);
+    let mut db = db.lock().unwrap();
+
+    let has_expiry = matches!(db.get(&key), Some(entry) if entry.expires_at().is_some());
+    if has_expiry {
+        db.set_expiry(&key, None);
+    }
+    Ok(Frame::Integer(has_expiry as i64))
+}
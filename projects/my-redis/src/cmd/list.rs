@@ -0,0 +1,341 @@
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+
+use super::{eq_ignore_case, key, parse_int, CommandError, CommandResult, Keys, Registry};
+use crate::db::{get_db_from_sharded_db, lock_shards, End, Shard, ShardedDb, Value};
+use crate::frame::Frame;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register_write("lpush", -3, lpush);
+    registry.register_write("rpush", -3, rpush);
+    registry.register_write("lpushx", -3, lpushx);
+    registry.register_write("rpushx", -3, rpushx);
+    registry.register_write("lpop", -2, lpop);
+    registry.register_write("rpop", -2, rpop);
+    registry.register("lrange", 4, lrange);
+    registry.register("llen", 2, llen);
+    registry.register("lindex", 3, lindex);
+    registry.register_write("lset", 4, lset);
+    registry.register_write("ltrim", 4, ltrim);
+    registry.register_write("lmove", 5, lmove);
+    registry.set_keys("lmove", Keys::FIRST_TWO);
+    for name in ["lpush", "rpush", "lpushx", "rpushx", "lset", "lmove"] {
+        registry.mark_denyoom(name);
+    }
+}
+
+// キーに対応するリストを参照する
+// キーが存在しなければ None、リスト以外の値であれば WRONGTYPE エラーを返す
+pub(super) fn get_list<'a>(
+    shard: &'a mut Shard,
+    key: &str,
+) -> Result<Option<&'a mut VecDeque<Bytes>>, CommandError> {
+    match shard.get_mut(key) {
+        None => Ok(None),
+        Some(entry) => match &mut entry.value {
+            Value::List(list) => Ok(Some(list)),
+            _ => Err(CommandError::wrong_type()),
+        },
+    }
+}
+
+// キーに対応するリストを読み取り専用で参照する
+// 変更しないので、WATCH しているトランザクションを失敗させない
+pub(super) fn read_list<'a>(
+    shard: &'a mut Shard,
+    key: &str,
+) -> Result<Option<&'a VecDeque<Bytes>>, CommandError> {
+    match shard.get(key) {
+        None => Ok(None),
+        Some(entry) => match &entry.value {
+            Value::List(v) => Ok(Some(v)),
+            _ => Err(CommandError::wrong_type()),
+        },
+    }
+}
+
+// リストの端に要素を追加し、追加後の長さを返す
+// create が false ならキーが存在しないときは何もしない
+pub(super) fn push(
+    shard: &mut Shard,
+    key: String,
+    values: &[Bytes],
+    end: End,
+    create: bool,
+) -> Result<usize, CommandError> {
+    let list = match get_list(shard, &key)? {
+        Some(list) => list,
+        None if create => {
+            shard.insert(key.clone(), Value::List(VecDeque::new()), None);
+            get_list(shard, &key)?.unwrap()
+        }
+        None => return Ok(0),
+    };
+
+    for value in values {
+        match end {
+            End::Left => list.push_front(value.clone()),
+            End::Right => list.push_back(value.clone()),
+        }
+    }
+    let len = list.len();
+
+    // ブロッキングポップで待っているクライアントがいれば要素を渡す
+    // 返り値の長さは Redis と同様に、渡す前の長さとする
+    shard.serve_waiters(&key);
+    Ok(len)
+}
+
+// リストの端から要素を一つ取り出す
+// 空になったリストはキーごと削除する
+pub(super) fn pop(shard: &mut Shard, key: &str, end: End) -> Result<Option<Bytes>, CommandError> {
+    let value = match get_list(shard, key)? {
+        Some(list) => match end {
+            End::Left => list.pop_front(),
+            End::Right => list.pop_back(),
+        },
+        None => None,
+    };
+    shard.remove_if_empty(key);
+    Ok(value)
+}
+
+// LPUSH key element [element ...]
+fn lpush(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    push_command(db, args, End::Left, true)
+}
+
+// RPUSH key element [element ...]
+fn rpush(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    push_command(db, args, End::Right, true)
+}
+
+// LPUSHX key element [element ...]
+fn lpushx(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    push_command(db, args, End::Left, false)
+}
+
+// RPUSHX key element [element ...]
+fn rpushx(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    push_command(db, args, End::Right, false)
+}
+
+fn push_command(db: &ShardedDb, args: &[Bytes], end: End, create: bool) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+    let len = push(&mut shard, key, &args[1..], end, create)?;
+    Ok(Frame::Integer(len as i64))
+}
+
+// LPOP key [count]
+fn lpop(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    pop_command(db, args, End::Left)
+}
+
+// RPOP key [count]
+fn rpop(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    pop_command(db, args, End::Right)
+}
+
+fn pop_command(db: &ShardedDb, args: &[Bytes], end: End) -> CommandResult {
+    let count = match args {
+        [_] => None,
+        [_, count] => {
+            let count = parse_int(count)?;
+            if count < 0 {
+                return Err(CommandError::new(
+                    "ERR value is out of range, must be positive",
+                ));
+            }
+            Some(count as usize)
+        }
+        _ => return Err(CommandError::syntax()),
+    };
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let count = match count {
+        // count を指定しない場合は要素そのものを返す
+        None => return Ok(pop(&mut shard, &key, end)?.map_or(Frame::Null, Frame::Bulk)),
+        Some(count) => count,
+    };
+
+    if get_list(&mut shard, &key)?.is_none() {
+        return Ok(Frame::Null);
+    }
+    let mut popped = Vec::new();
+    while popped.len() < count {
+        match pop(&mut shard, &key, end)? {
+            Some(value) => popped.push(Frame::Bulk(value)),
+            None => break,
+        }
+    }
+    Ok(Frame::Array(popped))
+}
+
+// 負の値を末尾からの位置として解釈し、[start, stop] の範囲を
+// 0 以上 len 未満のインデックスに変換する
+// 範囲が空であれば None を返す
+pub(super) fn normalize_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (start + len).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        stop + len
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+// 負の値を末尾からの位置として解釈し、範囲内のインデックスに変換する
+fn normalize_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { index + len as i64 } else { index };
+    (0 <= index && index < len as i64).then_some(index as usize)
+}
+
+// LRANGE key start stop
+fn lrange(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let start = parse_int(&args[1])?;
+    let stop = parse_int(&args[2])?;
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let list = match read_list(&mut shard, &key)? {
+        Some(list) => list,
+        None => return Ok(Frame::Array(vec![])),
+    };
+    let items = match normalize_range(start, stop, list.len()) {
+        Some((start, stop)) => list
+            .range(start..=stop)
+            .map(|value| Frame::Bulk(value.clone()))
+            .collect(),
+        None => vec![],
+    };
+    Ok(Frame::Array(items))
+}
+
+// LLEN key
+fn llen(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+    let len = read_list(&mut shard, &key)?.map_or(0, |list| list.len());
+    Ok(Frame::Integer(len as i64))
+}
+
+// LINDEX key index
+fn lindex(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let index = parse_int(&args[1])?;
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let value = read_list(&mut shard, &key)?
+        .and_then(|list| normalize_index(index, list.len()).map(|i| list[i].clone()));
+    Ok(value.map_or(Frame::Null, Frame::Bulk))
+}
+
+// LSET key index element
+fn lset(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let index = parse_int(&args[1])?;
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let list = get_list(&mut shard, &key)?.ok_or_else(|| CommandError::new("ERR no such key"))?;
+    let index = normalize_index(index, list.len())
+        .ok_or_else(|| CommandError::new("ERR index out of range"))?;
+    list[index] = args[2].clone();
+    Ok(Frame::Simple("OK".to_string()))
+}
+
+// LTRIM key start stop
+fn ltrim(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let start = parse_int(&args[1])?;
+    let stop = parse_int(&args[2])?;
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    if let Some(list) = get_list(&mut shard, &key)? {
+        match normalize_range(start, stop, list.len()) {
+            Some((start, stop)) => {
+                list.truncate(stop + 1);
+                list.drain(..start);
+            }
+            None => list.clear(),
+        }
+    }
+    shard.remove_if_empty(&key);
+    Ok(Frame::Simple("OK".to_string()))
+}
+
+// LEFT または RIGHT を解釈する
+pub(super) fn parse_end(arg: &[u8]) -> Result<End, CommandError> {
+    if eq_ignore_case(arg, "left") {
+        Ok(End::Left)
+    } else if eq_ignore_case(arg, "right") {
+        Ok(End::Right)
+    } else {
+        Err(CommandError::syntax())
+    }
+}
+
+// LMOVE source destination LEFT|RIGHT LEFT|RIGHT
+pub(super) fn lmove(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let from = parse_end(&args[2])?;
+    let to = parse_end(&args[3])?;
+    let src = key(&args[0]);
+    let dst = key(&args[1]);
+
+    let mut locked = lock_shards(db, &[&src, &dst]);
+    let (src_shard, dst_shard) = locked.pair(&src, &dst);
+    move_element(src_shard, dst_shard, &src, &dst, from, to)
+}
+
+// src_shard のリスト src の端から要素を取り出し、
+// dst_shard（None なら src_shard 自身）のリスト dst の端に追加する
+fn move_element(
+    src_shard: &mut Shard,
+    mut dst_shard: Option<&mut Shard>,
+    src: &str,
+    dst: &str,
+    from: End,
+    to: End,
+) -> CommandResult {
+    // 要素を取り出す前に、移動先がリストでなければエラーにする
+    match &mut dst_shard {
+        Some(shard) => read_list(shard, dst)?,
+        None => read_list(src_shard, dst)?,
+    };
+
+    let value = match pop(src_shard, src, from)? {
+        Some(value) => value,
+        None => return Ok(Frame::Null),
+    };
+    push(
+        dst_shard.unwrap_or(src_shard),
+        dst.to_string(),
+        std::slice::from_ref(&value),
+        to,
+        true,
+    )?;
+    Ok(Frame::Bulk(value))
+}
@@ -0,0 +1,524 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+
+use bytes::Bytes;
+
+use crate::aof::Aof;
+use crate::db::{get_db_from_sharded_db, ShardedDb, Value};
+use crate::evict;
+use crate::frame::Frame;
+use crate::propagate;
+
+mod blocking;
+mod cluster;
+mod connection;
+mod hash;
+mod info;
+mod keys;
+mod list;
+mod persistence;
+mod replication;
+mod set;
+mod stream;
+mod string;
+mod zset;
+
+// コマンドの処理を行う関数の型
+// 引数にはコマンド名を除いた引数列が渡される
+pub type Handler = fn(&ShardedDb, &[Bytes]) -> CommandResult;
+
+// 結果が得られるまでクライアントを待たせることのあるコマンドの処理を行う関数の型
+// BLPOP などは、この関数が返す Future を .await して結果を得る
+pub type BlockingHandler =
+    fn(ShardedDb, Vec<Bytes>) -> Pin<Box<dyn Future<Output = CommandResult> + Send>>;
+
+// コマンドの実行結果
+// エラーの場合もクライアントにはエラーフレームとして返される
+pub type CommandResult = std::result::Result<Frame, CommandError>;
+
+// コマンドの実行に失敗したときにクライアントへ返すエラー
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandError(String);
+
+impl CommandError {
+    // エラーは 1 行の単純文字列として書き出すので、CR や LF が混ざると応答の区切りが崩れる
+    // Redis と同じく空白に置き換える
+    pub fn new(msg: impl Into<String>) -> Self {
+        Self(msg.into().replace(['\r', '\n'], " "))
+    }
+
+    pub fn syntax() -> Self {
+        Self::new("ERR syntax error")
+    }
+
+    pub fn not_integer() -> Self {
+        Self::new("ERR value is not an integer or out of range")
+    }
+
+    pub fn wrong_type() -> Self {
+        Self::new("WRONGTYPE Operation against a key holding the wrong kind of value")
+    }
+
+    pub fn invalid_key() -> Self {
+        Self::new("ERR invalid key: keys must be valid UTF-8")
+    }
+
+    // AOF への書き込みか fsync に失敗している
+    pub fn aof_failed(err: &str) -> Self {
+        Self::new(format!("MISCONF Errors writing to the AOF file: {}", err))
+    }
+
+    pub fn wrong_arity(name: &str) -> Self {
+        Self::new(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ))
+    }
+}
+
+impl From<CommandError> for Frame {
+    fn from(err: CommandError) -> Frame {
+        Frame::Error(err.0)
+    }
+}
+
+// 登録されたコマンドの情報
+//
+// arity は Redis と同じ規約に従い、コマンド名も含めた引数の個数を表す
+// - 正の値 N なら、ちょうど N 個の引数を要求する
+// - 負の値 -N なら、N 個以上の引数を要求する
+//
+// blocking が登録されているコマンドは、execute では blocking を使って待ち、
+// dispatch では handler を使って待たずに結果を返す
+//
+// keys はコマンドの引数のうちキーである位置で、クラスタモードでキーを担当するノードを決めるのに使う
+//
+// write はキーを変更することのあるコマンドで、実行したら AOF とフォロワーに伝える
+// （blocking を使って実行した場合は、blocking の中で伝える）
+// フォロワーとして動いている間は、クライアントからは実行できない
+//
+// denyoom は書き込みコマンドのうちメモリを増やすことのあるコマンドで、
+// maxmemory を超えていてキーを追い出せないときは実行せずに -OOM を返す
+#[derive(Clone, Copy)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub arity: i32,
+    pub handler: Handler,
+    pub blocking: Option<BlockingHandler>,
+    pub keys: Keys,
+    pub write: bool,
+    pub denyoom: bool,
+}
+
+// コマンドの引数のうちキーである位置
+// 位置はコマンド名を 0 番目として数える
+#[derive(Clone, Copy)]
+pub enum Keys {
+    // キーを取らない
+    None,
+    // first 番目から step おきに last 番目までの引数
+    // last が負なら末尾から数える（-1 は最後の引数）
+    Range {
+        first: usize,
+        last: i32,
+        step: usize,
+    },
+    // 引数の並びからキーを取り出す関数（XREAD の STREAMS の後など）
+    Custom(fn(&[Bytes]) -> Vec<Bytes>),
+}
+
+impl Keys {
+    // ほとんどのコマンドは最初の引数だけがキー
+    pub const FIRST: Keys = Keys::Range {
+        first: 1,
+        last: 1,
+        step: 1,
+    };
+
+    // 最初の 2 つの引数（RENAME の変更前と変更後など）
+    pub const FIRST_TWO: Keys = Keys::Range {
+        first: 1,
+        last: 2,
+        step: 1,
+    };
+
+    // すべての引数
+    pub const ALL: Keys = Keys::Range {
+        first: 1,
+        last: -1,
+        step: 1,
+    };
+
+    // コマンド名を含む引数の並びからキーを取り出す
+    fn extract(&self, args: &[Bytes]) -> Vec<Bytes> {
+        match *self {
+            Keys::None => Vec::new(),
+            Keys::Range { first, last, step } => {
+                let last = if last < 0 {
+                    args.len() as i64 + last as i64
+                } else {
+                    last as i64
+                };
+                (first..args.len())
+                    .step_by(step)
+                    .take_while(|&i| i as i64 <= last)
+                    .map(|i| args[i].clone())
+                    .collect()
+            }
+            Keys::Custom(extract) => extract(args),
+        }
+    }
+}
+
+impl CommandSpec {
+    // 引数の個数（コマンド名を含む）が arity を満たすかを判定する
+    fn accepts(&self, argc: usize) -> bool {
+        let argc = argc as i64;
+        let arity = self.arity as i64;
+        if arity >= 0 {
+            argc == arity
+        } else {
+            argc >= -arity
+        }
+    }
+}
+
+// コマンド名をキーとしてハンドラを保持するレジストリ
+pub struct Registry {
+    commands: HashMap<&'static str, CommandSpec>,
+}
+
+impl Registry {
+    // 何も登録されていない空のレジストリを作成する
+    pub fn empty() -> Self {
+        Self {
+            commands: HashMap::new(),
+        }
+    }
+
+    // 標準のコマンドがすべて登録されたレジストリを作成する
+    pub fn new() -> Self {
+        let mut registry = Self::empty();
+        blocking::register(&mut registry);
+        cluster::register(&mut registry);
+        connection::register(&mut registry);
+        hash::register(&mut registry);
+        info::register(&mut registry);
+        keys::register(&mut registry);
+        list::register(&mut registry);
+        persistence::register(&mut registry);
+        replication::register(&mut registry);
+        set::register(&mut registry);
+        stream::register(&mut registry);
+        string::register(&mut registry);
+        zset::register(&mut registry);
+        registry
+    }
+
+    // コマンドを登録する
+    // コマンド名は小文字で登録し、検索時に大文字小文字を区別しないようにする
+    pub fn register(&mut self, name: &'static str, arity: i32, handler: Handler) {
+        debug_assert!(name.bytes().all(|b| !b.is_ascii_uppercase()));
+        self.commands.insert(
+            name,
+            CommandSpec {
+                name,
+                arity,
+                handler,
+                blocking: None,
+                keys: Keys::FIRST,
+                write: false,
+                denyoom: false,
+            },
+        );
+    }
+
+    // キーを変更することのあるコマンドを登録する
+    pub fn register_write(&mut self, name: &'static str, arity: i32, handler: Handler) {
+        self.register(name, arity, handler);
+        self.mark_write(name);
+    }
+
+    // 登録済みのコマンドを、キーを変更することのあるコマンドとする
+    pub fn mark_write(&mut self, name: &'static str) {
+        self.commands.get_mut(name).unwrap().write = true;
+    }
+
+    // 登録済みの書き込みコマンドを、メモリを増やすことのあるコマンドとする
+    pub fn mark_denyoom(&mut self, name: &'static str) {
+        let spec = self.commands.get_mut(name).unwrap();
+        debug_assert!(spec.write);
+        spec.denyoom = true;
+    }
+
+    // 登録済みのコマンドの、キーである引数の位置を変更する
+    // 登録したときは最初の引数だけをキーとする
+    pub fn set_keys(&mut self, name: &'static str, keys: Keys) {
+        self.commands.get_mut(name).unwrap().keys = keys;
+    }
+
+    // 待つことのあるコマンドを登録する
+    // handler には待たずに結果を返す場合の処理を渡す
+    pub fn register_blocking(
+        &mut self,
+        name: &'static str,
+        arity: i32,
+        handler: Handler,
+        blocking: BlockingHandler,
+    ) {
+        self.register(name, arity, handler);
+        self.commands.get_mut(name).unwrap().blocking = Some(blocking);
+    }
+
+    // コマンド名から登録済みのコマンドを探す
+    pub fn lookup(&self, name: &str) -> Option<&CommandSpec> {
+        self.commands.get(name.to_ascii_lowercase().as_str())
+    }
+
+    // 受け取ったフレームをコマンドとして解釈し、対応するハンドラを呼び出す
+    // エラーはすべて Frame::Error としてクライアントに返すフレームになる
+    //
+    // BLPOP などのコマンドも待たずに実行し、すぐに結果を返す
+    pub fn dispatch(&self, db: &ShardedDb, frame: Frame) -> Frame {
+        match self.resolve(frame) {
+            Ok((spec, args)) => call(db, spec, &args),
+            Err(err) => err.into(),
+        }
+    }
+
+    // dispatch と同様にコマンドを実行する
+    // BLPOP などのコマンドは、結果が得られるかタイムアウトするまで待つ
+    //
+    // dispatch と異なり、実行中の EXEC があれば終わるまで待ってから実行する
+    pub async fn execute(&self, db: &ShardedDb, frame: Frame) -> Frame {
+        let (spec, mut args) = match self.resolve(frame) {
+            Ok(resolved) => resolved,
+            Err(err) => return err.into(),
+        };
+        if let Err(err) = check_writable(db, spec) {
+            return err.into();
+        }
+
+        match spec.blocking {
+            Some(blocking) => {
+                args.remove(0);
+                blocking(db.clone(), args).await.unwrap_or_else(Frame::from)
+            }
+            None if spec.write => {
+                let _gate = db.write_gate();
+                call(db, spec, &args)
+            }
+            None => {
+                let _gate = db.shared();
+                call(db, spec, &args)
+            }
+        }
+    }
+
+    // コマンドの引数のうちキーであるもの
+    // 未知のコマンドや引数の個数が誤っているコマンドは、キーを取らないものとする
+    pub fn keys(&self, frame: &Frame) -> Vec<Bytes> {
+        match self.resolve(frame.clone()) {
+            Ok((spec, args)) => spec.keys.extract(&args),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    // キーを変更することのあるコマンドか
+    pub fn is_write(&self, frame: &Frame) -> bool {
+        matches!(self.resolve(frame.clone()), Ok((spec, _)) if spec.write)
+    }
+
+    // フレームが実行できるコマンドかどうか（コマンド名と引数の個数、フォロワーへの書き込み）を検査する
+    // MULTI の後にコマンドをキューに積むときに使う
+    pub fn check(&self, db: &ShardedDb, frame: &Frame) -> Result<(), CommandError> {
+        let (spec, _) = self.resolve(frame.clone())?;
+        check_writable(db, spec)
+    }
+
+    // フレームを引数列に変換し、コマンド名に対応するコマンドを探して引数の個数を検査する
+    fn resolve(&self, frame: Frame) -> Result<(&CommandSpec, Vec<Bytes>), CommandError> {
+        let args = into_args(frame)?;
+
+        let name = String::from_utf8_lossy(&args[0]);
+        let spec = match self.lookup(&name) {
+            Some(spec) => spec,
+            None => return Err(unknown_command(&name, &args[1..])),
+        };
+
+        if !spec.accepts(args.len()) {
+            return Err(CommandError::wrong_arity(spec.name));
+        }
+
+        // キースペースは String をキーとするので、UTF-8 として読めないキーは
+        // 置換文字に置き換わって別のキーと区別できなくなる
+        // 取り違えて読み書きしないよう、実行する前にエラーにする
+        let keys = spec.keys.extract(&args);
+        if keys.iter().any(|key| std::str::from_utf8(key).is_err()) {
+            return Err(CommandError::invalid_key());
+        }
+
+        Ok((spec, args))
+    }
+}
+
+// フォロワーはリーダーから受け取ったコマンドでしか変更しないので、
+// クライアントからの書き込みコマンドはエラーにする
+fn check_writable(db: &ShardedDb, spec: &CommandSpec) -> Result<(), CommandError> {
+    if spec.write && db.replication().is_follower() {
+        return Err(CommandError::new(
+            "READONLY You can't write against a read only replica.",
+        ));
+    }
+    // AOF に書き込めない間は、記録できない書き込みを受け付けない
+    if spec.write {
+        if let Some(err) = db.aof().and_then(Aof::error) {
+            return Err(CommandError::aof_failed(&err));
+        }
+    }
+    Ok(())
+}
+
+// ハンドラを呼び出す
+// 書き込みコマンドが成功したら、そのコマンドと、実行中に記録された副作用を AOF とフォロワーに伝える
+//
+// maxmemory を超えていれば、書き込みコマンドの前にキーを追い出す
+// 追い出しきれなければ、メモリを増やすコマンドは実行しない（DEL などは実行してメモリを空ける）
+fn call(db: &ShardedDb, spec: &CommandSpec, args: &[Bytes]) -> Frame {
+    if spec.write && !evict::free_memory(db) && spec.denyoom {
+        return CommandError::new("OOM command not allowed when used memory > 'maxmemory'.").into();
+    }
+    let result = (spec.handler)(db, &args[1..]);
+    if spec.write && db.propagating() {
+        if let Ok(reply) = &result {
+            for command in propagated_form(db, args, reply) {
+                propagate::command(db, command);
+            }
+        }
+        propagate::effects(db);
+    }
+    if spec.write {
+        blocking::serve_moves(db);
+    }
+    result.unwrap_or_else(Frame::from)
+}
+
+// AOF とフォロワーに伝えるコマンド
+//
+// 読み込み直したときに同じ結果にならないコマンドは、同じ結果になるように書き換える
+// - 相対的な有効期限は、実行した時点から数えた絶対時刻の PEXPIREAT を追加する
+// - XADD の自動生成の ID は、実際に割り当てた ID に置き換える
+fn propagated_form(db: &ShardedDb, args: &[Bytes], reply: &Frame) -> Vec<Vec<Bytes>> {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    let mut command = args.to_vec();
+    if name == "xadd" {
+        stream::fill_in_id(&mut command, reply);
+    }
+
+    let mut commands = vec![command];
+    if matches!(name.as_str(), "set" | "expire" | "pexpire" | "restore") {
+        let key = key(&args[1]);
+        let shard = get_db_from_sharded_db(db, &key);
+        // 伝えるための参照なので、LRU や LFU のアクセスとしては数えない
+        let shard = shard.lock().unwrap();
+        if let Some(when) = shard.peek(&key).and_then(|entry| entry.expires_at()) {
+            let at = keys::instant_to_unix_millis(when);
+            commands.push(vec![
+                Bytes::from("PEXPIREAT"),
+                args[1].clone(),
+                Bytes::from(at.to_string()),
+            ]);
+        }
+    }
+    commands
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// コマンドのフレームを引数のバイト列の並びに変換する
+// コマンドは空でないバルク文字列（または単純文字列）の配列でなければならない
+fn into_args(frame: Frame) -> Result<Vec<Bytes>, CommandError> {
+    let parts = match frame {
+        Frame::Array(parts) if !parts.is_empty() => parts,
+        _ => {
+            return Err(CommandError::new(
+                "ERR Protocol error: expected a non-empty array",
+            ))
+        }
+    };
+
+    parts
+        .into_iter()
+        .map(|part| match part {
+            Frame::Bulk(bytes) => Ok(bytes),
+            Frame::Simple(s) => Ok(Bytes::from(s)),
+            _ => Err(CommandError::new(
+                "ERR Protocol error: expected bulk strings",
+            )),
+        })
+        .collect()
+}
+
+// 未知のコマンドを受け取ったときのエラー
+// 巨大な引数をそのまま返さないよう、Redis と同じくコマンド名は 128 バイトまで、
+// 引数は引用符を含めて合わせて 128 バイトを超えたところで打ち切る
+fn unknown_command(name: &str, args: &[Bytes]) -> CommandError {
+    const MAX_ECHO: usize = 128;
+
+    let mut msg = format!(
+        "ERR unknown command '{}', with args beginning with:",
+        truncate(name, MAX_ECHO)
+    );
+    let mut echoed = 0;
+    for arg in args {
+        if echoed >= MAX_ECHO {
+            break;
+        }
+        let arg = String::from_utf8_lossy(arg);
+        let arg = truncate(&arg, MAX_ECHO - echoed);
+        msg.push_str(&format!(" '{}'", arg));
+        echoed += arg.len() + 3;
+    }
+    CommandError::new(msg)
+}
+
+// 文字の境界を保ったまま、先頭から max バイト以内に切り詰める
+fn truncate(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+// 引数のバイト列をキーとして扱う文字列に変換する
+// キーの位置にある引数は、resolve で UTF-8 であることを確かめてある
+pub(crate) fn key(arg: &Bytes) -> String {
+    String::from_utf8_lossy(arg).into_owned()
+}
+
+// 引数のバイト列を 10 進の整数として解釈する
+pub(crate) fn parse_int(arg: &[u8]) -> Result<i64, CommandError> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(CommandError::not_integer)
+}
+
+// オプション名などを大文字小文字を区別せずに比較する
+pub(crate) fn eq_ignore_case(arg: &[u8], expected: &str) -> bool {
+    arg.eq_ignore_ascii_case(expected.as_bytes())
+}
+
+// 文字列型の値を取り出す
+pub(crate) fn as_string(value: &Value) -> Result<&Bytes, CommandError> {
+    match value {
+        Value::String(bytes) => Ok(bytes),
+        _ => Err(CommandError::wrong_type()),
+    }
+}
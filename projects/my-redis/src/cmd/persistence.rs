@@ -0,0 +1,51 @@
+use std::io;
+
+use bytes::Bytes;
+
+use super::{CommandError, CommandResult, Keys, Registry};
+use crate::aof;
+use crate::db::ShardedDb;
+use crate::frame::Frame;
+use crate::rdb;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register("save", 1, save);
+    registry.register("bgsave", 1, bgsave);
+    registry.register("lastsave", 1, lastsave);
+    registry.register("bgrewriteaof", 1, bgrewriteaof);
+    for name in ["save", "bgsave", "lastsave", "bgrewriteaof"] {
+        registry.set_keys(name, Keys::None);
+    }
+}
+
+fn io_error(err: io::Error) -> CommandError {
+    CommandError::new(format!("ERR {}", err))
+}
+
+// SAVE
+fn save(db: &ShardedDb, _args: &[Bytes]) -> CommandResult {
+    rdb::save(db).map_err(io_error)?;
+    Ok(Frame::Simple("OK".to_string()))
+}
+
+// BGSAVE
+fn bgsave(db: &ShardedDb, _args: &[Bytes]) -> CommandResult {
+    rdb::bgsave(db).map_err(io_error)?;
+    Ok(Frame::Simple("Background saving started".to_string()))
+}
+
+// LASTSAVE
+fn lastsave(db: &ShardedDb, _args: &[Bytes]) -> CommandResult {
+    let snapshots = db
+        .snapshots()
+        .ok_or_else(|| CommandError::new("ERR no snapshot file is configured"))?;
+    Ok(Frame::Integer(snapshots.last_save() as i64))
+}
+
+// BGREWRITEAOF
+fn bgrewriteaof(db: &ShardedDb, _args: &[Bytes]) -> CommandResult {
+    aof::rewrite(db).map_err(io_error)?;
+    Ok(Frame::Simple(
+        "Background append only file rewriting started".to_string(),
+    ))
+}
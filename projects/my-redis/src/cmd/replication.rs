@@ -0,0 +1,138 @@
+use std::fmt::Write;
+
+use bytes::Bytes;
+
+use super::{eq_ignore_case, key, CommandError, CommandResult, Keys, Registry};
+use crate::db::ShardedDb;
+use crate::frame::Frame;
+use crate::replication::LinkState;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register("replicaof", 3, replicaof);
+    registry.register("role", 1, role);
+    registry.register("replconf", -1, replconf);
+    for name in ["replicaof", "role", "replconf"] {
+        registry.set_keys(name, Keys::None);
+    }
+}
+
+// REPLICAOF host port
+// REPLICAOF NO ONE
+fn replicaof(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    if eq_ignore_case(&args[0], "no") && eq_ignore_case(&args[1], "one") {
+        db.replication().stop_following();
+        return Ok(Frame::Simple("OK".to_string()));
+    }
+
+    let port = std::str::from_utf8(&args[1])
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .ok_or_else(|| CommandError::new("ERR Invalid master port"))?;
+    db.replication().follow(db, key(&args[0]), port);
+    Ok(Frame::Simple("OK".to_string()))
+}
+
+// ROLE
+//
+// リーダーなら ["master", オフセット, [[ip, port, 処理済みのオフセット], ...]]
+// フォロワーなら ["slave", リーダーの host, リーダーの port, 接続の状態, 処理済みのオフセット]
+fn role(db: &ShardedDb, _args: &[Bytes]) -> CommandResult {
+    let replication = db.replication();
+    if let Some(status) = replication.follower_status() {
+        return Ok(Frame::Array(vec![
+            bulk("slave"),
+            bulk(&status.host),
+            Frame::Integer(status.port as i64),
+            bulk(status.link.state.as_str()),
+            Frame::Integer(status.link.offset.map_or(-1, |offset| offset as i64)),
+        ]));
+    }
+
+    let (offset, followers) = match replication.leader() {
+        Some(leader) => (leader.offset(), leader.followers()),
+        None => (0, vec![]),
+    };
+    let followers = followers
+        .into_iter()
+        .map(|info| {
+            Frame::Array(vec![
+                bulk(&info.addr.ip().to_string()),
+                bulk(&info.addr.port().to_string()),
+                bulk(&info.ack.to_string()),
+            ])
+        })
+        .collect();
+    Ok(Frame::Array(vec![
+        bulk("master"),
+        Frame::Integer(offset as i64),
+        Frame::Array(followers),
+    ]))
+}
+
+// INFO replication の内容
+pub(super) fn info_section(db: &ShardedDb, out: &mut String) {
+    let replication = db.replication();
+    out.push_str("# Replication\r\n");
+    match replication.follower_status() {
+        Some(status) => {
+            let up = status.link.state == LinkState::Connected;
+            let _ = write!(
+                out,
+                "role:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\nmaster_link_status:{}\r\n",
+                status.host,
+                status.port,
+                if up { "up" } else { "down" }
+            );
+            let _ = write!(
+                out,
+                "slave_repl_offset:{}\r\nmaster_replid:{}\r\n",
+                status.link.offset.map_or(-1, |offset| offset as i64),
+                status.link.replid.as_deref().unwrap_or("?")
+            );
+        }
+        None => {
+            let leader = replication.leader();
+            let followers = leader.map(|leader| leader.followers()).unwrap_or_default();
+            let _ = write!(
+                out,
+                "role:master\r\nconnected_slaves:{}\r\n",
+                followers.len()
+            );
+            for (i, info) in followers.iter().enumerate() {
+                let _ = write!(
+                    out,
+                    "slave{}:ip={},port={},offset={}\r\n",
+                    i,
+                    info.addr.ip(),
+                    info.addr.port(),
+                    info.ack
+                );
+            }
+            let _ = write!(
+                out,
+                "master_replid:{}\r\nmaster_repl_offset:{}\r\n",
+                replication.replid(),
+                leader.map_or(0, |leader| leader.offset())
+            );
+            let _ = write!(
+                out,
+                "sync_full:{}\r\nsync_partial_ok:{}\r\nsync_partial_err:{}\r\n",
+                leader.map_or(0, |leader| leader.sync_full()),
+                leader.map_or(0, |leader| leader.sync_partial_ok()),
+                leader.map_or(0, |leader| leader.sync_partial_err())
+            );
+        }
+    }
+}
+
+// REPLCONF option value ...
+//
+// フォロワーが PSYNC の前に送る設定は受け付けるだけで使わない
+// PSYNC の後の REPLCONF ACK はレプリケーションの接続の中で処理する
+fn replconf(_db: &ShardedDb, _args: &[Bytes]) -> CommandResult {
+    Ok(Frame::Simple("OK".to_string()))
+}
+
+fn bulk(s: &str) -> Frame {
+    Frame::Bulk(Bytes::from(s.to_string()))
+}
@@ -0,0 +1,228 @@
+use std::collections::HashSet;
+
+use bytes::Bytes;
+
+use super::{key, CommandError, CommandResult, Keys, Registry};
+use crate::db::{get_db_from_sharded_db, lock_shards, LockedShards, Shard, ShardedDb, Value};
+use crate::frame::Frame;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register_write("sadd", -3, sadd);
+    registry.register_write("srem", -3, srem);
+    registry.register("smembers", 2, smembers);
+    registry.register("sismember", 3, sismember);
+    registry.register("scard", 2, scard);
+    registry.register("sinter", -2, sinter);
+    registry.register("sunion", -2, sunion);
+    registry.register("sdiff", -2, sdiff);
+    registry.register_write("sinterstore", -3, sinterstore);
+    registry.register_write("sunionstore", -3, sunionstore);
+    registry.register_write("sdiffstore", -3, sdiffstore);
+    for name in [
+        "sinter",
+        "sunion",
+        "sdiff",
+        "sinterstore",
+        "sunionstore",
+        "sdiffstore",
+    ] {
+        registry.set_keys(name, Keys::ALL);
+    }
+    for name in ["sadd", "sinterstore", "sunionstore", "sdiffstore"] {
+        registry.mark_denyoom(name);
+    }
+}
+
+// キーに対応する集合を参照する
+// キーが存在しなければ None、集合以外の値であれば WRONGTYPE エラーを返す
+fn get_set<'a>(
+    shard: &'a mut Shard,
+    key: &str,
+) -> Result<Option<&'a mut HashSet<Bytes>>, CommandError> {
+    match shard.get_mut(key) {
+        None => Ok(None),
+        Some(entry) => match &mut entry.value {
+            Value::Set(set) => Ok(Some(set)),
+            _ => Err(CommandError::wrong_type()),
+        },
+    }
+}
+
+// キーに対応する集合を読み取り専用で参照する
+// 変更しないので、WATCH しているトランザクションを失敗させない
+fn read_set<'a>(
+    shard: &'a mut Shard,
+    key: &str,
+) -> Result<Option<&'a HashSet<Bytes>>, CommandError> {
+    match shard.get(key) {
+        None => Ok(None),
+        Some(entry) => match &entry.value {
+            Value::Set(v) => Ok(Some(v)),
+            _ => Err(CommandError::wrong_type()),
+        },
+    }
+}
+
+// SADD key member [member ...]
+fn sadd(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    if get_set(&mut shard, &key)?.is_none() {
+        shard.insert(key.clone(), Value::Set(HashSet::new()), None);
+    }
+    let set = get_set(&mut shard, &key)?.unwrap();
+    let added = args[1..]
+        .iter()
+        .filter(|member| set.insert((*member).clone()))
+        .count();
+    Ok(Frame::Integer(added as i64))
+}
+
+// SREM key member [member ...]
+fn srem(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let removed = match get_set(&mut shard, &key)? {
+        Some(set) => args[1..]
+            .iter()
+            .filter(|member| set.remove(*member))
+            .count(),
+        None => 0,
+    };
+    shard.remove_if_empty(&key);
+    Ok(Frame::Integer(removed as i64))
+}
+
+// SMEMBERS key
+fn smembers(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let members = read_set(&mut shard, &key)?.map_or_else(HashSet::new, |set| set.clone());
+    Ok(members_reply(members))
+}
+
+// SISMEMBER key member
+fn sismember(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let found = read_set(&mut shard, &key)?.is_some_and(|set| set.contains(&args[1]));
+    Ok(Frame::Integer(found as i64))
+}
+
+// SCARD key
+fn scard(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let len = read_set(&mut shard, &key)?.map_or(0, |set| set.len());
+    Ok(Frame::Integer(len as i64))
+}
+
+// 複数の集合に対する演算
+#[derive(Clone, Copy)]
+enum Op {
+    Inter,
+    Union,
+    Diff,
+}
+
+// SINTER key [key ...]
+fn sinter(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    combine(db, args, Op::Inter)
+}
+
+// SUNION key [key ...]
+fn sunion(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    combine(db, args, Op::Union)
+}
+
+// SDIFF key [key ...]
+fn sdiff(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    combine(db, args, Op::Diff)
+}
+
+// SINTERSTORE destination key [key ...]
+fn sinterstore(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    combine_and_store(db, args, Op::Inter)
+}
+
+// SUNIONSTORE destination key [key ...]
+fn sunionstore(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    combine_and_store(db, args, Op::Union)
+}
+
+// SDIFFSTORE destination key [key ...]
+fn sdiffstore(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    combine_and_store(db, args, Op::Diff)
+}
+
+fn combine(db: &ShardedDb, args: &[Bytes], op: Op) -> CommandResult {
+    let keys: Vec<String> = args.iter().map(key).collect();
+
+    // 演算の途中で他のコネクションが集合を書き換えないように、関係するシャードをすべてロックする
+    let mut locked = lock_shards(db, &keys);
+    let result = evaluate(&mut locked, &keys, op)?;
+    Ok(members_reply(result))
+}
+
+fn combine_and_store(db: &ShardedDb, args: &[Bytes], op: Op) -> CommandResult {
+    let dst = key(&args[0]);
+    let keys: Vec<String> = args[1..].iter().map(key).collect();
+
+    // 読み出す集合と書き込み先を、一度にまとめてロックする
+    let mut all = keys.clone();
+    all.push(dst.clone());
+    let mut locked = lock_shards(db, &all);
+
+    let result = evaluate(&mut locked, &keys, op)?;
+    let len = result.len();
+
+    // 結果が空なら書き込み先のキーを削除する
+    // 書き込み先にもともとあった値は、型に関係なく上書きする
+    let shard = locked.shard(&dst);
+    if result.is_empty() {
+        shard.remove(&dst);
+    } else {
+        shard.insert(dst, Value::Set(result), None);
+    }
+    Ok(Frame::Integer(len as i64))
+}
+
+// ロック済みのシャードから集合を読み出して演算する
+// 存在しないキーは空集合として扱う
+fn evaluate(
+    locked: &mut LockedShards<'_>,
+    keys: &[String],
+    op: Op,
+) -> Result<HashSet<Bytes>, CommandError> {
+    let mut sets = Vec::with_capacity(keys.len());
+    for key in keys {
+        let set = read_set(locked.shard(key), key)?.cloned();
+        sets.push(set.unwrap_or_default());
+    }
+
+    let mut sets = sets.into_iter();
+    let mut result = sets.next().unwrap_or_default();
+    for set in sets {
+        match op {
+            Op::Inter => result.retain(|member| set.contains(member)),
+            Op::Union => result.extend(set),
+            Op::Diff => result.retain(|member| !set.contains(member)),
+        }
+    }
+    Ok(result)
+}
+
+// RESP3 では集合として返す（RESP2 では配列になる）
+fn members_reply(members: HashSet<Bytes>) -> Frame {
+    Frame::Set(members.into_iter().map(Frame::Bulk).collect())
+}
@@ -0,0 +1,924 @@
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use super::{eq_ignore_case, key, parse_int, CommandError, CommandResult, Keys, Registry};
+use crate::db::{
+    get_db_from_sharded_db, Fields, PendingEntry, Shard, ShardedDb, Stream, StreamId, StreamWaiter,
+    Value,
+};
+use crate::frame::Frame;
+use crate::propagate;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register_write("xadd", -5, xadd);
+    registry.register("xlen", 2, xlen);
+    registry.register("xrange", -4, xrange);
+    registry.register("xrevrange", -4, xrevrange);
+    registry.register_blocking("xread", -4, xread, |db, args| {
+        Box::pin(blocking_read(db, args, false))
+    });
+    registry.register_blocking("xreadgroup", -7, xreadgroup, |db, args| {
+        Box::pin(blocking_read(db, args, true))
+    });
+    registry.register_write("xgroup", -2, xgroup);
+    registry.register_write("xack", -4, xack);
+    registry.register("xpending", -3, xpending);
+    registry.mark_write("xreadgroup");
+    registry.set_keys("xread", Keys::Custom(stream_keys));
+    registry.set_keys("xreadgroup", Keys::Custom(stream_keys));
+    for name in ["xadd", "xgroup"] {
+        registry.mark_denyoom(name);
+    }
+}
+
+// キーに対応するストリームを参照する
+// キーが存在しなければ None、ストリーム以外の値であれば WRONGTYPE エラーを返す
+fn get_stream<'a>(shard: &'a mut Shard, key: &str) -> Result<Option<&'a mut Stream>, CommandError> {
+    match shard.get_mut(key) {
+        None => Ok(None),
+        Some(entry) => match &mut entry.value {
+            Value::Stream(stream) => Ok(Some(stream)),
+            _ => Err(CommandError::wrong_type()),
+        },
+    }
+}
+
+// キーに対応するストリームを読み取り専用で参照する
+// 変更しないので、WATCH しているトランザクションを失敗させない
+fn read_stream<'a>(shard: &'a mut Shard, key: &str) -> Result<Option<&'a Stream>, CommandError> {
+    match shard.get(key) {
+        None => Ok(None),
+        Some(entry) => match &entry.value {
+            Value::Stream(stream) => Ok(Some(stream)),
+            _ => Err(CommandError::wrong_type()),
+        },
+    }
+}
+
+fn invalid_id() -> CommandError {
+    CommandError::new("ERR Invalid stream ID specified as stream command argument")
+}
+
+// "ms-seq" または "ms" の形の ID を解釈する
+// seq を省略した場合は default_seq を使う
+fn parse_id(arg: &[u8], default_seq: u64) -> Result<StreamId, CommandError> {
+    let s = std::str::from_utf8(arg).map_err(|_| invalid_id())?;
+    let (ms, seq) = match s.split_once('-') {
+        Some((ms, seq)) => (ms, Some(seq)),
+        None => (s, None),
+    };
+    let ms = ms.parse().map_err(|_| invalid_id())?;
+    let seq = match seq {
+        Some(seq) => seq.parse().map_err(|_| invalid_id())?,
+        None => default_seq,
+    };
+    Ok(StreamId::new(ms, seq))
+}
+
+// XRANGE の範囲の始点を解釈する
+// - は最小、( をつけるとその ID を含まない
+fn parse_start(arg: &[u8]) -> Result<Bound<StreamId>, CommandError> {
+    match arg {
+        b"-" => Ok(Bound::Unbounded),
+        b"+" => Ok(Bound::Included(StreamId::MAX)),
+        _ => match arg.strip_prefix(b"(") {
+            Some(id) => Ok(Bound::Excluded(parse_id(id, 0)?)),
+            None => Ok(Bound::Included(parse_id(arg, 0)?)),
+        },
+    }
+}
+
+// XRANGE の範囲の終点を解釈する
+// + は最大、seq を省略した場合はその時刻の最後の ID までを含む
+fn parse_end(arg: &[u8]) -> Result<Bound<StreamId>, CommandError> {
+    match arg {
+        b"+" => Ok(Bound::Unbounded),
+        b"-" => Ok(Bound::Included(StreamId::MIN)),
+        _ => match arg.strip_prefix(b"(") {
+            Some(id) => Ok(Bound::Excluded(parse_id(id, u64::MAX)?)),
+            None => Ok(Bound::Included(parse_id(arg, u64::MAX)?)),
+        },
+    }
+}
+
+fn id_frame(id: StreamId) -> Frame {
+    Frame::Bulk(Bytes::from(id.to_string()))
+}
+
+// [ID, [field, value, ...]] の形のエントリ
+fn entry_frame(id: StreamId, fields: &Fields) -> Frame {
+    let fields = fields
+        .iter()
+        .flat_map(|(field, value)| [Frame::Bulk(field.clone()), Frame::Bulk(value.clone())])
+        .collect();
+    Frame::Array(vec![id_frame(id), Frame::Array(fields)])
+}
+
+fn entries_frame(entries: Vec<(StreamId, &Fields)>) -> Frame {
+    Frame::Array(
+        entries
+            .into_iter()
+            .map(|(id, fields)| entry_frame(id, fields))
+            .collect(),
+    )
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// XADD で指定された ID
+enum IdSpec {
+    // *
+    Auto,
+    // ms-*
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl IdSpec {
+    fn parse(arg: &[u8]) -> Result<Self, CommandError> {
+        if arg == b"*" {
+            return Ok(IdSpec::Auto);
+        }
+        if let Some(ms) = arg.strip_suffix(b"-*") {
+            let ms = std::str::from_utf8(ms)
+                .ok()
+                .and_then(|ms| ms.parse().ok())
+                .ok_or_else(invalid_id)?;
+            return Ok(IdSpec::AutoSeq(ms));
+        }
+        Ok(IdSpec::Explicit(parse_id(arg, 0)?))
+    }
+
+    // ストリームに追加するエントリの ID を決める
+    fn resolve(&self, stream: &Stream) -> Result<StreamId, CommandError> {
+        let last = stream.last_id();
+        let too_small = || {
+            CommandError::new(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item",
+            )
+        };
+        let id = match *self {
+            IdSpec::Auto => stream.next_id(unix_millis()),
+            IdSpec::AutoSeq(ms) if ms > last.ms => Some(StreamId::new(ms, 0)),
+            IdSpec::AutoSeq(ms) if ms == last.ms => last.next(),
+            IdSpec::AutoSeq(_) => return Err(too_small()),
+            IdSpec::Explicit(StreamId::MIN) => {
+                return Err(CommandError::new(
+                    "ERR The ID specified in XADD must be greater than 0-0",
+                ))
+            }
+            IdSpec::Explicit(id) => Some(id),
+        };
+        match id {
+            Some(id) if id > last => Ok(id),
+            _ => Err(too_small()),
+        }
+    }
+}
+
+// XADD key [NOMKSTREAM] [MAXLEN [=|~] threshold] <*|id> field value [field value ...]
+//
+// MAXLEN の ~ は = と同じく、正確に threshold 件まで削除する
+fn xadd(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let mut nomkstream = false;
+    let mut maxlen = None;
+    let mut i = 1;
+    loop {
+        let arg = &args[i];
+        if eq_ignore_case(arg, "nomkstream") {
+            nomkstream = true;
+            i += 1;
+        } else if eq_ignore_case(arg, "maxlen") {
+            i += 1;
+            if matches!(args.get(i).map(|a| a.as_ref()), Some(b"=" | b"~")) {
+                i += 1;
+            }
+            let n = parse_int(args.get(i).ok_or_else(CommandError::syntax)?)?;
+            if n < 0 {
+                return Err(CommandError::new("ERR The MAXLEN argument must be >= 0."));
+            }
+            maxlen = Some(n as usize);
+            i += 1;
+        } else {
+            break;
+        }
+        if i >= args.len() {
+            return Err(CommandError::syntax());
+        }
+    }
+
+    let id = IdSpec::parse(&args[i])?;
+    let pairs = &args[i + 1..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return Err(CommandError::wrong_arity("xadd"));
+    }
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let id = match read_stream(&mut shard, &key)? {
+        Some(stream) => id.resolve(stream)?,
+        None if nomkstream => return Ok(Frame::Null),
+        None => id.resolve(&Stream::new())?,
+    };
+
+    if read_stream(&mut shard, &key)?.is_none() {
+        shard.insert(key.clone(), Value::Stream(Stream::new()), None);
+    }
+    let stream = get_stream(&mut shard, &key)?.unwrap();
+    let fields = pairs
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+    stream.insert(id, fields);
+    if let Some(maxlen) = maxlen {
+        stream.trim(maxlen);
+    }
+
+    // XREAD BLOCK などで待っているクライアントに知らせる
+    shard.notify_stream_waiters(&key);
+    Ok(id_frame(id))
+}
+
+// AOF に記録する XADD の ID を、実際に割り当てた ID に置き換える
+// command はコマンド名を含む引数列で、reply は XADD の結果
+pub(super) fn fill_in_id(command: &mut [Bytes], reply: &Frame) {
+    let id = match reply {
+        Frame::Bulk(id) => id.clone(),
+        _ => return,
+    };
+    let mut i = 2;
+    while i < command.len() {
+        if eq_ignore_case(&command[i], "nomkstream") {
+            i += 1;
+        } else if eq_ignore_case(&command[i], "maxlen") {
+            i += 1;
+            if matches!(command.get(i).map(|a| a.as_ref()), Some(b"=" | b"~")) {
+                i += 1;
+            }
+            i += 1;
+        } else {
+            command[i] = id;
+            return;
+        }
+    }
+}
+
+// XLEN key
+fn xlen(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let len = read_stream(&mut shard, &key)?.map_or(0, |stream| stream.len());
+    Ok(Frame::Integer(len as i64))
+}
+
+// COUNT count を解釈する
+fn parse_count(args: &[Bytes]) -> Result<Option<usize>, CommandError> {
+    match args {
+        [] => Ok(None),
+        [name, count] if eq_ignore_case(name, "count") => {
+            Ok(Some(parse_int(count)?.max(0) as usize))
+        }
+        _ => Err(CommandError::syntax()),
+    }
+}
+
+// XRANGE key start end [COUNT count]
+fn xrange(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let start = parse_start(&args[1])?;
+    let end = parse_end(&args[2])?;
+    let count = parse_count(&args[3..])?;
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    Ok(match read_stream(&mut shard, &key)? {
+        Some(stream) => entries_frame(stream.range(start, end, count)),
+        None => Frame::Array(vec![]),
+    })
+}
+
+// XREVRANGE key end start [COUNT count]
+fn xrevrange(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let end = parse_end(&args[1])?;
+    let start = parse_start(&args[2])?;
+    let count = parse_count(&args[3..])?;
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    Ok(match read_stream(&mut shard, &key)? {
+        Some(stream) => entries_frame(stream.rev_range(start, end, count)),
+        None => Frame::Array(vec![]),
+    })
+}
+
+// XREAD と XREADGROUP の引数
+struct ReadRequest {
+    // (グループ名, コンシューマ名)
+    group: Option<(Bytes, Bytes)>,
+    count: Option<usize>,
+    // BLOCK の指定（0 なら無期限に待つので Some(None)）
+    block: Option<Option<Duration>>,
+    noack: bool,
+    keys: Vec<String>,
+    ids: Vec<Bytes>,
+}
+
+// どこから読み出すか
+#[derive(Clone, Copy)]
+enum ReadFrom {
+    // この ID より後のエントリ
+    After(StreamId),
+    // XREADGROUP の >（グループにまだ渡していないエントリ）
+    New,
+    // XREADGROUP で ID を指定した場合（このコンシューマの PEL のうち、この ID より後のもの）
+    History(StreamId),
+}
+
+impl ReadRequest {
+    // XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id ...]
+    // XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds] [NOACK] STREAMS key [key ...] id [id ...]
+    fn parse(args: &[Bytes], with_group: bool) -> Result<Self, CommandError> {
+        let command = if with_group { "xreadgroup" } else { "xread" };
+        let mut i = 0;
+
+        let group = if with_group {
+            if args.len() < 3 || !eq_ignore_case(&args[0], "group") {
+                return Err(CommandError::syntax());
+            }
+            i = 3;
+            Some((args[1].clone(), args[2].clone()))
+        } else {
+            None
+        };
+
+        let mut count = None;
+        let mut block = None;
+        let mut noack = false;
+        loop {
+            let arg = args.get(i).ok_or_else(CommandError::syntax)?;
+            if eq_ignore_case(arg, "streams") {
+                i += 1;
+                break;
+            } else if eq_ignore_case(arg, "count") {
+                let n = parse_int(args.get(i + 1).ok_or_else(CommandError::syntax)?)?;
+                count = (n > 0).then_some(n as usize);
+                i += 2;
+            } else if eq_ignore_case(arg, "block") {
+                let ms = parse_int(args.get(i + 1).ok_or_else(CommandError::syntax)?)?;
+                if ms < 0 {
+                    return Err(CommandError::new("ERR timeout is negative"));
+                }
+                block = Some((ms > 0).then(|| Duration::from_millis(ms as u64)));
+                i += 2;
+            } else if with_group && eq_ignore_case(arg, "noack") {
+                noack = true;
+                i += 1;
+            } else {
+                return Err(CommandError::syntax());
+            }
+        }
+
+        let rest = &args[i..];
+        if rest.is_empty() || !rest.len().is_multiple_of(2) {
+            return Err(CommandError::new(format!(
+                "ERR Unbalanced '{}' list of streams: for each stream key an ID or '$' must be specified.",
+                command
+            )));
+        }
+        let (keys, ids) = rest.split_at(rest.len() / 2);
+        Ok(ReadRequest {
+            group,
+            count,
+            block,
+            noack,
+            keys: keys.iter().map(key).collect(),
+            ids: ids.to_vec(),
+        })
+    }
+
+    // ID の指定を解釈する
+    // $ はこの時点のストリームの最後の ID に置き換える
+    fn resolve(&self, db: &ShardedDb) -> Result<Vec<ReadFrom>, CommandError> {
+        self.keys
+            .iter()
+            .zip(&self.ids)
+            .map(|(key, id)| match (&self.group, id.as_ref()) {
+                (Some(_), b">") => Ok(ReadFrom::New),
+                (Some(_), b"$") => Err(CommandError::new(
+                    "ERR The $ ID is meaningless in the context of XREADGROUP: you want to read the history of this consumer by specifying a proper ID, or use the > ID to get new messages. The $ ID would just return an empty result set.",
+                )),
+                (Some(_), id) => Ok(ReadFrom::History(parse_id(id, 0)?)),
+                (None, b"$") => {
+                    let shard = get_db_from_sharded_db(db, key);
+                    let mut shard = shard.lock().unwrap();
+                    let last = read_stream(&mut shard, key)?.map_or(StreamId::MIN, |s| s.last_id());
+                    Ok(ReadFrom::After(last))
+                }
+                (None, id) => Ok(ReadFrom::After(parse_id(id, 0)?)),
+            })
+            .collect()
+    }
+
+    // 新しいエントリが届くまで待ってよいか
+    // XREADGROUP で履歴を読む場合は待たない
+    fn may_block(&self, froms: &[ReadFrom]) -> bool {
+        !froms
+            .iter()
+            .any(|from| matches!(from, ReadFrom::History(_)))
+    }
+
+    // 各キーから一度だけ読み出す
+    // 読み出せるエントリが一つもなければ None を返す
+    fn read(&self, db: &ShardedDb, froms: &[ReadFrom]) -> Result<Option<Frame>, CommandError> {
+        let mut results = Vec::new();
+        for (key, from) in self.keys.iter().zip(froms) {
+            let shard = get_db_from_sharded_db(db, key);
+            let mut shard = shard.lock().unwrap();
+
+            let entries = match (&self.group, *from) {
+                (None, ReadFrom::After(id)) => match read_stream(&mut shard, key)? {
+                    Some(stream) => stream
+                        .range(Bound::Excluded(id), Bound::Unbounded, self.count)
+                        .into_iter()
+                        .map(|(id, fields)| entry_frame(id, fields))
+                        .collect(),
+                    None => vec![],
+                },
+                (Some((group, consumer)), from) => {
+                    let stream = get_stream(&mut shard, key)?;
+                    let stream = match stream {
+                        Some(stream) if stream.group(group).is_some() => stream,
+                        _ => {
+                            return Err(CommandError::new(format!(
+                                "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                                key,
+                                String::from_utf8_lossy(group)
+                            )))
+                        }
+                    };
+                    let entries = read_group(stream, group, consumer, from, self.count, self.noack);
+                    // 履歴の読み出しは、エントリがなくてもキーを含めて返す
+                    if matches!(from, ReadFrom::History(_)) {
+                        results.push(Frame::Array(vec![
+                            Frame::Bulk(Bytes::from(key.clone())),
+                            Frame::Array(entries),
+                        ]));
+                        continue;
+                    }
+                    entries
+                }
+                (None, _) => unreachable!(),
+            };
+            if !entries.is_empty() {
+                results.push(Frame::Array(vec![
+                    Frame::Bulk(Bytes::from(key.clone())),
+                    Frame::Array(entries),
+                ]));
+            }
+        }
+        Ok((!results.is_empty()).then_some(Frame::Array(results)))
+    }
+}
+
+// コンシューマグループとしてエントリを読み出す
+//
+// > の場合はまだグループに渡していないエントリを渡し、PEL に登録する
+// ID を指定した場合は、このコンシューマの PEL にあるエントリを返す
+// （XACK される前にストリームから削除されたエントリは、フィールドを Null として返す）
+fn read_group(
+    stream: &mut Stream,
+    group: &[u8],
+    consumer: &Bytes,
+    from: ReadFrom,
+    count: Option<usize>,
+    noack: bool,
+) -> Vec<Frame> {
+    let now = Instant::now();
+    let last_delivered = stream.group(group).unwrap().last_delivered;
+
+    match from {
+        ReadFrom::New => {
+            let entries: Vec<(StreamId, Fields)> = stream
+                .range(Bound::Excluded(last_delivered), Bound::Unbounded, count)
+                .into_iter()
+                .map(|(id, fields)| (id, fields.clone()))
+                .collect();
+
+            let group = stream.group_mut(group).unwrap();
+            group.consumers.insert(consumer.clone(), now);
+            if let Some((last, _)) = entries.last() {
+                group.last_delivered = *last;
+            }
+            if !noack {
+                for (id, _) in &entries {
+                    group.pending.insert(
+                        *id,
+                        PendingEntry {
+                            consumer: consumer.clone(),
+                            delivered_at: now,
+                            delivery_count: 1,
+                        },
+                    );
+                }
+            }
+            entries
+                .iter()
+                .map(|(id, fields)| entry_frame(*id, fields))
+                .collect()
+        }
+        ReadFrom::History(after) => {
+            let ids: Vec<StreamId> = {
+                let group = stream.group_mut(group).unwrap();
+                group.consumers.insert(consumer.clone(), now);
+                group
+                    .pending
+                    .range((Bound::Excluded(after), Bound::Unbounded))
+                    .filter(|(_, pending)| pending.consumer == *consumer)
+                    .map(|(id, _)| *id)
+                    .take(count.unwrap_or(usize::MAX))
+                    .collect()
+            };
+            ids.into_iter()
+                .map(|id| match stream.get(&id) {
+                    Some(fields) => entry_frame(id, fields),
+                    None => Frame::Array(vec![id_frame(id), Frame::Null]),
+                })
+                .collect()
+        }
+        ReadFrom::After(_) => unreachable!(),
+    }
+}
+
+// XREAD, XREADGROUP の STREAMS の後のキー
+fn stream_keys(args: &[Bytes]) -> Vec<Bytes> {
+    let with_group = eq_ignore_case(&args[0], "xreadgroup");
+    match ReadRequest::parse(&args[1..], with_group) {
+        Ok(req) => req.keys.into_iter().map(Bytes::from).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+// XREAD（待たずに実行する場合）
+fn xread(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    read_now(db, &ReadRequest::parse(args, false)?)
+}
+
+// XREADGROUP（待たずに実行する場合）
+fn xreadgroup(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    read_now(db, &ReadRequest::parse(args, true)?)
+}
+
+fn read_now(db: &ShardedDb, req: &ReadRequest) -> CommandResult {
+    let froms = req.resolve(db)?;
+    Ok(req.read(db, &froms)?.unwrap_or(Frame::NullArray))
+}
+
+// XREAD ... BLOCK milliseconds ...
+// XREADGROUP ... BLOCK milliseconds ...
+//
+// 読み出せるエントリがなければ、各キーの待ち行列に Notify を登録して XADD を待つ
+// 知らされたら読み直し、まだエントリがなければ（他のコンシューマが先に読んだなど）再び待つ
+async fn blocking_read(db: ShardedDb, args: Vec<Bytes>, with_group: bool) -> CommandResult {
+    let req = ReadRequest::parse(&args, with_group)?;
+    let timeout = match req.block {
+        Some(timeout) => timeout,
+        None => {
+            let _gate = db.write_gate();
+            let reply = read_now(&db, &req)?;
+            propagate_read(&db, &req, &args);
+            return Ok(reply);
+        }
+    };
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    let notify = Arc::new(Notify::new());
+    let waiter = StreamWaiter::new(notify.clone());
+    // この Future が途中でドロップされても、ガードが待ち行列から登録を取り除く
+    let _guard = StreamWaitGuard {
+        db: &db,
+        keys: req.keys.clone(),
+        id: waiter.id,
+    };
+
+    // 読み出す前に登録しておけば、読み出してから待つまでの間に追加されても
+    // Notify に通知が溜まるので取りこぼさない
+    let froms = {
+        let _gate = db.shared();
+        for key in &req.keys {
+            let shard = get_db_from_sharded_db(&db, key);
+            shard.lock().unwrap().add_stream_waiter(key, waiter.clone());
+        }
+        req.resolve(&db)?
+    };
+
+    loop {
+        let read = {
+            let _gate = db.write_gate();
+            let read = req.read(&db, &froms)?;
+            if read.is_some() || !req.may_block(&froms) {
+                propagate_read(&db, &req, &args);
+            }
+            read
+        };
+        match read {
+            Some(frame) => return Ok(frame),
+            None if !req.may_block(&froms) => return Ok(Frame::NullArray),
+            None => {}
+        }
+
+        match deadline {
+            None => notify.notified().await,
+            Some(deadline) => {
+                if tokio::time::timeout_at(deadline, notify.notified())
+                    .await
+                    .is_err()
+                {
+                    return Ok(Frame::NullArray);
+                }
+            }
+        }
+    }
+}
+
+// XREADGROUP はコンシューマグループの状態を変更するので AOF とフォロワーに伝える
+// 読み込み直すときは待たずに実行されるので、記録した時点と同じエントリを読み出す
+fn propagate_read(db: &ShardedDb, req: &ReadRequest, args: &[Bytes]) {
+    if req.group.is_some() {
+        let mut command = vec![Bytes::from("XREADGROUP")];
+        command.extend(args.iter().cloned());
+        propagate::command(db, command);
+    }
+}
+
+// 待ち行列への登録を、待つのをやめたときに取り除くためのガード
+struct StreamWaitGuard<'a> {
+    db: &'a ShardedDb,
+    keys: Vec<String>,
+    id: u64,
+}
+
+impl Drop for StreamWaitGuard<'_> {
+    fn drop(&mut self) {
+        for key in &self.keys {
+            let shard = get_db_from_sharded_db(self.db, key);
+            shard.lock().unwrap().remove_stream_waiter(key, self.id);
+        }
+    }
+}
+
+fn no_group(key: &str, group: &[u8]) -> CommandError {
+    CommandError::new(format!(
+        "NOGROUP No such key '{}' or consumer group '{}'",
+        key,
+        String::from_utf8_lossy(group)
+    ))
+}
+
+// XGROUP CREATE key group <id|$> [MKSTREAM]
+// XGROUP SETID key group <id|$>
+// XGROUP DESTROY key group
+// XGROUP CREATECONSUMER key group consumer
+// XGROUP DELCONSUMER key group consumer
+fn xgroup(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let sub = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    let arity = match sub.as_str() {
+        "create" => 4..=5,
+        "setid" | "createconsumer" | "delconsumer" => 4..=4,
+        "destroy" => 3..=3,
+        _ => {
+            return Err(CommandError::new(format!(
+                "ERR unknown subcommand '{}'. Try XGROUP HELP.",
+                String::from_utf8_lossy(&args[0])
+            )))
+        }
+    };
+    if !arity.contains(&args.len()) {
+        return Err(CommandError::new(format!(
+            "ERR wrong number of arguments for 'xgroup|{}' command",
+            sub
+        )));
+    }
+
+    let key = key(&args[1]);
+    let group = &args[2];
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    if sub == "create" {
+        let mkstream = match args.get(4) {
+            Some(arg) if eq_ignore_case(arg, "mkstream") => true,
+            Some(_) => return Err(CommandError::syntax()),
+            None => false,
+        };
+        if read_stream(&mut shard, &key)?.is_none() {
+            if !mkstream {
+                return Err(CommandError::new(
+                    "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.",
+                ));
+            }
+            shard.insert(key.clone(), Value::Stream(Stream::new()), None);
+        }
+        let stream = get_stream(&mut shard, &key)?.unwrap();
+        let id = group_start_id(stream, &args[3])?;
+        if !stream.create_group(group.clone(), id) {
+            return Err(CommandError::new(
+                "BUSYGROUP Consumer Group name already exists",
+            ));
+        }
+        return Ok(Frame::Simple("OK".to_string()));
+    }
+
+    let stream = match get_stream(&mut shard, &key)? {
+        Some(stream) => stream,
+        None => {
+            return Err(CommandError::new(
+                "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.",
+            ))
+        }
+    };
+    if sub == "destroy" {
+        return Ok(Frame::Integer(stream.destroy_group(group) as i64));
+    }
+
+    let id = match sub.as_str() {
+        "setid" => Some(group_start_id(stream, &args[3])?),
+        _ => None,
+    };
+    let group_state = match stream.group_mut(group) {
+        Some(group_state) => group_state,
+        None => return Err(no_group(&key, group)),
+    };
+    match sub.as_str() {
+        "setid" => {
+            group_state.last_delivered = id.unwrap();
+            Ok(Frame::Simple("OK".to_string()))
+        }
+        "createconsumer" => {
+            let created = !group_state.consumers.contains_key(&args[3]);
+            if created {
+                group_state
+                    .consumers
+                    .insert(args[3].clone(), Instant::now());
+            }
+            Ok(Frame::Integer(created as i64))
+        }
+        "delconsumer" => {
+            let consumer = &args[3];
+            let before = group_state.pending.len();
+            group_state
+                .pending
+                .retain(|_, pending| pending.consumer != *consumer);
+            group_state.consumers.remove(consumer);
+            Ok(Frame::Integer((before - group_state.pending.len()) as i64))
+        }
+        _ => unreachable!(),
+    }
+}
+
+// グループの読み出し開始位置（$ ならストリームの最後の ID）
+fn group_start_id(stream: &Stream, arg: &[u8]) -> Result<StreamId, CommandError> {
+    match arg {
+        b"$" => Ok(stream.last_id()),
+        _ => parse_id(arg, 0),
+    }
+}
+
+// XACK key group id [id ...]
+fn xack(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let ids = args[2..]
+        .iter()
+        .map(|id| parse_id(id, 0))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let group = match get_stream(&mut shard, &key)? {
+        Some(stream) => stream.group_mut(&args[1]),
+        None => None,
+    };
+    let acked = match group {
+        Some(group) => ids
+            .iter()
+            .filter(|id| group.pending.remove(id).is_some())
+            .count(),
+        None => 0,
+    };
+    Ok(Frame::Integer(acked as i64))
+}
+
+// XPENDING key group [[IDLE min-idle-time] start end count [consumer]]
+//
+// 範囲を指定しなければ、PEL の件数、最小と最大の ID、コンシューマごとの件数を返す
+// 範囲を指定すれば、各エントリの ID、コンシューマ、経過ミリ秒、配送回数を返す
+fn xpending(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let mut rest = &args[2..];
+    let mut min_idle = None;
+    if rest.first().is_some_and(|arg| eq_ignore_case(arg, "idle")) {
+        let ms = parse_int(rest.get(1).ok_or_else(CommandError::syntax)?)?;
+        min_idle = Some(Duration::from_millis(ms.max(0) as u64));
+        rest = &rest[2..];
+    }
+    let range = match rest {
+        [] if min_idle.is_none() => None,
+        [start, end, count] | [start, end, count, _] => Some((
+            parse_start(start)?,
+            parse_end(end)?,
+            parse_int(count)?.max(0) as usize,
+        )),
+        _ => return Err(CommandError::syntax()),
+    };
+    let consumer = rest.get(3);
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let group = match read_stream(&mut shard, &key)?.and_then(|stream| stream.group(&args[1])) {
+        Some(group) => group,
+        None => return Err(no_group(&key, &args[1])),
+    };
+
+    let (start, end, count) = match range {
+        Some(range) => range,
+        None => return Ok(pending_summary(&group.pending)),
+    };
+    let now = Instant::now();
+    let items = group
+        .pending
+        .range((start, end))
+        .filter(|(_, pending)| consumer.is_none_or(|consumer| pending.consumer == *consumer))
+        .filter(|(_, pending)| min_idle.is_none_or(|idle| now - pending.delivered_at >= idle))
+        .take(count)
+        .map(|(id, pending)| {
+            Frame::Array(vec![
+                id_frame(*id),
+                Frame::Bulk(pending.consumer.clone()),
+                Frame::Integer((now - pending.delivered_at).as_millis() as i64),
+                Frame::Integer(pending.delivery_count as i64),
+            ])
+        })
+        .collect();
+    Ok(Frame::Array(items))
+}
+
+fn pending_summary(pending: &std::collections::BTreeMap<StreamId, PendingEntry>) -> Frame {
+    let (first, last) = match (pending.keys().next(), pending.keys().next_back()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => {
+            return Frame::Array(vec![
+                Frame::Integer(0),
+                Frame::Null,
+                Frame::Null,
+                Frame::NullArray,
+            ])
+        }
+    };
+
+    let mut per_consumer: Vec<(Bytes, usize)> = Vec::new();
+    for entry in pending.values() {
+        match per_consumer
+            .iter_mut()
+            .find(|(name, _)| *name == entry.consumer)
+        {
+            Some((_, n)) => *n += 1,
+            None => per_consumer.push((entry.consumer.clone(), 1)),
+        }
+    }
+    per_consumer.sort();
+
+    Frame::Array(vec![
+        Frame::Integer(pending.len() as i64),
+        id_frame(first),
+        id_frame(last),
+        Frame::Array(
+            per_consumer
+                .into_iter()
+                .map(|(name, n)| {
+                    Frame::Array(vec![
+                        Frame::Bulk(name),
+                        Frame::Bulk(Bytes::from(n.to_string())),
+                    ])
+                })
+                .collect(),
+        ),
+    ])
+}
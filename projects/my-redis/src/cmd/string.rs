@@ -0,0 +1,219 @@
+use bytes::Bytes;
+use tokio::time::Instant;
+
+use super::keys::{deadline_at_unix_millis, deadline_in_millis, parse_millis};
+use super::{as_string, eq_ignore_case, key, parse_int, CommandError, CommandResult, Registry};
+use crate::db::{get_db_from_sharded_db, Shard, ShardedDb, Value};
+use crate::frame::Frame;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register("get", 2, get);
+    registry.register_write("set", -3, set);
+    registry.register_write("incr", 2, incr);
+    registry.register_write("decr", 2, decr);
+    registry.register_write("incrby", 3, incrby);
+    registry.register_write("decrby", 3, decrby);
+    registry.register_write("incrbyfloat", 3, incrbyfloat);
+    for name in ["set", "incr", "decr", "incrby", "decrby", "incrbyfloat"] {
+        registry.mark_denyoom(name);
+    }
+}
+
+// GET key
+fn get(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let db = get_db_from_sharded_db(db, &key);
+    let mut db = db.lock().unwrap();
+    if let Some(entry) = db.get(&key) {
+        // `Frame::Bulk` はデータが Bytes` 型であることを期待する
+        Ok(Frame::Bulk(as_string(&entry.value)?.clone()))
+    } else {
+        Ok(Frame::Null)
+    }
+}
+
+// SET の有効期限に関するオプション
+enum Expiry {
+    // 期限なしで上書きする
+    None,
+    // 指定した時刻に期限切れにする
+    At(Instant),
+    // 既存の期限を引き継ぐ
+    Keep,
+}
+
+// SET key value [NX | XX] [GET] [EX seconds | PX milliseconds |
+//     EXAT unix-time-seconds | PXAT unix-time-milliseconds | KEEPTTL]
+fn set(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let (mut nx, mut xx, mut get) = (false, false, false);
+    let mut expiry = Expiry::None;
+    let mut has_expiry = false;
+
+    let mut opts = args[2..].iter();
+    while let Some(opt) = opts.next() {
+        if eq_ignore_case(opt, "nx") && !xx {
+            nx = true;
+        } else if eq_ignore_case(opt, "xx") && !nx {
+            xx = true;
+        } else if eq_ignore_case(opt, "get") {
+            get = true;
+        } else if eq_ignore_case(opt, "keepttl") && !has_expiry {
+            expiry = Expiry::Keep;
+            has_expiry = true;
+        } else if !has_expiry {
+            let (unit, absolute) = if eq_ignore_case(opt, "ex") {
+                (1000, false)
+            } else if eq_ignore_case(opt, "px") {
+                (1, false)
+            } else if eq_ignore_case(opt, "exat") {
+                (1000, true)
+            } else if eq_ignore_case(opt, "pxat") {
+                (1, true)
+            } else {
+                return Err(CommandError::syntax());
+            };
+
+            let arg = opts.next().ok_or_else(CommandError::syntax)?;
+            let ms = parse_millis(arg, unit, "set")?;
+            if ms <= 0 {
+                return Err(CommandError::new(
+                    "ERR invalid expire time in 'set' command",
+                ));
+            }
+            let deadline = if absolute {
+                deadline_at_unix_millis(ms, "set")?
+            } else {
+                deadline_in_millis(ms, "set")?
+            };
+            expiry = Expiry::At(deadline);
+            has_expiry = true;
+        } else {
+            return Err(CommandError::syntax());
+        }
+    }
+
+    let key = key(&args[0]);
+    let db = get_db_from_sharded_db(db, &key);
+    let mut db = db.lock().unwrap();
+
+    let (exists, prev_value, prev_expiry) = match db.get(&key) {
+        // GET オプションつきの場合は、以前の値が文字列でなければエラーにする
+        Some(entry) if get => (
+            true,
+            Some(as_string(&entry.value)?.clone()),
+            entry.expires_at(),
+        ),
+        Some(entry) => (true, None, entry.expires_at()),
+        None => (false, None, None),
+    };
+
+    // GET オプションの場合は以前の値を、そうでなければ OK を返す
+    let reply = |applied: bool| {
+        if get {
+            prev_value.clone().map_or(Frame::Null, Frame::Bulk)
+        } else if applied {
+            Frame::Simple("OK".to_string())
+        } else {
+            Frame::Null
+        }
+    };
+
+    if (nx && exists) || (xx && !exists) {
+        return Ok(reply(false));
+    }
+
+    let expires_at = match expiry {
+        Expiry::None => None,
+        Expiry::At(when) => Some(when),
+        Expiry::Keep => prev_expiry,
+    };
+    db.insert(key, Value::String(args[1].clone()), expires_at);
+    Ok(reply(true))
+}
+
+// INCR key
+fn incr(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    increment(db, &args[0], 1)
+}
+
+// DECR key
+fn decr(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    increment(db, &args[0], -1)
+}
+
+// INCRBY key increment
+fn incrby(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    increment(db, &args[0], parse_int(&args[1])?)
+}
+
+// DECRBY key decrement
+fn decrby(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let delta = parse_int(&args[1])?
+        .checked_neg()
+        .ok_or_else(|| CommandError::new("ERR decrement would overflow"))?;
+    increment(db, &args[0], delta)
+}
+
+// キーの値を整数として解釈して delta を足す
+// キーが存在しなければ 0 とみなす
+// 読み出しから書き込みまでシャードのロックを保持するので、並行した更新が失われることはない
+fn increment(db: &ShardedDb, key: &Bytes, delta: i64) -> CommandResult {
+    let key = super::key(key);
+    let db = get_db_from_sharded_db(db, &key);
+    let mut db = db.lock().unwrap();
+
+    let current = match db.get(&key) {
+        Some(entry) => parse_int(as_string(&entry.value)?)?,
+        None => 0,
+    };
+    let value = current
+        .checked_add(delta)
+        .ok_or_else(|| CommandError::new("ERR increment or decrement would overflow"))?;
+
+    store(&mut db, key, Bytes::from(value.to_string()));
+    Ok(Frame::Integer(value))
+}
+
+// INCRBYFLOAT key increment
+fn incrbyfloat(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let delta = parse_float(&args[1])?;
+
+    let key = key(&args[0]);
+    let db = get_db_from_sharded_db(db, &key);
+    let mut db = db.lock().unwrap();
+
+    let current = match db.get(&key) {
+        Some(entry) => parse_float(as_string(&entry.value)?)?,
+        None => 0.0,
+    };
+    let value = current + delta;
+    if !value.is_finite() {
+        return Err(CommandError::new(
+            "ERR increment would produce NaN or Infinity",
+        ));
+    }
+
+    let value = Bytes::from(value.to_string());
+    store(&mut db, key, value.clone());
+    Ok(Frame::Bulk(value))
+}
+
+// 値を書き換える
+// 既存のキーであれば有効期限はそのまま残す
+fn store(db: &mut Shard, key: String, value: Bytes) {
+    match db.get_mut(&key) {
+        Some(entry) => entry.value = Value::String(value),
+        None => {
+            db.insert(key, Value::String(value), None);
+        }
+    }
+}
+
+// 引数のバイト列を有限の浮動小数点数として解釈する
+fn parse_float(arg: &[u8]) -> Result<f64, CommandError> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|f| f.is_finite())
+        .ok_or_else(|| CommandError::new("ERR value is not a valid float"))
+}
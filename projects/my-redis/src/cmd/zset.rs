@@ -0,0 +1,487 @@
+use bytes::Bytes;
+
+use super::list::normalize_range;
+use super::{eq_ignore_case, key, parse_int, CommandError, CommandResult, Registry};
+use crate::db::{get_db_from_sharded_db, ScoreBound, Shard, ShardedDb, SortedSet, Value};
+use crate::frame::Frame;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register_write("zadd", -4, zadd);
+    registry.register_write("zincrby", 4, zincrby);
+    registry.register_write("zrem", -3, zrem);
+    registry.register("zscore", 3, zscore);
+    registry.register("zcard", 2, zcard);
+    registry.register("zrank", 3, zrank);
+    registry.register("zrevrank", 3, zrevrank);
+    registry.register("zrange", -4, zrange);
+    registry.register("zrevrange", -4, zrevrange);
+    registry.register("zrangebyscore", -4, zrangebyscore);
+    registry.register("zrevrangebyscore", -4, zrevrangebyscore);
+    registry.register_write("zpopmin", -2, zpopmin);
+    registry.register_write("zpopmax", -2, zpopmax);
+    for name in ["zadd", "zincrby"] {
+        registry.mark_denyoom(name);
+    }
+}
+
+// キーに対応するソート済み集合を参照する
+// キーが存在しなければ None、ソート済み集合以外の値であれば WRONGTYPE エラーを返す
+fn get_zset<'a>(
+    shard: &'a mut Shard,
+    key: &str,
+) -> Result<Option<&'a mut SortedSet>, CommandError> {
+    match shard.get_mut(key) {
+        None => Ok(None),
+        Some(entry) => match &mut entry.value {
+            Value::ZSet(zset) => Ok(Some(zset)),
+            _ => Err(CommandError::wrong_type()),
+        },
+    }
+}
+
+// キーに対応するソート済み集合を読み取り専用で参照する
+// 変更しないので、WATCH しているトランザクションを失敗させない
+fn read_zset<'a>(shard: &'a mut Shard, key: &str) -> Result<Option<&'a SortedSet>, CommandError> {
+    match shard.get(key) {
+        None => Ok(None),
+        Some(entry) => match &entry.value {
+            Value::ZSet(v) => Ok(Some(v)),
+            _ => Err(CommandError::wrong_type()),
+        },
+    }
+}
+
+// キーに対応するソート済み集合を参照する
+// キーが存在しなければ空の集合を作成する
+fn get_or_create_zset<'a>(
+    shard: &'a mut Shard,
+    key: &str,
+) -> Result<&'a mut SortedSet, CommandError> {
+    if get_zset(shard, key)?.is_none() {
+        shard.insert(key.to_string(), Value::ZSet(SortedSet::new()), None);
+    }
+    Ok(get_zset(shard, key)?.unwrap())
+}
+
+// スコアを解釈する
+// INCRBYFLOAT と異なり、inf や -inf も受け付ける
+fn parse_score(arg: &[u8]) -> Result<f64, CommandError> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|f| !f.is_nan())
+        .ok_or_else(|| CommandError::new("ERR value is not a valid float"))
+}
+
+// スコア範囲の端を解釈する
+// 先頭に ( をつけると、その値を含まない範囲になる
+fn parse_bound(arg: &[u8]) -> Result<ScoreBound, CommandError> {
+    let (exclusive, value) = match arg.strip_prefix(b"(") {
+        Some(rest) => (true, rest),
+        None => (false, arg),
+    };
+    let value =
+        parse_score(value).map_err(|_| CommandError::new("ERR min or max is not a float"))?;
+    Ok(if exclusive {
+        ScoreBound::Exclusive(value)
+    } else {
+        ScoreBound::Inclusive(value)
+    })
+}
+
+// RESP3 では Double として返す（RESP2 ではこれまでどおり文字列になる）
+fn score_frame(score: f64) -> Frame {
+    Frame::Double(score)
+}
+
+// (メンバー, スコア) の列を応答の配列にする
+fn entries_frame(entries: Vec<(Bytes, f64)>, with_scores: bool) -> Frame {
+    let mut items = Vec::new();
+    for (member, score) in entries {
+        items.push(Frame::Bulk(member));
+        if with_scores {
+            items.push(score_frame(score));
+        }
+    }
+    Frame::Array(items)
+}
+
+// ZADD の更新条件
+#[derive(Default)]
+struct AddOptions {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+    ch: bool,
+    incr: bool,
+}
+
+impl AddOptions {
+    // 既存のスコア current を new に更新してよいか
+    fn allows(&self, current: Option<f64>, new: f64) -> bool {
+        match current {
+            None => !self.xx,
+            Some(current) => !(self.nx || self.gt && new <= current || self.lt && new >= current),
+        }
+    }
+}
+
+// ZADD key [NX|XX] [GT|LT] [CH] [INCR] score member [score member ...]
+fn zadd(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let mut opts = AddOptions::default();
+    let mut i = 1;
+    while i < args.len() {
+        let arg = &args[i];
+        if eq_ignore_case(arg, "nx") {
+            opts.nx = true;
+        } else if eq_ignore_case(arg, "xx") {
+            opts.xx = true;
+        } else if eq_ignore_case(arg, "gt") {
+            opts.gt = true;
+        } else if eq_ignore_case(arg, "lt") {
+            opts.lt = true;
+        } else if eq_ignore_case(arg, "ch") {
+            opts.ch = true;
+        } else if eq_ignore_case(arg, "incr") {
+            opts.incr = true;
+        } else {
+            break;
+        }
+        i += 1;
+    }
+
+    let pairs = &args[i..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return Err(CommandError::syntax());
+    }
+    if opts.nx && opts.xx {
+        return Err(CommandError::new(
+            "ERR XX and NX options at the same time are not compatible",
+        ));
+    }
+    if (opts.gt && opts.lt) || (opts.nx && (opts.gt || opts.lt)) {
+        return Err(CommandError::new(
+            "ERR GT, LT, and/or NX options at the same time are not compatible",
+        ));
+    }
+    if opts.incr && pairs.len() != 2 {
+        return Err(CommandError::new(
+            "ERR INCR option supports a single increment-element pair",
+        ));
+    }
+    // 集合を変更する前にすべてのスコアを検査する
+    let scores = pairs
+        .chunks(2)
+        .map(|pair| parse_score(&pair[0]))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+    let zset = get_or_create_zset(&mut shard, &key)?;
+
+    if opts.incr {
+        let member = &pairs[1];
+        let current = zset.score(member);
+        let score = current.unwrap_or(0.0) + scores[0];
+        if score.is_nan() {
+            shard.remove_if_empty(&key);
+            return Err(CommandError::new(
+                "ERR resulting score is not a number (NaN)",
+            ));
+        }
+        let reply = if opts.allows(current, score) {
+            zset.insert(member.clone(), score);
+            score_frame(score)
+        } else {
+            Frame::Null
+        };
+        shard.remove_if_empty(&key);
+        return Ok(reply);
+    }
+
+    let mut added = 0;
+    let mut changed = 0;
+    for (pair, score) in pairs.chunks(2).zip(scores) {
+        let current = zset.score(&pair[1]);
+        if !opts.allows(current, score) {
+            continue;
+        }
+        match current {
+            None => added += 1,
+            Some(current) if current != score => changed += 1,
+            Some(_) => {}
+        }
+        zset.insert(pair[1].clone(), score);
+    }
+    // XX などで何も追加されなかった場合は、作成した空の集合を削除する
+    shard.remove_if_empty(&key);
+
+    let count = if opts.ch { added + changed } else { added };
+    Ok(Frame::Integer(count))
+}
+
+// ZINCRBY key increment member
+fn zincrby(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let delta = parse_score(&args[1])?;
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+    let zset = get_or_create_zset(&mut shard, &key)?;
+
+    let score = zset.score(&args[2]).unwrap_or(0.0) + delta;
+    if score.is_nan() {
+        shard.remove_if_empty(&key);
+        return Err(CommandError::new(
+            "ERR resulting score is not a number (NaN)",
+        ));
+    }
+    zset.insert(args[2].clone(), score);
+    Ok(score_frame(score))
+}
+
+// ZREM key member [member ...]
+fn zrem(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let removed = match get_zset(&mut shard, &key)? {
+        Some(zset) => args[1..]
+            .iter()
+            .filter(|member| zset.remove(member))
+            .count(),
+        None => 0,
+    };
+    shard.remove_if_empty(&key);
+    Ok(Frame::Integer(removed as i64))
+}
+
+// ZSCORE key member
+fn zscore(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let score = read_zset(&mut shard, &key)?.and_then(|zset| zset.score(&args[1]));
+    Ok(score.map_or(Frame::Null, score_frame))
+}
+
+// ZCARD key
+fn zcard(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let len = read_zset(&mut shard, &key)?.map_or(0, |zset| zset.len());
+    Ok(Frame::Integer(len as i64))
+}
+
+// ZRANK key member
+fn zrank(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    rank(db, args, false)
+}
+
+// ZREVRANK key member
+fn zrevrank(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    rank(db, args, true)
+}
+
+fn rank(db: &ShardedDb, args: &[Bytes], rev: bool) -> CommandResult {
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let rank = read_zset(&mut shard, &key)?.and_then(|zset| {
+        let rank = zset.rank(&args[1])?;
+        Some(if rev { zset.len() - 1 - rank } else { rank })
+    });
+    Ok(rank.map_or(Frame::Null, |rank| Frame::Integer(rank as i64)))
+}
+
+// ZRANGE 系コマンドの範囲指定
+#[derive(Default)]
+struct RangeOptions {
+    by_score: bool,
+    rev: bool,
+    // (offset, count)、count が None なら制限なし
+    limit: Option<(i64, Option<usize>)>,
+    with_scores: bool,
+}
+
+impl RangeOptions {
+    // [BYSCORE] [REV] [LIMIT offset count] [WITHSCORES] を解釈する
+    // allowed に含まれないオプションは構文エラーにする
+    fn parse(args: &[Bytes], allowed: &[&str]) -> Result<Self, CommandError> {
+        let mut opts = RangeOptions::default();
+        let mut i = 0;
+        while i < args.len() {
+            let arg = &args[i];
+            let name = match allowed.iter().find(|name| eq_ignore_case(arg, name)) {
+                Some(name) => *name,
+                None => return Err(CommandError::syntax()),
+            };
+            match name {
+                "byscore" => opts.by_score = true,
+                "rev" => opts.rev = true,
+                "withscores" => opts.with_scores = true,
+                "limit" => {
+                    if i + 2 >= args.len() {
+                        return Err(CommandError::syntax());
+                    }
+                    let offset = parse_int(&args[i + 1])?;
+                    let count = parse_int(&args[i + 2])?;
+                    // 負の count は制限なしを表す
+                    opts.limit = Some((offset, (count >= 0).then_some(count as usize)));
+                    i += 2;
+                }
+                _ => unreachable!(),
+            }
+            i += 1;
+        }
+        Ok(opts)
+    }
+}
+
+// ZRANGE key start stop [BYSCORE] [REV] [LIMIT offset count] [WITHSCORES]
+//
+// BYSCORE と REV を同時に指定した場合は、start と stop を max と min として解釈する
+fn zrange(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let opts = RangeOptions::parse(&args[3..], &["byscore", "rev", "limit", "withscores"])?;
+    if opts.limit.is_some() && !opts.by_score {
+        return Err(CommandError::new(
+            "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX",
+        ));
+    }
+    if opts.by_score && opts.rev {
+        range_by_score(db, &args[0], &args[2], &args[1], opts)
+    } else if opts.by_score {
+        range_by_score(db, &args[0], &args[1], &args[2], opts)
+    } else {
+        range_by_rank(db, &args[0], &args[1], &args[2], opts)
+    }
+}
+
+// ZREVRANGE key start stop [WITHSCORES]
+fn zrevrange(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let mut opts = RangeOptions::parse(&args[3..], &["withscores"])?;
+    opts.rev = true;
+    range_by_rank(db, &args[0], &args[1], &args[2], opts)
+}
+
+// ZRANGEBYSCORE key min max [WITHSCORES] [LIMIT offset count]
+fn zrangebyscore(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let opts = RangeOptions::parse(&args[3..], &["withscores", "limit"])?;
+    range_by_score(db, &args[0], &args[1], &args[2], opts)
+}
+
+// ZREVRANGEBYSCORE key max min [WITHSCORES] [LIMIT offset count]
+fn zrevrangebyscore(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    let mut opts = RangeOptions::parse(&args[3..], &["withscores", "limit"])?;
+    opts.rev = true;
+    range_by_score(db, &args[0], &args[2], &args[1], opts)
+}
+
+// 順位で範囲を指定する
+// REV の場合、順位はスコアの降順で数える
+fn range_by_rank(
+    db: &ShardedDb,
+    key: &Bytes,
+    start: &Bytes,
+    stop: &Bytes,
+    opts: RangeOptions,
+) -> CommandResult {
+    let start = parse_int(start)?;
+    let stop = parse_int(stop)?;
+
+    let key = super::key(key);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let entries = match read_zset(&mut shard, &key)? {
+        Some(zset) => match normalize_range(start, stop, zset.len()) {
+            Some((start, stop)) if opts.rev => zset.rev_range_by_rank(start, stop),
+            Some((start, stop)) => zset.range_by_rank(start, stop),
+            None => vec![],
+        },
+        None => vec![],
+    };
+    Ok(entries_frame(entries, opts.with_scores))
+}
+
+// スコアで範囲を指定する
+// REV の場合は降順に並べ、LIMIT の offset もその順に数える
+fn range_by_score(
+    db: &ShardedDb,
+    key: &Bytes,
+    min: &Bytes,
+    max: &Bytes,
+    opts: RangeOptions,
+) -> CommandResult {
+    let min = parse_bound(min)?;
+    let max = parse_bound(max)?;
+    let (offset, count) = opts.limit.unwrap_or((0, None));
+    if offset < 0 {
+        return Ok(Frame::Array(vec![]));
+    }
+
+    let key = super::key(key);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let entries = match read_zset(&mut shard, &key)? {
+        Some(zset) if opts.rev => zset.rev_range_by_score(min, max, offset as usize, count),
+        Some(zset) => zset.range_by_score(min, max, offset as usize, count),
+        None => vec![],
+    };
+    Ok(entries_frame(entries, opts.with_scores))
+}
+
+// ZPOPMIN key [count]
+fn zpopmin(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    pop_command(db, args, SortedSet::pop_min)
+}
+
+// ZPOPMAX key [count]
+fn zpopmax(db: &ShardedDb, args: &[Bytes]) -> CommandResult {
+    pop_command(db, args, SortedSet::pop_max)
+}
+
+// メンバーとスコアを交互に並べた配列を返す
+fn pop_command(
+    db: &ShardedDb,
+    args: &[Bytes],
+    pop: fn(&mut SortedSet) -> Option<(Bytes, f64)>,
+) -> CommandResult {
+    let count = match args {
+        [_] => 1,
+        [_, count] => {
+            let count = parse_int(count)?;
+            if count < 0 {
+                return Err(CommandError::new(
+                    "ERR value is out of range, must be positive",
+                ));
+            }
+            count as usize
+        }
+        _ => return Err(CommandError::syntax()),
+    };
+
+    let key = key(&args[0]);
+    let shard = get_db_from_sharded_db(db, &key);
+    let mut shard = shard.lock().unwrap();
+
+    let mut popped = Vec::new();
+    if let Some(zset) = get_zset(&mut shard, &key)? {
+        while popped.len() < count {
+            match pop(zset) {
+                Some(entry) => popped.push(entry),
+                None => break,
+            }
+        }
+    }
+    shard.remove_if_empty(&key);
+    Ok(entries_frame(popped, true))
+}
@@ -0,0 +1,73 @@
+// tokio_util::codec で RESP のフレームを読み書きするためのコーデック
+//
+// Framed などと組み合わせると、ストリームを Stream<Item = Frame> と Sink<Frame> として扱える
+// パースは Connection と同じ connection::parse_frame を、
+// エンコードは frame::encode を使うので、読み書きの結果は Connection と変わらない
+
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::connection;
+use crate::frame::{encode, Frame, Limits, Protocol};
+
+#[derive(Debug, Clone, Default)]
+pub struct RespCodec {
+    // 書き込むフレームのプロトコル
+    protocol: Protocol,
+    // 読み込むフレームの大きさの上限
+    limits: Limits,
+}
+
+impl RespCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    // 以降に書き込むフレームのプロトコルを切り替える
+    // Framed の中にあるときは codec_mut() から呼び出す
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    // 読み込むフレームの大きさの上限を変える
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Frame;
+    type Error = crate::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> crate::Result<Option<Frame>> {
+        if let Some(frame) = connection::parse_frame(src, &self.limits)? {
+            return Ok(Some(frame));
+        }
+        // フレームにならないまま上限を超えて溜まったら、それ以上は読み込まない
+        if src.len() > self.limits.max_buffer {
+            return Err(self.limits.buffer_exceeded(src.len()).into());
+        }
+        Ok(None)
+    }
+}
+
+impl Encoder<Frame> for RespCodec {
+    type Error = crate::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> crate::Result<()> {
+        self.encode(&frame, dst)
+    }
+}
+
+impl Encoder<&Frame> for RespCodec {
+    type Error = crate::Error;
+
+    fn encode(&mut self, frame: &Frame, dst: &mut BytesMut) -> crate::Result<()> {
+        encode(frame, self.protocol, &mut dst.writer())?;
+        Ok(())
+    }
+}
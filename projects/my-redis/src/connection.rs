@@ -1,26 +1,54 @@
-use std::io::{Cursor, self};
+use std::io::{self, Cursor};
 
-use bytes::{Buf, BytesMut};
-use mini_redis::frame::Error::Incomplete;
-use mini_redis::{Frame, Result};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 
-pub struct Connection {
-    stream: BufWriter<TcpStream>,
+use crate::frame::{self, Error::Incomplete, Frame, Limits, Protocol};
+use crate::Result;
+
+// TCP に限らず、AsyncRead と AsyncWrite を実装した任意のストリームの上でフレームを読み書きする
+// （Unix ドメインソケット、TLS、テストで使う tokio::io::duplex など）
+// 型引数を省略した `Connection` は TCP のコネクションを表す
+pub struct Connection<S = TcpStream> {
+    stream: S,
     buffer: BytesMut,
+    // 書き込むフレームをエンコードしておくバッファ
+    write_buffer: BytesMut,
+    // HELLO で取り決めたプロトコルのバージョン
+    protocol: Protocol,
+    // 読み込むフレームの大きさの上限
+    limits: Limits,
 }
 
-impl Connection {
-    pub async fn new(stream: TcpStream) -> Self {
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(stream: S) -> Self {
         Self {
-            // ただ BufWriter でラップするだけで良しなにバッファリングしてくれる
-            stream: BufWriter::new(stream),
+            stream,
             // 4KB のキャパシティをもつバッファを確保する
             buffer: BytesMut::with_capacity(4096),
+            write_buffer: BytesMut::with_capacity(4096),
+            protocol: Protocol::default(),
+            limits: Limits::default(),
         }
     }
 
+    // 読み込むフレームの大きさの上限を変える
+    // 上限を超えるフレームを受け取ると、read_frame はプロトコルのエラーを返す
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    // 以降に書き込むフレームのプロトコルを切り替える
+    // RESP2 のときは、RESP3 にしかない型を RESP2 の近い型に置き換えて書き込む
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
     // ストリームからフレームを一つ読み込む
     // EOF であれば None を返す
     pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
@@ -37,6 +65,12 @@ impl Connection {
                 return Ok(Some(frame));
             }
 
+            // フレームにならないまま上限を超えて溜まったら、それ以上は読み込まない
+            // （巨大なバルク文字列を少しずつ送り続けられても、メモリを使い果たさない）
+            if self.buffer.len() > self.limits.max_buffer {
+                return Err(self.limits.buffer_exceeded(self.buffer.len()).into());
+            }
+
             // ストリームからバッファに読み出しを行い、
             // 読み取った値の長さを取得
             // もし、新たに読み取ったバイト列の長さが 0 なら
@@ -63,104 +97,69 @@ impl Connection {
         }
     }
 
-    // コネクションから単一のフレームをパースする関数
-    // 1. 十分な量のデータがバッファされていたら、
-    //    バッファからフレームを取り除いて、それを返却する
-    // 2. フレームをパースするのに十分な量のデータがバッファされていなかったら
-    //    Ok(None) を返却して、フレームを取り出せなかったが、
-    //    追加でデータをバッファすれば問題ないはずであるか、
-    //    読み込むべきデータがもうないかのいずれかであろうことを伝える
-    // 3. 内部で問題が発生したら Err を返す
+    // コネクションから単一のフレームをパースする
+    // バッファの扱いは parse_frame 関数を参照
     fn parse_frame(&mut self) -> Result<Option<Frame>> {
-        // Frame 構造体はパースの実行のためにカーソルを用いる
-        let mut buf = Cursor::new(&self.buffer[..]);
-
-        // まず、単一のフレームをパースするのに十分なデータがバッファに蓄えられているかをチェックする
-        // その結果によって場合分けする：
-        //      もし、十分な量蓄えられていたら、Ok(_) のアームに進む
-        //      不十分なら Err(Incomplete) のアームに進む
-        //      それ以外のエラーが発生しているようならエラーを返す
-        match Frame::check(&mut buf) {
-            Ok(_) => {
-                // parse に成功した場合、`Frame::check` 関数は
-                // カーソルの位置をフレームの終端にまで進める
-                // なので、カーソル位置を取得するとフレームの長さがわかる
-                let len = buf.position() as usize;
-
-                // パースの実行のためにカーソル位置を先頭に戻す
-                buf.set_position(0);
-
-                // フレームを取得する
-                // もし、エラーが返ってきたら、送られてきたフレームの内容が不正であることを表すので、
-                // このコネクションを切断する
-                let frame = Frame::parse(&mut buf)?;
-
-                // バッファされているデータのうち、パースし終えた部分を破棄する
-                //      advance(cnt) が読み込み用のバッファに対して呼ばれると、
-                //      `cnt` までのデータがすべて破棄される
-                self.buffer.advance(len);
-
-                // パースに成功したフレームを返す
-                Ok(Some(frame))
-            }
-            // 十分な量のデータがバッファされていなかった場合
-            Err(Incomplete) => Ok(None),
-            // エラーが発生した場合
-            Err(e) => Err(e.into()),
-        }
+        parse_frame(&mut self.buffer, &self.limits)
     }
 
     // コネクションにフレームを書き込む
+    // フレーム全体を write_buffer にエンコードしてから、一度の write_all で書き込む
     pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
-        match frame {
-            Frame::Simple(val) => {
-                self.stream.write_u8(b'+').await?;
-                self.stream.write_all(val.as_bytes()).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            Frame::Error(val) => {
-                self.stream.write_u8(b'-').await?;
-                self.stream.write_all(val.as_bytes()).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            Frame::Integer(val) => {
-                self.stream.write_u8(b':').await?;
-                self.write_decimal(*val).await?;
-            }
-            Frame::Null => {
-                self.stream.write_all(b"$-1\r\n").await?;
-            }
-            Frame::Bulk(val) => {
-                let len = val.len();
-
-                self.stream.write_u8(b'$').await?;
-                self.write_decimal(len as u64).await?;
-                self.stream.write_all(val).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            Frame::Array(_val) => unimplemented!(),
-        }
+        self.write_buffer.clear();
+        frame::encode(frame, self.protocol, &mut (&mut self.write_buffer).writer())?;
 
-        // BufWriter は中間バッファに書き込みを蓄えるので
-        // write を呼び出してもデータがソケットへと書き込まれることは保証されない
-        // そこで return する前にフレームがソケットへと書き込まれるように
-        // flush() を呼んびだして、バッファの中で保留状態となっているデータをすべてソケットへと書き込む
+        self.stream.write_all(&self.write_buffer).await?;
+        // TLS などのストリームは書き込みを中間バッファに蓄えることがあるので、
+        // return する前に flush() を呼び出してすべてソケットへと書き込む
         self.stream.flush().await
     }
+}
 
-    /// Write a decimal frame to the stream
-    async fn write_decimal(&mut self, val: u64) -> io::Result<()> {
-        use std::io::Write;
-
-        // Convert the value to a string
-        let mut buf = [0u8; 20];
-        let mut buf = Cursor::new(&mut buf[..]);
-        write!(&mut buf, "{}", val)?;
-
-        let pos = buf.position() as usize;
-        self.stream.write_all(&buf.get_ref()[..pos]).await?;
-        self.stream.write_all(b"\r\n").await?;
-
-        Ok(())
+// バッファから単一のフレームをパースする関数
+// Connection と、tokio_util のコーデック（codec::RespCodec）の両方で使う
+// 1. 十分な量のデータがバッファされていたら、
+//    バッファからフレームを取り除いて、それを返却する
+// 2. フレームをパースするのに十分な量のデータがバッファされていなかったら
+//    Ok(None) を返却して、フレームを取り出せなかったが、
+//    追加でデータをバッファすれば問題ないはずであるか、
+//    読み込むべきデータがもうないかのいずれかであろうことを伝える
+// 3. 内部で問題が発生したら Err を返す
+pub(crate) fn parse_frame(buffer: &mut BytesMut, limits: &Limits) -> Result<Option<Frame>> {
+    // Frame 構造体はパースの実行のためにカーソルを用いる
+    let mut buf = Cursor::new(&buffer[..]);
+
+    // まず、単一のフレームをパースするのに十分なデータがバッファに蓄えられているかをチェックする
+    // その結果によって場合分けする：
+    //      もし、十分な量蓄えられていたら、Ok(_) のアームに進む
+    //      不十分なら Err(Incomplete) のアームに進む
+    //      それ以外のエラーが発生しているようならエラーを返す
+    match Frame::check_with_limits(&mut buf, limits) {
+        Ok(_) => {
+            // parse に成功した場合、`Frame::check` 関数は
+            // カーソルの位置をフレームの終端にまで進める
+            // なので、カーソル位置を取得するとフレームの長さがわかる
+            let len = buf.position() as usize;
+
+            // パースの実行のためにカーソル位置を先頭に戻す
+            buf.set_position(0);
+
+            // フレームを取得する
+            // もし、エラーが返ってきたら、送られてきたフレームの内容が不正であることを表すので、
+            // このコネクションを切断する
+            let frame = Frame::parse(&mut buf)?;
+
+            // バッファされているデータのうち、パースし終えた部分を破棄する
+            //      advance(cnt) が読み込み用のバッファに対して呼ばれると、
+            //      `cnt` までのデータがすべて破棄される
+            buffer.advance(len);
+
+            // パースに成功したフレームを返す
+            Ok(Some(frame))
+        }
+        // 十分な量のデータがバッファされていなかった場合
+        Err(Incomplete) => Ok(None),
+        // エラーが発生した場合
+        Err(e) => Err(e.into()),
     }
 }
@@ -1,27 +1,60 @@
-use bytes::BytesMut;
-use mini_redis::{Frame, Result};
-use tokio::io::AsyncReadExt;
+use std::io::{self, Cursor};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 
-pub struct Connection {
-    stream: TcpStream,
+use crate::frame::{self, Error::Incomplete, Frame, Limits, Protocol};
+use crate::Result;
+
+// 読み込みバッファの初期サイズ
+const INITIAL_CAPACITY: usize = 4096;
+
+// connection::Connection と同じく、任意のストリームの上で使える
+pub struct Connection<S = TcpStream> {
+    stream: S,
     // バッファを Vec<u8> に置き換える
     buffer: Vec<u8>,
     // バッファのどの位置までデータが書き込まれているかを
     // 記憶する cursor フィールドが追加で必要になる
     cursor: usize,
+    // 書き込むフレームを一旦ためておく Vec<u8>
+    write_buffer: Vec<u8>,
+    // HELLO で取り決めたプロトコルのバージョン
+    protocol: Protocol,
+    // 読み込むフレームの大きさの上限
+    limits: Limits,
 }
 
-impl Connection {
-    pub async fn new(stream: TcpStream) -> Self {
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(stream: S) -> Self {
         Self {
             stream,
-            // 4KB のキャパシティをもつバッファを確保する
-            buffer: Vec::with_capacity(4096),
+            // 4KB のバッファを確保する
+            // Vec の場合は len の範囲にしか読み込めないので、
+            // キャパシティではなく長さとして確保しておく必要がある
+            buffer: vec![0; INITIAL_CAPACITY],
             cursor: 0,
+            write_buffer: Vec::with_capacity(INITIAL_CAPACITY),
+            protocol: Protocol::default(),
+            limits: Limits::default(),
         }
     }
 
+    // 読み込むフレームの大きさの上限を変える
+    // 上限を超えるフレームを受け取ると、read_frame はプロトコルのエラーを返す
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    // 以降に書き込むフレームのプロトコルを切り替える
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
     pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
         loop {
             // Buf トレイトの有無に関わらない部分はそのままでよい
@@ -29,11 +62,16 @@ impl Connection {
                 return Ok(Some(frame));
             }
 
+            if self.cursor > self.limits.max_buffer {
+                return Err(self.limits.buffer_exceeded(self.cursor).into());
+            }
+
             // 追加で、バッファが十分なキャパシティを持つように調整する処理が必要になる
             //     cursor の位置が、バッファの末端まで到達したら、
             //     バッファを拡張する
             if self.buffer.len() == self.cursor {
-                self.buffer.resize(self.cursor * 2, 0);
+                let new_len = (self.cursor * 2).max(INITIAL_CAPACITY);
+                self.buffer.resize(new_len, 0);
             }
 
             // ストリームからデータをバッファに読みだす時には、
@@ -41,7 +79,9 @@ impl Connection {
             // cursor の位置を参考に、データの入っていない部分に書き込むよう注意する
             let n = self.stream.read(&mut self.buffer[self.cursor..]).await?;
             if 0 == n {
-                if self.buffer.is_empty() {
+                // バッファの長さは常に確保済みの領域を表すので、
+                // データが残っているかどうかは cursor で判定する
+                if self.cursor == 0 {
                     return Ok(None);
                 } else {
                     return Err("Connection reset by peer".into());
@@ -61,12 +101,36 @@ impl Connection {
     //    追加でデータをバッファすれば問題ないはずであるか、
     //    読み込むべきデータがもうないかのいずれかであろうことを伝える
     // 3. 内部で問題が発生したら Err を返す
-    fn parse_frame(&self) -> Result<Option<Frame>> {
-        todo!()
+    fn parse_frame(&mut self) -> Result<Option<Frame>> {
+        // 読み込み済みの範囲だけをパースの対象にする
+        let mut buf = Cursor::new(&self.buffer[..self.cursor]);
+
+        match Frame::check_with_limits(&mut buf, &self.limits) {
+            Ok(_) => {
+                let len = buf.position() as usize;
+
+                buf.set_position(0);
+                let frame = Frame::parse(&mut buf)?;
+
+                // advance の代わりに、パースし終えた部分の後ろに残っているデータを
+                // バッファの先頭に詰め直して、cursor を戻す
+                self.buffer.copy_within(len..self.cursor, 0);
+                self.cursor -= len;
+
+                Ok(Some(frame))
+            }
+            Err(Incomplete) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
     }
 
     // コネクションにフレームを書き込む
-    pub async fn write_frame(&mut self) -> Result<()> {
-        todo!()
+    // フレーム全体を write_buffer にエンコードしてから、一度の write_all で書き込む
+    pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        self.write_buffer.clear();
+        frame::encode(frame, self.protocol, &mut self.write_buffer)?;
+
+        self.stream.write_all(&self.write_buffer).await?;
+        self.stream.flush().await
     }
 }
@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use tokio::sync::{oneshot, Notify};
+
+// リストのどちらの端を操作するか
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+// ブロッキングポップで待っているクライアントに渡す (キー, 要素) の組
+pub type Delivery = (String, Bytes);
+
+// 待機中のクライアント一つ分の受け取り口
+//
+// BLPOP は複数のキーで同時に待つので、同じ受け取り口が複数のキーの待ち行列に登録される
+// 最初に要素を渡した側が Sender を取り出すので、要素が二重に渡されることはない
+// タイムアウトしたクライアントも Sender を取り出して、受け取り口を閉じる
+#[derive(Debug)]
+pub struct WaiterSlot(Mutex<Option<oneshot::Sender<Delivery>>>);
+
+impl WaiterSlot {
+    pub fn new(tx: oneshot::Sender<Delivery>) -> Self {
+        Self(Mutex::new(Some(tx)))
+    }
+
+    // まだ要素を受け取っていなければ Sender を取り出す
+    pub fn take(&self) -> Option<oneshot::Sender<Delivery>> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+// あるキーの待ち行列に並んでいるクライアント
+//
+// BLMOVE で待っているクライアントは、受け取った要素を追加する先のキーと端を target に持つ
+#[derive(Debug, Clone)]
+pub struct Waiter {
+    pub id: u64,
+    pub end: End,
+    pub target: Option<(String, End)>,
+    pub slot: Arc<WaiterSlot>,
+}
+
+impl Waiter {
+    pub fn new(end: End, target: Option<(String, End)>, slot: Arc<WaiterSlot>) -> Self {
+        Self {
+            id: next_id(),
+            end,
+            target,
+            slot,
+        }
+    }
+}
+
+// 待っているクライアントを待ち行列から取り除くときに使う ID を払い出す
+fn next_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// キーごとの待ち行列
+// 先に並んだクライアントから順に要素を受け取る
+pub type WaitQueue = VecDeque<Waiter>;
+
+// ストリームへのエントリの追加を待っているクライアント
+//
+// リストのブロッキングポップと異なり、要素を取り合うことはないので
+// 追加されたら待っているクライアント全員に知らせ、各自で読み直してもらう
+// notify_one は通知を一つ溜めておけるので、登録してから .await するまでの間に
+// 追加されても取りこぼさない
+#[derive(Debug, Clone)]
+pub struct StreamWaiter {
+    pub id: u64,
+    pub notify: Arc<Notify>,
+}
+
+impl StreamWaiter {
+    pub fn new(notify: Arc<Notify>) -> Self {
+        Self {
+            id: next_id(),
+            notify,
+        }
+    }
+}
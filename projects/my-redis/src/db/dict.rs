@@ -0,0 +1,86 @@
+use std::collections::{BTreeSet, HashMap};
+
+use bytes::Bytes;
+
+use super::{scan_position, take_scan};
+
+// ハッシュ型の値
+//
+// フィールドから値を引くためのハッシュマップと、
+// (SCAN の位置, フィールド) の順に並べた索引の 2 つでフィールドを管理する
+// HSCAN は索引を途中からたどるので、一度の呼び出しで調べるのは返すフィールドの分だけで済む
+#[derive(Clone, Debug, Default)]
+pub struct Dict {
+    fields: HashMap<Bytes, Bytes>,
+    positions: BTreeSet<(u64, Bytes)>,
+}
+
+impl PartialEq for Dict {
+    fn eq(&self, other: &Self) -> bool {
+        self.fields == other.fields
+    }
+}
+
+impl Dict {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&Bytes> {
+        self.fields.get(field)
+    }
+
+    pub fn contains_key(&self, field: &[u8]) -> bool {
+        self.fields.contains_key(field)
+    }
+
+    // フィールドに値を設定する
+    // 以前の値があれば返す
+    pub fn insert(&mut self, field: Bytes, value: Bytes) -> Option<Bytes> {
+        if !self.fields.contains_key(&field) {
+            self.positions
+                .insert((scan_position(&field), field.clone()));
+        }
+        self.fields.insert(field, value)
+    }
+
+    // フィールドを削除し、その値を返す
+    pub fn remove(&mut self, field: &[u8]) -> Option<Bytes> {
+        let (field, value) = self.fields.remove_entry(field)?;
+        self.positions.remove(&(scan_position(&field), field));
+        Some(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Bytes)> {
+        self.fields.iter()
+    }
+
+    // 位置が from 以上のフィールドと値の組を、位置の昇順に count 個程度取り出す
+    // 残りのフィールドがなければ、次の位置として None を返す
+    pub fn scan(&self, from: u64, count: usize) -> (Vec<(&Bytes, &Bytes)>, Option<u64>) {
+        let (fields, next) = take_scan(self.positions.range((from, Bytes::new())..), count);
+        let pairs = fields
+            .into_iter()
+            .map(|field| (field, &self.fields[field]))
+            .collect();
+        (pairs, next)
+    }
+}
+
+impl FromIterator<(Bytes, Bytes)> for Dict {
+    fn from_iter<I: IntoIterator<Item = (Bytes, Bytes)>>(iter: I) -> Self {
+        let mut dict = Dict::new();
+        for (field, value) in iter {
+            dict.insert(field, value);
+        }
+        dict
+    }
+}
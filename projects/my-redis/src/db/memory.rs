@@ -0,0 +1,124 @@
+// キーが使うメモリの見積もりと、追い出すキーを選ぶための記録
+//
+// 使用量は正確には数えず、キーと値のバイト数に要素ごとの大まかなオーバーヘッドを足して見積もる
+// アクセスの記録は Redis と同様に、最後にアクセスした時刻（LRU）と、
+// アクセスされるほど増えにくくなる対数のカウンタ（LFU）の 2 つを持つ
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use super::{Entry, Shard, Value};
+
+// 新しいキーの LFU カウンタの初期値
+// すぐに追い出されないように、0 より少し大きくしておく
+pub(super) const LFU_INIT: u8 = 5;
+
+// カウンタが大きいほど増えにくくする度合い
+const LFU_LOG_FACTOR: f64 = 10.0;
+
+// アクセスされない時間がこれだけ経つごとに、LFU カウンタを 1 減らす
+const LFU_DECAY: Duration = Duration::from_secs(60);
+
+// 数え直すのを待っているキーがこれだけ溜まったら、次の変更の前に数え直す
+pub(super) const DIRTY_LIMIT: usize = 1024;
+
+// キーごとに加える、ハッシュテーブルのエントリなどの大きさの見積もり
+const ENTRY_OVERHEAD: usize = 64;
+
+// キーと値が使うおおよそのバイト数
+pub(super) fn entry_size(key: &str, value: &Value) -> usize {
+    ENTRY_OVERHEAD + key.len() + value.approx_size()
+}
+
+impl Entry {
+    // 最後にアクセスしてから経った時間
+    pub fn idle(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_access)
+    }
+
+    // アクセスされない間に減らした後の LFU カウンタ
+    pub fn frequency(&self, now: Instant) -> u8 {
+        let periods = self.idle(now).as_secs() / LFU_DECAY.as_secs();
+        self.frequency
+            .saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    // アクセスを記録する
+    // chance は 0 以上 1 未満の乱数で、カウンタが大きいほど増やす確率を下げるのに使う
+    pub(super) fn access(&mut self, chance: f64) {
+        let now = Instant::now();
+        let mut frequency = self.frequency(now);
+        if frequency < u8::MAX {
+            let base = frequency.saturating_sub(LFU_INIT) as f64;
+            if chance < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+                frequency += 1;
+            }
+        }
+        self.frequency = frequency;
+        self.last_access = now;
+    }
+}
+
+impl Shard {
+    // キーと値が使うおおよそのバイト数の合計
+    pub fn used_memory(&mut self) -> usize {
+        self.refresh_sizes();
+        self.used_memory
+    }
+
+    // 追い出す候補として、ランダムに選んだキーを最大 count 個返す
+    // 同じキーが重複して選ばれることもある
+    pub fn sample(&mut self, count: usize) -> Vec<(&String, &Entry)> {
+        if self.keys.is_empty() {
+            return Vec::new();
+        }
+        let indices: Vec<usize> = (0..count)
+            .map(|_| (self.random() % self.keys.len() as u64) as usize)
+            .collect();
+        indices
+            .into_iter()
+            .map(|i| {
+                let key = &self.keys[i];
+                (key, &self.entries[key])
+            })
+            .collect()
+    }
+
+    // 有効期限が最も早いキー
+    pub fn first_expiring(&self) -> Option<(Instant, &String)> {
+        self.expirations.first().map(|(when, key)| (*when, key))
+    }
+
+    // 変更されたキーの大きさを数え直す
+    pub(super) fn refresh_sizes(&mut self) {
+        for key in std::mem::take(&mut self.dirty) {
+            if let Some(entry) = self.entries.get_mut(&key) {
+                let size = entry_size(&key, &entry.value);
+                self.used_memory = self.used_memory - entry.size + size;
+                entry.size = size;
+            }
+        }
+    }
+
+    // 0 以上 1 未満の乱数
+    pub(super) fn random_unit(&mut self) -> f64 {
+        (self.random() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // xorshift による乱数
+    // 最初に呼ばれたときに、種を RandomState から作る
+    fn random(&mut self) -> u64 {
+        if self.rng == 0 {
+            self.rng = RandomState::new().build_hasher().finish() | 1;
+        }
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        x
+    }
+}
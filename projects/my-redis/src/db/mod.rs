@@ -0,0 +1,804 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    ops::Deref,
+    sync::{Arc, Mutex, MutexGuard, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tokio::time::Instant;
+
+use crate::aof::Aof;
+use crate::cluster::Cluster;
+use crate::evict::MaxMemory;
+use crate::rdb::Snapshots;
+use crate::replication::{Leader, Replication};
+
+mod blocking;
+pub use blocking::{Delivery, End, StreamWaiter, Waiter, WaiterSlot};
+
+mod dict;
+pub use dict::Dict;
+
+mod memory;
+
+mod value;
+pub use value::Value;
+
+mod stream;
+pub use stream::{ConsumerGroup, Fields, PendingEntry, Stream, StreamId};
+
+mod zset;
+pub use zset::{ScoreBound, SortedSet};
+
+pub type Db = Mutex<Shard>;
+pub type ShardedDb = Arc<Shards>;
+
+// シャードの並び
+//
+// 各コマンドは必要なシャードだけをロックして実行されるが、
+// MULTI/EXEC のトランザクションは複数のコマンドをまとめて実行するので、
+// その途中の状態を他のコネクションから見られないようにする必要がある
+// そのため、通常のコマンドは gate を共有ロックしてから実行し、
+// EXEC は gate を排他ロックしてから実行する
+//
+// スナップショットの保存先が設定されていれば snapshots に保持し、SAVE などのコマンドから参照する
+// AOF も同様に aof に保持する
+// レプリケーションの状態（リーダーとしてのバックログや、フォロワーとしての接続）は replication に保持する
+// クラスタモードで起動していれば、ハッシュスロットの担当などのクラスタの状態を cluster に保持する
+// メモリの上限が設定されていれば、その上限と追い出しのポリシーを maxmemory に保持する
+#[derive(Debug)]
+pub struct Shards {
+    shards: Vec<Db>,
+    gate: RwLock<()>,
+    snapshots: OnceLock<Snapshots>,
+    aof: OnceLock<Aof>,
+    replication: Replication,
+    cluster: OnceLock<Cluster>,
+    maxmemory: OnceLock<MaxMemory>,
+}
+
+// write_gate が返すガード
+// 共有ロックと排他ロックのどちらか一方を保持する
+pub struct Gate<'a> {
+    _shared: Option<RwLockReadGuard<'a, ()>>,
+    _exclusive: Option<RwLockWriteGuard<'a, ()>>,
+}
+
+impl Shards {
+    // 通常のコマンドを実行する間、EXEC の実行を待たせる
+    pub fn shared(&self) -> RwLockReadGuard<'_, ()> {
+        self.gate.read().unwrap()
+    }
+
+    // EXEC を実行する間、他のコマンドの実行を待たせる
+    pub fn exclusive(&self) -> RwLockWriteGuard<'_, ()> {
+        self.gate.write().unwrap()
+    }
+
+    // 書き込みコマンドを実行する間、gate をロックする
+    //
+    // AOF やフォロワーに伝えている間は、書き込みコマンドを排他ロックで一つずつ実行する
+    // 複数のコネクションの書き込みが並行して実行されると、実行した順序と
+    // 伝える順序が入れ替わり、実行し直したときに同じ内容にならないことがある
+    pub fn write_gate(&self) -> Gate<'_> {
+        if self.propagating() {
+            Gate {
+                _shared: None,
+                _exclusive: Some(self.exclusive()),
+            }
+        } else {
+            Gate {
+                _shared: Some(self.shared()),
+                _exclusive: None,
+            }
+        }
+    }
+
+    // スナップショットの保存先を設定する
+    // すでに設定されていれば何もせずに false を返す
+    pub fn enable_snapshots(&self, snapshots: Snapshots) -> bool {
+        self.snapshots.set(snapshots).is_ok()
+    }
+
+    pub fn snapshots(&self) -> Option<&Snapshots> {
+        self.snapshots.get()
+    }
+
+    // AOF を設定し、各シャードで副作用の記録を始める
+    // すでに設定されていれば何もせずに false を返す
+    pub fn enable_aof(&self, aof: Aof) -> bool {
+        if self.aof.set(aof).is_err() {
+            return false;
+        }
+        self.record_effects();
+        true
+    }
+
+    pub fn aof(&self) -> Option<&Aof> {
+        self.aof.get()
+    }
+
+    pub fn replication(&self) -> &Replication {
+        &self.replication
+    }
+
+    // クラスタモードにする
+    // すでにクラスタモードなら何もせずに false を返す
+    pub fn enable_cluster(&self, cluster: Cluster) -> bool {
+        self.cluster.set(cluster).is_ok()
+    }
+
+    pub fn cluster(&self) -> Option<&Cluster> {
+        self.cluster.get()
+    }
+
+    // メモリの上限を設定する
+    // すでに設定されていれば何もせずに false を返す
+    pub fn enable_maxmemory(&self, maxmemory: MaxMemory) -> bool {
+        self.maxmemory.set(maxmemory).is_ok()
+    }
+
+    pub fn maxmemory(&self) -> Option<&MaxMemory> {
+        self.maxmemory.get()
+    }
+
+    // リーダーとしてフォロワーに書き込みコマンドを伝え始め、そのバックログを返す
+    // すでに始めていれば、そのバックログを返す
+    //
+    // 伝え始める前に実行中の書き込みがないように、gate を排他ロックしてから呼び出すこと
+    pub fn enable_leader(&self) -> &Leader {
+        let leader = self.replication.start_leading();
+        self.record_effects();
+        leader
+    }
+
+    // 書き込みコマンドを AOF かフォロワーに伝えているか
+    pub fn propagating(&self) -> bool {
+        self.aof.get().is_some() || self.replication.leader().is_some()
+    }
+
+    // 各シャードで副作用の記録を始める
+    fn record_effects(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().effects.get_or_insert_with(Vec::new);
+        }
+    }
+
+    // 全シャードでこれまでにキーが変更された回数の合計
+    pub fn changes(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().changes())
+            .sum()
+    }
+}
+
+impl Deref for Shards {
+    type Target = [Db];
+
+    fn deref(&self) -> &[Db] {
+        &self.shards
+    }
+}
+
+// 期限切れのキーをバックグラウンドで削除する間隔
+const PURGE_INTERVAL: Duration = Duration::from_millis(100);
+
+// シャーディングされた db を作成する関数
+pub fn new_sharded_db(num_shards: usize) -> ShardedDb {
+    let mut db = Vec::with_capacity(num_shards);
+    for _ in 0..num_shards {
+        let m = Mutex::new(Shard::default());
+        db.push(m);
+    }
+    Arc::new(Shards {
+        shards: db,
+        gate: RwLock::new(()),
+        snapshots: OnceLock::new(),
+        aof: OnceLock::new(),
+        replication: Replication::new(),
+        cluster: OnceLock::new(),
+        maxmemory: OnceLock::new(),
+    })
+}
+
+// シャーディングされた db の中から該当の db を拾い上げる関数
+pub fn get_db_from_sharded_db<'a>(shaded_db: &'a ShardedDb, key: &str) -> &'a Db {
+    &shaded_db[shard_index(shaded_db, key)]
+}
+
+// キーが属するシャードの番号を返す関数
+pub fn shard_index(shaded_db: &ShardedDb, key: &str) -> usize {
+    hash(key) as usize % shaded_db.len()
+}
+
+// 複数のキーが属するシャードをまとめてロックする関数
+//
+// SINTER や RENAME のように複数のキーを扱うコマンドは、複数のシャードを同時にロックする
+// コネクションごとにロックする順序がばらばらだと、互いに相手のロックを待ってデッドロックするので、
+// 必ずシャードの番号の小さい順にロックする
+// 同じシャードに属するキーが複数あっても、そのシャードをロックするのは一度だけ
+pub fn lock_shards<'a, K: AsRef<str>>(shaded_db: &'a ShardedDb, keys: &[K]) -> LockedShards<'a> {
+    let indices: BTreeSet<usize> = keys
+        .iter()
+        .map(|key| shard_index(shaded_db, key.as_ref()))
+        .collect();
+
+    // BTreeSet は昇順にたどられるので、番号の小さいシャードから順にロックされる
+    let guards = indices
+        .into_iter()
+        .map(|i| (i, shaded_db[i].lock().unwrap()))
+        .collect();
+
+    LockedShards {
+        db: shaded_db,
+        guards,
+    }
+}
+
+// lock_shards でロックしたシャードの組
+pub struct LockedShards<'a> {
+    db: &'a ShardedDb,
+    guards: BTreeMap<usize, MutexGuard<'a, Shard>>,
+}
+
+impl LockedShards<'_> {
+    // キーが属するシャードを返す
+    //
+    // # Panics
+    //
+    // lock_shards に渡さなかったキーを指定すると panic する
+    pub fn shard(&mut self, key: &str) -> &mut Shard {
+        let i = shard_index(self.db, key);
+        self.guards
+            .get_mut(&i)
+            .expect("the shard for this key is not locked")
+    }
+
+    // 2 つのキーが属するシャードを同時に返す
+    // 同じシャードに属する場合は、2 つ目は None になる
+    pub fn pair(&mut self, a: &str, b: &str) -> (&mut Shard, Option<&mut Shard>) {
+        let (i, j) = (shard_index(self.db, a), shard_index(self.db, b));
+        let mut first = None;
+        let mut second = None;
+        for (k, guard) in self.guards.iter_mut() {
+            if *k == i {
+                first = Some(&mut **guard);
+            } else if *k == j {
+                second = Some(&mut **guard);
+            }
+        }
+        (first.expect("the shard for this key is not locked"), second)
+    }
+}
+
+// ハッシュ化関数
+fn hash<T: Hash + ?Sized>(key: &T) -> u64 {
+    let mut s = DefaultHasher::new();
+    key.hash(&mut s);
+    s.finish()
+}
+
+// SCAN 系コマンドのカーソルで、要素の位置を表すのに使うビット数
+pub const SCAN_BITS: u32 = 48;
+
+// SCAN 系コマンドで要素をたどる順序を決める位置
+// 要素のハッシュ値で決まるので、他の要素の追加や削除の影響を受けない
+pub fn scan_position<T: Hash + ?Sized>(item: &T) -> u64 {
+    hash(item) >> (64 - SCAN_BITS)
+}
+
+// 位置の昇順に並べた (位置, 要素) の索引から、要素を count 個程度取り出す
+// 索引は、再開する位置から始まる範囲を渡す
+//
+// 位置が同じ要素は途中で分割せずにまとめて返すので、
+// 次回は最後に返した位置 + 1 から再開すればよい
+// 残りの要素がなければ、次の位置として None を返す
+fn take_scan<'a, T: 'a>(
+    index: impl Iterator<Item = &'a (u64, T)>,
+    count: usize,
+) -> (Vec<&'a T>, Option<u64>) {
+    let count = count.max(1);
+    let mut taken = Vec::new();
+    let mut last = None;
+    for (pos, item) in index {
+        if taken.len() >= count && last != Some(*pos) {
+            return (taken, Some(*pos));
+        }
+        taken.push(item);
+        last = Some(*pos);
+    }
+    (taken, None)
+}
+
+// 期限切れのキーを定期的に削除するタスクを起動する
+//
+// アクセス時の遅延削除だけでは、二度と読まれないキーがいつまでもメモリに残るので、
+// 各シャードを順番にロックして期限切れのキーを取り除く
+// タスクは db を弱参照で持ち、db がドロップされたら終了する
+pub fn spawn_purge_task(db: &ShardedDb) {
+    let db = Arc::downgrade(db);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let db = match db.upgrade() {
+                Some(db) => db,
+                None => return,
+            };
+
+            let now = Instant::now();
+            // 一度にロックするのは 1 シャードだけにして、他のシャードへのアクセスを止めない
+            for shard in db.iter() {
+                shard.lock().unwrap().purge_expired(now);
+            }
+        }
+    });
+}
+
+// db に保存される一つの値
+#[derive(Debug)]
+pub struct Entry {
+    pub value: Value,
+    expires_at: Option<Instant>,
+    // 最後に数えたときの、キーと値のおおよそのバイト数
+    size: usize,
+    // 最後に読み書きした時刻（LRU で使う）
+    last_access: Instant,
+    // 読み書きされた頻度を対数で表したカウンタ（LFU で使う）
+    frequency: u8,
+    // Shard::keys の中での位置
+    index: usize,
+}
+
+impl Entry {
+    // キーの有効期限
+    pub fn expires_at(&self) -> Option<Instant> {
+        self.expires_at
+    }
+
+    // TYPE コマンドで返す値の型名
+    pub fn type_name(&self) -> &'static str {
+        self.value.type_name()
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        matches!(self.expires_at, Some(when) if when <= now)
+    }
+}
+
+// 一つのシャードが保持するデータ
+//
+// 有効期限つきのキーは (期限, キー) の組で expirations にも登録しておき、
+// 期限の早い順にたどって削除できるようにする
+//
+// ブロッキングポップで待っているクライアントの待ち行列もキーと同じシャードに置く
+// リストが空であることの確認と待ち行列への登録を同じロックの中で行えるので、
+// その間に追加された要素を取りこぼすことがない
+//
+// WATCH されているキーは versions でバージョン番号を管理する
+// キーを変更する操作（get_mut, insert, remove など）はバージョンを進めるので、
+// WATCH した時点のバージョンと比べれば、その後に変更されたかどうかがわかる
+// また、すべての変更を changes で数えておき、自動でスナップショットを保存する判断に使う
+//
+// AOF やフォロワーに伝えている間は、コマンドの引数からはわからない変更（待っているクライアントに
+// 要素を渡したことなど）を、それと同じ結果になるコマンドとして effects に記録する
+//
+// BLMOVE で待っているクライアントへの要素の移動は、移動先のシャードもロックする必要があるので、
+// serve_waiters では行わずに移動元のキーを moves に積んでおく
+//
+// maxmemory を超えたときに追い出すキーを選べるように、キーが使うおおよそのメモリを used_memory で数える
+// 値は get_mut で直接書き換えられるので、変更されたキーを dirty に覚えておき、
+// used_memory を読むときにまとめて数え直す
+// また、ランダムにキーを選べるように、すべてのキーを keys にも並べておく
+#[derive(Debug, Default)]
+pub struct Shard {
+    entries: HashMap<String, Entry>,
+    expirations: BTreeSet<(Instant, String)>,
+    scan_index: BTreeSet<(u64, String)>,
+    waiters: HashMap<String, blocking::WaitQueue>,
+    moves: Vec<String>,
+    stream_waiters: HashMap<String, Vec<StreamWaiter>>,
+    versions: HashMap<String, Watched>,
+    clock: u64,
+    changes: u64,
+    effects: Option<Vec<Vec<Bytes>>>,
+    keys: Vec<String>,
+    used_memory: usize,
+    dirty: HashSet<String>,
+    rng: u64,
+}
+
+// WATCH されているキーのバージョンと、WATCH しているコネクションの数
+#[derive(Debug)]
+struct Watched {
+    version: u64,
+    watchers: usize,
+}
+
+impl Shard {
+    // キーに対応する値を取得する
+    // 期限切れのキーはこの時点で削除し、存在しないものとして扱う
+    pub fn get(&mut self, key: &str) -> Option<&Entry> {
+        self.remove_if_expired(key);
+        let chance = self.random_unit();
+        let entry = self.entries.get_mut(key)?;
+        entry.access(chance);
+        Some(entry)
+    }
+
+    // キーに対応する値を、期限切れのキーを削除せずに参照する
+    // ロックを共有参照で保持したまま複数のキーを調べるときに使う
+    pub fn peek(&self, key: &str) -> Option<&Entry> {
+        self.entries
+            .get(key)
+            .filter(|entry| !entry.is_expired(Instant::now()))
+    }
+
+    // キーに対応する値を、変更するために可変参照で取得する
+    // 値を読むだけなら、WATCH しているトランザクションを失敗させないように get を使うこと
+    // 有効期限の変更は set_expiry を使うこと
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Entry> {
+        self.remove_if_expired(key);
+        if self.entries.contains_key(key) {
+            self.touch(key);
+        }
+        let chance = self.random_unit();
+        let entry = self.entries.get_mut(key)?;
+        entry.access(chance);
+        Some(entry)
+    }
+
+    // 値を保存する
+    // 以前の値があれば、その有効期限ごと置き換えて返す
+    pub fn insert(
+        &mut self,
+        key: String,
+        value: Value,
+        expires_at: Option<Instant>,
+    ) -> Option<Entry> {
+        let prev = self.remove(&key);
+        self.touch(&key);
+
+        if let Some(when) = expires_at {
+            self.expirations.insert((when, key.clone()));
+        }
+        let mut entry = Entry {
+            value,
+            expires_at,
+            size: 0,
+            last_access: Instant::now(),
+            frequency: memory::LFU_INIT,
+            index: self.keys.len(),
+        };
+        entry.access(self.random_unit());
+        entry.size = memory::entry_size(&key, &entry.value);
+        self.used_memory += entry.size;
+        self.scan_index.insert((scan_position(&key), key.clone()));
+        self.keys.push(key.clone());
+        self.entries.insert(key, entry);
+
+        prev
+    }
+
+    // キーを削除する
+    pub fn remove(&mut self, key: &str) -> Option<Entry> {
+        self.remove_if_expired(key);
+        let entry = self.take_entry(key)?;
+        self.touch(key);
+        Some(entry)
+    }
+
+    // 既存のキーの有効期限を変更する（None なら期限なしにする）
+    // キーが存在しなければ false を返す
+    pub fn set_expiry(&mut self, key: &str, expires_at: Option<Instant>) -> bool {
+        self.remove_if_expired(key);
+        let entry = match self.entries.get_mut(key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        if let Some(when) = entry.expires_at {
+            self.expirations.remove(&(when, key.to_string()));
+        }
+        if let Some(when) = expires_at {
+            self.expirations.insert((when, key.to_string()));
+        }
+        entry.expires_at = expires_at;
+        self.touch(key);
+        true
+    }
+
+    // すべてのキーを削除する
+    // フォロワーがリーダーのスナップショットを読み込む前に使う
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.expirations.clear();
+        self.scan_index.clear();
+        for key in std::mem::take(&mut self.keys) {
+            self.touch(&key);
+        }
+        self.dirty.clear();
+        self.used_memory = 0;
+    }
+
+    // 期限が now 以前のキーをすべて削除し、削除した個数を返す
+    pub fn purge_expired(&mut self, now: Instant) -> usize {
+        let mut purged = 0;
+        while let Some((when, key)) = self.expirations.first().cloned() {
+            if when > now {
+                break;
+            }
+            self.take_entry(&key);
+            self.touch(&key);
+            purged += 1;
+        }
+        purged
+    }
+
+    // 期限切れでないキーと値の組をすべてたどる
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Entry)> {
+        let now = Instant::now();
+        self.entries
+            .iter()
+            .filter(move |(_, entry)| !entry.is_expired(now))
+    }
+
+    // 位置が from 以上の期限切れでないキーを、位置の昇順に count 個程度取り出す
+    // 残りのキーがなければ、次の位置として None を返す
+    pub fn scan(&self, from: u64, count: usize) -> (Vec<&str>, Option<u64>) {
+        let now = Instant::now();
+        let index = self
+            .scan_index
+            .range((from, String::new())..)
+            .filter(|(_, key)| !self.entries[key].is_expired(now));
+        let (keys, next) = take_scan(index, count);
+        (keys.into_iter().map(String::as_str).collect(), next)
+    }
+
+    // シャードが保持しているキーの数
+    // まだ削除されていない期限切れのキーも含む
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // これまでにキーが変更された回数
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    // 変更をコマンドとして記録する（AOF やフォロワーに伝えていなければ何もしない）
+    pub fn record_effect(&mut self, command: Vec<Bytes>) {
+        if let Some(effects) = &mut self.effects {
+            effects.push(command);
+        }
+    }
+
+    // 記録した変更を取り出す
+    pub fn take_effects(&mut self) -> Vec<Vec<Bytes>> {
+        self.effects
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    // キーの待ち行列の末尾にクライアントを登録する
+    pub fn add_waiter(&mut self, key: &str, waiter: Waiter) {
+        self.waiters
+            .entry(key.to_string())
+            .or_default()
+            .push_back(waiter);
+    }
+
+    // キーの待ち行列の先頭に並んでいるクライアント
+    pub fn first_waiter(&self, key: &str) -> Option<&Waiter> {
+        self.waiters.get(key)?.front()
+    }
+
+    // キーの待ち行列の先頭からクライアントを取り出す
+    pub fn pop_waiter(&mut self, key: &str) -> Option<Waiter> {
+        let queue = self.waiters.get_mut(key)?;
+        let waiter = queue.pop_front();
+        if queue.is_empty() {
+            self.waiters.remove(key);
+        }
+        waiter
+    }
+
+    // serve_waiters が後回しにした、BLMOVE で待っているクライアントのいるキーを取り出す
+    pub fn take_moves(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.moves)
+    }
+
+    // キーの待ち行列からクライアントを取り除く
+    pub fn remove_waiter(&mut self, key: &str, id: u64) {
+        if let Some(queue) = self.waiters.get_mut(key) {
+            queue.retain(|waiter| waiter.id != id);
+            if queue.is_empty() {
+                self.waiters.remove(key);
+            }
+        }
+    }
+
+    // キーの待ち行列に並んでいるクライアントに、リストの要素を先頭から順に渡す
+    //
+    // リストに要素を追加したら必ず呼び出すこと
+    // すでに他のキーで要素を受け取ったり、タイムアウトや切断で受け取り口が閉じていたりする
+    // クライアントは読み飛ばし、渡せなかった要素はリストの元の位置に戻す
+    //
+    // 先頭のクライアントが BLMOVE で待っていれば、そこで止めてキーを moves に積む
+    // 呼び出し側がシャードのロックを解放した後に、cmd::blocking::serve_moves が移動する
+    pub fn serve_waiters(&mut self, key: &str) {
+        self.remove_if_expired(key);
+
+        while let Some(queue) = self.waiters.get_mut(key) {
+            let list = match self.entries.get_mut(key) {
+                Some(Entry {
+                    value: Value::List(list),
+                    ..
+                }) if !list.is_empty() => list,
+                _ => break,
+            };
+
+            match queue.front() {
+                Some(waiter) if waiter.target.is_some() => {
+                    if !self.moves.iter().any(|moving| moving == key) {
+                        self.moves.push(key.to_string());
+                    }
+                    break;
+                }
+                Some(_) => {}
+                None => {
+                    self.waiters.remove(key);
+                    break;
+                }
+            }
+            let waiter = queue.pop_front().unwrap();
+            let tx = match waiter.slot.take() {
+                Some(tx) => tx,
+                None => continue,
+            };
+
+            let value = match waiter.end {
+                End::Left => list.pop_front(),
+                End::Right => list.pop_back(),
+            }
+            .unwrap();
+            match tx.send((key.to_string(), value)) {
+                Ok(()) => {
+                    self.touch(key);
+                    let command = match waiter.end {
+                        End::Left => "LPOP",
+                        End::Right => "RPOP",
+                    };
+                    self.record_effect(vec![Bytes::from(command), Bytes::from(key.to_string())]);
+                }
+                Err((_, value)) => match waiter.end {
+                    End::Left => list.push_front(value),
+                    End::Right => list.push_back(value),
+                },
+            }
+        }
+
+        if matches!(self.waiters.get(key), Some(queue) if queue.is_empty()) {
+            self.waiters.remove(key);
+        }
+        self.remove_if_empty(key);
+    }
+
+    // ストリームへの追加を待つクライアントを登録する
+    pub fn add_stream_waiter(&mut self, key: &str, waiter: StreamWaiter) {
+        self.stream_waiters
+            .entry(key.to_string())
+            .or_default()
+            .push(waiter);
+    }
+
+    // ストリームへの追加を待つクライアントの登録を取り除く
+    pub fn remove_stream_waiter(&mut self, key: &str, id: u64) {
+        if let Some(waiters) = self.stream_waiters.get_mut(key) {
+            waiters.retain(|waiter| waiter.id != id);
+            if waiters.is_empty() {
+                self.stream_waiters.remove(key);
+            }
+        }
+    }
+
+    // ストリームにエントリを追加したら、待っているクライアント全員に知らせる
+    pub fn notify_stream_waiters(&mut self, key: &str) {
+        if let Some(waiters) = self.stream_waiters.get(key) {
+            for waiter in waiters {
+                waiter.notify.notify_one();
+            }
+        }
+    }
+
+    // リストやハッシュなどが空になっていたらキーごと削除する
+    pub fn remove_if_empty(&mut self, key: &str) {
+        if matches!(self.entries.get(key), Some(entry) if entry.value.is_empty_collection()) {
+            self.remove(key);
+        }
+    }
+
+    fn remove_if_expired(&mut self, key: &str) {
+        let expired = match self.entries.get(key) {
+            Some(entry) => entry.is_expired(Instant::now()),
+            None => false,
+        };
+        if expired {
+            self.take_entry(key);
+            self.touch(key);
+        }
+    }
+
+    // キーを WATCH し、現在のバージョンを返す
+    // 同じキーを WATCH したコネクションの数を数えておき、unwatch ですべて外れたら忘れる
+    pub fn watch(&mut self, key: &str) -> u64 {
+        self.remove_if_expired(key);
+        let clock = self.clock;
+        let watched = self.versions.entry(key.to_string()).or_insert(Watched {
+            version: clock,
+            watchers: 0,
+        });
+        watched.watchers += 1;
+        watched.version
+    }
+
+    // WATCH を解除する
+    pub fn unwatch(&mut self, key: &str) {
+        if let Some(watched) = self.versions.get_mut(key) {
+            watched.watchers -= 1;
+            if watched.watchers == 0 {
+                self.versions.remove(key);
+            }
+        }
+    }
+
+    // WATCH されているキーの現在のバージョン
+    // 期限切れになったキーは、この時点で削除して変更されたものとして扱う
+    pub fn version(&mut self, key: &str) -> Option<u64> {
+        self.remove_if_expired(key);
+        self.versions.get(key).map(|watched| watched.version)
+    }
+
+    // キーと値を取り除き、有効期限やメモリの使用量などの記録からも外す
+    fn take_entry(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        if let Some(when) = entry.expires_at {
+            self.expirations.remove(&(when, key.to_string()));
+        }
+        self.scan_index
+            .remove(&(scan_position(key), key.to_string()));
+        self.keys.swap_remove(entry.index);
+        if let Some(moved) = self.keys.get(entry.index) {
+            self.entries.get_mut(moved).unwrap().index = entry.index;
+        }
+        self.used_memory -= entry.size;
+        self.dirty.remove(key);
+        Some(entry)
+    }
+
+    // キーの変更を数え、WATCH されていればバージョンを進める
+    // 値の大きさが変わったかもしれないので、次に used_memory を読むときに数え直す
+    fn touch(&mut self, key: &str) {
+        if self.entries.contains_key(key) && !self.dirty.contains(key) {
+            // 数え直すのを待っているキーが溜まりすぎないように、ときどき先に数え直しておく
+            if self.dirty.len() >= memory::DIRTY_LIMIT {
+                self.refresh_sizes();
+            }
+            self.dirty.insert(key.to_string());
+        }
+        self.changes += 1;
+        if let Some(watched) = self.versions.get_mut(key) {
+            self.clock += 1;
+            watched.version = self.clock;
+        }
+    }
+}
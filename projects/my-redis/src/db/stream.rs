@@ -0,0 +1,217 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Bound;
+
+use bytes::Bytes;
+use tokio::time::Instant;
+
+// ストリームのエントリの ID
+// ミリ秒単位の時刻と、同じ時刻の中での連番の組で、この順に比較する
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        Self { ms, seq }
+    }
+
+    // 直後の ID（これ以上大きい ID がなければ None）
+    pub fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => self.ms.checked_add(1).map(|ms| StreamId::new(ms, 0)),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}-{}", self.ms, self.seq)
+    }
+}
+
+// エントリのフィールドと値の組
+pub type Fields = Vec<(Bytes, Bytes)>;
+
+// ストリーム
+//
+// エントリは ID の順に BTreeMap に保持するので、範囲の読み出しは O(log N + 件数) で行える
+// エントリをすべて削除しても、last_id は残る（同じ ID を二度と使わないようにするため）
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    last_id: StreamId,
+    groups: HashMap<Bytes, ConsumerGroup>,
+}
+
+// コンシューマグループ
+//
+// last_delivered は、グループのいずれかのコンシューマに最後に渡したエントリの ID
+// pending は、渡したがまだ XACK されていないエントリ（PEL）
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsumerGroup {
+    pub last_delivered: StreamId,
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    // コンシューマ名と、最後に読み出しを行った時刻
+    pub consumers: HashMap<Bytes, Instant>,
+}
+
+// XACK されていないエントリの情報
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingEntry {
+    pub consumer: Bytes,
+    pub delivered_at: Instant,
+    pub delivery_count: u64,
+}
+
+impl ConsumerGroup {
+    pub fn new(last_delivered: StreamId) -> Self {
+        Self {
+            last_delivered,
+            pending: BTreeMap::new(),
+            consumers: HashMap::new(),
+        }
+    }
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // これまでに追加された最大の ID
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    // 現在時刻 now_ms をもとに、次に自動で割り当てる ID を返す
+    // 時計が戻った場合や同じミリ秒の中では、最後の ID の連番を進める
+    pub fn next_id(&self, now_ms: u64) -> Option<StreamId> {
+        if now_ms > self.last_id.ms {
+            Some(StreamId::new(now_ms, 0))
+        } else {
+            self.last_id.next()
+        }
+    }
+
+    // エントリを追加する
+    // ID は last_id より大きくなければならない（0-0 は使えない）
+    pub fn insert(&mut self, id: StreamId, fields: Fields) -> bool {
+        if id <= self.last_id {
+            return false;
+        }
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        true
+    }
+
+    // 最後の ID を進める
+    // スナップショットから読み込むときに、削除済みのエントリの ID を復元するために使う
+    pub fn set_last_id(&mut self, id: StreamId) {
+        self.last_id = self.last_id.max(id);
+    }
+
+    pub fn get(&self, id: &StreamId) -> Option<&Fields> {
+        self.entries.get(id)
+    }
+
+    // ID が範囲に含まれるエントリを昇順に最大 count 件返す
+    pub fn range(
+        &self,
+        start: Bound<StreamId>,
+        end: Bound<StreamId>,
+        count: Option<usize>,
+    ) -> Vec<(StreamId, &Fields)> {
+        if is_empty_range(start, end) {
+            return vec![];
+        }
+        self.entries
+            .range((start, end))
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| (*id, fields))
+            .collect()
+    }
+
+    // ID が範囲に含まれるエントリを降順に最大 count 件返す
+    pub fn rev_range(
+        &self,
+        start: Bound<StreamId>,
+        end: Bound<StreamId>,
+        count: Option<usize>,
+    ) -> Vec<(StreamId, &Fields)> {
+        if is_empty_range(start, end) {
+            return vec![];
+        }
+        self.entries
+            .range((start, end))
+            .rev()
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| (*id, fields))
+            .collect()
+    }
+
+    // 古いエントリから削除して、エントリ数を maxlen 以下にする
+    // 削除した件数を返す
+    pub fn trim(&mut self, maxlen: usize) -> usize {
+        let mut removed = 0;
+        while self.entries.len() > maxlen {
+            self.entries.pop_first();
+            removed += 1;
+        }
+        removed
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = (&Bytes, &ConsumerGroup)> {
+        self.groups.iter()
+    }
+
+    pub fn group(&self, name: &[u8]) -> Option<&ConsumerGroup> {
+        self.groups.get(name)
+    }
+
+    pub fn group_mut(&mut self, name: &[u8]) -> Option<&mut ConsumerGroup> {
+        self.groups.get_mut(name)
+    }
+
+    // コンシューマグループを作成する
+    // 同じ名前のグループがすでにあれば false を返す
+    pub fn create_group(&mut self, name: Bytes, last_delivered: StreamId) -> bool {
+        if self.groups.contains_key(&name) {
+            return false;
+        }
+        self.groups.insert(name, ConsumerGroup::new(last_delivered));
+        true
+    }
+
+    pub fn destroy_group(&mut self, name: &[u8]) -> bool {
+        self.groups.remove(name).is_some()
+    }
+}
+
+// BTreeMap::range は start > end のときに panic するので、事前に空の範囲を判定する
+fn is_empty_range(start: Bound<StreamId>, end: Bound<StreamId>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s), Bound::Excluded(e))
+        | (Bound::Excluded(s), Bound::Included(e))
+        | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+        _ => false,
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::{HashSet, VecDeque};
+use std::ops::Bound;
+
+use bytes::Bytes;
+
+use super::{Dict, SortedSet, Stream};
+
+// 要素を持つ値の大きさを見積もるときに調べる要素の数
+const SIZE_SAMPLES: usize = 8;
+
+// 要素ごとに加える、ポインタや長さなどの大きさの見積もり
+const ELEMENT_OVERHEAD: usize = 16;
+
+// db に保存される値
+// Redis と同様に、一つのキーには型のついた値が一つ対応する
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Hash(Dict),
+    Set(HashSet<Bytes>),
+    ZSet(SortedSet),
+    Stream(Stream),
+}
+
+impl Value {
+    // TYPE コマンドで返す型名
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    // 値が使うおおよそのバイト数
+    //
+    // 要素を持つ型は、先頭のいくつかの要素の平均の大きさに要素の数を掛けて見積もる
+    // 大きなリストなどでも、要素をすべてたどらずに見積もれる
+    pub fn approx_size(&self) -> usize {
+        match self {
+            Value::String(bytes) => bytes.len(),
+            Value::List(list) => estimate(list.len(), list.iter().map(|item| item.len())),
+            Value::Hash(hash) => estimate(
+                hash.len(),
+                hash.iter().map(|(field, value)| field.len() + value.len()),
+            ),
+            Value::Set(set) => estimate(set.len(), set.iter().map(|member| member.len())),
+            Value::ZSet(zset) => {
+                estimate(zset.len(), zset.iter().map(|(member, _)| member.len() + 8))
+            }
+            Value::Stream(stream) => {
+                let entries = stream.range(Bound::Unbounded, Bound::Unbounded, Some(SIZE_SAMPLES));
+                estimate(
+                    stream.len(),
+                    entries.into_iter().map(|(_, fields)| {
+                        fields
+                            .iter()
+                            .map(|(field, value)| field.len() + value.len() + ELEMENT_OVERHEAD)
+                            .sum::<usize>()
+                    }),
+                )
+            }
+        }
+    }
+
+    // 要素を持つ型の値が空になったかどうか
+    // Redis では空になったリストなどはキーごと削除する
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::List(list) => list.is_empty(),
+            Value::Hash(hash) => hash.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::ZSet(zset) => zset.is_empty(),
+            // ストリームはエントリがなくなっても、最後の ID やグループを保つために残す
+            Value::Stream(_) => false,
+        }
+    }
+}
+
+// len 個の要素の大きさを、先頭の要素の大きさ sizes から見積もる
+fn estimate(len: usize, sizes: impl Iterator<Item = usize>) -> usize {
+    let (count, total) = sizes
+        .take(SIZE_SAMPLES)
+        .fold((0, 0), |(count, total), size| (count + 1, total + size));
+    if count == 0 {
+        return 0;
+    }
+    len * (total / count + ELEMENT_OVERHEAD)
+}
+
+impl From<Bytes> for Value {
+    fn from(bytes: Bytes) -> Value {
+        Value::String(bytes)
+    }
+}
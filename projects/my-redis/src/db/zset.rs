@@ -0,0 +1,475 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+// スキップリストのレベルの上限
+const MAX_LEVEL: usize = 32;
+
+// ヘッドノードは常に nodes[0] に置く
+const HEAD: usize = 0;
+
+// スコア範囲の端
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScoreBound {
+    Inclusive(f64),
+    Exclusive(f64),
+}
+
+impl ScoreBound {
+    // score がこの値を下限として範囲に入るか
+    pub fn above_min(&self, score: f64) -> bool {
+        match *self {
+            ScoreBound::Inclusive(min) => score >= min,
+            ScoreBound::Exclusive(min) => score > min,
+        }
+    }
+
+    // score がこの値を上限として範囲に入るか
+    pub fn below_max(&self, score: f64) -> bool {
+        match *self {
+            ScoreBound::Inclusive(max) => score <= max,
+            ScoreBound::Exclusive(max) => score < max,
+        }
+    }
+}
+
+// ソート済み集合
+//
+// メンバーからスコアを引くためのハッシュマップと、
+// (スコア, メンバー) の順に並べたスキップリストの 2 つで要素を管理する
+// スキップリストの各リンクは飛び越す要素数（span）を持つので、
+// 順位の計算や順位による参照も O(log N) で行える
+#[derive(Clone, Debug, Default)]
+pub struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    list: SkipList,
+}
+
+impl PartialEq for SortedSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.scores == other.scores
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    // メンバーを追加する、またはスコアを更新する
+    // 新たに追加された場合は true を返す
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        match self.scores.insert(member.clone(), score) {
+            Some(prev) if prev == score => false,
+            Some(prev) => {
+                self.list.remove(prev, &member);
+                self.list.insert(score, member);
+                false
+            }
+            None => {
+                self.list.insert(score, member);
+                true
+            }
+        }
+    }
+
+    // メンバーを削除する
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.list.remove(score, member);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // スコアの昇順での順位（0 始まり）
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        self.list.rank(score, member)
+    }
+
+    // 順位が start 以上 stop 以下の要素を昇順に返す
+    pub fn range_by_rank(&self, start: usize, stop: usize) -> Vec<(Bytes, f64)> {
+        let mut out = Vec::new();
+        let mut node = self.list.by_rank(start);
+        while let Some(i) = node {
+            if out.len() > stop - start {
+                break;
+            }
+            out.push(self.list.entry(i));
+            node = self.list.next(i);
+        }
+        out
+    }
+
+    // 順位が start 以上 stop 以下の要素を、降順での順位として解釈して返す
+    pub fn rev_range_by_rank(&self, start: usize, stop: usize) -> Vec<(Bytes, f64)> {
+        let len = self.len();
+        if start >= len {
+            return vec![];
+        }
+        let stop = stop.min(len - 1);
+        let mut out = self.range_by_rank(len - 1 - stop, len - 1 - start);
+        out.reverse();
+        out
+    }
+
+    // スコアが min 以上 max 以下の要素を昇順に返す
+    // offset 個読み飛ばしてから、最大 limit 個（None なら制限なし）を返す
+    pub fn range_by_score(
+        &self,
+        min: ScoreBound,
+        max: ScoreBound,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Vec<(Bytes, f64)> {
+        let mut out = Vec::new();
+        let mut node = self.list.first_above(min);
+        let mut skipped = 0;
+        while let Some(i) = node {
+            if limit.is_some_and(|limit| out.len() >= limit)
+                || !max.below_max(self.list.nodes[i].score)
+            {
+                break;
+            }
+            if skipped < offset {
+                skipped += 1;
+            } else {
+                out.push(self.list.entry(i));
+            }
+            node = self.list.next(i);
+        }
+        out
+    }
+
+    // スコアが min 以上 max 以下の要素を降順に返す
+    pub fn rev_range_by_score(
+        &self,
+        min: ScoreBound,
+        max: ScoreBound,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Vec<(Bytes, f64)> {
+        let mut out = Vec::new();
+        let mut node = self.list.last_below(max);
+        let mut skipped = 0;
+        while let Some(i) = node {
+            if limit.is_some_and(|limit| out.len() >= limit)
+                || !min.above_min(self.list.nodes[i].score)
+            {
+                break;
+            }
+            if skipped < offset {
+                skipped += 1;
+            } else {
+                out.push(self.list.entry(i));
+            }
+            node = self.list.prev(i);
+        }
+        out
+    }
+
+    // スコアが最小の要素を取り出す
+    pub fn pop_min(&mut self) -> Option<(Bytes, f64)> {
+        let first = self.list.next(HEAD)?;
+        let (member, score) = self.list.entry(first);
+        self.remove(&member);
+        Some((member, score))
+    }
+
+    // スコアが最大の要素を取り出す
+    pub fn pop_max(&mut self) -> Option<(Bytes, f64)> {
+        let last = self.list.tail?;
+        let (member, score) = self.list.entry(last);
+        self.remove(&member);
+        Some((member, score))
+    }
+
+    // すべての要素をスコアの昇順にたどる
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, f64)> {
+        let mut node = self.list.next(HEAD);
+        std::iter::from_fn(move || {
+            let i = node?;
+            node = self.list.next(i);
+            let n = &self.list.nodes[i];
+            Some((&n.member, n.score))
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Link {
+    next: Option<usize>,
+    // このリンクで飛び越す要素数
+    // next が None の場合は、リストの末尾までの要素数を表す
+    span: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    member: Bytes,
+    score: f64,
+    levels: Vec<Link>,
+    backward: Option<usize>,
+}
+
+// ノードを Vec に並べ、リンクは添字で表すスキップリスト
+// 削除されたノードの添字は free に積んでおき、次の挿入で再利用する
+#[derive(Clone, Debug)]
+struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    tail: Option<usize>,
+    level: usize,
+    len: usize,
+    rng: u64,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        let head = Node {
+            member: Bytes::new(),
+            score: 0.0,
+            levels: vec![Link::default(); MAX_LEVEL],
+            backward: None,
+        };
+        Self {
+            nodes: vec![head],
+            free: Vec::new(),
+            tail: None,
+            level: 1,
+            len: 0,
+            rng: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+// (スコア, メンバー) の順序で比較する
+fn compare(a_score: f64, a_member: &[u8], b_score: f64, b_member: &[u8]) -> Ordering {
+    a_score
+        .partial_cmp(&b_score)
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| a_member.cmp(b_member))
+}
+
+impl SkipList {
+    fn entry(&self, i: usize) -> (Bytes, f64) {
+        (self.nodes[i].member.clone(), self.nodes[i].score)
+    }
+
+    fn next(&self, i: usize) -> Option<usize> {
+        self.nodes[i].levels[0].next
+    }
+
+    fn prev(&self, i: usize) -> Option<usize> {
+        self.nodes[i].backward
+    }
+
+    // ノード i が (score, member) より前に並ぶか
+    fn precedes(&self, i: usize, score: f64, member: &[u8]) -> bool {
+        let n = &self.nodes[i];
+        compare(n.score, &n.member, score, member) == Ordering::Less
+    }
+
+    // 1/4 の確率で一段ずつ高くなるレベルを選ぶ（xorshift による疑似乱数）
+    fn random_level(&mut self) -> usize {
+        let mut level = 1;
+        loop {
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 7;
+            self.rng ^= self.rng << 17;
+            if level >= MAX_LEVEL || self.rng & 3 != 0 {
+                return level;
+            }
+            level += 1;
+        }
+    }
+
+    // (score, member) を挿入すべき位置の直前のノードを、レベルごとに求める
+    // rank[i] はヘッドから update[i] までの要素数
+    fn find_update(&self, score: f64, member: &[u8]) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i + 1 == self.level { 0 } else { rank[i + 1] };
+            while let Some(next) = self.nodes[x].levels[i].next {
+                if !self.precedes(next, score, member) {
+                    break;
+                }
+                rank[i] += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+        (update, rank)
+    }
+
+    fn insert(&mut self, score: f64, member: Bytes) {
+        let (mut update, mut rank) = self.find_update(score, &member);
+
+        let level = self.random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let node = Node {
+            member,
+            score,
+            levels: vec![Link::default(); level],
+            backward: (update[0] != HEAD).then_some(update[0]),
+        };
+        let x = match self.free.pop() {
+            Some(i) => {
+                self.nodes[i] = node;
+                i
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        for i in 0..level {
+            let prev = self.nodes[update[i]].levels[i];
+            self.nodes[x].levels[i] = Link {
+                next: prev.next,
+                span: prev.span - (rank[0] - rank[i]),
+            };
+            self.nodes[update[i]].levels[i] = Link {
+                next: Some(x),
+                span: (rank[0] - rank[i]) + 1,
+            };
+        }
+        // 新しいノードより高いレベルのリンクは、新しいノードを飛び越すことになる
+        for (i, &prev) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[prev].levels[i].span += 1;
+        }
+
+        match self.nodes[x].levels[0].next {
+            Some(next) => self.nodes[next].backward = Some(x),
+            None => self.tail = Some(x),
+        }
+        self.len += 1;
+    }
+
+    fn remove(&mut self, score: f64, member: &[u8]) {
+        let (update, _) = self.find_update(score, member);
+        let x = match self.nodes[update[0]].levels[0].next {
+            Some(x) if self.nodes[x].score == score && self.nodes[x].member == member => x,
+            _ => return,
+        };
+
+        for (i, &prev) in update.iter().enumerate().take(self.level) {
+            if self.nodes[prev].levels[i].next == Some(x) {
+                let removed = self.nodes[x].levels[i];
+                let link = &mut self.nodes[prev].levels[i];
+                link.span = link.span + removed.span - 1;
+                link.next = removed.next;
+            } else {
+                self.nodes[prev].levels[i].span -= 1;
+            }
+        }
+
+        match self.nodes[x].levels[0].next {
+            Some(next) => self.nodes[next].backward = self.nodes[x].backward,
+            None => self.tail = self.nodes[x].backward,
+        }
+        while self.level > 1 && self.nodes[HEAD].levels[self.level - 1].next.is_none() {
+            self.level -= 1;
+        }
+
+        self.nodes[x].member = Bytes::new();
+        self.nodes[x].levels.clear();
+        self.free.push(x);
+        self.len -= 1;
+    }
+
+    // (score, member) の順位（0 始まり）
+    fn rank(&self, score: f64, member: &[u8]) -> Option<usize> {
+        let mut rank = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].next {
+                let n = &self.nodes[next];
+                if compare(n.score, &n.member, score, member) == Ordering::Greater {
+                    break;
+                }
+                rank += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if x != HEAD && self.nodes[x].score == score && self.nodes[x].member == member {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+
+    // 順位（0 始まり）が rank のノード
+    fn by_rank(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].next {
+                if traversed + self.nodes[x].levels[i].span > target {
+                    break;
+                }
+                traversed += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    // スコアが min を満たす最初のノード
+    fn first_above(&self, min: ScoreBound) -> Option<usize> {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].next {
+                if min.above_min(self.nodes[next].score) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        self.next(x)
+    }
+
+    // スコアが max を満たす最後のノード
+    fn last_below(&self, max: ScoreBound) -> Option<usize> {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].next {
+                if !max.below_max(self.nodes[next].score) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        (x != HEAD).then_some(x)
+    }
+}
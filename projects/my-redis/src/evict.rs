@@ -0,0 +1,190 @@
+// maxmemory を超えたときのキーの追い出し
+//
+// 書き込みコマンドを実行する前に、キーが使うおおよそのメモリの合計が maxmemory を超えていれば、
+// ポリシーに従ってキーを選んで削除し、maxmemory 以下に戻す
+//
+// すべてのキーを比べるのではなく、各シャードからランダムに数個ずつ選んだキーの中で
+// 最も追い出すのに適したキーを削除する（Redis と同じ近似）
+// キーの数やシャードの数が増えても、一度に調べるキーの数は変わらない
+// volatile-ttl だけは、各シャードで有効期限の早い順に並べているので、最も早いキーを正確に選べる
+//
+// 追い出したキーは DEL として AOF とフォロワーに伝えるので、書き込みコマンドと同じく
+// db の write_gate を保持したまま呼び出すこと
+// フォロワーはリーダーから受け取った DEL で追い出すので、自分では追い出さない
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use bytes::Bytes;
+use tokio::time::Instant;
+
+use crate::db::ShardedDb;
+use crate::propagate;
+
+// 各シャードからランダムに選ぶキーの数
+const SAMPLES: usize = 5;
+
+// 追い出すキーの選び方
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Policy {
+    // 追い出さず、メモリを増やすコマンドを -OOM で拒否する
+    #[default]
+    NoEviction,
+    // 最後にアクセスしてから最も時間が経ったキー
+    AllKeysLru,
+    // アクセスされた頻度が最も低いキー
+    AllKeysLfu,
+    // 有効期限つきのキーのうち、期限が最も早いキー
+    VolatileTtl,
+    // ランダムなキー
+    AllKeysRandom,
+}
+
+impl Policy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Policy::NoEviction => "noeviction",
+            Policy::AllKeysLru => "allkeys-lru",
+            Policy::AllKeysLfu => "allkeys-lfu",
+            Policy::VolatileTtl => "volatile-ttl",
+            Policy::AllKeysRandom => "allkeys-random",
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "noeviction" => Ok(Policy::NoEviction),
+            "allkeys-lru" => Ok(Policy::AllKeysLru),
+            "allkeys-lfu" => Ok(Policy::AllKeysLfu),
+            "volatile-ttl" => Ok(Policy::VolatileTtl),
+            "allkeys-random" => Ok(Policy::AllKeysRandom),
+            _ => Err(format!("unknown maxmemory policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// メモリの上限と追い出しの設定
+#[derive(Debug)]
+pub struct MaxMemory {
+    bytes: usize,
+    policy: Policy,
+    // これまでに追い出したキーの数
+    evicted: AtomicU64,
+    // allkeys-random で次に選ぶシャード（シャードの間で偏らないように順に回す）
+    next_shard: AtomicUsize,
+}
+
+impl MaxMemory {
+    pub fn new(bytes: usize, policy: Policy) -> Self {
+        Self {
+            bytes,
+            policy,
+            evicted: AtomicU64::new(0),
+            next_shard: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+}
+
+// 全シャードのキーが使うおおよそのメモリの合計
+pub fn used_memory(db: &ShardedDb) -> usize {
+    db.iter()
+        .map(|shard| shard.lock().unwrap().used_memory())
+        .sum()
+}
+
+// maxmemory 以下になるまでキーを追い出す
+// 追い出せるキーがなくなっても maxmemory を超えていれば false を返す
+pub fn free_memory(db: &ShardedDb) -> bool {
+    let maxmemory = match db.maxmemory() {
+        Some(maxmemory) => maxmemory,
+        None => return true,
+    };
+    if db.replication().is_follower() {
+        return true;
+    }
+
+    while used_memory(db) > maxmemory.bytes {
+        let (index, key) = match select(db, maxmemory) {
+            Some(selected) => selected,
+            None => return false,
+        };
+        if db[index].lock().unwrap().remove(&key).is_none() {
+            continue;
+        }
+        maxmemory.evicted.fetch_add(1, Ordering::Relaxed);
+        propagate::command(db, vec![Bytes::from("DEL"), Bytes::from(key)]);
+    }
+    true
+}
+
+// 追い出すキーを選び、そのシャードの番号とキーを返す
+fn select(db: &ShardedDb, maxmemory: &MaxMemory) -> Option<(usize, String)> {
+    let now = Instant::now();
+    match maxmemory.policy {
+        Policy::NoEviction => None,
+        Policy::VolatileTtl => db
+            .iter()
+            .enumerate()
+            .filter_map(|(index, shard)| {
+                let shard = shard.lock().unwrap();
+                let (when, key) = shard.first_expiring()?;
+                Some((when, index, key.clone()))
+            })
+            .min()
+            .map(|(_, index, key)| (index, key)),
+        Policy::AllKeysRandom => {
+            let start = maxmemory.next_shard.fetch_add(1, Ordering::Relaxed);
+            (0..db.len())
+                .map(|i| (start + i) % db.len())
+                .find_map(|index| {
+                    let mut shard = db[index].lock().unwrap();
+                    let sample = shard.sample(1);
+                    sample.first().map(|(key, _)| (index, (*key).clone()))
+                })
+        }
+        // 値が大きいほど追い出すのに適している
+        Policy::AllKeysLru | Policy::AllKeysLfu => db
+            .iter()
+            .enumerate()
+            .filter_map(|(index, shard)| {
+                let mut shard = shard.lock().unwrap();
+                shard
+                    .sample(SAMPLES)
+                    .into_iter()
+                    .map(|(key, entry)| {
+                        let idle = entry.idle(now).as_millis() as u64;
+                        let rarity = match maxmemory.policy {
+                            Policy::AllKeysLfu => u8::MAX - entry.frequency(now),
+                            _ => 0,
+                        };
+                        ((rarity, idle), index, key.clone())
+                    })
+                    .max()
+            })
+            .max()
+            .map(|(_, index, key)| (index, key)),
+    }
+}
@@ -0,0 +1,770 @@
+use std::fmt;
+use std::io::{self, Cursor, Write};
+
+use bytes::{Buf, Bytes};
+
+// Redis プロトコルの一つのフレーム
+//
+// mini-redis の Frame とほぼ同じ形だが、
+// 整数は負の値（TTL の -1, -2 など）も表現できるように i64 で保持する
+//
+// Map 以降は RESP3 で追加された型で、HELLO 3 で RESP3 を選んだクライアントにはそのまま送る
+// RESP2 のクライアントには、Connection が書き込むときに RESP2 の近い型に置き換える
+// （Map は [key, value, ...] の配列、Double は文字列、Boolean は 1 か 0 の整数など）
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Null,
+    Array(Vec<Frame>),
+    // `*-1` で表される Null 配列
+    // WATCH したキーが変更されて EXEC が中止されたときなどに返す
+    NullArray,
+    // `%` キーと値の組の並び（HGETALL など）
+    Map(Vec<(Frame, Frame)>),
+    // `~` 順序のない要素の並び（SMEMBERS など）
+    Set(Vec<Frame>),
+    // `,` 浮動小数点数（ZSCORE など）
+    Double(f64),
+    // `#t` または `#f`
+    Boolean(bool),
+    // `(` 任意の桁数の整数（10 進数の文字列で保持する）
+    BigNumber(String),
+    // `=` 形式（txt や mkd）つきの文字列
+    Verbatim { format: String, text: Bytes },
+    // `|` 続くフレームに付け加える情報
+    Attribute(Vec<(Frame, Frame)>, Box<Frame>),
+    // `>` クライアントが要求していないのに送るデータ（Pub/Sub のメッセージなど）
+    Push(Vec<Frame>),
+}
+
+// クライアントとやりとりするプロトコルのバージョン
+// 接続した直後は RESP2 で、HELLO 3 を受け取ったら RESP3 に切り替える
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+impl Protocol {
+    // HELLO で指定されたバージョン
+    pub fn from_version(version: i64) -> Option<Protocol> {
+        match version {
+            2 => Some(Protocol::Resp2),
+            3 => Some(Protocol::Resp3),
+            _ => None,
+        }
+    }
+
+    pub fn version(&self) -> i64 {
+        match self {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        }
+    }
+}
+
+// 一つのフレームとして受け付ける大きさの上限
+// 巨大な長さを宣言したり深く入れ子にしたりしたフレームで、メモリを使い果たさないようにする
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    // バルク文字列の長さ（バイト数）
+    pub max_bulk_len: usize,
+    // 配列などの要素の数（マップと属性は組の数）
+    pub max_array_len: usize,
+    // 配列などを入れ子にできる深さ（コマンドの配列が 1）
+    pub max_depth: usize,
+    // 一つのコネクションで、まだフレームになっていないまま読み込んでおけるバイト数
+    pub max_buffer: usize,
+}
+
+impl Default for Limits {
+    // Redis の proto-max-bulk-len と client-query-buffer-limit に合わせる
+    fn default() -> Self {
+        Self {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_array_len: 1024 * 1024,
+            max_depth: 64,
+            max_buffer: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    // まだフレームになっていないデータが len バイト溜まり、max_buffer を超えたときのエラー
+    pub(crate) fn buffer_exceeded(&self, len: usize) -> Error {
+        format!(
+            "protocol error; {} bytes buffered without a complete frame, exceeding the limit of {}",
+            len, self.max_buffer
+        )
+        .into()
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    // フレームをパースするのに十分なデータがまだバッファされていない
+    Incomplete,
+
+    // フレームの形式が不正
+    Other(crate::Error),
+}
+
+impl Frame {
+    // バルク文字列の配列を作成する
+    // クライアントからサーバに送るコマンドはこの形をとる
+    pub fn command<I, T>(parts: I) -> Frame
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Bytes>,
+    {
+        Frame::Array(parts.into_iter().map(|p| Frame::Bulk(p.into())).collect())
+    }
+
+    // コマンドのフレームであれば、コマンド名を小文字にして返す
+    pub fn command_name(&self) -> Option<String> {
+        match self {
+            Frame::Array(parts) => match parts.first()? {
+                Frame::Bulk(name) => Some(String::from_utf8_lossy(name).to_ascii_lowercase()),
+                Frame::Simple(name) => Some(name.to_ascii_lowercase()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // バッファから単一のフレームをデコードできるだけのデータがあるかをチェックする
+    pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
+        Frame::check_with_limits(src, &Limits::default())
+    }
+
+    // 上限を指定してチェックする
+    // 上限を超える長さや深さは、データが揃うのを待たずにその時点でエラーにする
+    //
+    // RESP の型を表すバイトで始まらなければ、インラインコマンドとして扱う
+    // インラインコマンドは最上位のフレームとしてだけ受け付け、配列の要素などでは受け付けない
+    pub fn check_with_limits(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<(), Error> {
+        if is_type_byte(peek_u8(src)?) {
+            return check_frame(src, limits, 0);
+        }
+        if skip_blank_lines(src)? {
+            return Frame::check_with_limits(src, limits);
+        }
+        get_inline(src)?;
+        Ok(())
+    }
+
+    // フレームをパースする
+    // 事前に `check` でフレーム全体がバッファされていることを確認しておく
+    //
+    // check_with_limits と同じく、インラインコマンドは最上位のフレームとしてだけ受け付ける
+    pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
+        if is_type_byte(peek_u8(src)?) {
+            return Frame::parse_item(src);
+        }
+        if skip_blank_lines(src)? {
+            return Frame::parse(src);
+        }
+        let args = split_args(get_inline(src)?)?;
+        Ok(Frame::Array(args.into_iter().map(Frame::Bulk).collect()))
+    }
+
+    // RESP の型を表すバイトで始まるフレームをパースする（配列の要素などもこちらでパースする）
+    fn parse_item(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
+        match get_u8(src)? {
+            b'+' => {
+                let line = get_line(src)?.to_vec();
+                let string = String::from_utf8(line)?;
+                Ok(Frame::Simple(string))
+            }
+            b'-' => {
+                let line = get_line(src)?.to_vec();
+                let string = String::from_utf8(line)?;
+                Ok(Frame::Error(string))
+            }
+            b':' => {
+                let val = get_integer(src)?;
+                Ok(Frame::Integer(val))
+            }
+            b'$' => {
+                if b'-' == peek_u8(src)? {
+                    let line = get_line(src)?;
+                    if line != b"-1" {
+                        return Err("protocol error; invalid frame format".into());
+                    }
+                    Ok(Frame::Null)
+                } else {
+                    let len = get_decimal(src)?;
+                    let n = len + 2;
+
+                    if src.remaining() < n {
+                        return Err(Error::Incomplete);
+                    }
+
+                    let data = Bytes::copy_from_slice(&src.chunk()[..len]);
+
+                    // 長さ + 2 (\r\n) だけ読み飛ばす
+                    skip(src, n)?;
+
+                    Ok(Frame::Bulk(data))
+                }
+            }
+            b'*' => {
+                if b'-' == peek_u8(src)? {
+                    let line = get_line(src)?;
+                    if line != b"-1" {
+                        return Err("protocol error; invalid frame format".into());
+                    }
+                    return Ok(Frame::NullArray);
+                }
+
+                Ok(Frame::Array(parse_items(src)?))
+            }
+            b'_' => {
+                if !get_line(src)?.is_empty() {
+                    return Err("protocol error; invalid frame format".into());
+                }
+                Ok(Frame::Null)
+            }
+            b',' => {
+                let line = get_line(src)?;
+                std::str::from_utf8(line)
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .map(Frame::Double)
+                    .ok_or_else(|| "protocol error; invalid frame format".into())
+            }
+            b'#' => match get_line(src)? {
+                b"t" => Ok(Frame::Boolean(true)),
+                b"f" => Ok(Frame::Boolean(false)),
+                _ => Err("protocol error; invalid frame format".into()),
+            },
+            b'(' => {
+                let line = get_line(src)?;
+                let digits = line.strip_prefix(b"-").unwrap_or(line);
+                if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                    return Err("protocol error; invalid frame format".into());
+                }
+                Ok(Frame::BigNumber(String::from_utf8(line.to_vec())?))
+            }
+            // =<len>\r\n<3 文字の形式>:<文字列>\r\n
+            b'=' => {
+                let len = get_decimal(src)?;
+                if src.remaining() < len + 2 {
+                    return Err(Error::Incomplete);
+                }
+                let data = &src.chunk()[..len];
+                if len < 4 || data[3] != b':' {
+                    return Err("protocol error; invalid frame format".into());
+                }
+                let format = String::from_utf8(data[..3].to_vec())?;
+                let text = Bytes::copy_from_slice(&data[4..]);
+                skip(src, len + 2)?;
+                Ok(Frame::Verbatim { format, text })
+            }
+            b'~' => Ok(Frame::Set(parse_items(src)?)),
+            b'>' => Ok(Frame::Push(parse_items(src)?)),
+            b'%' => Ok(Frame::Map(parse_pairs(src)?)),
+            // 連なった属性は入れ子の深さとして check で制限しているので、ここで再帰しても深くなりすぎない
+            b'|' => {
+                let attributes = parse_pairs(src)?;
+                let frame = Frame::parse_item(src)?;
+                Ok(Frame::Attribute(attributes, Box::new(frame)))
+            }
+            actual => Err(invalid_type_byte(actual)),
+        }
+    }
+}
+
+// check_with_limits の本体
+// depth はこのフレームを囲んでいる配列などの数
+fn check_frame(src: &mut Cursor<&[u8]>, limits: &Limits, depth: usize) -> Result<(), Error> {
+    match get_u8(src)? {
+        b'+' | b'-' => {
+            get_line(src)?;
+            Ok(())
+        }
+        b':' => {
+            let _ = get_integer(src)?;
+            Ok(())
+        }
+        b'$' => {
+            if b'-' == peek_u8(src)? {
+                // '-1\r\n' を読み飛ばす
+                skip(src, 4)
+            } else {
+                // バルク文字列の長さを読み取る
+                let len = get_bulk_len(src, limits)?;
+
+                // 長さ + 2 (\r\n) だけ読み飛ばす
+                skip(src, len + 2)
+            }
+        }
+        b'*' => {
+            if b'-' == peek_u8(src)? {
+                // '-1\r\n' を読み飛ばす
+                return skip(src, 4);
+            }
+
+            let len = get_array_len(src, limits, depth)?;
+            for _ in 0..len {
+                check_frame(src, limits, depth + 1)?;
+            }
+
+            Ok(())
+        }
+        b'_' | b',' | b'#' | b'(' => {
+            get_line(src)?;
+            Ok(())
+        }
+        b'=' => {
+            let len = get_bulk_len(src, limits)?;
+            skip(src, len + 2)
+        }
+        b'~' | b'>' => {
+            let len = get_array_len(src, limits, depth)?;
+            for _ in 0..len {
+                check_frame(src, limits, depth + 1)?;
+            }
+            Ok(())
+        }
+        b'%' => {
+            let len = get_array_len(src, limits, depth)?;
+            for _ in 0..len * 2 {
+                check_frame(src, limits, depth + 1)?;
+            }
+            Ok(())
+        }
+        // 付け加える情報の後に、本体のフレームが続く
+        // 本体も属性の内側に入れ子にするので、属性が連なるだけでも深さが増える
+        b'|' => {
+            let len = get_array_len(src, limits, depth)?;
+            for _ in 0..len * 2 {
+                check_frame(src, limits, depth + 1)?;
+            }
+            check_frame(src, limits, depth + 1)
+        }
+        actual => Err(invalid_type_byte(actual)),
+    }
+}
+
+// RESP2 と RESP3 のフレームの先頭に来る、型を表すバイトか
+fn is_type_byte(byte: u8) -> bool {
+    b"+-:$*_,#(=~>%|".contains(&byte)
+}
+
+fn invalid_type_byte(actual: u8) -> Error {
+    format!("protocol error; invalid frame type byte `{}`", actual).into()
+}
+
+// バルク文字列の長さを読み取り、上限を超えていないか確かめる
+fn get_bulk_len(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<usize, Error> {
+    let len = get_decimal(src)?;
+    if len > limits.max_bulk_len {
+        return Err(format!(
+            "protocol error; bulk length {} exceeds the limit of {}",
+            len, limits.max_bulk_len
+        )
+        .into());
+    }
+    Ok(len)
+}
+
+// 配列などの要素の数を読み取り、要素の数と入れ子の深さが上限を超えていないか確かめる
+fn get_array_len(src: &mut Cursor<&[u8]>, limits: &Limits, depth: usize) -> Result<usize, Error> {
+    if depth >= limits.max_depth {
+        return Err(format!(
+            "protocol error; nesting depth exceeds the limit of {}",
+            limits.max_depth
+        )
+        .into());
+    }
+    let len = get_decimal(src)?;
+    if len > limits.max_array_len {
+        return Err(format!(
+            "protocol error; array length {} exceeds the limit of {}",
+            len, limits.max_array_len
+        )
+        .into());
+    }
+    Ok(len)
+}
+
+// 要素の数に続く要素の並びをパースする（配列、集合、プッシュ）
+fn parse_items(src: &mut Cursor<&[u8]>) -> Result<Vec<Frame>, Error> {
+    let len = get_decimal(src)?;
+    let mut out = Vec::with_capacity(len);
+    for _ in 0..len {
+        out.push(Frame::parse_item(src)?);
+    }
+    Ok(out)
+}
+
+// 組の数に続くキーと値の並びをパースする（マップ、属性）
+fn parse_pairs(src: &mut Cursor<&[u8]>) -> Result<Vec<(Frame, Frame)>, Error> {
+    let len = get_decimal(src)?;
+    let mut out = Vec::with_capacity(len);
+    for _ in 0..len {
+        let key = Frame::parse_item(src)?;
+        let value = Frame::parse_item(src)?;
+        out.push((key, value));
+    }
+    Ok(out)
+}
+
+// 浮動小数点数を RESP3 の Double の形式で表す（RESP2 では同じ文字列をバルク文字列で送る）
+pub fn format_double(value: f64) -> String {
+    if value.is_nan() {
+        "nan".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+// フレームを RESP のバイト列として dst に書き込む
+// 2 種類の Connection、コーデック、AOF、レプリケーションのいずれもこのエンコーダで書き込む
+// dst は Vec<u8> のほか、BytesMut の writer() など io::Write を実装したものなら何でもよい
+//
+// RESP2 を指定すると、RESP3 にしかない型は RESP2 の近い型に置き換えて書き込む
+pub fn encode(frame: &Frame, protocol: Protocol, dst: &mut impl Write) -> io::Result<()> {
+    let resp3 = protocol == Protocol::Resp3;
+    match frame {
+        Frame::Simple(val) => {
+            write!(dst, "+{}\r\n", val)?;
+        }
+        Frame::Error(val) => {
+            write!(dst, "-{}\r\n", val)?;
+        }
+        Frame::Integer(val) => {
+            write!(dst, ":{}\r\n", val)?;
+        }
+        Frame::Null | Frame::NullArray if resp3 => {
+            dst.write_all(b"_\r\n")?;
+        }
+        Frame::Null => {
+            dst.write_all(b"$-1\r\n")?;
+        }
+        Frame::NullArray => {
+            dst.write_all(b"*-1\r\n")?;
+        }
+        Frame::Bulk(val) => {
+            encode_bulk(val, dst)?;
+        }
+        Frame::Array(val) => {
+            encode_items(b'*', val, protocol, dst)?;
+        }
+        Frame::Set(val) => {
+            encode_items(if resp3 { b'~' } else { b'*' }, val, protocol, dst)?;
+        }
+        Frame::Push(val) => {
+            encode_items(if resp3 { b'>' } else { b'*' }, val, protocol, dst)?;
+        }
+        // RESP2 ではキーと値を交互に並べた配列にする
+        Frame::Map(pairs) => {
+            if resp3 {
+                write!(dst, "%{}\r\n", pairs.len())?;
+            } else {
+                write!(dst, "*{}\r\n", pairs.len() * 2)?;
+            }
+            for (key, value) in pairs {
+                encode(key, protocol, dst)?;
+                encode(value, protocol, dst)?;
+            }
+        }
+        Frame::Double(val) if resp3 => {
+            write!(dst, ",{}\r\n", format_double(*val))?;
+        }
+        Frame::Double(val) => {
+            encode_bulk(format_double(*val).as_bytes(), dst)?;
+        }
+        Frame::Boolean(val) if resp3 => {
+            dst.write_all(if *val { b"#t\r\n" } else { b"#f\r\n" })?;
+        }
+        Frame::Boolean(val) => {
+            write!(dst, ":{}\r\n", *val as i64)?;
+        }
+        Frame::BigNumber(val) if resp3 => {
+            write!(dst, "({}\r\n", val)?;
+        }
+        Frame::BigNumber(val) => {
+            encode_bulk(val.as_bytes(), dst)?;
+        }
+        Frame::Verbatim { format, text } if resp3 => {
+            write!(dst, "={}\r\n{}:", text.len() + 4, format)?;
+            dst.write_all(text)?;
+            dst.write_all(b"\r\n")?;
+        }
+        Frame::Verbatim { text, .. } => {
+            encode_bulk(text, dst)?;
+        }
+        // RESP2 には付け加える情報を送る方法がないので、本体のフレームだけを書き込む
+        Frame::Attribute(attributes, val) => {
+            if resp3 {
+                write!(dst, "|{}\r\n", attributes.len())?;
+                for (key, value) in attributes {
+                    encode(key, protocol, dst)?;
+                    encode(value, protocol, dst)?;
+                }
+            }
+            encode(val, protocol, dst)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn encode_bulk(val: &[u8], dst: &mut impl Write) -> io::Result<()> {
+    write!(dst, "${}\r\n", val.len())?;
+    dst.write_all(val)?;
+    dst.write_all(b"\r\n")
+}
+
+fn encode_items(
+    kind: u8,
+    items: &[Frame],
+    protocol: Protocol,
+    dst: &mut impl Write,
+) -> io::Result<()> {
+    write!(dst, "{}{}\r\n", kind as char, items.len())?;
+    for item in items {
+        encode(item, protocol, dst)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Frame::Simple(response) => response.fmt(fmt),
+            Frame::Error(msg) => write!(fmt, "error: {}", msg),
+            Frame::Integer(num) => num.fmt(fmt),
+            Frame::Bulk(msg) => match std::str::from_utf8(msg) {
+                Ok(string) => string.fmt(fmt),
+                Err(_) => write!(fmt, "{:?}", msg),
+            },
+            Frame::Null | Frame::NullArray => "(nil)".fmt(fmt),
+            Frame::Array(parts) | Frame::Set(parts) | Frame::Push(parts) => {
+                for (i, part) in parts.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    part.fmt(fmt)?;
+                }
+                Ok(())
+            }
+            Frame::Map(pairs) => {
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    write!(fmt, "{} => {}", key, value)?;
+                }
+                Ok(())
+            }
+            Frame::Double(value) => format_double(*value).fmt(fmt),
+            Frame::Boolean(value) => value.fmt(fmt),
+            Frame::BigNumber(digits) => digits.fmt(fmt),
+            Frame::Verbatim { text, .. } => String::from_utf8_lossy(text).fmt(fmt),
+            Frame::Attribute(_, frame) => frame.fmt(fmt),
+        }
+    }
+}
+
+fn peek_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete);
+    }
+
+    Ok(src.chunk()[0])
+}
+
+fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete);
+    }
+
+    Ok(src.get_u8())
+}
+
+fn skip(src: &mut Cursor<&[u8]>, n: usize) -> Result<(), Error> {
+    if src.remaining() < n {
+        return Err(Error::Incomplete);
+    }
+
+    src.advance(n);
+    Ok(())
+}
+
+// 改行で終わる非負の 10 進数（長さ）を読み取る
+fn get_decimal(src: &mut Cursor<&[u8]>) -> Result<usize, Error> {
+    let line = get_line(src)?;
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| "protocol error; invalid frame format".into())
+}
+
+// 改行で終わる符号付きの 10 進数（整数フレーム）を読み取る
+fn get_integer(src: &mut Cursor<&[u8]>) -> Result<i64, Error> {
+    let line = get_line(src)?;
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| "protocol error; invalid frame format".into())
+}
+
+// \r\n で終わる一行を探す
+fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
+    let start = src.position() as usize;
+    let buf = *src.get_ref();
+
+    if let Some(i) = buf[start..].windows(2).position(|w| w == b"\r\n") {
+        let end = start + i;
+        // \n の直後までカーソルを進める
+        src.set_position((end + 2) as u64);
+        return Ok(&buf[start..end]);
+    }
+
+    Err(Error::Incomplete)
+}
+
+// インラインコマンドの一行を読み取る
+// telnet や netcat から送られることを想定して、\n だけで終わる行も受け付ける
+fn get_inline<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
+    let start = src.position() as usize;
+    let buf = *src.get_ref();
+
+    let end = match buf[start..].iter().position(|&b| b == b'\n') {
+        Some(i) => start + i,
+        None => return Err(Error::Incomplete),
+    };
+    src.set_position((end + 1) as u64);
+
+    let line = &buf[start..end];
+    Ok(line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+// 空白だけの行を読み飛ばし、一行でも読み飛ばしたら true を返す
+// 空白でないバイトが届くまでは、次のコマンドがどの形式か分からないので待つ
+fn skip_blank_lines(src: &mut Cursor<&[u8]>) -> Result<bool, Error> {
+    let start = src.position() as usize;
+    let rest = &src.get_ref()[start..];
+
+    let first = rest
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .ok_or(Error::Incomplete)?;
+    match rest[..first].iter().rposition(|&b| b == b'\n') {
+        Some(newline) => {
+            src.set_position((start + newline + 1) as u64);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// インラインコマンドを空白で区切って引数に分ける
+//
+// Redis と同じく引用符を扱う
+// - "..." の中では \n \r \t \b \a \\ \" と \xHH のエスケープを使える
+// - '...' の中では \' だけを使える
+// 閉じる引用符の直後は空白か行末でなければならない
+fn split_args(line: &[u8]) -> Result<Vec<Bytes>, Error> {
+    let unbalanced = || Error::from("protocol error; unbalanced quotes in inline command");
+
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == line.len() {
+            return Ok(args);
+        }
+
+        let mut arg = Vec::new();
+        let mut quote = None;
+        loop {
+            let c = match line.get(i) {
+                Some(&c) => c,
+                None if quote.is_some() => return Err(unbalanced()),
+                None => break,
+            };
+            i += 1;
+            match quote {
+                None => match c {
+                    b'"' | b'\'' => quote = Some(c),
+                    c if c.is_ascii_whitespace() => break,
+                    c => arg.push(c),
+                },
+                Some(q) if c == q => {
+                    if line.get(i).is_some_and(|c| !c.is_ascii_whitespace()) {
+                        return Err(unbalanced());
+                    }
+                    break;
+                }
+                Some(b'"') if c == b'\\' => {
+                    let hex = line
+                        .get(i..i + 3)
+                        .filter(|escape| {
+                            escape[0] == b'x' && escape[1..].iter().all(u8::is_ascii_hexdigit)
+                        })
+                        .and_then(|escape| std::str::from_utf8(&escape[1..]).ok())
+                        .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+                    if let Some(byte) = hex {
+                        arg.push(byte);
+                        i += 3;
+                    } else if let Some(&escaped) = line.get(i) {
+                        arg.push(match escaped {
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b't' => b'\t',
+                            b'b' => 0x08,
+                            b'a' => 0x07,
+                            other => other,
+                        });
+                        i += 1;
+                    }
+                }
+                Some(b'\'') if c == b'\\' && line.get(i) == Some(&b'\'') => {
+                    arg.push(b'\'');
+                    i += 1;
+                }
+                Some(_) => arg.push(c),
+            }
+        }
+        args.push(Bytes::from(arg));
+    }
+}
+
+impl From<String> for Error {
+    fn from(src: String) -> Error {
+        Error::Other(src.into())
+    }
+}
+
+impl From<&str> for Error {
+    fn from(src: &str) -> Error {
+        src.to_string().into()
+    }
+}
+
+impl From<std::string::FromUtf8Error> for Error {
+    fn from(_src: std::string::FromUtf8Error) -> Error {
+        "protocol error; invalid frame format".into()
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Incomplete => "stream ended early".fmt(fmt),
+            Error::Other(err) => err.fmt(fmt),
+        }
+    }
+}
@@ -0,0 +1,78 @@
+// Redis の KEYS や PSUBSCRIBE で使われる glob 形式のパターンマッチ
+//
+// - `*` は任意の長さの文字列にマッチする
+// - `?` は任意の 1 文字にマッチする
+// - `[abc]`, `[a-z]`, `[^a]` は文字クラスにマッチする
+// - `\` は直後の文字をエスケープする
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // 直前に出現した `*` の位置と、そのときの string 側の位置
+    // マッチに失敗したら、`*` がもう 1 文字多く飲み込んだものとしてやり直す
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, s));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => match_class(&pattern[p..], string[s]),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == string[s]).then_some(2),
+            Some(&c) => (c == string[s]).then_some(1),
+            None => None,
+        };
+
+        match step {
+            Some(len) => {
+                p += len;
+                s += 1;
+            }
+            None => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    s = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    // 残りのパターンが `*` だけならマッチする
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// `[...]` の文字クラスと 1 文字を照合する
+// マッチすればクラス全体の長さを返す
+fn match_class(pattern: &[u8], c: u8) -> Option<usize> {
+    let mut i = 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (lo, hi) = if pattern[i] <= pattern[i + 2] {
+                (pattern[i], pattern[i + 2])
+            } else {
+                (pattern[i + 2], pattern[i])
+            };
+            matched |= lo <= c && c <= hi;
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+
+    // 閉じ括弧がなければ、パターンの終わりまでをクラスとみなす
+    let len = (i + 1).min(pattern.len());
+    (matched != negate).then_some(len)
+}
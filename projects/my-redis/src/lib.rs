@@ -1,5 +1,32 @@
-mod connection;
+// フレームの読み書きを行う Connection の実装は 2 種類ある
+// - connection: BytesMut と Buf トレイトを使う実装（デフォルト）
+// - connection_without_buf_trait: Vec<u8> と cursor を使う実装
+// `vec-buffer` feature を有効にすると、後者が `my_redis::Connection` として使われる
+// 性能を比較できるように、どちらのモジュールも常にコンパイルしておく
+// どちらも AsyncRead + AsyncWrite を実装した任意のストリームで使える（省略すると TcpStream）
+pub mod connection;
+pub mod connection_without_buf_trait;
+
+#[cfg(not(feature = "vec-buffer"))]
 pub use connection::Connection;
+#[cfg(feature = "vec-buffer")]
+pub use connection_without_buf_trait::Connection;
+
+pub mod aof;
+pub mod cluster;
+pub mod cmd;
+pub mod codec;
+pub mod db;
+pub mod evict;
+pub mod frame;
+pub mod glob;
+pub mod propagate;
+pub mod pubsub;
+pub mod rdb;
+pub mod replication;
+pub mod server;
+pub mod transaction;
 
-// mod connection_without_buf_trait;
-// pub use connection_without_buf_trait::Connection;
\ No newline at end of file
+// mini-redis と同様に、エラーはトレイトオブジェクトとして扱う
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub type Result<T> = std::result::Result<T, Error>;
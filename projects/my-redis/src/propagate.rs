@@ -0,0 +1,37 @@
+// 書き込みコマンドの伝播
+//
+// 実行した書き込みコマンドを、AOF とフォロワーへのレプリケーションストリームに伝える
+// どちらも実行した順序のまま読み込み直す（実行し直す）ので、
+// db の write_gate を保持したまま呼び出すこと（db.propagating() なら排他ロックになる）
+
+use bytes::Bytes;
+
+use crate::db::ShardedDb;
+
+// 書き込みコマンドを伝える（AOF もフォロワーもなければ何もしない）
+pub fn command(db: &ShardedDb, command: Vec<Bytes>) {
+    append(db, &[command]);
+}
+
+// 各シャードに記録された副作用を伝える
+pub fn effects(db: &ShardedDb) {
+    if !db.propagating() {
+        return;
+    }
+    let effects: Vec<Vec<Bytes>> = db
+        .iter()
+        .flat_map(|shard| shard.lock().unwrap().take_effects())
+        .collect();
+    if !effects.is_empty() {
+        append(db, &effects);
+    }
+}
+
+fn append(db: &ShardedDb, commands: &[Vec<Bytes>]) {
+    if let Some(aof) = db.aof() {
+        aof.append(commands);
+    }
+    if let Some(leader) = db.replication().leader() {
+        leader.append(commands);
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+
+use crate::frame::Frame;
+use crate::glob::glob_match;
+use crate::{Connection, Result};
+
+// チャンネルごとの broadcast チャンネルに溜めておけるメッセージの数
+// これを超えて読み遅れた購読者は、古いメッセージを読み飛ばす
+const CHANNEL_CAPACITY: usize = 1024;
+
+// 購読者のコネクションに送る前のメッセージを溜めておける数
+const OUTBOX_CAPACITY: usize = 1024;
+
+// チャンネル名とパターンごとに broadcast チャンネルを持つ、Pub/Sub の中継点
+// すべてのコネクションで共有する
+//
+// 購読者がいなくなったチャンネルとパターンは、購読を解除したときに取り除く
+#[derive(Debug, Default)]
+pub struct Hub {
+    channels: Mutex<HashMap<Bytes, broadcast::Sender<Bytes>>>,
+    // パターン購読者には (チャンネル名, メッセージ) を送る
+    patterns: Mutex<HashMap<Bytes, broadcast::Sender<(Bytes, Bytes)>>>,
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // チャンネルを購読する
+    pub fn subscribe(&self, channel: &Bytes) -> broadcast::Receiver<Bytes> {
+        let mut channels = self.channels.lock().unwrap();
+        match channels.get(channel) {
+            Some(tx) => tx.subscribe(),
+            None => {
+                let (tx, rx) = broadcast::channel(CHANNEL_CAPACITY);
+                channels.insert(channel.clone(), tx);
+                rx
+            }
+        }
+    }
+
+    // パターンに一致するすべてのチャンネルを購読する
+    pub fn psubscribe(&self, pattern: &Bytes) -> broadcast::Receiver<(Bytes, Bytes)> {
+        let mut patterns = self.patterns.lock().unwrap();
+        match patterns.get(pattern) {
+            Some(tx) => tx.subscribe(),
+            None => {
+                let (tx, rx) = broadcast::channel(CHANNEL_CAPACITY);
+                patterns.insert(pattern.clone(), tx);
+                rx
+            }
+        }
+    }
+
+    // 購読者がいなくなっていれば、チャンネルを取り除く
+    // 購読を解除して Receiver をドロップした後に呼び出す
+    pub fn release_channel(&self, channel: &Bytes) {
+        let mut channels = self.channels.lock().unwrap();
+        if matches!(channels.get(channel), Some(tx) if tx.receiver_count() == 0) {
+            channels.remove(channel);
+        }
+    }
+
+    // 購読者がいなくなっていれば、パターンを取り除く
+    pub fn release_pattern(&self, pattern: &Bytes) {
+        let mut patterns = self.patterns.lock().unwrap();
+        if matches!(patterns.get(pattern), Some(tx) if tx.receiver_count() == 0) {
+            patterns.remove(pattern);
+        }
+    }
+
+    // 購読者のいるチャンネルとパターンの数
+    pub fn counts(&self) -> (usize, usize) {
+        (
+            self.channels.lock().unwrap().len(),
+            self.patterns.lock().unwrap().len(),
+        )
+    }
+
+    // メッセージを送信し、受け取った購読者の数を返す
+    // パターンで購読している購読者も、一致したパターンごとに数える
+    //
+    // 購読者がいなくなったチャンネルやパターンはここで取り除く
+    pub fn publish(&self, channel: &Bytes, message: Bytes) -> usize {
+        let mut received = 0;
+
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(tx) = channels.get(channel) {
+            match tx.send(message.clone()) {
+                Ok(n) => received += n,
+                Err(_) => {
+                    channels.remove(channel);
+                }
+            }
+        }
+        drop(channels);
+
+        let mut patterns = self.patterns.lock().unwrap();
+        patterns.retain(|pattern, tx| {
+            if !glob_match(pattern, channel) {
+                return tx.receiver_count() > 0;
+            }
+            match tx.send((channel.clone(), message.clone())) {
+                Ok(n) => {
+                    received += n;
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+
+        received
+    }
+}
+
+// Pub/Sub に関係するコマンド
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Subscribe(Vec<Bytes>),
+    Unsubscribe(Vec<Bytes>),
+    PSubscribe(Vec<Bytes>),
+    PUnsubscribe(Vec<Bytes>),
+    Publish(Bytes, Bytes),
+    Ping(Option<Bytes>),
+}
+
+impl Command {
+    // フレームが Pub/Sub のコマンドであれば解釈する
+    // それ以外のコマンドであれば None を返す
+    // 引数の個数が誤っている場合は、クライアントに返すエラーフレームを返す
+    pub fn parse(frame: &Frame) -> Option<std::result::Result<Command, Frame>> {
+        let parts = match frame {
+            Frame::Array(parts) if !parts.is_empty() => parts,
+            _ => return None,
+        };
+        let args = parts
+            .iter()
+            .map(|part| match part {
+                Frame::Bulk(bytes) => Some(bytes.clone()),
+                Frame::Simple(s) => Some(Bytes::from(s.clone())),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+        let rest = &args[1..];
+        let command = match (name.as_str(), rest) {
+            ("subscribe", [_, ..]) => Command::Subscribe(rest.to_vec()),
+            ("psubscribe", [_, ..]) => Command::PSubscribe(rest.to_vec()),
+            ("unsubscribe", _) => Command::Unsubscribe(rest.to_vec()),
+            ("punsubscribe", _) => Command::PUnsubscribe(rest.to_vec()),
+            ("publish", [channel, message]) => Command::Publish(channel.clone(), message.clone()),
+            ("ping", []) => Command::Ping(None),
+            ("ping", [msg]) => Command::Ping(Some(msg.clone())),
+            ("subscribe" | "psubscribe" | "publish" | "ping", _) => {
+                return Some(Err(Frame::Error(format!(
+                    "ERR wrong number of arguments for '{}' command",
+                    name
+                ))))
+            }
+            _ => return None,
+        };
+        Some(Ok(command))
+    }
+}
+
+// 購読モードに入ったコネクションの状態
+//
+// 購読しているチャンネルとパターンごとに、broadcast から受け取ったメッセージを
+// outbox に転送するタスクを起動しておく
+// コネクションは outbox とクライアントからのコマンドを select! で待ち受ける
+pub struct Subscriber {
+    hub: Arc<Hub>,
+    channels: HashMap<Bytes, JoinHandle<()>>,
+    patterns: HashMap<Bytes, JoinHandle<()>>,
+    outbox_tx: mpsc::Sender<Frame>,
+    outbox_rx: mpsc::Receiver<Frame>,
+}
+
+impl Subscriber {
+    pub fn new(hub: Arc<Hub>) -> Self {
+        let (outbox_tx, outbox_rx) = mpsc::channel(OUTBOX_CAPACITY);
+        Self {
+            hub,
+            channels: HashMap::new(),
+            patterns: HashMap::new(),
+            outbox_tx,
+            outbox_rx,
+        }
+    }
+
+    // 購読しているチャンネルとパターンの合計数
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    // SUBSCRIBE などを受け取ってから、すべての購読を解除するまでコネクションを処理する
+    // クライアントが接続を切った場合もそのまま戻る
+    pub async fn run(&mut self, connection: &mut Connection, command: Command) -> Result<()> {
+        self.apply(connection, command).await?;
+
+        while self.count() > 0 {
+            tokio::select! {
+                Some(message) = self.outbox_rx.recv() => {
+                    connection.write_frame(&message).await?;
+                }
+                frame = connection.read_frame() => {
+                    let frame = match frame? {
+                        Some(frame) => frame,
+                        None => return Ok(()),
+                    };
+                    match Command::parse(&frame) {
+                        Some(Ok(Command::Publish(..))) | None => {
+                            connection.write_frame(&not_allowed(&frame)).await?;
+                        }
+                        Some(Ok(command)) => self.apply(connection, command).await?,
+                        Some(Err(err)) => connection.write_frame(&err).await?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 購読の追加・解除を行い、チャンネルごとに応答を書き込む
+    async fn apply(&mut self, connection: &mut Connection, command: Command) -> Result<()> {
+        match command {
+            Command::Subscribe(channels) => {
+                for channel in channels {
+                    if !self.channels.contains_key(&channel) {
+                        let rx = self.hub.subscribe(&channel);
+                        let outbox = self.outbox_tx.clone();
+                        let c = channel.clone();
+                        let hub = self.hub.clone();
+                        let name = channel.clone();
+                        let task = forward(
+                            rx,
+                            outbox,
+                            move |message| push("message", [c.clone(), message]),
+                            move || hub.release_channel(&name),
+                        );
+                        self.channels.insert(channel.clone(), task);
+                    }
+                    connection
+                        .write_frame(&self.reply("subscribe", Some(channel)))
+                        .await?;
+                }
+            }
+            Command::PSubscribe(patterns) => {
+                for pattern in patterns {
+                    if !self.patterns.contains_key(&pattern) {
+                        let rx = self.hub.psubscribe(&pattern);
+                        let outbox = self.outbox_tx.clone();
+                        let p = pattern.clone();
+                        let hub = self.hub.clone();
+                        let name = pattern.clone();
+                        let task = forward(
+                            rx,
+                            outbox,
+                            move |(channel, message)| {
+                                push("pmessage", [p.clone(), channel, message])
+                            },
+                            move || hub.release_pattern(&name),
+                        );
+                        self.patterns.insert(pattern.clone(), task);
+                    }
+                    connection
+                        .write_frame(&self.reply("psubscribe", Some(pattern)))
+                        .await?;
+                }
+            }
+            Command::Unsubscribe(channels) => {
+                let replies = unsubscribe(&mut self.channels, channels);
+                self.write_unsubscribed(connection, "unsubscribe", replies)
+                    .await?;
+            }
+            Command::PUnsubscribe(patterns) => {
+                let replies = unsubscribe(&mut self.patterns, patterns);
+                self.write_unsubscribed(connection, "punsubscribe", replies)
+                    .await?;
+            }
+            Command::Ping(msg) => {
+                let reply = Frame::Array(vec![
+                    Frame::Bulk(Bytes::from("pong")),
+                    Frame::Bulk(msg.unwrap_or_default()),
+                ]);
+                connection.write_frame(&reply).await?;
+            }
+            Command::Publish(..) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    // 購読を解除したチャンネルごとに、その時点の購読数を含めた応答を書き込む
+    // 何も購読していなかった場合は、チャンネル名を Null とした応答を一つ返す
+    async fn write_unsubscribed(
+        &mut self,
+        connection: &mut Connection,
+        kind: &str,
+        removed: Vec<(Bytes, usize)>,
+    ) -> Result<()> {
+        if removed.is_empty() {
+            connection.write_frame(&self.reply(kind, None)).await?;
+            return Ok(());
+        }
+        let others = match kind {
+            "unsubscribe" => self.patterns.len(),
+            _ => self.channels.len(),
+        };
+        for (name, remaining) in removed {
+            let reply = Frame::Push(vec![
+                Frame::Bulk(Bytes::from(kind.to_string())),
+                Frame::Bulk(name),
+                Frame::Integer((remaining + others) as i64),
+            ]);
+            connection.write_frame(&reply).await?;
+        }
+        Ok(())
+    }
+
+    // [種類, 名前, 購読数] の応答
+    fn reply(&self, kind: &str, name: Option<Bytes>) -> Frame {
+        Frame::Push(vec![
+            Frame::Bulk(Bytes::from(kind.to_string())),
+            name.map_or(Frame::Null, Frame::Bulk),
+            Frame::Integer(self.count() as i64),
+        ])
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        for task in self.channels.values().chain(self.patterns.values()) {
+            task.abort();
+        }
+    }
+}
+
+// 指定された名前（空ならすべて）の購読を解除する
+// 解除した名前と、解除した直後に残っている同じ種類の購読数の組を返す
+fn unsubscribe(
+    subscriptions: &mut HashMap<Bytes, JoinHandle<()>>,
+    names: Vec<Bytes>,
+) -> Vec<(Bytes, usize)> {
+    let names = if names.is_empty() {
+        subscriptions.keys().cloned().collect()
+    } else {
+        names
+    };
+    names
+        .into_iter()
+        .map(|name| {
+            if let Some(task) = subscriptions.remove(&name) {
+                task.abort();
+            }
+            (name, subscriptions.len())
+        })
+        .collect()
+}
+
+// broadcast から受け取ったメッセージをフレームに変換して outbox に送り続けるタスクを起動する
+//
+// タスクが終わるか中断されたら、Receiver をドロップしてから release を呼び出す
+// 中断したタスクの Future は後からドロップされるので、購読を解除した時点ではなくここで後始末する
+fn forward<T, F, R>(
+    rx: broadcast::Receiver<T>,
+    outbox: mpsc::Sender<Frame>,
+    to_frame: F,
+    release: R,
+) -> JoinHandle<()>
+where
+    T: Clone + Send + 'static,
+    F: Fn(T) -> Frame + Send + 'static,
+    R: FnMut() + Send + 'static,
+{
+    let mut subscription = Subscription {
+        rx: Some(rx),
+        release,
+    };
+    tokio::spawn(async move {
+        let rx = subscription.rx.as_mut().unwrap();
+        loop {
+            match rx.recv().await {
+                Ok(message) => {
+                    if outbox.send(to_frame(message)).await.is_err() {
+                        return;
+                    }
+                }
+                // 読み遅れた分のメッセージは読み飛ばす
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    })
+}
+
+// forward のタスクが持つ Receiver と、ドロップした後の後始末
+struct Subscription<T, R: FnMut()> {
+    rx: Option<broadcast::Receiver<T>>,
+    release: R,
+}
+
+impl<T, R: FnMut()> Drop for Subscription<T, R> {
+    fn drop(&mut self) {
+        self.rx.take();
+        (self.release)();
+    }
+}
+
+// 購読しているクライアントに送るメッセージ
+// RESP3 ではプッシュとして送り、RESP2 では配列として送る
+fn push<const N: usize>(kind: &str, items: [Bytes; N]) -> Frame {
+    let mut frames = vec![Frame::Bulk(Bytes::from(kind.to_string()))];
+    frames.extend(items.into_iter().map(Frame::Bulk));
+    Frame::Push(frames)
+}
+
+// 購読モードでは実行できないコマンドのエラー
+fn not_allowed(frame: &Frame) -> Frame {
+    let name = match frame {
+        Frame::Array(parts) => match parts.first() {
+            Some(Frame::Bulk(name)) => String::from_utf8_lossy(name).to_ascii_lowercase(),
+            Some(Frame::Simple(name)) => name.to_ascii_lowercase(),
+            _ => String::new(),
+        },
+        _ => String::new(),
+    };
+    Frame::Error(format!(
+        "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in this context",
+        name
+    ))
+}
@@ -0,0 +1,720 @@
+// スナップショット（RDB ファイル）の保存と読み込み
+//
+// ある時点の db の内容を一つのファイルに書き出し、サーバの起動時に読み込んで復元する
+// 書き出すときは一時ファイルに書いてから rename するので、途中で落ちても古いファイルは壊れない
+//
+// # ファイル形式
+//
+// 整数はすべてビッグエンディアンで、文字列は長さ（u32）の後にバイト列を並べる
+//
+// ```text
+// "MYRDB" バージョン(u8 = 1)
+// レコード*
+// 0xFF チェックサム(u64)
+// ```
+//
+// レコードは一つのキーを表す
+//
+// ```text
+// [0xFC 有効期限(u64, UNIX 時刻のミリ秒)] 型(u8) キー(文字列) 値
+// ```
+//
+// 値の形式は型ごとに決まっている（要素数は u32）
+//
+// | 型 | 値                                                                   |
+// |----|----------------------------------------------------------------------|
+// | 0  | string: 文字列                                                       |
+// | 1  | list: 要素数 要素(文字列)*                                           |
+// | 2  | hash: 要素数 (フィールド 値)*                                        |
+// | 3  | set: 要素数 要素(文字列)*                                            |
+// | 4  | zset: 要素数 (要素 スコア(f64))*                                     |
+// | 5  | stream: 最後の ID エントリ数 エントリ* グループ数 グループ*          |
+//
+// ストリームの ID は (ms(u64), seq(u64)) で、各部分は次のとおり
+//
+// ```text
+// エントリ:   ID フィールド数 (フィールド 値)*
+// グループ:   名前 最後に渡した ID PEL 数 PEL* コンシューマ数 (名前 最終読み出し時刻(u64))*
+// PEL:        ID コンシューマ 配送時刻(u64) 配送回数(u64)
+// ```
+//
+// 時刻は有効期限と同じく UNIX 時刻のミリ秒で保存する
+// チェックサムは、先頭からチェックサムの直前までのバイト列の FNV-1a (64 ビット) ハッシュ
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Buf, BufMut, Bytes};
+use tokio::time::Instant;
+
+use crate::db::{
+    get_db_from_sharded_db, Dict, PendingEntry, Shard, ShardedDb, SortedSet, Stream, StreamId,
+    Value,
+};
+
+const MAGIC: &[u8] = b"MYRDB";
+const VERSION: u8 = 1;
+
+const OP_EXPIRE_MS: u8 = 0xFC;
+const OP_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_HASH: u8 = 2;
+const TYPE_SET: u8 = 3;
+const TYPE_ZSET: u8 = 4;
+const TYPE_STREAM: u8 = 5;
+
+// 自動保存の条件を確かめる間隔
+const SAVE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// 自動保存に失敗したとき、次に試みるまで待つ時間
+const SAVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// スナップショットに含める一つのキー
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub key: String,
+    pub value: Value,
+    // 有効期限（UNIX 時刻のミリ秒）
+    pub expires_at_ms: Option<u64>,
+}
+
+// 自動保存の条件
+// 前回の保存から seconds 秒以上経ち、その間に changes 回以上の変更があれば保存する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+impl SaveRule {
+    // Redis の既定値と同じ条件（1 時間で 1 回、5 分で 100 回、1 分で 10000 回）
+    pub fn defaults() -> Vec<SaveRule> {
+        vec![
+            SaveRule {
+                seconds: 3600,
+                changes: 1,
+            },
+            SaveRule {
+                seconds: 300,
+                changes: 100,
+            },
+            SaveRule {
+                seconds: 60,
+                changes: 10000,
+            },
+        ]
+    }
+}
+
+// スナップショットの保存先と、保存の状態
+#[derive(Debug)]
+pub struct Snapshots {
+    path: PathBuf,
+    rules: Vec<SaveRule>,
+    state: Mutex<SaveState>,
+}
+
+#[derive(Debug)]
+struct SaveState {
+    // 保存中（SAVE または BGSAVE の実行中）か
+    saving: bool,
+    // 最後に保存に成功した時刻（LASTSAVE で返す）
+    last_save: SystemTime,
+    // 自動保存の間隔を測るための、最後に保存を試みた時刻
+    last_attempt: Instant,
+    last_attempt_ok: bool,
+    // 最後に保存した時点の db の変更回数
+    saved_changes: u64,
+}
+
+impl Snapshots {
+    // changes には、この時点で保存済みとみなす db の変更回数を渡す
+    pub fn new(path: impl Into<PathBuf>, rules: Vec<SaveRule>, changes: u64) -> Self {
+        Self {
+            path: path.into(),
+            rules,
+            state: Mutex::new(SaveState {
+                saving: false,
+                last_save: SystemTime::now(),
+                last_attempt: Instant::now(),
+                last_attempt_ok: true,
+                saved_changes: changes,
+            }),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // 最後に保存に成功した時刻（UNIX 時刻の秒）
+    pub fn last_save(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state
+            .last_save
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    }
+
+    pub fn is_saving(&self) -> bool {
+        self.state.lock().unwrap().saving
+    }
+
+    // 保存を始める
+    // すでに保存中であれば false を返す
+    fn begin(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.saving {
+            return false;
+        }
+        state.saving = true;
+        true
+    }
+
+    // 保存が終わったことを記録する
+    // changes には、複製した時点の db の変更回数を渡す
+    fn finish(&self, result: &io::Result<()>, changes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.saving = false;
+        state.last_attempt = Instant::now();
+        state.last_attempt_ok = result.is_ok();
+        if result.is_ok() {
+            state.last_save = SystemTime::now();
+            state.saved_changes = changes;
+        }
+    }
+
+    // 自動保存の条件を満たしているか
+    fn should_save(&self, changes: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        if state.saving {
+            return false;
+        }
+        let elapsed = state.last_attempt.elapsed();
+        if !state.last_attempt_ok && elapsed < SAVE_RETRY_DELAY {
+            return false;
+        }
+        let dirty = changes.saturating_sub(state.saved_changes);
+        self.rules
+            .iter()
+            .any(|rule| dirty >= rule.changes && elapsed >= Duration::from_secs(rule.seconds))
+    }
+}
+
+// SAVE
+//
+// すべてのシャードを同時にロックして複製するので、ある時点の内容がそのまま保存される
+// ファイルへの書き込みが終わるまで戻らない
+pub fn save(db: &ShardedDb) -> io::Result<()> {
+    let snapshots = db.snapshots().ok_or_else(not_configured)?;
+    if !snapshots.begin() {
+        return Err(in_progress());
+    }
+
+    let (records, changes) = {
+        // lock_shards と同じく、番号の小さいシャードから順にロックする
+        let guards: Vec<_> = db.iter().map(|shard| shard.lock().unwrap()).collect();
+        let now = Clock::now();
+        let records = guards
+            .iter()
+            .flat_map(|shard| copy_shard(shard, &now))
+            .collect::<Vec<_>>();
+        let changes = guards.iter().map(|shard| shard.changes()).sum();
+        (records, changes)
+    };
+
+    let result = write_file(&snapshots.path, &records);
+    snapshots.finish(&result, changes);
+    result
+}
+
+// BGSAVE
+//
+// シャードを 1 つずつロックして複製し、ファイルへの書き込みは別スレッドで行う
+// 複製している間も、ロックしていないシャードへのアクセスは止まらない
+// （そのため、シャードをまたいだ同時点の内容にはならない）
+// 呼び出し元が db の gate を共有ロックしていれば、EXEC の途中の状態が保存されることはない
+//
+// 保存を始めたら、書き込みの完了を待たずに戻る
+pub fn bgsave(db: &ShardedDb) -> io::Result<()> {
+    let snapshots = db.snapshots().ok_or_else(not_configured)?;
+    if !snapshots.begin() {
+        return Err(in_progress());
+    }
+
+    let (records, changes) = copy(db);
+
+    let db = db.clone();
+    tokio::task::spawn_blocking(move || {
+        let snapshots = db.snapshots().unwrap();
+        let result = write_file(&snapshots.path, &records);
+        if let Err(err) = &result {
+            eprintln!("background save failed: {}", err);
+        }
+        snapshots.finish(&result, changes);
+    });
+    Ok(())
+}
+
+// シャードを 1 つずつロックして、期限切れでないキーを複製する
+// 複製した時点の db の変更回数もあわせて返す
+pub fn copy(db: &ShardedDb) -> (Vec<Record>, u64) {
+    let now = Clock::now();
+    let mut records = Vec::new();
+    let mut changes = 0;
+    for shard in db.iter() {
+        let shard = shard.lock().unwrap();
+        records.extend(copy_shard(&shard, &now));
+        changes += shard.changes();
+    }
+    (records, changes)
+}
+
+// 自動保存の条件を定期的に確かめ、満たしていれば BGSAVE を始めるタスクを起動する
+// タスクは db を弱参照で持ち、db がドロップされたら終了する
+pub fn spawn_save_task(db: &ShardedDb) {
+    let db = Arc::downgrade(db);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SAVE_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let db = match db.upgrade() {
+                Some(db) => db,
+                None => return,
+            };
+            let snapshots = match db.snapshots() {
+                Some(snapshots) => snapshots,
+                None => continue,
+            };
+
+            let _gate = db.shared();
+            if snapshots.should_save(db.changes()) {
+                let _ = bgsave(&db);
+            }
+        }
+    });
+}
+
+// スナップショットを読み込んで db に追加し、読み込んだキーの数を返す
+// ファイルがなければ何もしない
+// 有効期限がすでに過ぎたキーは読み込まない
+pub fn load(db: &ShardedDb, path: &Path) -> crate::Result<usize> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(restore(db, decode(&buf)?))
+}
+
+// レコードを db に追加し、追加したキーの数を返す
+// 有効期限がすでに過ぎたキーは追加しない
+pub fn restore(db: &ShardedDb, records: Vec<Record>) -> usize {
+    let now = Clock::now();
+    let mut restored = 0;
+    for record in records {
+        let expires_at = match record.expires_at_ms {
+            Some(ms) if ms <= now.unix_ms => continue,
+            Some(ms) => Some(now.instant + Duration::from_millis(ms - now.unix_ms)),
+            None => None,
+        };
+        let shard = get_db_from_sharded_db(db, &record.key);
+        shard
+            .lock()
+            .unwrap()
+            .insert(record.key, record.value, expires_at);
+        restored += 1;
+    }
+    restored
+}
+
+// レコードをファイル形式に変換する
+pub fn encode(records: &[Record]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.put_slice(MAGIC);
+    buf.put_u8(VERSION);
+
+    for record in records {
+        if let Some(ms) = record.expires_at_ms {
+            buf.put_u8(OP_EXPIRE_MS);
+            buf.put_u64(ms);
+        }
+        buf.put_u8(type_code(&record.value));
+        put_bytes(&mut buf, record.key.as_bytes());
+        put_value(&mut buf, &record.value);
+    }
+
+    buf.put_u8(OP_EOF);
+    let sum = checksum(&buf);
+    buf.put_u64(sum);
+    buf
+}
+
+// ファイルの内容をレコードに戻す
+// 形式が正しくない場合や、途中で切れている場合はエラーを返す
+pub fn decode(buf: &[u8]) -> crate::Result<Vec<Record>> {
+    let (records, len) = decode_prefix(buf)?;
+    if len != buf.len() {
+        return Err("trailing bytes after the end of the snapshot".into());
+    }
+    Ok(records)
+}
+
+// バイト列の先頭にあるスナップショットをレコードに戻し、スナップショットの長さもあわせて返す
+// AOF の先頭に書き込んだスナップショットを読み込むときに使う
+pub fn decode_prefix(buf: &[u8]) -> crate::Result<(Vec<Record>, usize)> {
+    if !is_snapshot(buf) {
+        return Err("not a snapshot file".into());
+    }
+
+    let mut src = Reader(&buf[MAGIC.len()..]);
+    let version = src.u8()?;
+    if version != VERSION {
+        return Err(format!("unsupported snapshot version {}", version).into());
+    }
+
+    let mut records = Vec::new();
+    loop {
+        let mut op = src.u8()?;
+        if op == OP_EOF {
+            break;
+        }
+        let mut expires_at_ms = None;
+        if op == OP_EXPIRE_MS {
+            expires_at_ms = Some(src.u64()?);
+            op = src.u8()?;
+        }
+        let key = String::from_utf8(src.bytes()?.to_vec())?;
+        let value = read_value(&mut src, op)?;
+        records.push(Record {
+            key,
+            value,
+            expires_at_ms,
+        });
+    }
+
+    let len = buf.len() - src.0.remaining();
+    let sum = src.u64()?;
+    if checksum(&buf[..len]) != sum {
+        return Err("snapshot checksum mismatch".into());
+    }
+    Ok((records, len + 8))
+}
+
+// バイト列がスナップショットで始まっているか
+pub fn is_snapshot(buf: &[u8]) -> bool {
+    buf.starts_with(MAGIC)
+}
+
+// 保存と読み込みで使う現在時刻
+// Instant と UNIX 時刻を同じ時点で取っておき、相互に変換する
+struct Clock {
+    instant: Instant,
+    unix_ms: u64,
+}
+
+impl Clock {
+    fn now() -> Self {
+        Self {
+            instant: Instant::now(),
+            unix_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+        }
+    }
+
+    fn to_unix_ms(&self, instant: Instant) -> u64 {
+        if instant >= self.instant {
+            self.unix_ms + (instant - self.instant).as_millis() as u64
+        } else {
+            self.unix_ms
+                .saturating_sub((self.instant - instant).as_millis() as u64)
+        }
+    }
+
+    // 過去の時刻を Instant に戻す（プロセスの起動より前なら現在時刻にする）
+    fn past_instant(&self, unix_ms: u64) -> Instant {
+        let ago = Duration::from_millis(self.unix_ms.saturating_sub(unix_ms));
+        self.instant.checked_sub(ago).unwrap_or(self.instant)
+    }
+}
+
+// シャードの期限切れでないキーを複製する
+fn copy_shard(shard: &Shard, now: &Clock) -> Vec<Record> {
+    shard
+        .iter()
+        .map(|(key, entry)| Record {
+            key: key.clone(),
+            value: entry.value.clone(),
+            expires_at_ms: entry.expires_at().map(|when| now.to_unix_ms(when)),
+        })
+        .collect()
+}
+
+// 一時ファイルに書き込んでから置き換える
+fn write_file(path: &Path, records: &[Record]) -> io::Result<()> {
+    let buf = encode(records);
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+fn in_progress() -> io::Error {
+    io::Error::other("Background save already in progress")
+}
+
+fn not_configured() -> io::Error {
+    io::Error::other("no snapshot file is configured")
+}
+
+fn checksum(buf: &[u8]) -> u64 {
+    // FNV-1a
+    buf.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+fn type_code(value: &Value) -> u8 {
+    match value {
+        Value::String(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+        Value::Hash(_) => TYPE_HASH,
+        Value::Set(_) => TYPE_SET,
+        Value::ZSet(_) => TYPE_ZSET,
+        Value::Stream(_) => TYPE_STREAM,
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.put_u32(bytes.len() as u32);
+    buf.put_slice(bytes);
+}
+
+fn put_id(buf: &mut Vec<u8>, id: StreamId) {
+    buf.put_u64(id.ms);
+    buf.put_u64(id.seq);
+}
+
+fn put_value(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(s) => put_bytes(buf, s),
+        Value::List(list) => {
+            buf.put_u32(list.len() as u32);
+            for item in list {
+                put_bytes(buf, item);
+            }
+        }
+        Value::Hash(hash) => {
+            buf.put_u32(hash.len() as u32);
+            for (field, value) in hash.iter() {
+                put_bytes(buf, field);
+                put_bytes(buf, value);
+            }
+        }
+        Value::Set(set) => {
+            buf.put_u32(set.len() as u32);
+            for member in set {
+                put_bytes(buf, member);
+            }
+        }
+        Value::ZSet(zset) => {
+            buf.put_u32(zset.len() as u32);
+            for (member, score) in zset.iter() {
+                put_bytes(buf, member);
+                buf.put_f64(score);
+            }
+        }
+        Value::Stream(stream) => put_stream(buf, stream),
+    }
+}
+
+fn put_stream(buf: &mut Vec<u8>, stream: &Stream) {
+    // 保存するときの時刻は、PEL などの経過時間を保つためにだけ使う
+    let now = Clock::now();
+
+    put_id(buf, stream.last_id());
+    let entries = stream.range(Bound::Unbounded, Bound::Unbounded, None);
+    buf.put_u32(entries.len() as u32);
+    for (id, fields) in entries {
+        put_id(buf, id);
+        buf.put_u32(fields.len() as u32);
+        for (field, value) in fields {
+            put_bytes(buf, field);
+            put_bytes(buf, value);
+        }
+    }
+
+    let groups: Vec<_> = stream.groups().collect();
+    buf.put_u32(groups.len() as u32);
+    for (name, group) in groups {
+        put_bytes(buf, name);
+        put_id(buf, group.last_delivered);
+        buf.put_u32(group.pending.len() as u32);
+        for (id, pending) in &group.pending {
+            put_id(buf, *id);
+            put_bytes(buf, &pending.consumer);
+            buf.put_u64(now.to_unix_ms(pending.delivered_at));
+            buf.put_u64(pending.delivery_count);
+        }
+        buf.put_u32(group.consumers.len() as u32);
+        for (consumer, seen) in &group.consumers {
+            put_bytes(buf, consumer);
+            buf.put_u64(now.to_unix_ms(*seen));
+        }
+    }
+}
+
+// 読み込み中のバイト列
+// Buf の get_* は足りないときに panic するので、残りの長さを確かめてから読む
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn ensure(&self, n: usize) -> crate::Result<()> {
+        if self.0.remaining() < n {
+            return Err("snapshot ended early".into());
+        }
+        Ok(())
+    }
+
+    fn u8(&mut self) -> crate::Result<u8> {
+        self.ensure(1)?;
+        Ok(self.0.get_u8())
+    }
+
+    fn u32(&mut self) -> crate::Result<u32> {
+        self.ensure(4)?;
+        Ok(self.0.get_u32())
+    }
+
+    fn u64(&mut self) -> crate::Result<u64> {
+        self.ensure(8)?;
+        Ok(self.0.get_u64())
+    }
+
+    fn f64(&mut self) -> crate::Result<f64> {
+        self.ensure(8)?;
+        Ok(self.0.get_f64())
+    }
+
+    fn bytes(&mut self) -> crate::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.ensure(len)?;
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn owned(&mut self) -> crate::Result<Bytes> {
+        Ok(Bytes::copy_from_slice(self.bytes()?))
+    }
+
+    fn id(&mut self) -> crate::Result<StreamId> {
+        Ok(StreamId::new(self.u64()?, self.u64()?))
+    }
+}
+
+fn read_value(src: &mut Reader, type_code: u8) -> crate::Result<Value> {
+    let value = match type_code {
+        TYPE_STRING => Value::String(src.owned()?),
+        TYPE_LIST => {
+            let len = src.u32()?;
+            let mut list = VecDeque::new();
+            for _ in 0..len {
+                list.push_back(src.owned()?);
+            }
+            Value::List(list)
+        }
+        TYPE_HASH => {
+            let len = src.u32()?;
+            let mut hash = Dict::new();
+            for _ in 0..len {
+                hash.insert(src.owned()?, src.owned()?);
+            }
+            Value::Hash(hash)
+        }
+        TYPE_SET => {
+            let len = src.u32()?;
+            let mut set = HashSet::new();
+            for _ in 0..len {
+                set.insert(src.owned()?);
+            }
+            Value::Set(set)
+        }
+        TYPE_ZSET => {
+            let len = src.u32()?;
+            let mut zset = SortedSet::new();
+            for _ in 0..len {
+                let member = src.owned()?;
+                zset.insert(member, src.f64()?);
+            }
+            Value::ZSet(zset)
+        }
+        TYPE_STREAM => Value::Stream(read_stream(src)?),
+        _ => return Err(format!("unknown value type {} in snapshot", type_code).into()),
+    };
+    Ok(value)
+}
+
+fn read_stream(src: &mut Reader) -> crate::Result<Stream> {
+    let now = Clock::now();
+    let mut stream = Stream::new();
+
+    let last_id = src.id()?;
+    let len = src.u32()?;
+    for _ in 0..len {
+        let id = src.id()?;
+        let nfields = src.u32()?;
+        let mut fields = Vec::new();
+        for _ in 0..nfields {
+            fields.push((src.owned()?, src.owned()?));
+        }
+        if !stream.insert(id, fields) {
+            return Err("stream entries are out of order in snapshot".into());
+        }
+    }
+    stream.set_last_id(last_id);
+
+    let ngroups = src.u32()?;
+    for _ in 0..ngroups {
+        let name = src.owned()?;
+        let last_delivered = src.id()?;
+        stream.create_group(name.clone(), last_delivered);
+        let group = stream.group_mut(&name).unwrap();
+
+        let npending = src.u32()?;
+        for _ in 0..npending {
+            let id = src.id()?;
+            let consumer = src.owned()?;
+            let delivered_at = now.past_instant(src.u64()?);
+            let delivery_count = src.u64()?;
+            group.pending.insert(
+                id,
+                PendingEntry {
+                    consumer,
+                    delivered_at,
+                    delivery_count,
+                },
+            );
+        }
+        let nconsumers = src.u32()?;
+        for _ in 0..nconsumers {
+            let consumer = src.owned()?;
+            let seen = now.past_instant(src.u64()?);
+            group.consumers.insert(consumer, seen);
+        }
+    }
+    Ok(stream)
+}
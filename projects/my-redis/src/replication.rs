@@ -0,0 +1,625 @@
+// リーダーとフォロワーによるレプリケーション
+//
+// フォロワーはリーダーに TCP で接続して PSYNC を送り、リーダーの db の複製を受け取り続ける
+//
+// ```text
+// フォロワー -> リーダー: PSYNC <replid> <offset>（初めて同期するときは PSYNC ? -1）
+// リーダー -> フォロワー: +FULLRESYNC <replid> <offset> $<スナップショット> コマンド*
+//                     または +CONTINUE <replid> コマンド*
+// フォロワー -> リーダー: REPLCONF ACK <offset>（1 秒ごと）
+// ```
+//
+// リーダーは実行した書き込みコマンドを、AOF に記録するのと同じ RESP の配列フレームとして送る
+// 送ったコマンドのバイト数の累計をオフセットと呼び、リーダーとフォロワーがそれぞれ数える
+// フォロワーは受け取ったフレームを同じエンコーダでエンコードし直して数えるので、両者のオフセットは一致する
+//
+// リーダーは最近送ったコマンドをバックログに残しておく
+// 接続が切れたフォロワーは、覚えている replid とオフセットで PSYNC を送り直し、
+// その続きがバックログに残っていれば、続きのコマンドだけを受け取る（部分再同期）
+// replid が違うか、続きがバックログから溢れていれば、スナップショットから送り直す（完全再同期）
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+
+use crate::cmd::Registry;
+use crate::db::{ShardedDb, Shards};
+use crate::frame::{encode, Frame, Protocol};
+use crate::{aof, propagate, rdb};
+use crate::{Connection, Result};
+
+// バックログに残しておくコマンドのバイト数
+const BACKLOG_SIZE: usize = 1024 * 1024;
+
+// フォロワーに送る前のコマンドを溜めておける数
+// これを超えて読み遅れたフォロワーは接続を切られ、再接続して部分再同期する
+const STREAM_CAPACITY: usize = 4096;
+
+// フォロワーがリーダーに処理済みのオフセットを知らせる間隔
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+
+// フォロワーがリーダーとの接続を失ってから再接続するまでの間隔
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
+// サーバのレプリケーションの状態
+//
+// どのサーバもリーダーになれるが、バックログを持つのは最初のフォロワーが接続してからにする
+// フォロワーのいないサーバが、書き込みのたびにバックログを更新しなくて済むようにするため
+#[derive(Debug)]
+pub struct Replication {
+    replid: String,
+    leader: OnceLock<Leader>,
+    follower: Mutex<Option<Follower>>,
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        Self {
+            replid: random_hex_id(),
+            leader: OnceLock::new(),
+            follower: Mutex::new(None),
+        }
+    }
+
+    // このサーバがリーダーとして送るコマンドの列を識別する ID
+    pub fn replid(&self) -> &str {
+        &self.replid
+    }
+
+    // フォロワーが接続したことがあれば、リーダーとしての状態を返す
+    pub fn leader(&self) -> Option<&Leader> {
+        self.leader.get()
+    }
+
+    pub(crate) fn start_leading(&self) -> &Leader {
+        self.leader.get_or_init(|| Leader::new(self.replid.clone()))
+    }
+
+    // フォロワーとして動いているか
+    pub fn is_follower(&self) -> bool {
+        self.follower.lock().unwrap().is_some()
+    }
+
+    // フォロワーとしての状態
+    pub fn follower_status(&self) -> Option<FollowerStatus> {
+        let follower = self.follower.lock().unwrap();
+        follower.as_ref().map(|follower| FollowerStatus {
+            host: follower.host.clone(),
+            port: follower.port,
+            link: follower.link.lock().unwrap().clone(),
+        })
+    }
+
+    // 指定したリーダーのフォロワーになる
+    // すでに別のリーダーのフォロワーであれば、その接続を切ってから完全再同期する
+    pub fn follow(&self, db: &ShardedDb, host: String, port: u16) {
+        let mut follower = self.follower.lock().unwrap();
+        if matches!(&*follower, Some(f) if f.host == host && f.port == port) {
+            return;
+        }
+
+        let link = Arc::new(Mutex::new(Link::default()));
+        let task = tokio::spawn(run_follower(
+            Arc::downgrade(db),
+            format!("{}:{}", host, port),
+            link.clone(),
+        ));
+        *follower = Some(Follower {
+            host,
+            port,
+            link,
+            task,
+        });
+    }
+
+    // フォロワーをやめて、クライアントからの書き込みを受け付けるようにする
+    // それまでに複製したキーはそのまま残す
+    pub fn stop_following(&self) {
+        self.follower.lock().unwrap().take();
+    }
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// リーダーとしての状態
+#[derive(Debug)]
+pub struct Leader {
+    replid: String,
+    backlog: Mutex<Backlog>,
+    // バックログに追加したコマンドを、接続中のフォロワーに配る
+    // バックログと同じ順序になるように、backlog をロックしたまま送る
+    tx: broadcast::Sender<Frame>,
+    followers: Mutex<HashMap<u64, FollowerInfo>>,
+    next_id: AtomicU64,
+    sync_full: AtomicU64,
+    sync_partial_ok: AtomicU64,
+    sync_partial_err: AtomicU64,
+}
+
+// 接続中のフォロワー
+#[derive(Debug, Clone)]
+pub struct FollowerInfo {
+    pub addr: SocketAddr,
+    // フォロワーが最後に知らせてきた処理済みのオフセット
+    pub ack: u64,
+}
+
+// 最近送ったコマンドと、それぞれのバイト数
+#[derive(Debug, Default)]
+struct Backlog {
+    // これまでに送ったコマンドのバイト数の累計
+    offset: u64,
+    // frames の先頭のコマンドの直前のオフセット
+    start: u64,
+    size: usize,
+    frames: VecDeque<(Frame, usize)>,
+}
+
+impl Backlog {
+    fn push(&mut self, frame: Frame) {
+        let len = encoded_len(&frame);
+        self.offset += len as u64;
+        self.size += len;
+        self.frames.push_back((frame, len));
+        while self.size > BACKLOG_SIZE {
+            let (_, len) = self.frames.pop_front().unwrap();
+            self.start += len as u64;
+            self.size -= len;
+        }
+    }
+
+    // offset の続きのコマンドを返す
+    // 続きがバックログに残っていなければ None を返す
+    fn since(&self, offset: u64) -> Option<Vec<Frame>> {
+        if offset < self.start || offset > self.offset {
+            return None;
+        }
+        let mut pos = self.start;
+        let mut frames = self.frames.iter();
+        while pos < offset {
+            let (_, len) = frames.next()?;
+            pos += *len as u64;
+        }
+        // コマンドの途中を指すオフセットは受け付けない
+        if pos != offset {
+            return None;
+        }
+        Some(frames.map(|(frame, _)| frame.clone()).collect())
+    }
+}
+
+impl Leader {
+    fn new(replid: String) -> Self {
+        let (tx, _) = broadcast::channel(STREAM_CAPACITY);
+        Self {
+            replid,
+            backlog: Mutex::new(Backlog::default()),
+            tx,
+            followers: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            sync_full: AtomicU64::new(0),
+            sync_partial_ok: AtomicU64::new(0),
+            sync_partial_err: AtomicU64::new(0),
+        }
+    }
+
+    // これまでに送ったコマンドのバイト数の累計
+    pub fn offset(&self) -> u64 {
+        self.backlog.lock().unwrap().offset
+    }
+
+    pub fn followers(&self) -> Vec<FollowerInfo> {
+        let followers = self.followers.lock().unwrap();
+        let mut followers: Vec<_> = followers.iter().collect();
+        followers.sort_by_key(|(id, _)| **id);
+        followers
+            .into_iter()
+            .map(|(_, info)| info.clone())
+            .collect()
+    }
+
+    // 完全再同期した回数
+    pub fn sync_full(&self) -> u64 {
+        self.sync_full.load(Ordering::Relaxed)
+    }
+
+    // 部分再同期に成功した回数
+    pub fn sync_partial_ok(&self) -> u64 {
+        self.sync_partial_ok.load(Ordering::Relaxed)
+    }
+
+    // 部分再同期を求められたが、完全再同期になった回数
+    pub fn sync_partial_err(&self) -> u64 {
+        self.sync_partial_err.load(Ordering::Relaxed)
+    }
+
+    // コマンドをバックログに追加し、接続中のフォロワーに送る
+    pub(crate) fn append(&self, commands: &[Vec<Bytes>]) {
+        let mut backlog = self.backlog.lock().unwrap();
+        for command in commands {
+            let frame = Frame::command(command.iter().cloned());
+            backlog.push(frame.clone());
+            // フォロワーがいなければ送れないが、バックログには残っている
+            let _ = self.tx.send(frame);
+        }
+    }
+}
+
+// PSYNC に対して送るもの
+enum Resync {
+    Full {
+        offset: u64,
+        records: Vec<rdb::Record>,
+    },
+    Partial(Vec<Frame>),
+}
+
+// PSYNC replid offset
+//
+// 接続してきたフォロワーにスナップショットか続きのコマンドを送り、
+// その後は接続が切れるまで書き込みコマンドを送り続ける
+pub async fn serve_follower(
+    connection: &mut Connection,
+    db: &ShardedDb,
+    psync: &Frame,
+    addr: SocketAddr,
+) -> Result<()> {
+    let (replid, offset) = match parse_psync(psync) {
+        Some(parsed) => parsed,
+        None => {
+            let err = Frame::Error("ERR wrong number of arguments for 'psync' command".into());
+            connection.write_frame(&err).await?;
+            return Ok(());
+        }
+    };
+
+    // 実行中の書き込みがない状態で、送るものとフォロワーに配られるコマンドの受け取り口を用意する
+    // ゲートを保持したまま .await しないように、ブロックの中で解放する
+    let (leader, resync, mut rx) = {
+        let _gate = db.exclusive();
+        let leader = db.enable_leader();
+        let backlog = leader.backlog.lock().unwrap();
+        let rx = leader.tx.subscribe();
+        let partial = match offset {
+            Some(offset) if replid == leader.replid => backlog.since(offset),
+            _ => None,
+        };
+        let resync = match partial {
+            Some(frames) => {
+                leader.sync_partial_ok.fetch_add(1, Ordering::Relaxed);
+                Resync::Partial(frames)
+            }
+            None => {
+                if offset.is_some() {
+                    leader.sync_partial_err.fetch_add(1, Ordering::Relaxed);
+                }
+                leader.sync_full.fetch_add(1, Ordering::Relaxed);
+                let (records, _) = rdb::copy(db);
+                Resync::Full {
+                    offset: backlog.offset,
+                    records,
+                }
+            }
+        };
+        (leader, resync, rx)
+    };
+
+    match resync {
+        Resync::Full { offset, records } => {
+            let reply = format!("FULLRESYNC {} {}", leader.replid, offset);
+            connection.write_frame(&Frame::Simple(reply)).await?;
+            let snapshot = Frame::Bulk(Bytes::from(rdb::encode(&records)));
+            connection.write_frame(&snapshot).await?;
+        }
+        Resync::Partial(frames) => {
+            let reply = format!("CONTINUE {}", leader.replid);
+            connection.write_frame(&Frame::Simple(reply)).await?;
+            for frame in &frames {
+                connection.write_frame(frame).await?;
+            }
+        }
+    }
+
+    let id = leader.next_id.fetch_add(1, Ordering::Relaxed);
+    leader
+        .followers
+        .lock()
+        .unwrap()
+        .insert(id, FollowerInfo { addr, ack: 0 });
+    // 接続が切れたら（この Future がドロップされても）フォロワーの一覧から取り除く
+    let _guard = FollowerGuard { leader, id };
+
+    loop {
+        tokio::select! {
+            frame = rx.recv() => match frame {
+                Ok(frame) => connection.write_frame(&frame).await?,
+                Err(RecvError::Lagged(_)) => {
+                    return Err("the follower fell behind the replication stream".into())
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            frame = connection.read_frame() => match frame? {
+                Some(frame) => {
+                    if let Some(ack) = parse_ack(&frame) {
+                        if let Some(info) = leader.followers.lock().unwrap().get_mut(&id) {
+                            info.ack = ack;
+                        }
+                    }
+                }
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+// 接続中のフォロワーを一覧から取り除くためのガード
+struct FollowerGuard<'a> {
+    leader: &'a Leader,
+    id: u64,
+}
+
+impl Drop for FollowerGuard<'_> {
+    fn drop(&mut self) {
+        self.leader.followers.lock().unwrap().remove(&self.id);
+    }
+}
+
+// PSYNC replid offset を解釈する
+// PSYNC ? -1 のように、オフセットが負であれば None とする
+fn parse_psync(frame: &Frame) -> Option<(String, Option<u64>)> {
+    let parts = match frame {
+        Frame::Array(parts) if parts.len() == 3 => parts,
+        _ => return None,
+    };
+    let arg = |i: usize| match &parts[i] {
+        Frame::Bulk(bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
+        Frame::Simple(s) => Some(s.clone()),
+        _ => None,
+    };
+    let offset: i64 = arg(2)?.parse().ok()?;
+    Some((arg(1)?, u64::try_from(offset).ok()))
+}
+
+// REPLCONF ACK offset を解釈する
+fn parse_ack(frame: &Frame) -> Option<u64> {
+    match frame {
+        Frame::Array(parts) if parts.len() == 3 => match (&parts[0], &parts[1], &parts[2]) {
+            (Frame::Bulk(name), Frame::Bulk(sub), Frame::Bulk(offset))
+                if name.eq_ignore_ascii_case(b"replconf") && sub.eq_ignore_ascii_case(b"ack") =>
+            {
+                std::str::from_utf8(offset).ok()?.parse().ok()
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// フォロワーとしての状態
+#[derive(Debug)]
+struct Follower {
+    host: String,
+    port: u16,
+    link: Arc<Mutex<Link>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// フォロワーとしての状態のうち、ROLE や INFO で返すもの
+#[derive(Debug, Clone)]
+pub struct FollowerStatus {
+    pub host: String,
+    pub port: u16,
+    pub link: Link,
+}
+
+// リーダーとの接続の状態
+#[derive(Debug, Clone, Default)]
+pub struct Link {
+    pub state: LinkState,
+    // 同期したリーダーの replid と、処理済みのオフセット
+    // 一度も同期していなければ None
+    pub replid: Option<String>,
+    pub offset: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinkState {
+    // 接続していない（接続しようとしている）
+    #[default]
+    Connect,
+    // 接続して、スナップショットか続きのコマンドを待っている
+    Sync,
+    // 同期を終えて、コマンドを受け取っている
+    Connected,
+}
+
+impl LinkState {
+    // ROLE で返す文字列
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkState::Connect => "connect",
+            LinkState::Sync => "sync",
+            LinkState::Connected => "connected",
+        }
+    }
+}
+
+// リーダーとの接続を保ち続けるタスク
+// 接続が切れたら、少し待ってから部分再同期を試みる
+// db がドロップされたら終了する
+async fn run_follower(db: Weak<Shards>, addr: String, link: Arc<Mutex<Link>>) {
+    loop {
+        let db = match db.upgrade() {
+            Some(db) => db,
+            None => return,
+        };
+        if let Err(err) = sync_with_leader(&db, &addr, &link).await {
+            eprintln!("replication link to {} failed: {}", addr, err);
+        }
+        link.lock().unwrap().state = LinkState::Connect;
+        drop(db);
+
+        tokio::time::sleep(RECONNECT_INTERVAL).await;
+    }
+}
+
+// リーダーに接続して同期し、接続が切れるまで受け取ったコマンドを実行する
+async fn sync_with_leader(db: &ShardedDb, addr: &str, link: &Mutex<Link>) -> Result<()> {
+    let mut connection = Connection::new(TcpStream::connect(addr).await?);
+
+    let psync = {
+        let mut link = link.lock().unwrap();
+        link.state = LinkState::Sync;
+        match (&link.replid, link.offset) {
+            (Some(replid), Some(offset)) => {
+                ["PSYNC".to_string(), replid.clone(), offset.to_string()]
+            }
+            _ => ["PSYNC".to_string(), "?".to_string(), "-1".to_string()],
+        }
+    };
+    connection.write_frame(&Frame::command(psync)).await?;
+
+    let reply = match connection.read_frame().await? {
+        Some(Frame::Simple(reply)) => reply,
+        Some(Frame::Error(err)) => return Err(err.into()),
+        Some(frame) => return Err(format!("unexpected reply to PSYNC: {:?}", frame).into()),
+        None => return Ok(()),
+    };
+    let parts: Vec<&str> = reply.split(' ').collect();
+    match parts.as_slice() {
+        ["FULLRESYNC", replid, offset] => {
+            let offset: u64 = offset.parse()?;
+            let snapshot = match connection.read_frame().await? {
+                Some(Frame::Bulk(snapshot)) => snapshot,
+                _ => return Err("expected a snapshot after FULLRESYNC".into()),
+            };
+            load_snapshot(db, rdb::decode(&snapshot)?);
+
+            let mut link = link.lock().unwrap();
+            link.replid = Some(replid.to_string());
+            link.offset = Some(offset);
+        }
+        ["CONTINUE", ..] => {}
+        _ => return Err(format!("unexpected reply to PSYNC: {}", reply).into()),
+    }
+    link.lock().unwrap().state = LinkState::Connected;
+
+    let registry = Registry::new();
+    // MULTI の後に受け取ったコマンドと、MULTI からのバイト数
+    // EXEC を受け取るまでは実行せず、オフセットも進めない
+    let mut transaction: Option<(Vec<Frame>, u64)> = None;
+    let mut ack = tokio::time::interval(ACK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            frame = connection.read_frame() => {
+                let frame = match frame? {
+                    Some(frame) => frame,
+                    None => return Ok(()),
+                };
+                let len = encoded_len(&frame) as u64;
+                let applied = match (frame.command_name().as_deref(), &mut transaction) {
+                    (Some("multi"), None) => {
+                        transaction = Some((Vec::new(), len));
+                        0
+                    }
+                    (Some("exec"), Some(_)) => {
+                        let (queued, pending) = transaction.take().unwrap();
+                        apply_transaction(db, &registry, queued);
+                        pending + len
+                    }
+                    (_, Some((queued, pending))) => {
+                        queued.push(frame);
+                        *pending += len;
+                        0
+                    }
+                    (_, None) => {
+                        let _gate = db.write_gate();
+                        registry.dispatch(db, frame);
+                        len
+                    }
+                };
+                if applied > 0 {
+                    *link.lock().unwrap().offset.get_or_insert(0) += applied;
+                }
+            }
+            _ = ack.tick() => {
+                let offset = link.lock().unwrap().offset.unwrap_or(0);
+                let ack = Frame::command(["REPLCONF".to_string(), "ACK".to_string(), offset.to_string()]);
+                connection.write_frame(&ack).await?;
+            }
+        }
+    }
+}
+
+// リーダーのスナップショットで db の内容を置き換える
+//
+// AOF に記録していれば、置き換えた内容で AOF を書き直す
+// 置き換える前のコマンドを記録した AOF を読み込むと、リーダーと違う内容になってしまうため
+fn load_snapshot(db: &ShardedDb, records: Vec<rdb::Record>) {
+    let _gate = db.exclusive();
+    for shard in db.iter() {
+        shard.lock().unwrap().clear();
+    }
+    rdb::restore(db, records);
+    if db.aof().is_some() {
+        if let Err(err) = aof::rewrite(db) {
+            eprintln!("failed to rewrite the AOF after a full resync: {}", err);
+        }
+    }
+}
+
+// リーダーから受け取ったトランザクションをまとめて実行する
+fn apply_transaction(db: &ShardedDb, registry: &Registry, queued: Vec<Frame>) {
+    let _gate = db.exclusive();
+    let wrap = queued.iter().any(|frame| registry.is_write(frame));
+    if wrap {
+        propagate::command(db, vec![Bytes::from("MULTI")]);
+    }
+    for frame in queued {
+        registry.dispatch(db, frame);
+    }
+    if wrap {
+        propagate::command(db, vec![Bytes::from("EXEC")]);
+    }
+}
+
+// フレームを RESP にエンコードしたときのバイト数
+fn encoded_len(frame: &Frame) -> usize {
+    let mut buf = Vec::new();
+    // Vec への書き込みは失敗しない
+    encode(frame, Protocol::Resp2, &mut buf).unwrap();
+    buf.len()
+}
+
+// 40 文字の 16 進数からなる、ランダムな ID を作る（replid やクラスタのノード ID）
+pub(crate) fn random_hex_id() -> String {
+    let state = RandomState::new();
+    (0..3)
+        .map(|i| {
+            let mut hasher = state.build_hasher();
+            hasher.write_u32(i);
+            format!("{:016x}", hasher.finish())
+        })
+        .collect::<String>()[..40]
+        .to_string()
+}
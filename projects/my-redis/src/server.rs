@@ -0,0 +1,357 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::aof::{self, Aof, Fsync};
+use crate::cluster::{self, Cluster};
+use crate::cmd::{CommandError, Registry};
+use crate::db::{new_sharded_db, spawn_purge_task, ShardedDb};
+use crate::evict::{MaxMemory, Policy};
+use crate::frame::{self, Frame, Limits, Protocol};
+use crate::pubsub::{Command, Hub, Subscriber};
+use crate::rdb::{self, SaveRule, Snapshots};
+use crate::replication;
+use crate::transaction::{Outcome, Transaction};
+use crate::{Connection, Result};
+
+// db を分割するシャードの数
+const NUM_SHARDS: usize = 5;
+
+// サーバの設定
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    // スナップショットのファイル
+    // 設定すれば起動時に読み込み、SAVE などで保存する（None なら保存も読み込みもしない）
+    pub dbfilename: Option<PathBuf>,
+    // スナップショットを自動で保存する条件
+    pub save_rules: Vec<SaveRule>,
+    // AOF のファイル
+    // 設定すれば書き込みコマンドを記録し、起動時にはスナップショットの代わりにこちらを読み込む
+    pub appendfilename: Option<PathBuf>,
+    // AOF を fsync するタイミング
+    pub appendfsync: Fsync,
+    // 設定すれば、起動したときからこのリーダー（host, port）のフォロワーとして動く
+    pub replicaof: Option<(String, u16)>,
+    // クラスタモードで動かすか
+    // 有効にすると、担当しているハッシュスロットのキーだけを扱う
+    pub cluster_enabled: bool,
+    // キーが使うおおよそのメモリの上限（バイト数、0 なら上限なし）
+    pub maxmemory: usize,
+    // 上限を超えたときに追い出すキーの選び方
+    pub maxmemory_policy: Policy,
+    // クライアントから受け取るフレームの大きさの上限
+    pub limits: Limits,
+}
+
+// 受け付け済みのリスナーでサーバを動かす
+// テストではポート 0 で bind したリスナーを渡して使う
+pub async fn run(listener: TcpListener) -> Result<()> {
+    run_with_config(listener, Config::default()).await
+}
+
+// 設定を指定してサーバを動かす
+pub async fn run_with_config(listener: TcpListener, config: Config) -> Result<()> {
+    let db = new_sharded_db(NUM_SHARDS);
+    // 期限切れのキーを能動的に削除するタスクを起動する
+    spawn_purge_task(&db);
+
+    // AOF かスナップショットがあれば、接続を受け付ける前に読み込んでおく
+    // AOF のほうが新しい変更まで含んでいるので、AOF が設定されていればそちらを読み込む
+    if let Some(path) = &config.appendfilename {
+        let executed = aof::load(&db, path)?;
+        println!("executed {} commands from {}", executed, path.display());
+    } else if let Some(path) = &config.dbfilename {
+        let loaded = rdb::load(&db, path)?;
+        println!("loaded {} keys from {}", loaded, path.display());
+    }
+    if let Some(path) = config.dbfilename {
+        db.enable_snapshots(Snapshots::new(path, config.save_rules, db.changes()));
+        rdb::spawn_save_task(&db);
+    }
+    // 読み込み終えてから記録を始める
+    if let Some(path) = config.appendfilename {
+        db.enable_aof(Aof::open(path, config.appendfsync)?);
+        if config.appendfsync != Fsync::No {
+            aof::spawn_fsync_task(&db);
+        }
+    }
+    if let Some((host, port)) = config.replicaof {
+        db.replication().follow(&db, host, port);
+    }
+    // 上限は読み込み終えてから設定する（読み込んだキーは追い出さない）
+    if config.maxmemory > 0 {
+        db.enable_maxmemory(MaxMemory::new(config.maxmemory, config.maxmemory_policy));
+    }
+    // 他のノードには、リスナーのアドレスを自分のアドレスとして知らせる
+    if config.cluster_enabled {
+        db.enable_cluster(Cluster::new(listener.local_addr()?));
+        cluster::spawn_gossip_task(&db);
+    }
+    // コマンド名とハンドラの対応表は全コネクションで共有する
+    let registry = Arc::new(Registry::new());
+    // Pub/Sub のチャンネルも全コネクションで共有する
+    let hub = Arc::new(Hub::new());
+
+    loop {
+        // 接続を受け付け
+        // 接続が実際に来るまでコードをブロック
+        let (socket, address) = listener.accept().await?;
+        let db = db.clone();
+        let registry = registry.clone();
+        let hub = hub.clone();
+        let limits = config.limits;
+
+        // 接続元アドレスの表示
+        println!("accept connection from {}", address);
+
+        // リクエストの処理の実行
+        // それぞれのインバウンドコネクションに対して新しい「タスク」をスポーン
+        // ソケットをその「タスク」に move して利用する
+        tokio::spawn(async move {
+            let _ = process(socket, db, registry, hub, limits).await;
+        });
+    }
+}
+
+// 接続ごとに振る ID（HELLO の応答で返す）
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+// リクエストを処理する非同期関数
+async fn process(
+    socket: TcpStream,
+    db: ShardedDb,
+    registry: Arc<Registry>,
+    hub: Arc<Hub>,
+    limits: Limits,
+) -> Result<()> {
+    // 自前の `Connection` 構造体を用いることで、
+    // バイト列ではなく Redis の「フレーム」を読み書き出来る
+    let address = socket.peer_addr()?;
+    let mut connection = Connection::new(socket);
+    connection.set_limits(limits);
+
+    // 不正なフレームや上限を超えるフレームを受け取ったら、エラーを返してから接続を切る
+    let result = serve(&mut connection, address, db, registry, hub).await;
+    if let Err(err) = &result {
+        if err.is::<frame::Error>() {
+            let reply = Frame::Error(format!("ERR {}", err));
+            let _ = connection.write_frame(&reply).await;
+        }
+    }
+    result
+}
+
+// コネクションが閉じられるまでコマンドを処理する
+async fn serve(
+    connection: &mut Connection,
+    address: SocketAddr,
+    db: ShardedDb,
+    registry: Arc<Registry>,
+    hub: Arc<Hub>,
+) -> Result<()> {
+    let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+
+    // BLPOP などで待っている間に受け取った次のコマンド
+    let mut pending = None;
+
+    // MULTI/EXEC と WATCH の状態
+    let mut transaction = Transaction::new(db.clone(), hub.clone());
+
+    // 直前に ASKING を受け取ったか（次のコマンドにだけ効く）
+    let mut asking = false;
+
+    // 各コネクション内部で複数のコマンドを繰り返し受付できるように loop を回す
+    loop {
+        let frame = match pending.take() {
+            Some(frame) => frame,
+            None => match connection.read_frame().await? {
+                Some(frame) => frame,
+                None => return Ok(()),
+            },
+        };
+
+        // HELLO はこのコネクションで使うプロトコルを切り替える
+        if frame.command_name().as_deref() == Some("hello") {
+            let reply = match hello(&db, &frame, id, connection.protocol()) {
+                Ok((protocol, reply)) => {
+                    connection.set_protocol(protocol);
+                    reply
+                }
+                Err(err) => err,
+            };
+            connection.write_frame(&reply).await?;
+            continue;
+        }
+
+        // クラスタモードでは、他のノードが担当しているキーを扱うコマンドを -MOVED などで案内する
+        // ASKING の後のコマンドは、移行中のスロットのキーを移行先として受け付ける
+        if frame.command_name().as_deref() == Some("asking") {
+            asking = true;
+            connection
+                .write_frame(&Frame::Simple("OK".to_string()))
+                .await?;
+            continue;
+        }
+        if let Err(err) = cluster::route(&db, &registry.keys(&frame), std::mem::take(&mut asking)) {
+            transaction.reject();
+            connection.write_frame(&err.into()).await?;
+            continue;
+        }
+
+        // appendfsync always では、このコマンドが AOF に追記したデータを fsync してから応答する
+        let appended = db.aof().map(Aof::appended);
+
+        // MULTI の後のコマンドはキューに積み、EXEC でまとめて実行する
+        let frame = match transaction.handle(&registry, frame) {
+            Outcome::Reply(reply) => {
+                let reply = wait_for_aof(&db, appended, reply).await;
+                connection.write_frame(&reply).await?;
+                continue;
+            }
+            Outcome::Execute(frame) => frame,
+        };
+
+        // PSYNC を送ってきたのはフォロワーなので、以降はこのコネクションでコマンドを送り続ける
+        if frame.command_name().as_deref() == Some("psync") {
+            return replication::serve_follower(connection, &db, &frame, address).await;
+        }
+
+        // Pub/Sub のコマンドは db ではなく hub を操作する
+        // SUBSCRIBE などを受け取ったら、すべての購読を解除するまで購読モードに入る
+        // 購読モードの間に接続が切れた場合は、次の read_frame で None を受け取って終了する
+        match Command::parse(&frame) {
+            Some(Ok(Command::Publish(channel, message))) => {
+                let received = hub.publish(&channel, message);
+                connection
+                    .write_frame(&Frame::Integer(received as i64))
+                    .await?;
+                continue;
+            }
+            Some(Ok(Command::Ping(_))) | None => {}
+            Some(Ok(command)) => {
+                Subscriber::new(hub.clone())
+                    .run(connection, command)
+                    .await?;
+                continue;
+            }
+            Some(Err(err)) => {
+                connection.write_frame(&err).await?;
+                continue;
+            }
+        }
+
+        // コマンド名からハンドラを引いて実行する
+        // 未知のコマンドや引数の個数の誤りはエラーフレームとして返され、
+        // タスクが panic することはない
+        let execute = registry.execute(&db, frame);
+        tokio::pin!(execute);
+
+        // BLPOP などは結果が得られるまで待つので、その間もソケットを監視する
+        // クライアントが接続を切ったら、実行中のコマンドをドロップして待ち行列から抜ける
+        // 次のコマンドが届いた場合は、今のコマンドが終わるまで処理を保留する
+        let response = loop {
+            tokio::select! {
+                biased;
+                response = &mut execute => break response,
+                frame = connection.read_frame(), if pending.is_none() => match frame? {
+                    Some(frame) => pending = Some(frame),
+                    None => return Ok(()),
+                },
+            }
+        };
+
+        // クライアントへのレスポンスを書き込む
+        let response = wait_for_aof(&db, appended, response).await;
+        connection.write_frame(&response).await?;
+    }
+}
+
+// appended より後に AOF に追記したデータを書き込み終えるまで待ってから、応答を返す
+// 書き込めなかった場合は、応答の代わりに MISCONF エラーを返す
+async fn wait_for_aof(db: &ShardedDb, appended: Option<u64>, reply: Frame) -> Frame {
+    match (db.aof(), appended) {
+        (Some(aof), Some(appended)) => match aof.wait_written(appended).await {
+            Ok(()) => reply,
+            Err(err) => CommandError::aof_failed(&err).into(),
+        },
+        _ => reply,
+    }
+}
+
+// HELLO [protover [AUTH username password] [SETNAME clientname]]
+//
+// 切り替えるプロトコルと、サーバーの情報をまとめたマップを返す
+// protover を省略した場合はプロトコルを変えない
+// 認証とクライアント名は扱っていないので、AUTH と SETNAME は受け付けるだけで何もしない
+fn hello(
+    db: &ShardedDb,
+    frame: &Frame,
+    id: u64,
+    current: Protocol,
+) -> std::result::Result<(Protocol, Frame), Frame> {
+    let args: Vec<Bytes> = match frame {
+        Frame::Array(parts) => parts[1..]
+            .iter()
+            .map(|part| match part {
+                Frame::Bulk(bytes) => bytes.clone(),
+                Frame::Simple(s) => Bytes::from(s.clone()),
+                _ => Bytes::new(),
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let mut protocol = current;
+    if let Some(version) = args.first() {
+        let version = std::str::from_utf8(version)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or_else(|| {
+                Frame::Error("ERR Protocol version is not an integer or out of range".into())
+            })?;
+        protocol = Protocol::from_version(version)
+            .ok_or_else(|| Frame::Error("NOPROTO unsupported protocol version".into()))?;
+
+        let mut options = &args[1..];
+        while let Some(option) = options.first() {
+            let needed = match option.to_ascii_lowercase().as_slice() {
+                b"auth" => 2,
+                b"setname" => 1,
+                _ => options.len(),
+            };
+            if needed >= options.len() {
+                return Err(Frame::Error(format!(
+                    "ERR Syntax error in HELLO option '{}'",
+                    String::from_utf8_lossy(option)
+                )));
+            }
+            options = &options[needed + 1..];
+        }
+    }
+
+    let bulk = |s: &str| Frame::Bulk(Bytes::from(s.to_string()));
+    let mode = if db.cluster().is_some() {
+        "cluster"
+    } else {
+        "standalone"
+    };
+    let role = if db.replication().is_follower() {
+        "replica"
+    } else {
+        "master"
+    };
+    let reply = Frame::Map(vec![
+        (bulk("server"), bulk("redis")),
+        (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+        (bulk("proto"), Frame::Integer(protocol.version())),
+        (bulk("id"), Frame::Integer(id as i64)),
+        (bulk("mode"), bulk(mode)),
+        (bulk("role"), bulk(role)),
+        (bulk("modules"), Frame::Array(Vec::new())),
+    ]);
+    Ok((protocol, reply))
+}
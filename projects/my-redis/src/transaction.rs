@@ -0,0 +1,236 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::aof::Aof;
+use crate::cmd::{CommandError, Registry};
+use crate::db::{get_db_from_sharded_db, ShardedDb};
+use crate::frame::Frame;
+use crate::propagate;
+use crate::pubsub::{Command, Hub};
+
+// Transaction::handle の結果
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    // トランザクションとして処理したので、この応答をそのまま返す
+    Reply(Frame),
+    // トランザクションとは関係ないので、通常どおり実行する
+    Execute(Frame),
+}
+
+// コネクションごとのトランザクションの状態
+//
+// MULTI の後に受け取ったコマンドは queued に積んでおき、EXEC でまとめて実行する
+// WATCH したキーはそのときのバージョンを覚えておき、EXEC の時点でバージョンが
+// 変わっていたら（他のコネクションが変更していたら）コマンドを実行せずに中止する
+//
+// PUBLISH は db ではなく hub を操作するので Registry にはないが、キューに積んで EXEC で実行する
+pub struct Transaction {
+    db: ShardedDb,
+    hub: Arc<Hub>,
+    queued: Option<Vec<Frame>>,
+    // キューに積む前の検査でエラーになったコマンドがあれば、EXEC で中止する
+    failed: bool,
+    watched: Vec<(String, u64)>,
+}
+
+impl Transaction {
+    pub fn new(db: ShardedDb, hub: Arc<Hub>) -> Self {
+        Self {
+            db,
+            hub,
+            queued: None,
+            failed: false,
+            watched: Vec::new(),
+        }
+    }
+
+    // MULTI の後で、EXEC または DISCARD を待っているか
+    pub fn in_multi(&self) -> bool {
+        self.queued.is_some()
+    }
+
+    // MULTI の後に、キューに積む前のクラスタの検査などでコマンドを拒否したら、EXEC で中止する
+    pub fn reject(&mut self) {
+        self.failed |= self.in_multi();
+    }
+
+    // トランザクションに関係するコマンドを処理する
+    // MULTI の後は、EXEC などを除くすべてのコマンドをキューに積んで QUEUED を返す
+    pub fn handle(&mut self, registry: &Registry, frame: Frame) -> Outcome {
+        let name = match frame.command_name() {
+            Some(name) => name,
+            None => return self.queue_or_execute(registry, frame),
+        };
+        let argc = match &frame {
+            Frame::Array(parts) => parts.len(),
+            _ => 0,
+        };
+
+        let reply = match name.as_str() {
+            "multi" | "exec" | "discard" if argc != 1 => {
+                self.failed |= self.in_multi();
+                Err(CommandError::wrong_arity(&name))
+            }
+            "multi" if self.in_multi() => {
+                Err(CommandError::new("ERR MULTI calls can not be nested"))
+            }
+            "multi" => {
+                self.queued = Some(Vec::new());
+                Ok(Frame::Simple("OK".to_string()))
+            }
+            "exec" if !self.in_multi() => Err(CommandError::new("ERR EXEC without MULTI")),
+            "exec" => self.exec(registry),
+            "discard" if !self.in_multi() => Err(CommandError::new("ERR DISCARD without MULTI")),
+            "discard" => {
+                self.reset();
+                Ok(Frame::Simple("OK".to_string()))
+            }
+            "watch" if self.in_multi() => {
+                Err(CommandError::new("ERR WATCH inside MULTI is not allowed"))
+            }
+            "watch" if argc < 2 => Err(CommandError::wrong_arity("watch")),
+            "watch" => self.watch(&frame).map(|()| Frame::Simple("OK".to_string())),
+            "unwatch" if !self.in_multi() => {
+                self.unwatch_all();
+                Ok(Frame::Simple("OK".to_string()))
+            }
+            // MULTI の後の UNWATCH はキューに積むが、EXEC の時点で WATCH はすべて解除されるので何もしない
+            "unwatch" => {
+                self.queued.as_mut().unwrap().push(frame);
+                Ok(Frame::Simple("QUEUED".to_string()))
+            }
+            _ => return self.queue_or_execute(registry, frame),
+        };
+        Outcome::Reply(reply.unwrap_or_else(Frame::from))
+    }
+
+    // MULTI の後であればコマンドを検査してキューに積む
+    fn queue_or_execute(&mut self, registry: &Registry, frame: Frame) -> Outcome {
+        let queued = match &mut self.queued {
+            Some(queued) => queued,
+            None => return Outcome::Execute(frame),
+        };
+        let checked = match Command::parse(&frame) {
+            Some(Ok(Command::Publish(..))) => Ok(()),
+            Some(Err(err)) if frame.command_name().as_deref() == Some("publish") => Err(err),
+            _ => registry.check(&self.db, &frame).map_err(Frame::from),
+        };
+        match checked {
+            Ok(()) => {
+                queued.push(frame);
+                Outcome::Reply(Frame::Simple("QUEUED".to_string()))
+            }
+            Err(err) => {
+                self.failed = true;
+                Outcome::Reply(err)
+            }
+        }
+    }
+
+    // キューに積んだコマンドをまとめて実行する
+    //
+    // 実行している間は db の gate を排他ロックするので、他のコネクションのコマンドが
+    // 途中に割り込んだり、途中の状態を読んだりすることはない
+    fn exec(&mut self, registry: &Registry) -> Result<Frame, CommandError> {
+        let queued = self.queued.take().unwrap_or_default();
+        if std::mem::take(&mut self.failed) {
+            self.unwatch_all();
+            return Err(CommandError::new(
+                "EXECABORT Transaction discarded because of previous errors.",
+            ));
+        }
+
+        let db = self.db.clone();
+        let _gate = db.exclusive();
+
+        // キューに積んだ後で AOF に書き込めなくなっていれば、書き込みを含むトランザクションは実行しない
+        let wrap = queued.iter().any(|frame| registry.is_write(frame));
+        if let Some(err) = db.aof().and_then(Aof::error).filter(|_| wrap) {
+            self.unwatch_all();
+            return Err(CommandError::aof_failed(&err));
+        }
+
+        let changed = self.watched.iter().any(|(key, version)| {
+            let shard = get_db_from_sharded_db(&db, key);
+            let current = shard.lock().unwrap().version(key);
+            current != Some(*version)
+        });
+        self.unwatch_all();
+        if changed {
+            return Ok(Frame::NullArray);
+        }
+
+        // 書き込みコマンドを含むトランザクションは、AOF とフォロワーにも MULTI と EXEC で囲んで伝える
+        // 読み込み直すときは、EXEC まで記録されていた場合にだけまとめて実行される
+        if wrap {
+            propagate::command(&db, vec![Bytes::from("MULTI")]);
+        }
+        let replies = queued
+            .into_iter()
+            .map(|frame| {
+                if let Some(Ok(Command::Publish(channel, message))) = Command::parse(&frame) {
+                    return Frame::Integer(self.hub.publish(&channel, message) as i64);
+                }
+                match frame.command_name().as_deref() {
+                    Some("unwatch") => Frame::Simple("OK".to_string()),
+                    _ => registry.dispatch(&db, frame),
+                }
+            })
+            .collect();
+        if wrap {
+            propagate::command(&db, vec![Bytes::from("EXEC")]);
+        }
+        Ok(Frame::Array(replies))
+    }
+
+    // WATCH key [key ...]
+    // キーのどれかが UTF-8 として読めなければ、どのキーも監視せずにエラーを返す
+    fn watch(&mut self, frame: &Frame) -> Result<(), CommandError> {
+        let parts = match frame {
+            Frame::Array(parts) => parts,
+            _ => return Ok(()),
+        };
+        let mut keys = Vec::new();
+        for part in &parts[1..] {
+            match part {
+                Frame::Bulk(bytes) => match std::str::from_utf8(bytes) {
+                    Ok(key) => keys.push(key.to_string()),
+                    Err(_) => return Err(CommandError::invalid_key()),
+                },
+                Frame::Simple(s) => keys.push(s.clone()),
+                _ => continue,
+            }
+        }
+        for key in keys {
+            if self.watched.iter().any(|(watched, _)| *watched == key) {
+                continue;
+            }
+            let shard = get_db_from_sharded_db(&self.db, &key);
+            let version = shard.lock().unwrap().watch(&key);
+            self.watched.push((key, version));
+        }
+        Ok(())
+    }
+
+    fn unwatch_all(&mut self) {
+        for (key, _) in self.watched.drain(..) {
+            let shard = get_db_from_sharded_db(&self.db, &key);
+            shard.lock().unwrap().unwatch(&key);
+        }
+    }
+
+    // MULTI と WATCH の状態をすべて捨てる
+    fn reset(&mut self) {
+        self.queued = None;
+        self.failed = false;
+        self.unwatch_all();
+    }
+}
+
+impl Drop for Transaction {
+    // コネクションが切れたら WATCH を解除する
+    fn drop(&mut self) {
+        self.unwatch_all();
+    }
+}
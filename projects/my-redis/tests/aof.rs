@@ -0,0 +1,363 @@
+mod common;
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tokio::time;
+
+use common::{bulk, bulks, call, command, connect, request};
+use my_redis::aof::{self, Aof, Fsync};
+use my_redis::cmd::Registry;
+use my_redis::db::{new_sharded_db, ShardedDb};
+use my_redis::frame::Frame;
+use my_redis::rdb;
+use my_redis::server::Config;
+use my_redis::Connection;
+
+// テストごとに別の一時ファイルを使う
+fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("my-redis-{}-{}.aof", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+// AOF に記録する db を作る
+fn db_with_aof(path: &Path) -> ShardedDb {
+    let db = new_sharded_db(4);
+    db.enable_aof(Aof::open(path, Fsync::Always).unwrap());
+    db
+}
+
+// 追記したコマンドを書き込みスレッドがファイルに書き込むまで待つ
+fn flush(db: &ShardedDb) {
+    db.aof().unwrap().flush().unwrap();
+}
+
+// AOF を読み込んだ新しい db を作る
+fn reload(path: &Path) -> ShardedDb {
+    let db = new_sharded_db(4);
+    aof::load(&db, path).unwrap();
+    db
+}
+
+// AOF を設定したサーバを起動し、接続したコネクションを返す
+async fn start_server(path: &Path) -> (Connection, Connection) {
+    let config = Config {
+        appendfilename: Some(path.to_path_buf()),
+        appendfsync: Fsync::Always,
+        ..Config::default()
+    };
+    let addr = common::start_server(config).await;
+    (connect(addr).await, connect(addr).await)
+}
+
+#[tokio::test]
+async fn replays_logged_commands() {
+    let path = temp_path("replay");
+    let db = db_with_aof(&path);
+
+    call(&db, &["SET", "str", "hello"]);
+    call(&db, &["INCR", "counter"]);
+    call(&db, &["INCRBY", "counter", "41"]);
+    call(&db, &["RPUSH", "list", "a", "b", "c"]);
+    call(&db, &["LPOP", "list"]);
+    call(&db, &["HSET", "hash", "f", "v"]);
+    call(&db, &["SADD", "set", "m"]);
+    call(&db, &["ZADD", "zset", "2", "b", "1", "a"]);
+    call(&db, &["SET", "gone", "x"]);
+    call(&db, &["DEL", "gone"]);
+    // 失敗したコマンドと読み出すだけのコマンドは記録しない
+    call(&db, &["LPUSH", "str", "x"]);
+    call(&db, &["GET", "str"]);
+    let id = call(&db, &["XADD", "stream", "*", "k", "v"]);
+
+    flush(&db);
+    let loaded = reload(&path);
+    assert_eq!(call(&loaded, &["GET", "str"]), bulk("hello"));
+    assert_eq!(call(&loaded, &["GET", "counter"]), bulk("42"));
+    assert_eq!(
+        call(&loaded, &["LRANGE", "list", "0", "-1"]),
+        bulks(&["b", "c"])
+    );
+    assert_eq!(call(&loaded, &["HGET", "hash", "f"]), bulk("v"));
+    assert_eq!(call(&loaded, &["SISMEMBER", "set", "m"]), Frame::Integer(1));
+    assert_eq!(
+        call(&loaded, &["ZRANGE", "zset", "0", "-1"]),
+        bulks(&["a", "b"])
+    );
+    assert_eq!(call(&loaded, &["EXISTS", "gone"]), Frame::Integer(0));
+
+    // 自動生成した ID は、実際に割り当てた ID で記録される
+    let id = match id {
+        Frame::Bulk(id) => String::from_utf8(id.to_vec()).unwrap(),
+        other => panic!("unexpected reply {:?}", other),
+    };
+    let logged = std::fs::read(&path).unwrap();
+    assert!(!String::from_utf8_lossy(&logged).contains("$1\r\n*\r\n"));
+    assert!(matches!(
+        call(&loaded, &["XRANGE", "stream", "-", "+"]),
+        Frame::Array(entries) if entries == vec![Frame::Array(vec![bulk(&id), bulks(&["k", "v"])])]
+    ));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn relative_expiry_is_logged_as_an_absolute_time() {
+    let path = temp_path("expiry");
+    let db = db_with_aof(&path);
+
+    call(&db, &["SET", "short", "v", "PX", "50"]);
+    call(&db, &["SET", "long", "v"]);
+    call(&db, &["EXPIRE", "long", "100"]);
+
+    flush(&db);
+    let logged = std::fs::read(&path).unwrap();
+    assert!(String::from_utf8_lossy(&logged).contains("PEXPIREAT"));
+
+    // 読み込み直すまでに経過した時間も、有効期限から差し引かれる
+    time::sleep(Duration::from_millis(100)).await;
+    let loaded = reload(&path);
+    assert_eq!(call(&loaded, &["EXISTS", "short"]), Frame::Integer(0));
+    assert!(matches!(call(&loaded, &["TTL", "long"]), Frame::Integer(n) if n > 90 && n <= 100));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn expireat_sets_an_absolute_expiry() {
+    let db = new_sharded_db(4);
+    call(&db, &["SET", "k", "v"]);
+    call(&db, &["SET", "past", "v"]);
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    assert_eq!(
+        call(&db, &["EXPIREAT", "k", &(now + 100).to_string()]),
+        Frame::Integer(1)
+    );
+    assert!(matches!(call(&db, &["TTL", "k"]), Frame::Integer(n) if n > 90 && n <= 100));
+
+    // 過去の時刻を指定するとすぐに削除される
+    assert_eq!(call(&db, &["PEXPIREAT", "past", "1000"]), Frame::Integer(1));
+    assert_eq!(call(&db, &["EXISTS", "past"]), Frame::Integer(0));
+    assert_eq!(
+        call(&db, &["PEXPIREAT", "missing", "1000"]),
+        Frame::Integer(0)
+    );
+}
+
+#[tokio::test]
+async fn truncated_tail_is_discarded() {
+    let path = temp_path("truncated");
+    let db = db_with_aof(&path);
+    call(&db, &["SET", "a", "1"]);
+    call(&db, &["SET", "b", "2"]);
+    flush(&db);
+    let valid = std::fs::metadata(&path).unwrap().len();
+
+    // 書き込みの途中で落ちたときのように、最後のコマンドが途中で切れている
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    file.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nc\r\n$1").unwrap();
+    drop(file);
+
+    let loaded = new_sharded_db(4);
+    assert_eq!(aof::load(&loaded, &path).unwrap(), 2);
+    assert_eq!(call(&loaded, &["GET", "b"]), bulk("2"));
+    assert_eq!(call(&loaded, &["EXISTS", "c"]), Frame::Integer(0));
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), valid);
+
+    // EXEC まで記録されていないトランザクションも捨てる
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    file.write_all(b"*1\r\n$5\r\nMULTI\r\n*3\r\n$3\r\nSET\r\n$1\r\nc\r\n$1\r\n3\r\n")
+        .unwrap();
+    drop(file);
+
+    let loaded = new_sharded_db(4);
+    assert_eq!(aof::load(&loaded, &path).unwrap(), 2);
+    assert_eq!(call(&loaded, &["EXISTS", "c"]), Frame::Integer(0));
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), valid);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn transactions_and_blocking_pops_survive_a_restart() {
+    let path = temp_path("server");
+    let (mut a, mut b) = start_server(&path).await;
+
+    assert_eq!(
+        request(&mut a, &["MULTI"]).await,
+        Frame::Simple("OK".into())
+    );
+    request(&mut a, &["SET", "x", "1"]).await;
+    request(&mut a, &["INCR", "x"]).await;
+    assert_eq!(
+        request(&mut a, &["EXEC"]).await,
+        Frame::Array(vec![Frame::Simple("OK".into()), Frame::Integer(2)])
+    );
+
+    // BLPOP で待っているクライアントに渡した要素は、LPOP として記録される
+    a.write_frame(&command(&["BLPOP", "queue", "0"]))
+        .await
+        .unwrap();
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(
+        request(&mut b, &["RPUSH", "queue", "first", "second"]).await,
+        Frame::Integer(2)
+    );
+    assert_eq!(
+        a.read_frame().await.unwrap().unwrap(),
+        bulks(&["queue", "first"])
+    );
+
+    let logged = String::from_utf8(std::fs::read(&path).unwrap()).unwrap();
+    assert!(logged.contains("MULTI") && logged.contains("EXEC"));
+
+    // 同じファイルで起動し直したサーバは、記録したコマンドを実行し直す
+    let (mut c, _) = start_server(&path).await;
+    assert_eq!(request(&mut c, &["GET", "x"]).await, bulk("2"));
+    assert_eq!(
+        request(&mut c, &["LRANGE", "queue", "0", "-1"]).await,
+        bulks(&["second"])
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+// 待っていた BLMOVE への移動は、取り出しと追加に分けずに一つの LMOVE として記録される
+#[tokio::test]
+async fn blocked_blmove_is_logged_as_a_single_lmove() {
+    let path = temp_path("blmove");
+    let db = db_with_aof(&path);
+
+    let mover = {
+        let db = db.clone();
+        let frame = command(&["BLMOVE", "src", "dst", "RIGHT", "LEFT", "0"]);
+        tokio::spawn(async move { Registry::new().execute(&db, frame).await })
+    };
+    time::sleep(Duration::from_millis(50)).await;
+    call(&db, &["RPUSH", "src", "a", "b"]);
+    assert_eq!(mover.await.unwrap(), bulk("b"));
+
+    flush(&db);
+    let logged = String::from_utf8(std::fs::read(&path).unwrap()).unwrap();
+    assert!(
+        logged.contains("LMOVE") && !logged.contains("RPOP") && !logged.contains("LPUSH"),
+        "{}",
+        logged
+    );
+    let db = reload(&path);
+    assert_eq!(call(&db, &["LRANGE", "src", "0", "-1"]), bulks(&["a"]));
+    assert_eq!(call(&db, &["LRANGE", "dst", "0", "-1"]), bulks(&["b"]));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn bgrewriteaof_compacts_the_log() {
+    let path = temp_path("rewrite");
+    let db = db_with_aof(&path);
+    for _ in 0..100 {
+        call(&db, &["INCR", "counter"]);
+    }
+    flush(&db);
+    let before = std::fs::metadata(&path).unwrap().len();
+
+    assert_eq!(
+        call(&db, &["BGREWRITEAOF"]),
+        Frame::Simple("Background append only file rewriting started".into())
+    );
+    // 書き直している間のコマンドも失われない
+    call(&db, &["SET", "during", "v"]);
+    while db.aof().unwrap().is_rewriting() {
+        time::sleep(Duration::from_millis(5)).await;
+    }
+    call(&db, &["SET", "after", "v"]);
+
+    flush(&db);
+    let logged = std::fs::read(&path).unwrap();
+    assert!(rdb::is_snapshot(&logged));
+    assert!((logged.len() as u64) < before);
+
+    let loaded = reload(&path);
+    assert_eq!(call(&loaded, &["GET", "counter"]), bulk("100"));
+    assert_eq!(call(&loaded, &["GET", "during"]), bulk("v"));
+    assert_eq!(call(&loaded, &["GET", "after"]), bulk("v"));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn bgrewriteaof_without_a_file_is_an_error() {
+    let db = new_sharded_db(4);
+    assert_eq!(
+        call(&db, &["BGREWRITEAOF"]),
+        Frame::Error("ERR append only file is disabled".into())
+    );
+}
+
+// 書き込めない AOF（/dev/full）では、書き込みコマンドを MISCONF エラーで拒否する
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn write_errors_refuse_further_writes() {
+    let db = new_sharded_db(4);
+    db.enable_aof(Aof::open("/dev/full", Fsync::EverySec).unwrap());
+    let registry = Registry::new();
+
+    call(&db, &["SET", "k", "v"]);
+    let err = db.aof().unwrap().flush().unwrap_err();
+    assert_eq!(db.aof().unwrap().error(), Some(err.clone()));
+
+    assert_eq!(
+        registry.execute(&db, command(&["SET", "k", "w"])).await,
+        Frame::Error(format!("MISCONF Errors writing to the AOF file: {}", err))
+    );
+    assert!(registry.check(&db, &command(&["DEL", "k"])).is_err());
+    // 読み出すだけのコマンドは実行できる
+    assert_eq!(
+        registry.execute(&db, command(&["GET", "k"])).await,
+        bulk("v")
+    );
+}
+
+// appendfsync always では、追記したコマンドを fsync し終えるまで応答を待つ
+// 書き込めなければ、待っているクライアントにエラーを返す
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn always_waits_until_the_command_is_written() {
+    let path = temp_path("always");
+    let db = db_with_aof(&path);
+    let aof = db.aof().unwrap();
+    let appended = aof.appended();
+    call(&db, &["SET", "k", "v"]);
+    aof.wait_written(appended).await.unwrap();
+    assert!(String::from_utf8_lossy(&std::fs::read(&path).unwrap()).contains("SET"));
+    std::fs::remove_file(&path).unwrap();
+
+    let db = new_sharded_db(4);
+    db.enable_aof(Aof::open("/dev/full", Fsync::Always).unwrap());
+    let aof = db.aof().unwrap();
+    let appended = aof.appended();
+    call(&db, &["SET", "k", "v"]);
+    let err = aof.wait_written(appended).await.unwrap_err();
+    assert!(err.contains("No space left on device"), "{}", err);
+}
+
+#[test]
+fn parses_fsync_policies() {
+    assert_eq!("always".parse(), Ok(Fsync::Always));
+    assert_eq!("EVERYSEC".parse(), Ok(Fsync::EverySec));
+    assert_eq!("no".parse(), Ok(Fsync::No));
+    assert!("sometimes".parse::<Fsync>().is_err());
+    assert_eq!(Fsync::default(), Fsync::EverySec);
+}
@@ -0,0 +1,198 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use common::{call, command, connect, start_server};
+use my_redis::cmd::Registry;
+use my_redis::db::{new_sharded_db, ShardedDb};
+use my_redis::frame::Frame;
+use my_redis::server::Config;
+
+// コマンドを別タスクで実行し、待ち行列に並ぶまで少し待つ
+async fn spawn_blocking(db: &ShardedDb, args: &[&str]) -> JoinHandle<Frame> {
+    let db = db.clone();
+    let frame = command(args);
+    let handle = tokio::spawn(async move { Arc::new(Registry::new()).execute(&db, frame).await });
+    time::sleep(Duration::from_millis(10)).await;
+    handle
+}
+
+fn popped(key: &str, value: &str) -> Frame {
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::from(key.to_string())),
+        Frame::Bulk(Bytes::from(value.to_string())),
+    ])
+}
+
+#[tokio::test(start_paused = true)]
+async fn returns_immediately_when_an_element_exists() {
+    let db = new_sharded_db(4);
+    call(&db, &["RPUSH", "b", "1", "2"]);
+
+    let reply = Registry::new()
+        .execute(&db, command(&["BLPOP", "a", "b", "0"]))
+        .await;
+    assert_eq!(reply, popped("b", "1"));
+    let reply = Registry::new()
+        .execute(&db, command(&["BRPOP", "a", "b", "0"]))
+        .await;
+    assert_eq!(reply, popped("b", "2"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn push_wakes_one_waiter_in_fifo_order() {
+    let db = new_sharded_db(4);
+
+    let first = spawn_blocking(&db, &["BLPOP", "q", "0"]).await;
+    let second = spawn_blocking(&db, &["BRPOP", "other", "q", "0"]).await;
+
+    assert_eq!(call(&db, &["RPUSH", "q", "job1"]), Frame::Integer(1));
+    assert_eq!(first.await.unwrap(), popped("q", "job1"));
+
+    // 一つの要素は一人にしか渡されない
+    time::sleep(Duration::from_millis(10)).await;
+    assert!(!second.is_finished());
+    assert_eq!(call(&db, &["LLEN", "q"]), Frame::Integer(0));
+
+    call(&db, &["RPUSH", "q", "job2", "job3"]);
+    // BRPOP で待っていたクライアントは右端の要素を受け取る
+    assert_eq!(second.await.unwrap(), popped("q", "job3"));
+    assert_eq!(
+        call(&db, &["LRANGE", "q", "0", "-1"]),
+        Frame::Array(vec![Frame::Bulk("job2".into())])
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn timeout_returns_null_and_leaves_the_queue() {
+    let db = new_sharded_db(4);
+
+    let waiter = spawn_blocking(&db, &["BLPOP", "q", "1.5"]).await;
+    time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(waiter.await.unwrap(), Frame::Null);
+
+    // タイムアウトしたクライアントには要素が渡されない
+    call(&db, &["RPUSH", "q", "v"]);
+    assert_eq!(call(&db, &["LLEN", "q"]), Frame::Integer(1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn aborted_waiter_does_not_swallow_elements() {
+    let db = new_sharded_db(4);
+
+    let gone = spawn_blocking(&db, &["BLPOP", "q", "0"]).await;
+    let alive = spawn_blocking(&db, &["BLPOP", "q", "0"]).await;
+    gone.abort();
+    let _ = gone.await;
+
+    call(&db, &["RPUSH", "q", "v"]);
+    assert_eq!(alive.await.unwrap(), popped("q", "v"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn blmove_moves_between_lists() {
+    let db = new_sharded_db(4);
+
+    let mover = spawn_blocking(&db, &["BLMOVE", "src", "dst", "RIGHT", "LEFT", "0"]).await;
+    let consumer = spawn_blocking(&db, &["BLPOP", "dst", "0"]).await;
+
+    call(&db, &["RPUSH", "src", "a", "b"]);
+    assert_eq!(mover.await.unwrap(), Frame::Bulk("b".into()));
+    // 移動先で待っているクライアントにも要素が渡される
+    assert_eq!(consumer.await.unwrap(), popped("dst", "b"));
+    assert_eq!(
+        call(&db, &["LRANGE", "src", "0", "-1"]),
+        Frame::Array(vec![Frame::Bulk("a".into())])
+    );
+
+    assert_eq!(
+        call(&db, &["LMOVE", "src", "dst2", "LEFT", "RIGHT"]),
+        Frame::Bulk("a".into())
+    );
+    assert_eq!(call(&db, &["EXISTS", "src"]), Frame::Integer(0));
+}
+
+#[tokio::test(start_paused = true)]
+async fn blmove_fails_when_the_destination_is_not_a_list() {
+    let db = new_sharded_db(4);
+
+    let mover = spawn_blocking(&db, &["BLMOVE", "src", "dst", "LEFT", "LEFT", "0"]).await;
+    call(&db, &["SET", "dst", "v"]);
+    call(&db, &["RPUSH", "src", "a"]);
+    assert_eq!(
+        mover.await.unwrap(),
+        Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())
+    );
+    // 移動できなかった要素は移動元に残る
+    assert_eq!(
+        call(&db, &["LRANGE", "src", "0", "-1"]),
+        Frame::Array(vec![Frame::Bulk("a".into())])
+    );
+}
+
+// 要素を受け取った後、結果を返す前に待つのをやめたら、要素はリストに戻る
+#[tokio::test(start_paused = true)]
+async fn element_delivered_to_an_aborted_waiter_is_put_back() {
+    let db = new_sharded_db(4);
+
+    let waiter = spawn_blocking(&db, &["BLPOP", "q", "0"]).await;
+    // 要素を渡した直後、待っていたタスクが動き出す前に中断する
+    call(&db, &["RPUSH", "q", "a", "b"]);
+    waiter.abort();
+    let _ = waiter.await;
+
+    assert_eq!(
+        call(&db, &["LRANGE", "q", "0", "-1"]),
+        Frame::Array(vec![Frame::Bulk("a".into()), Frame::Bulk("b".into())])
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn errors_and_non_blocking_dispatch() {
+    let db = new_sharded_db(4);
+    call(&db, &["SET", "s", "v"]);
+
+    assert_eq!(call(&db, &["BLPOP", "q", "0"]), Frame::Null);
+    assert_eq!(
+        call(&db, &["BLPOP", "q", "-1"]),
+        Frame::Error("ERR timeout is negative".into())
+    );
+    assert_eq!(
+        call(&db, &["BLPOP", "q", "soon"]),
+        Frame::Error("ERR timeout is not a float or out of range".into())
+    );
+    let reply = Registry::new()
+        .execute(&db, command(&["BLPOP", "s", "0"]))
+        .await;
+    assert_eq!(
+        reply,
+        Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())
+    );
+}
+
+#[tokio::test]
+async fn disconnected_client_leaves_the_queue() {
+    let addr = start_server(Config::default()).await;
+
+    let mut blocked = connect(addr).await;
+    blocked
+        .write_frame(&command(&["BLPOP", "jobs", "0"]))
+        .await
+        .unwrap();
+    time::sleep(Duration::from_millis(50)).await;
+    drop(blocked);
+    time::sleep(Duration::from_millis(50)).await;
+
+    let mut conn = connect(addr).await;
+    conn.write_frame(&command(&["RPUSH", "jobs", "v"]))
+        .await
+        .unwrap();
+    assert_eq!(conn.read_frame().await.unwrap(), Some(Frame::Integer(1)));
+    conn.write_frame(&command(&["LLEN", "jobs"])).await.unwrap();
+    assert_eq!(conn.read_frame().await.unwrap(), Some(Frame::Integer(1)));
+}
@@ -0,0 +1,297 @@
+#[macro_use]
+mod common;
+
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+
+use common::{bulk, connect, error, field_value, ok, request, start_server};
+use my_redis::frame::Frame;
+use my_redis::server::Config;
+use my_redis::Connection;
+
+// cluster-enabled を指定してノードを起動する
+async fn start_node(cluster_enabled: bool) -> SocketAddr {
+    start_server(Config {
+        cluster_enabled,
+        ..Config::default()
+    })
+    .await
+}
+
+async fn text(conn: &mut Connection, args: &[&str]) -> String {
+    match request(conn, args).await {
+        Frame::Bulk(bytes) => String::from_utf8(bytes.to_vec()).unwrap(),
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+// CLUSTER INFO の中から指定した項目の値を取り出す
+async fn info_field(conn: &mut Connection, field: &str) -> String {
+    field_value(&text(conn, &["CLUSTER", "INFO"]).await, field)
+}
+
+// 2 つのノードを知り合わせ、スロットをすべて first に割り当てる
+async fn two_nodes() -> (SocketAddr, SocketAddr, Connection, Connection) {
+    let (a, b) = (start_node(true).await, start_node(true).await);
+    let (mut x, mut y) = (connect(a).await, connect(b).await);
+    request(&mut x, &["CLUSTER", "ADDSLOTSRANGE", "0", "16383"]).await;
+    let port = b.port().to_string();
+    request(&mut x, &["CLUSTER", "MEET", "127.0.0.1", &port]).await;
+    wait_until!(info_field(&mut y, "cluster_state").await == "ok");
+    wait_until!(info_field(&mut x, "cluster_known_nodes").await == "2");
+    (a, b, x, y)
+}
+
+#[tokio::test]
+async fn keyslot_follows_hash_tags() {
+    let mut conn = connect(start_node(false).await).await;
+    assert_eq!(
+        request(&mut conn, &["CLUSTER", "KEYSLOT", "foo"]).await,
+        Frame::Integer(12182)
+    );
+    assert_eq!(
+        request(&mut conn, &["CLUSTER", "KEYSLOT", "123456789"]).await,
+        Frame::Integer(12739)
+    );
+    // {} の中身だけでスロットが決まる（空の {} は無視する）
+    for key in ["{user1000}.following", "{user1000}.followers", "user1000"] {
+        assert_eq!(
+            request(&mut conn, &["CLUSTER", "KEYSLOT", key]).await,
+            Frame::Integer(3443),
+            "{}",
+            key
+        );
+    }
+    assert_ne!(
+        request(&mut conn, &["CLUSTER", "KEYSLOT", "{}foo"]).await,
+        request(&mut conn, &["CLUSTER", "KEYSLOT", "foo"]).await
+    );
+
+    // クラスタモードでなければ、他のサブコマンドは使えず、キーのリダイレクトもしない
+    assert_eq!(
+        request(&mut conn, &["CLUSTER", "INFO"]).await,
+        error("ERR This instance has cluster support disabled")
+    );
+    assert_eq!(
+        request(&mut conn, &["EXISTS", "a", "b"]).await,
+        Frame::Integer(0)
+    );
+}
+
+#[tokio::test]
+async fn nodes_learn_the_slot_map_and_redirect_with_moved() {
+    let addrs = [
+        start_node(true).await,
+        start_node(true).await,
+        start_node(true).await,
+    ];
+    let mut conns = Vec::new();
+    for addr in addrs {
+        conns.push(connect(addr).await);
+    }
+    let ranges = [["0", "5460"], ["5461", "10922"], ["10923", "16383"]];
+    for (conn, [start, end]) in conns.iter_mut().zip(ranges) {
+        assert_eq!(
+            request(conn, &["CLUSTER", "ADDSLOTSRANGE", start, end]).await,
+            ok()
+        );
+    }
+
+    // 最初のノードが他の 2 つと知り合えば、ゴシップで全員が全員を知る
+    for addr in &addrs[1..] {
+        let port = addr.port().to_string();
+        request(&mut conns[0], &["CLUSTER", "MEET", "127.0.0.1", &port]).await;
+    }
+    for conn in conns.iter_mut() {
+        wait_until!(info_field(conn, "cluster_state").await == "ok");
+        wait_until!(info_field(conn, "cluster_known_nodes").await == "3");
+    }
+    assert_eq!(info_field(&mut conns[1], "cluster_size").await, "3");
+    assert_eq!(
+        request(&mut conns[0], &["CLUSTER", "ADDSLOTS", "10923"]).await,
+        error("ERR Slot 10923 is already busy")
+    );
+
+    let third = text(&mut conns[2], &["CLUSTER", "MYID"]).await;
+    let slots = request(&mut conns[0], &["CLUSTER", "SLOTS"]).await;
+    let expected = Frame::Array(vec![
+        Frame::Integer(10923),
+        Frame::Integer(16383),
+        Frame::Array(vec![
+            bulk("127.0.0.1"),
+            Frame::Integer(addrs[2].port() as i64),
+            bulk(&third),
+        ]),
+    ]);
+    assert!(
+        matches!(&slots, Frame::Array(ranges) if ranges.len() == 3 && ranges[2] == expected),
+        "{:?}",
+        slots
+    );
+    let nodes = text(&mut conns[1], &["CLUSTER", "NODES"]).await;
+    assert!(nodes
+        .lines()
+        .any(|line| line.starts_with(&third) && line.ends_with("connected 10923-16383")));
+
+    // "foo" のスロット 12182 は 3 番目のノードが担当している
+    let moved = format!("MOVED 12182 {}", addrs[2]);
+    assert_eq!(
+        request(&mut conns[0], &["SET", "foo", "1"]).await,
+        error(&moved)
+    );
+    assert_eq!(request(&mut conns[2], &["SET", "foo", "1"]).await, ok());
+    assert_eq!(request(&mut conns[2], &["GET", "foo"]).await, bulk("1"));
+
+    // 複数のキーは同じスロットに入っていなければならない
+    assert_eq!(
+        request(&mut conns[2], &["EXISTS", "foo", "bar"]).await,
+        error("CROSSSLOT Keys in request don't hash to the same slot")
+    );
+    assert_eq!(
+        request(&mut conns[2], &["EXISTS", "foo", "{foo}a"]).await,
+        Frame::Integer(1)
+    );
+
+    // MULTI の中でリダイレクトされたコマンドがあれば、EXEC は中止される
+    request(&mut conns[0], &["MULTI"]).await;
+    assert_eq!(request(&mut conns[0], &["GET", "foo"]).await, error(&moved));
+    assert!(matches!(
+        request(&mut conns[0], &["EXEC"]).await,
+        Frame::Error(err) if err.starts_with("EXECABORT")
+    ));
+}
+
+#[tokio::test]
+async fn slot_migration_redirects_with_ask_until_the_slot_moves() {
+    let (a, b, mut x, mut y) = two_nodes().await;
+    let source = text(&mut x, &["CLUSTER", "MYID"]).await;
+    let target = text(&mut y, &["CLUSTER", "MYID"]).await;
+    request(&mut x, &["SET", "foo", "1"]).await;
+    request(&mut x, &["RPUSH", "{foo}list", "a", "b"]).await;
+    request(&mut x, &["PEXPIRE", "{foo}list", "100000"]).await;
+
+    assert_eq!(
+        request(
+            &mut y,
+            &["CLUSTER", "SETSLOT", "12182", "IMPORTING", &source]
+        )
+        .await,
+        ok()
+    );
+    assert_eq!(
+        request(
+            &mut x,
+            &["CLUSTER", "SETSLOT", "12182", "MIGRATING", &target]
+        )
+        .await,
+        ok()
+    );
+
+    // 移行元にあるキーはそのまま扱い、ないキーは移行先に案内する
+    let ask = error(&format!("ASK 12182 {}", b));
+    assert_eq!(request(&mut x, &["GET", "foo"]).await, bulk("1"));
+    assert_eq!(request(&mut x, &["GET", "{foo}new"]).await, ask);
+    assert!(matches!(
+        request(&mut x, &["EXISTS", "foo", "{foo}new"]).await,
+        Frame::Error(err) if err.starts_with("TRYAGAIN")
+    ));
+    // 移行先は ASKING の直後のコマンドだけを受け付ける
+    let moved = error(&format!("MOVED 12182 {}", a));
+    assert_eq!(request(&mut y, &["GET", "{foo}new"]).await, moved);
+    assert_eq!(request(&mut y, &["ASKING"]).await, ok());
+    assert_eq!(request(&mut y, &["GET", "{foo}new"]).await, Frame::Null);
+    assert_eq!(request(&mut y, &["GET", "{foo}new"]).await, moved);
+
+    let port = b.port().to_string();
+    let mut keys = match request(&mut x, &["CLUSTER", "GETKEYSINSLOT", "12182", "10"]).await {
+        Frame::Array(keys) => keys,
+        other => panic!("unexpected reply {:?}", other),
+    };
+    keys.sort_by_key(|key| format!("{:?}", key));
+    assert_eq!(keys, vec![bulk("foo"), bulk("{foo}list")]);
+    let migrate = [
+        "MIGRATE",
+        "127.0.0.1",
+        &port,
+        "",
+        "0",
+        "1000",
+        "KEYS",
+        "foo",
+        "{foo}list",
+    ];
+    assert_eq!(request(&mut x, &migrate).await, ok());
+    assert_eq!(
+        request(&mut x, &["CLUSTER", "COUNTKEYSINSLOT", "12182"]).await,
+        Frame::Integer(0)
+    );
+    assert_eq!(
+        request(&mut x, &["MIGRATE", "127.0.0.1", &port, "foo", "0", "1000"]).await,
+        Frame::Simple("NOKEY".into())
+    );
+
+    // 移行を終えるまでは、移行元は移行先に案内する
+    assert_eq!(request(&mut x, &["GET", "foo"]).await, ask);
+    request(&mut y, &["ASKING"]).await;
+    assert_eq!(request(&mut y, &["GET", "foo"]).await, bulk("1"));
+    request(&mut y, &["ASKING"]).await;
+    assert!(matches!(
+        request(&mut y, &["PTTL", "{foo}list"]).await,
+        Frame::Integer(ttl) if ttl > 90000
+    ));
+
+    // スロットの担当を書き換えれば、移行元は MOVED で案内する
+    for (conn, slot_owner) in [(&mut y, &target), (&mut x, &target)] {
+        assert_eq!(
+            request(conn, &["CLUSTER", "SETSLOT", "12182", "NODE", slot_owner]).await,
+            ok()
+        );
+    }
+    assert_eq!(
+        request(&mut x, &["GET", "foo"]).await,
+        error(&format!("MOVED 12182 {}", b))
+    );
+    assert_eq!(
+        request(&mut y, &["LRANGE", "{foo}list", "0", "-1"]).await,
+        Frame::Array(vec![bulk("a"), bulk("b")])
+    );
+    assert!(!text(&mut x, &["CLUSTER", "NODES"]).await.contains("->-"));
+}
+
+#[tokio::test]
+async fn dump_and_restore_copy_a_value() {
+    let mut conn = connect(start_node(false).await).await;
+    request(&mut conn, &["HSET", "h", "f", "v"]).await;
+    let payload = match request(&mut conn, &["DUMP", "h"]).await {
+        Frame::Bulk(payload) => payload,
+        other => panic!("unexpected reply {:?}", other),
+    };
+    assert_eq!(request(&mut conn, &["DUMP", "missing"]).await, Frame::Null);
+
+    let restore = |key: &str, extra: &[&str]| {
+        let mut parts = vec![
+            Bytes::from("RESTORE"),
+            Bytes::from(key.to_string()),
+            Bytes::from("0"),
+            payload.clone(),
+        ];
+        parts.extend(extra.iter().map(|s| Bytes::from(s.to_string())));
+        Frame::command(parts)
+    };
+    conn.write_frame(&restore("h", &[])).await.unwrap();
+    assert_eq!(
+        conn.read_frame().await.unwrap().unwrap(),
+        error("BUSYKEY Target key name already exists.")
+    );
+    conn.write_frame(&restore("copy", &[])).await.unwrap();
+    assert_eq!(conn.read_frame().await.unwrap().unwrap(), ok());
+    conn.write_frame(&restore("h", &["REPLACE"])).await.unwrap();
+    assert_eq!(conn.read_frame().await.unwrap().unwrap(), ok());
+    assert_eq!(request(&mut conn, &["HGET", "copy", "f"]).await, bulk("v"));
+
+    assert_eq!(
+        request(&mut conn, &["RESTORE", "bad", "0", "garbage"]).await,
+        error("ERR DUMP payload version or checksum are wrong")
+    );
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use bytes::{Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedRead, FramedWrite};
+
+use common::{bulk, start_server};
+use my_redis::codec::RespCodec;
+use my_redis::frame::{Frame, Limits, Protocol};
+use my_redis::server::Config;
+use my_redis::Connection;
+
+fn frames() -> Vec<Frame> {
+    vec![
+        Frame::Simple("OK".into()),
+        Frame::Error("ERR oops".into()),
+        Frame::Integer(-42),
+        Frame::Null,
+        Frame::Bulk(Bytes::from(vec![b'x'; 10_000])),
+        Frame::command(["SET", "foo", "bar"]),
+        Frame::Array(vec![Frame::Integer(1), Frame::Array(vec![bulk("deep")])]),
+    ]
+}
+
+#[test]
+fn decoder_waits_for_a_whole_frame() {
+    let mut codec = RespCodec::new();
+    let mut buf = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$3\r\nfo"[..]);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+
+    buf.extend_from_slice(b"o\r\n+OK\r\n");
+    assert_eq!(
+        codec.decode(&mut buf).unwrap(),
+        Some(Frame::command(["GET", "foo"]))
+    );
+    assert_eq!(
+        codec.decode(&mut buf).unwrap(),
+        Some(Frame::Simple("OK".into()))
+    );
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn encoder_follows_the_protocol() {
+    let mut codec = RespCodec::new();
+    let map = Frame::Map(vec![(bulk("a"), Frame::Double(1.5))]);
+
+    let mut buf = BytesMut::new();
+    codec.encode(&map, &mut buf).unwrap();
+    assert_eq!(&buf[..], b"*2\r\n$1\r\na\r\n$3\r\n1.5\r\n");
+
+    codec.set_protocol(Protocol::Resp3);
+    buf.clear();
+    codec.encode(map, &mut buf).unwrap();
+    assert_eq!(&buf[..], b"%1\r\n$1\r\na\r\n,1.5\r\n");
+}
+
+#[test]
+fn decoder_enforces_limits() {
+    let mut codec = RespCodec::new();
+    codec.set_limits(Limits {
+        max_bulk_len: 4,
+        ..Limits::default()
+    });
+    let mut buf = BytesMut::from(&b"$5\r\n"[..]);
+    let err = codec.decode(&mut buf).unwrap_err();
+    assert!(err.to_string().contains("bulk length 5"), "{}", err);
+}
+
+#[tokio::test]
+async fn framed_streams_and_sinks_round_trip() {
+    let (a, b) = tokio::io::duplex(64);
+    let mut sink = FramedWrite::new(a, RespCodec::new());
+    let stream = FramedRead::new(b, RespCodec::new());
+
+    let write = tokio::spawn(async move {
+        for frame in frames() {
+            sink.send(frame).await.unwrap();
+        }
+    });
+
+    // 他のアダプタと組み合わせられる
+    let received: Vec<Frame> = stream.map(|frame| frame.unwrap()).collect().await;
+    write.await.unwrap();
+    assert_eq!(received, frames());
+}
+
+// Connection で書き込んだフレームをコーデックで読み出せる（逆も同じ）
+#[tokio::test]
+async fn codec_interoperates_with_connection() {
+    // 読み書きを交互に行うので、一番大きなフレームが収まるバッファにしておく
+    let (a, b) = tokio::io::duplex(64 * 1024);
+    let mut connection = Connection::new(a);
+    let mut framed = Framed::new(b, RespCodec::new());
+
+    for frame in frames() {
+        connection.write_frame(&frame).await.unwrap();
+        assert_eq!(framed.next().await.unwrap().unwrap(), frame);
+
+        framed.send(frame.clone()).await.unwrap();
+        assert_eq!(connection.read_frame().await.unwrap(), Some(frame));
+    }
+}
+
+#[tokio::test]
+async fn framed_client_talks_to_the_server() {
+    let addr = start_server(Config::default()).await;
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut client = Framed::new(stream, RespCodec::new());
+
+    client
+        .send(Frame::command(["HSET", "h", "f", "v"]))
+        .await
+        .unwrap();
+    assert_eq!(client.next().await.unwrap().unwrap(), Frame::Integer(1));
+
+    client.send(Frame::command(["HELLO", "3"])).await.unwrap();
+    assert!(matches!(
+        client.next().await.unwrap().unwrap(),
+        Frame::Map(_)
+    ));
+    client.send(Frame::command(["HGETALL", "h"])).await.unwrap();
+    assert_eq!(
+        client.next().await.unwrap().unwrap(),
+        Frame::Map(vec![(bulk("f"), bulk("v"))])
+    );
+}
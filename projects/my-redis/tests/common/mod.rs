@@ -0,0 +1,104 @@
+// 結合テストで共有する補助関数
+//
+// 各テストファイルから `mod common;` で取り込む（wait_until! を使うファイルは #[macro_use] をつける）
+// ファイルごとに使う関数が異なるので、使わない関数やマクロの警告は出さない
+#![allow(dead_code, unused_macros)]
+
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use tokio::net::{TcpListener, TcpStream};
+
+use my_redis::cmd::Registry;
+use my_redis::db::ShardedDb;
+use my_redis::frame::Frame;
+use my_redis::server::{self, Config};
+use my_redis::Connection;
+
+pub fn command(args: &[&str]) -> Frame {
+    Frame::command(args.iter().map(|s| Bytes::from(s.to_string())))
+}
+
+pub fn bulk(s: &str) -> Frame {
+    Frame::Bulk(Bytes::from(s.to_string()))
+}
+
+pub fn bulks(items: &[&str]) -> Frame {
+    Frame::Array(items.iter().map(|s| bulk(s)).collect())
+}
+
+pub fn ok() -> Frame {
+    Frame::Simple("OK".into())
+}
+
+pub fn error(s: &str) -> Frame {
+    Frame::Error(s.to_string())
+}
+
+// サーバを介さずに、コマンドのハンドラを直接呼び出す
+pub fn call(db: &ShardedDb, args: &[&str]) -> Frame {
+    Registry::new().dispatch(db, command(args))
+}
+
+// ループバックの空きポートでサーバを起動し、そのアドレスを返す
+pub async fn start_server(config: Config) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server::run_with_config(listener, config));
+    addr
+}
+
+pub async fn connect(addr: SocketAddr) -> Connection {
+    Connection::new(TcpStream::connect(addr).await.unwrap())
+}
+
+// コマンドを送り、応答を一つ読む
+pub async fn request(conn: &mut Connection, args: &[&str]) -> Frame {
+    conn.write_frame(&command(args)).await.unwrap();
+    conn.read_frame().await.unwrap().unwrap()
+}
+
+// 互いに接続した TCP ソケットの組（クライアント側, サーバ側）
+pub async fn socket_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = TcpStream::connect(addr).await.unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (client, server)
+}
+
+// INFO の指定したセクションから、指定した項目の値を取り出す
+pub async fn info_field(conn: &mut Connection, section: &str, field: &str) -> String {
+    let info = match request(conn, &["INFO", section]).await {
+        Frame::Bulk(info) => String::from_utf8(info.to_vec()).unwrap(),
+        other => panic!("unexpected reply {:?}", other),
+    };
+    field_value(&info, field)
+}
+
+// INFO や CLUSTER INFO の形式（項目名:値 の行）から、指定した項目の値を取り出す
+pub fn field_value(info: &str, field: &str) -> String {
+    info.lines()
+        .find_map(|line| line.strip_prefix(&format!("{}:", field)))
+        .unwrap_or_else(|| panic!("no {} in {}", field, info))
+        .to_string()
+}
+
+// 条件を満たすまで少しずつ待つ
+macro_rules! wait_until {
+    ($condition:expr) => {{
+        let mut met = false;
+        for _ in 0..200 {
+            if $condition {
+                met = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(
+            met,
+            "condition was not met in time: {}",
+            stringify!($condition)
+        );
+    }};
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use common::socket_pair;
+use my_redis::frame::Frame;
+use my_redis::Connection;
+
+// 入れ子の配列は、要素ごとに型に応じた形式で書き出し、そのまま読み戻せる
+#[tokio::test]
+async fn nested_arrays_round_trip() {
+    let frame = Frame::Array(vec![
+        Frame::Integer(7),
+        Frame::Array(vec![
+            Frame::Null,
+            Frame::Error("ERR inner".into()),
+            Frame::Array(vec![Frame::Integer(3), Frame::Array(vec![])]),
+        ]),
+        Frame::Bulk(Bytes::from("tail")),
+    ]);
+    let encoded: &[u8] = b"*3\r\n:7\r\n*3\r\n$-1\r\n-ERR inner\r\n*2\r\n:3\r\n*0\r\n$4\r\ntail\r\n";
+
+    let (a, mut b) = socket_pair().await;
+    let mut writer = Connection::new(a);
+    writer.write_frame(&frame).await.unwrap();
+    drop(writer);
+    let mut written = Vec::new();
+    b.read_to_end(&mut written).await.unwrap();
+    assert_eq!(written, encoded);
+
+    let (mut a, b) = socket_pair().await;
+    a.write_all(encoded).await.unwrap();
+    drop(a);
+    let mut reader = Connection::new(b);
+    assert_eq!(reader.read_frame().await.unwrap(), Some(frame));
+    assert_eq!(reader.read_frame().await.unwrap(), None);
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use std::sync::Arc;
+
+use common::{bulk, call, error};
+use my_redis::cmd::Registry;
+use my_redis::db::new_sharded_db;
+use my_redis::frame::Frame;
+
+#[tokio::test]
+async fn integer_counters() {
+    let db = new_sharded_db(4);
+
+    assert_eq!(call(&db, &["INCR", "n"]), Frame::Integer(1));
+    assert_eq!(call(&db, &["INCRBY", "n", "41"]), Frame::Integer(42));
+    assert_eq!(call(&db, &["DECR", "n"]), Frame::Integer(41));
+    assert_eq!(call(&db, &["DECRBY", "n", "50"]), Frame::Integer(-9));
+    assert_eq!(call(&db, &["GET", "n"]), bulk("-9"));
+
+    call(&db, &["SET", "s", "abc"]);
+    assert_eq!(
+        call(&db, &["INCR", "s"]),
+        error("ERR value is not an integer or out of range")
+    );
+    assert_eq!(
+        call(&db, &["INCRBY", "n", "1.5"]),
+        error("ERR value is not an integer or out of range")
+    );
+}
+
+#[tokio::test]
+async fn overflow_is_rejected() {
+    let db = new_sharded_db(4);
+
+    call(&db, &["SET", "max", &i64::MAX.to_string()]);
+    assert_eq!(
+        call(&db, &["INCR", "max"]),
+        error("ERR increment or decrement would overflow")
+    );
+    assert_eq!(call(&db, &["GET", "max"]), bulk(&i64::MAX.to_string()));
+    assert_eq!(
+        call(&db, &["DECRBY", "x", &i64::MIN.to_string()]),
+        error("ERR decrement would overflow")
+    );
+}
+
+#[tokio::test]
+async fn counters_keep_ttl() {
+    let db = new_sharded_db(4);
+
+    call(&db, &["SET", "n", "1", "EX", "100"]);
+    call(&db, &["INCR", "n"]);
+    assert_eq!(call(&db, &["TTL", "n"]), Frame::Integer(100));
+}
+
+#[tokio::test]
+async fn float_counters() {
+    let db = new_sharded_db(4);
+
+    assert_eq!(call(&db, &["INCRBYFLOAT", "f", "10.5"]), bulk("10.5"));
+    assert_eq!(call(&db, &["INCRBYFLOAT", "f", "0.1"]), bulk("10.6"));
+    assert_eq!(call(&db, &["INCRBYFLOAT", "f", "-5.6"]), bulk("5"));
+    // 浮動小数点で加算したあとでも、整数として解釈できれば INCR できる
+    assert_eq!(call(&db, &["INCR", "f"]), Frame::Integer(6));
+
+    assert_eq!(
+        call(&db, &["INCRBYFLOAT", "f", "abc"]),
+        error("ERR value is not a valid float")
+    );
+    call(&db, &["SET", "big", "1e308"]);
+    assert_eq!(
+        call(&db, &["INCRBYFLOAT", "big", "1e308"]),
+        error("ERR increment would produce NaN or Infinity")
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_increments_are_atomic() {
+    let db = new_sharded_db(4);
+    let registry = Arc::new(Registry::new());
+
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let db = db.clone();
+        let registry = registry.clone();
+        handles.push(tokio::spawn(async move {
+            for _ in 0..1000 {
+                registry.dispatch(&db, Frame::command(["INCR", "hits"]));
+            }
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+
+    assert_eq!(call(&db, &["GET", "hits"]), bulk("8000"));
+}
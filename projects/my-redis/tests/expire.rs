@@ -0,0 +1,127 @@
+mod common;
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::time;
+
+use common::{bulk, call, error, ok};
+use my_redis::cmd::Registry;
+use my_redis::db::{new_sharded_db, spawn_purge_task};
+use my_redis::frame::Frame;
+
+#[tokio::test(start_paused = true)]
+async fn set_with_ex_and_px_expires_lazily() {
+    let db = new_sharded_db(4);
+
+    assert_eq!(call(&db, &["SET", "a", "1", "EX", "10"]), ok());
+    assert_eq!(call(&db, &["SET", "b", "2", "PX", "1500"]), ok());
+    assert_eq!(call(&db, &["TTL", "a"]), Frame::Integer(10));
+    assert_eq!(call(&db, &["PTTL", "b"]), Frame::Integer(1500));
+
+    time::advance(Duration::from_millis(1500)).await;
+    assert_eq!(call(&db, &["GET", "a"]), bulk("1"));
+    assert_eq!(call(&db, &["GET", "b"]), Frame::Null);
+    assert_eq!(call(&db, &["PTTL", "b"]), Frame::Integer(-2));
+
+    time::advance(Duration::from_millis(8500)).await;
+    assert_eq!(call(&db, &["GET", "a"]), Frame::Null);
+}
+
+#[tokio::test(start_paused = true)]
+async fn set_options() {
+    let db = new_sharded_db(4);
+
+    assert_eq!(call(&db, &["SET", "k", "v", "XX"]), Frame::Null);
+    assert_eq!(call(&db, &["SET", "k", "v", "NX", "EX", "5"]), ok());
+    assert_eq!(call(&db, &["SET", "k", "w", "NX"]), Frame::Null);
+    assert_eq!(call(&db, &["SET", "k", "w", "XX", "KEEPTTL"]), ok());
+    assert_eq!(call(&db, &["TTL", "k"]), Frame::Integer(5));
+    assert_eq!(call(&db, &["SET", "k", "x", "GET"]), bulk("w"));
+    assert_eq!(call(&db, &["TTL", "k"]), Frame::Integer(-1));
+
+    assert_eq!(
+        call(&db, &["SET", "k", "v", "EX", "0"]),
+        Frame::Error("ERR invalid expire time in 'set' command".into())
+    );
+    assert_eq!(
+        call(&db, &["SET", "k", "v", "EX", "1", "PX", "1"]),
+        Frame::Error("ERR syntax error".into())
+    );
+    assert_eq!(
+        call(&db, &["SET", "k", "v", "EX", "soon"]),
+        Frame::Error("ERR value is not an integer or out of range".into())
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn expire_family() {
+    let db = new_sharded_db(4);
+
+    assert_eq!(call(&db, &["EXPIRE", "missing", "10"]), Frame::Integer(0));
+    assert_eq!(call(&db, &["TTL", "missing"]), Frame::Integer(-2));
+
+    call(&db, &["SET", "k", "v"]);
+    assert_eq!(call(&db, &["TTL", "k"]), Frame::Integer(-1));
+    assert_eq!(call(&db, &["EXPIRE", "k", "100", "XX"]), Frame::Integer(0));
+    assert_eq!(call(&db, &["EXPIRE", "k", "100", "NX"]), Frame::Integer(1));
+    assert_eq!(call(&db, &["EXPIRE", "k", "50", "GT"]), Frame::Integer(0));
+    assert_eq!(call(&db, &["EXPIRE", "k", "50", "LT"]), Frame::Integer(1));
+    assert_eq!(call(&db, &["PEXPIRE", "k", "2500"]), Frame::Integer(1));
+    assert_eq!(call(&db, &["PTTL", "k"]), Frame::Integer(2500));
+
+    time::advance(Duration::from_millis(1000)).await;
+    assert_eq!(call(&db, &["TTL", "k"]), Frame::Integer(2));
+    assert_eq!(call(&db, &["PERSIST", "k"]), Frame::Integer(1));
+    assert_eq!(call(&db, &["PERSIST", "k"]), Frame::Integer(0));
+
+    time::advance(Duration::from_secs(10)).await;
+    assert_eq!(call(&db, &["GET", "k"]), bulk("v"));
+
+    // 0 以下の期限を指定するとその場で削除される
+    assert_eq!(call(&db, &["EXPIRE", "k", "-1"]), Frame::Integer(1));
+    assert_eq!(call(&db, &["GET", "k"]), Frame::Null);
+}
+
+#[tokio::test(start_paused = true)]
+async fn purge_task_removes_unread_keys() {
+    let db = new_sharded_db(4);
+    spawn_purge_task(&db);
+
+    for key in ["a", "b", "c", "d", "e", "f"] {
+        call(&db, &["SET", key, "v", "PX", "50"]);
+    }
+    call(&db, &["SET", "kept", "v"]);
+
+    time::sleep(Duration::from_millis(500)).await;
+
+    let remaining: usize = db.iter().map(|shard| shard.lock().unwrap().len()).sum();
+    assert_eq!(remaining, 1);
+}
+
+// キーは UTF-8 の文字列として保持するので、UTF-8 でないキーは取り違えないように拒否する
+#[tokio::test]
+async fn keys_must_be_valid_utf8() {
+    let db = new_sharded_db(4);
+    let registry = Registry::new();
+    let raw = |parts: &[&'static [u8]]| Frame::command(parts.iter().map(|p| Bytes::from_static(p)));
+    let invalid = error("ERR invalid key: keys must be valid UTF-8");
+
+    assert_eq!(
+        registry.dispatch(&db, raw(&[b"SET", b"\xff", b"a"])),
+        invalid
+    );
+    assert_eq!(registry.dispatch(&db, raw(&[b"GET", b"\xfe"])), invalid);
+    assert_eq!(
+        registry.dispatch(&db, raw(&[b"DEL", b"ok", b"\xfe"])),
+        invalid
+    );
+    assert_eq!(call(&db, &["KEYS", "*"]), Frame::Array(vec![]));
+
+    // 値は UTF-8 でなくてもよい
+    assert_eq!(registry.dispatch(&db, raw(&[b"SET", b"k", b"\xff"])), ok());
+    assert_eq!(
+        registry.dispatch(&db, raw(&[b"GET", b"k"])),
+        Frame::Bulk(Bytes::from_static(b"\xff"))
+    );
+}
@@ -0,0 +1,267 @@
+mod common;
+
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use common::socket_pair;
+use my_redis::frame::{Frame, Protocol};
+use my_redis::{connection, connection_without_buf_trait};
+
+// 両方の Connection 実装で読み書きするフレームの一覧
+fn corpus() -> Vec<Frame> {
+    vec![
+        Frame::Simple("OK".into()),
+        Frame::Error("ERR something went wrong".into()),
+        Frame::Integer(0),
+        Frame::Integer(-42),
+        Frame::Integer(i64::MAX),
+        Frame::Null,
+        Frame::Bulk(Bytes::new()),
+        Frame::Bulk(Bytes::from("hello")),
+        Frame::Bulk(Bytes::from(vec![b'\r'; 3])),
+        // 初期バッファ (4KB) より大きなフレーム
+        Frame::Bulk(Bytes::from(vec![b'x'; 100_000])),
+        Frame::Array(vec![]),
+        Frame::command(["SET", "foo", "bar"]),
+        Frame::Array(vec![
+            Frame::Integer(1),
+            Frame::Null,
+            Frame::Error("ERR nested".into()),
+            Frame::Array(vec![Frame::Bulk(Bytes::from("deep")), Frame::Array(vec![])]),
+        ]),
+    ]
+}
+
+// RESP3 で読み書きするフレームの一覧
+// RESP3 では Null 配列も `_` になるので、NullArray は含めない
+fn resp3_corpus() -> Vec<Frame> {
+    let bulk = |s: &str| Frame::Bulk(Bytes::from(s.to_string()));
+    vec![
+        Frame::Null,
+        Frame::Map(vec![]),
+        Frame::Map(vec![
+            (bulk("field"), bulk("value")),
+            (Frame::Integer(1), Frame::Set(vec![bulk("a"), bulk("b")])),
+        ]),
+        Frame::Set(vec![]),
+        Frame::Double(1.5),
+        Frame::Double(-0.25),
+        Frame::Double(f64::INFINITY),
+        Frame::Double(f64::NEG_INFINITY),
+        Frame::Boolean(true),
+        Frame::Boolean(false),
+        Frame::BigNumber("3492890328409238509324850943850943825024385".into()),
+        Frame::BigNumber("-1".into()),
+        Frame::Verbatim {
+            format: "txt".into(),
+            text: Bytes::from("Some string\r\nwith a line break"),
+        },
+        Frame::Verbatim {
+            format: "mkd".into(),
+            text: Bytes::new(),
+        },
+        Frame::Attribute(
+            vec![(bulk("ttl"), Frame::Integer(3600))],
+            Box::new(Frame::Array(vec![
+                Frame::Integer(2039123),
+                Frame::Double(0.5),
+            ])),
+        ),
+        Frame::Push(vec![bulk("message"), bulk("news"), bulk("hello")]),
+        Frame::Array(vec![Frame::Null, Frame::Push(vec![]), Frame::Boolean(true)]),
+    ]
+}
+
+// 指定した Connection 型で書き込んだフレームが、
+// もう一方の Connection 型で同じフレームとして読み出せることを確かめる
+macro_rules! round_trip {
+    ($writer:ty, $reader:ty) => {
+        round_trip!($writer, $reader, Protocol::Resp2, corpus())
+    };
+    ($writer:ty, $reader:ty, $protocol:expr, $corpus:expr) => {{
+        let frames = $corpus;
+        round_trip!($writer, $reader, $protocol, frames.clone(), frames)
+    }};
+    // protocol で書き込んだ frames が、expected として読み出せることを確かめる
+    ($writer:ty, $reader:ty, $protocol:expr, $frames:expr, $expected:expr) => {{
+        let (a, b) = socket_pair().await;
+        let mut writer = <$writer>::new(a);
+        writer.set_protocol($protocol);
+        let mut reader = <$reader>::new(b);
+
+        let frames: Vec<Frame> = $frames;
+        let expected: Vec<Frame> = $expected;
+        let write = tokio::spawn(async move {
+            for frame in &frames {
+                writer.write_frame(frame).await.unwrap();
+            }
+        });
+
+        for frame in expected {
+            assert_eq!(reader.read_frame().await.unwrap(), Some(frame));
+        }
+        write.await.unwrap();
+
+        // 書き込み側がドロップされたら EOF として None が返る
+        assert_eq!(reader.read_frame().await.unwrap(), None);
+    }};
+}
+
+#[tokio::test]
+async fn bytes_mut_round_trip() {
+    round_trip!(connection::Connection, connection::Connection);
+}
+
+#[tokio::test]
+async fn vec_cursor_round_trip() {
+    round_trip!(
+        connection_without_buf_trait::Connection,
+        connection_without_buf_trait::Connection
+    );
+}
+
+#[tokio::test]
+async fn backends_interoperate() {
+    round_trip!(
+        connection::Connection,
+        connection_without_buf_trait::Connection
+    );
+    round_trip!(
+        connection_without_buf_trait::Connection,
+        connection::Connection
+    );
+}
+
+#[tokio::test]
+async fn resp3_round_trip() {
+    round_trip!(
+        connection::Connection,
+        connection::Connection,
+        Protocol::Resp3,
+        resp3_corpus()
+    );
+    round_trip!(
+        connection_without_buf_trait::Connection,
+        connection_without_buf_trait::Connection,
+        Protocol::Resp3,
+        resp3_corpus()
+    );
+    round_trip!(
+        connection::Connection,
+        connection_without_buf_trait::Connection,
+        Protocol::Resp3,
+        resp3_corpus()
+    );
+}
+
+// RESP2 で書き込むと、RESP3 にしかない型は RESP2 の近い型に置き換わる
+#[tokio::test]
+async fn resp3_frames_are_downgraded_for_resp2() {
+    let bulk = |s: &str| Frame::Bulk(Bytes::from(s.to_string()));
+    let cases = vec![
+        (
+            Frame::Map(vec![(bulk("a"), Frame::Integer(1))]),
+            Frame::Array(vec![bulk("a"), Frame::Integer(1)]),
+        ),
+        (Frame::Set(vec![bulk("x")]), Frame::Array(vec![bulk("x")])),
+        (Frame::Double(2.5), bulk("2.5")),
+        (Frame::Double(3.0), bulk("3")),
+        (Frame::Boolean(true), Frame::Integer(1)),
+        (Frame::Boolean(false), Frame::Integer(0)),
+        (
+            Frame::BigNumber("12345678901234567890".into()),
+            bulk("12345678901234567890"),
+        ),
+        (
+            Frame::Verbatim {
+                format: "txt".into(),
+                text: Bytes::from("text"),
+            },
+            bulk("text"),
+        ),
+        (
+            Frame::Attribute(vec![(bulk("k"), bulk("v"))], Box::new(Frame::Integer(7))),
+            Frame::Integer(7),
+        ),
+        (
+            Frame::Push(vec![bulk("message")]),
+            Frame::Array(vec![bulk("message")]),
+        ),
+        (Frame::NullArray, Frame::NullArray),
+    ];
+    let (frames, expected): (Vec<_>, Vec<_>) = cases.into_iter().unzip();
+
+    round_trip!(
+        connection::Connection,
+        connection::Connection,
+        Protocol::Resp2,
+        frames.clone(),
+        expected.clone()
+    );
+    round_trip!(
+        connection_without_buf_trait::Connection,
+        connection_without_buf_trait::Connection,
+        Protocol::Resp2,
+        frames,
+        expected
+    );
+}
+
+// TCP 以外のストリームの上でも、両方の Connection 実装で同じように読み書きできることを確かめる
+async fn round_trip_over<A, B>(a: A, b: B)
+where
+    A: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut writer = connection::Connection::new(a);
+    let mut reader = connection_without_buf_trait::Connection::new(b);
+    writer.set_protocol(Protocol::Resp3);
+
+    let frames = [corpus(), resp3_corpus()].concat();
+    let expected = frames
+        .iter()
+        .map(|frame| match frame {
+            // RESP3 では Null 配列も `_` になる
+            Frame::NullArray => Frame::Null,
+            frame => frame.clone(),
+        })
+        .collect::<Vec<_>>();
+    let write = tokio::spawn(async move {
+        for frame in &frames {
+            writer.write_frame(frame).await.unwrap();
+        }
+    });
+
+    for frame in expected {
+        assert_eq!(reader.read_frame().await.unwrap(), Some(frame));
+    }
+    write.await.unwrap();
+    assert_eq!(reader.read_frame().await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn duplex_round_trip() {
+    // バッファを小さくして、フレームが細かく分かれて届くようにする
+    let (a, b) = tokio::io::duplex(64);
+    round_trip_over(a, b).await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket_round_trip() {
+    let (a, b) = tokio::net::UnixStream::pair().unwrap();
+    round_trip_over(a, b).await;
+}
+
+#[tokio::test]
+async fn partial_frame_is_reported_as_reset() {
+    use tokio::io::AsyncWriteExt;
+
+    let (mut a, b) = socket_pair().await;
+    let mut reader = connection_without_buf_trait::Connection::new(b);
+
+    a.write_all(b"$5\r\nhel").await.unwrap();
+    drop(a);
+
+    assert!(reader.read_frame().await.is_err());
+}
@@ -0,0 +1,152 @@
+mod common;
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use common::{bulk, call};
+use my_redis::db::new_sharded_db;
+use my_redis::frame::Frame;
+
+// フィールドと値を交互に並べた配列をマップに変換する
+fn pairs(items: Vec<Frame>) -> HashMap<Bytes, Bytes> {
+    items
+        .chunks(2)
+        .map(|pair| match pair {
+            [Frame::Bulk(f), Frame::Bulk(v)] => (f.clone(), v.clone()),
+            other => panic!("unexpected {:?}", other),
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn set_get_and_delete_fields() {
+    let db = new_sharded_db(4);
+
+    assert_eq!(
+        call(&db, &["HSET", "user:1", "name", "alice", "age", "30"]),
+        Frame::Integer(2)
+    );
+    assert_eq!(
+        call(&db, &["HSET", "user:1", "name", "bob", "city", "tokyo"]),
+        Frame::Integer(1)
+    );
+    assert_eq!(call(&db, &["HGET", "user:1", "name"]), bulk("bob"));
+    assert_eq!(call(&db, &["HGET", "user:1", "nope"]), Frame::Null);
+    assert_eq!(
+        call(&db, &["HMGET", "user:1", "age", "nope", "city"]),
+        Frame::Array(vec![bulk("30"), Frame::Null, bulk("tokyo")])
+    );
+    assert_eq!(call(&db, &["HLEN", "user:1"]), Frame::Integer(3));
+    assert_eq!(call(&db, &["HEXISTS", "user:1", "age"]), Frame::Integer(1));
+    assert_eq!(
+        call(&db, &["HSETNX", "user:1", "age", "99"]),
+        Frame::Integer(0)
+    );
+    assert_eq!(call(&db, &["TYPE", "user:1"]), Frame::Simple("hash".into()));
+
+    let Frame::Map(all) = call(&db, &["HGETALL", "user:1"]) else {
+        panic!("expected a map")
+    };
+    let all: HashMap<Bytes, Bytes> = all
+        .into_iter()
+        .map(|pair| match pair {
+            (Frame::Bulk(f), Frame::Bulk(v)) => (f, v),
+            other => panic!("unexpected {:?}", other),
+        })
+        .collect();
+    assert_eq!(all.len(), 3);
+    assert_eq!(all[&Bytes::from("city")], Bytes::from("tokyo"));
+
+    assert_eq!(
+        call(&db, &["HDEL", "user:1", "name", "age", "city", "nope"]),
+        Frame::Integer(3)
+    );
+    assert_eq!(call(&db, &["EXISTS", "user:1"]), Frame::Integer(0));
+    assert_eq!(call(&db, &["HGETALL", "user:1"]), Frame::Map(vec![]));
+}
+
+#[tokio::test]
+async fn hincrby_and_errors() {
+    let db = new_sharded_db(4);
+
+    assert_eq!(call(&db, &["HINCRBY", "h", "n", "5"]), Frame::Integer(5));
+    assert_eq!(call(&db, &["HINCRBY", "h", "n", "-7"]), Frame::Integer(-2));
+    call(&db, &["HSET", "h", "s", "abc"]);
+    assert_eq!(
+        call(&db, &["HINCRBY", "h", "s", "1"]),
+        Frame::Error("ERR hash value is not an integer".into())
+    );
+    assert_eq!(
+        call(&db, &["HSET", "h", "dangling"]),
+        Frame::Error("ERR wrong number of arguments for 'hset' command".into())
+    );
+
+    call(&db, &["SET", "str", "v"]);
+    assert_eq!(
+        call(&db, &["HGET", "str", "f"]),
+        Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())
+    );
+}
+
+#[tokio::test]
+async fn hscan_walks_all_fields() {
+    let db = new_sharded_db(4);
+    for i in 0..200 {
+        call(&db, &["HSET", "big", &format!("f{}", i), &i.to_string()]);
+    }
+
+    let mut seen = HashMap::new();
+    let mut cursor = "0".to_string();
+    loop {
+        let Frame::Array(mut parts) = call(&db, &["HSCAN", "big", &cursor, "COUNT", "16"]) else {
+            panic!("expected an array")
+        };
+        let Some(Frame::Array(items)) = parts.pop() else {
+            panic!("expected items")
+        };
+        seen.extend(pairs(items));
+        cursor = match parts.pop() {
+            Some(Frame::Bulk(b)) => String::from_utf8(b.to_vec()).unwrap(),
+            other => panic!("unexpected {:?}", other),
+        };
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(seen.len(), 200);
+    assert_eq!(seen[&Bytes::from("f42")], Bytes::from("42"));
+
+    let Frame::Array(parts) = call(&db, &["HSCAN", "big", "0", "MATCH", "f1?", "COUNT", "1000"])
+    else {
+        panic!("expected an array")
+    };
+    let Frame::Array(items) = &parts[1] else {
+        panic!("expected items")
+    };
+    assert_eq!(pairs(items.clone()).len(), 10);
+}
+
+// HSCAN がたどる索引は、フィールドの削除や上書きに合わせて更新される
+#[tokio::test]
+async fn hscan_follows_deleted_and_overwritten_fields() {
+    let db = new_sharded_db(4);
+    call(&db, &["HSET", "h", "a", "1", "b", "2", "c", "3"]);
+    call(&db, &["HDEL", "h", "b"]);
+    call(&db, &["HSET", "h", "c", "30"]);
+
+    let Frame::Array(parts) = call(&db, &["HSCAN", "h", "0", "COUNT", "100"]) else {
+        panic!("expected an array")
+    };
+    assert_eq!(parts[0], Frame::Bulk(Bytes::from("0")));
+    let Frame::Array(items) = parts[1].clone() else {
+        panic!("expected items")
+    };
+    assert_eq!(
+        pairs(items),
+        HashMap::from([
+            (Bytes::from("a"), Bytes::from("1")),
+            (Bytes::from("c"), Bytes::from("30")),
+        ])
+    );
+}
@@ -0,0 +1,148 @@
+mod common;
+
+use std::io::Cursor;
+
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use common::start_server;
+use my_redis::frame::{self, Frame};
+use my_redis::server::Config;
+
+// netcat のように生のバイト列を送り、期待する応答がそのまま返ってくることを確かめる
+async fn exchange(stream: &mut TcpStream, request: &[u8], expected: &[u8]) {
+    stream.write_all(request).await.unwrap();
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&reply),
+        String::from_utf8_lossy(expected)
+    );
+}
+
+fn parse(src: &[u8]) -> Result<Frame, frame::Error> {
+    let mut cursor = Cursor::new(src);
+    Frame::check(&mut cursor)?;
+    cursor.set_position(0);
+    Frame::parse(&mut cursor)
+}
+
+fn command(args: &[&[u8]]) -> Frame {
+    Frame::Array(
+        args.iter()
+            .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg)))
+            .collect(),
+    )
+}
+
+#[test]
+fn inline_commands_are_split_into_arguments() {
+    let cases: &[(&[u8], &[&[u8]])] = &[
+        (b"PING\r\n", &[b"PING"]),
+        (b"set  foo   bar\n", &[b"set", b"foo", b"bar"]),
+        (b"\t GET foo \r\n", &[b"GET", b"foo"]),
+        (
+            b"SET k \"hello world\"\r\n",
+            &[b"SET", b"k", b"hello world"],
+        ),
+        (b"SET k \"\"\r\n", &[b"SET", b"k", b""]),
+        (
+            b"SET k \"a\\nb\\t\\\"c\\\\\"\r\n",
+            &[b"SET", b"k", b"a\nb\t\"c\\"],
+        ),
+        (b"SET k \"\\x41\\x7a\\xzz\"\r\n", &[b"SET", b"k", b"Azxzz"]),
+        (
+            b"SET k 'it\\'s \"raw\" \\n'\r\n",
+            &[b"SET", b"k", b"it's \"raw\" \\n"],
+        ),
+        (b"SET k foo\"bar baz\"\r\n", &[b"SET", b"k", b"foobar baz"]),
+        // 空白だけの行は読み飛ばす
+        (b"\r\n  \n\r\nPING\r\n", &[b"PING"]),
+    ];
+    for (src, args) in cases {
+        assert_eq!(
+            parse(src).unwrap(),
+            command(args),
+            "{}",
+            String::from_utf8_lossy(src)
+        );
+    }
+}
+
+#[test]
+fn inline_commands_wait_for_the_end_of_the_line() {
+    for src in [&b"SET foo ba"[..], b"\r\n", b"  ", b"\r\nGET"] {
+        assert!(matches!(parse(src), Err(frame::Error::Incomplete)));
+    }
+    // 空行の後の RESP の配列は、配列としてパースする
+    assert_eq!(
+        parse(b"\r\n*1\r\n$4\r\nPING\r\n").unwrap(),
+        command(&[b"PING"])
+    );
+}
+
+#[test]
+fn unbalanced_quotes_are_rejected() {
+    for src in [
+        &b"SET k \"abc\r\n"[..],
+        b"SET k 'abc\r\n",
+        b"SET k \"abc\"def\r\n",
+        b"SET k \"abc\\\"\r\n",
+    ] {
+        assert!(matches!(parse(src), Err(frame::Error::Other(_))));
+    }
+}
+
+// インラインコマンドは最上位のフレームとしてだけ受け付ける
+#[test]
+fn malformed_elements_inside_arrays_are_rejected() {
+    for src in [
+        &b"*2\r\n$3\r\nGET\r\nfoo\r\n"[..],
+        b"*1\r\n\r\n$4\r\nPING\r\n",
+        b"%1\r\nkey\r\n:1\r\n",
+        b"|1\r\n+a\r\n+b\r\nPING\r\n",
+    ] {
+        assert!(
+            matches!(parse(src), Err(frame::Error::Other(_))),
+            "{}",
+            String::from_utf8_lossy(src)
+        );
+    }
+}
+
+#[tokio::test]
+async fn inline_and_resp_commands_share_a_connection() {
+    let mut stream = TcpStream::connect(start_server(Config::default()).await)
+        .await
+        .unwrap();
+
+    exchange(&mut stream, b"PING\r\n", b"+PONG\r\n").await;
+    exchange(&mut stream, b"SET greeting \"hello world\"\n", b"+OK\r\n").await;
+    exchange(
+        &mut stream,
+        b"*2\r\n$3\r\nGET\r\n$8\r\ngreeting\r\n",
+        b"$11\r\nhello world\r\n",
+    )
+    .await;
+    // 一度に届いた複数のインラインコマンドと、分割して届いたコマンド
+    exchange(&mut stream, b"INCR n\r\nINCR n\r\n", b":1\r\n:2\r\n").await;
+    stream.write_all(b"GET ").await.unwrap();
+    exchange(&mut stream, b"n\r\n", b"$1\r\n2\r\n").await;
+    exchange(&mut stream, b"\r\nEXISTS greeting n\r\n", b":2\r\n").await;
+}
+
+#[tokio::test]
+async fn unbalanced_quotes_close_the_connection() {
+    let mut stream = TcpStream::connect(start_server(Config::default()).await)
+        .await
+        .unwrap();
+
+    stream.write_all(b"SET k \"oops\r\n").await.unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "-ERR protocol error; unbalanced quotes in inline command\r\n"
+    );
+}
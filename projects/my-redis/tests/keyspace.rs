@@ -0,0 +1,196 @@
+mod common;
+
+use std::collections::HashSet;
+
+use bytes::Bytes;
+
+use common::call;
+use my_redis::db::new_sharded_db;
+use my_redis::frame::Frame;
+
+fn int(n: i64) -> Frame {
+    Frame::Integer(n)
+}
+
+// 配列フレームに含まれるバルク文字列を集める
+fn strings(frame: Frame) -> HashSet<String> {
+    match frame {
+        Frame::Array(items) => items
+            .into_iter()
+            .map(|item| match item {
+                Frame::Bulk(b) => String::from_utf8(b.to_vec()).unwrap(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect(),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn del_exists_type() {
+    let db = new_sharded_db(4);
+    call(&db, &["SET", "a", "1"]);
+    call(&db, &["SET", "b", "2"]);
+
+    assert_eq!(call(&db, &["EXISTS", "a", "b", "c", "a"]), int(3));
+    assert_eq!(call(&db, &["TYPE", "a"]), Frame::Simple("string".into()));
+    assert_eq!(call(&db, &["TYPE", "c"]), Frame::Simple("none".into()));
+    assert_eq!(call(&db, &["DEL", "a", "c", "b"]), int(2));
+    assert_eq!(call(&db, &["EXISTS", "a", "b"]), int(0));
+}
+
+#[tokio::test]
+async fn rename_across_shards() {
+    let db = new_sharded_db(8);
+    call(&db, &["SET", "src", "v", "EX", "100"]);
+
+    // 別々のシャードに属するキーにも移動できる
+    for i in 0..16 {
+        let to = format!("dst{}", i);
+        let from = if i == 0 {
+            "src".to_string()
+        } else {
+            format!("dst{}", i - 1)
+        };
+        assert_eq!(
+            call(&db, &["RENAME", &from, &to]),
+            Frame::Simple("OK".into())
+        );
+    }
+    assert_eq!(call(&db, &["GET", "dst15"]), Frame::Bulk(Bytes::from("v")));
+    assert_eq!(call(&db, &["TTL", "dst15"]), int(100));
+    assert_eq!(call(&db, &["EXISTS", "src"]), int(0));
+
+    assert_eq!(
+        call(&db, &["RENAME", "nope", "x"]),
+        Frame::Error("ERR no such key".into())
+    );
+
+    call(&db, &["SET", "other", "w"]);
+    assert_eq!(call(&db, &["RENAMENX", "dst15", "other"]), int(0));
+    assert_eq!(call(&db, &["RENAMENX", "dst15", "fresh"]), int(1));
+    assert_eq!(call(&db, &["GET", "fresh"]), Frame::Bulk(Bytes::from("v")));
+}
+
+#[tokio::test]
+async fn keys_with_glob_patterns() {
+    let db = new_sharded_db(4);
+    for key in ["hello", "hallo", "hxllo", "hllo", "heeeello", "world"] {
+        call(&db, &["SET", key, "v"]);
+    }
+
+    let set = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect::<HashSet<_>>();
+    assert_eq!(
+        strings(call(&db, &["KEYS", "h?llo"])),
+        set(&["hello", "hallo", "hxllo"])
+    );
+    assert_eq!(
+        strings(call(&db, &["KEYS", "h*llo"])),
+        set(&["hello", "hallo", "hxllo", "hllo", "heeeello"])
+    );
+    assert_eq!(
+        strings(call(&db, &["KEYS", "h[ae]llo"])),
+        set(&["hello", "hallo"])
+    );
+    assert_eq!(
+        strings(call(&db, &["KEYS", "h[^e]llo"])),
+        set(&["hallo", "hxllo"])
+    );
+    assert_eq!(strings(call(&db, &["KEYS", "h[a-b]llo"])), set(&["hallo"]));
+    assert_eq!(strings(call(&db, &["KEYS", "*"])).len(), 6);
+}
+
+#[tokio::test]
+async fn scan_walks_every_key_once() {
+    let db = new_sharded_db(5);
+    let mut expected = HashSet::new();
+    for i in 0..500 {
+        let key = format!("key:{}", i);
+        call(&db, &["SET", &key, "v"]);
+        expected.insert(key);
+    }
+
+    let mut seen = HashSet::new();
+    let mut cursor = "0".to_string();
+    let mut calls = 0;
+    loop {
+        let reply = call(&db, &["SCAN", &cursor, "COUNT", "37"]);
+        let (next, keys) = match reply {
+            Frame::Array(mut parts) => {
+                let keys = parts.pop().unwrap();
+                (parts.pop().unwrap(), keys)
+            }
+            other => panic!("unexpected {:?}", other),
+        };
+        for key in strings(keys) {
+            assert!(seen.insert(key), "key returned twice");
+        }
+        calls += 1;
+
+        cursor = match next {
+            Frame::Bulk(b) => String::from_utf8(b.to_vec()).unwrap(),
+            other => panic!("unexpected {:?}", other),
+        };
+        if cursor == "0" {
+            break;
+        }
+    }
+
+    assert_eq!(seen, expected);
+    assert!(calls > 1);
+}
+
+#[tokio::test]
+async fn scan_survives_concurrent_writes() {
+    let db = new_sharded_db(3);
+    for i in 0..100 {
+        call(&db, &["SET", &format!("stable:{}", i), "v"]);
+    }
+
+    let mut seen = HashSet::new();
+    let mut cursor = "0".to_string();
+    let mut round = 0;
+    loop {
+        // 途中で追加・削除されるキーがあっても、ずっと存在するキーは取りこぼさない
+        call(&db, &["SET", &format!("temp:{}", round), "v"]);
+        call(&db, &["DEL", &format!("temp:{}", round.max(1) - 1)]);
+        round += 1;
+
+        let reply = call(&db, &["SCAN", &cursor, "MATCH", "stable:*", "COUNT", "10"]);
+        let Frame::Array(mut parts) = reply else {
+            panic!("unexpected reply")
+        };
+        seen.extend(strings(parts.pop().unwrap()));
+        cursor = match parts.pop().unwrap() {
+            Frame::Bulk(b) => String::from_utf8(b.to_vec()).unwrap(),
+            other => panic!("unexpected {:?}", other),
+        };
+        if cursor == "0" {
+            break;
+        }
+    }
+
+    assert_eq!(seen.len(), 100);
+}
+
+// SCAN がたどる索引は、キーの削除や名前の変更、期限切れに合わせて更新される
+#[tokio::test(start_paused = true)]
+async fn scan_skips_removed_renamed_and_expired_keys() {
+    let db = new_sharded_db(1);
+    for key in ["kept", "deleted", "old", "expiring"] {
+        call(&db, &["SET", key, "v"]);
+    }
+    call(&db, &["DEL", "deleted"]);
+    call(&db, &["RENAME", "old", "new"]);
+    call(&db, &["PEXPIRE", "expiring", "100"]);
+    tokio::time::advance(std::time::Duration::from_millis(200)).await;
+
+    let Frame::Array(mut parts) = call(&db, &["SCAN", "0", "COUNT", "100"]) else {
+        panic!("expected an array")
+    };
+    assert_eq!(
+        strings(parts.pop().unwrap()),
+        HashSet::from(["kept".to_string(), "new".to_string()])
+    );
+    assert_eq!(parts.pop(), Some(Frame::Bulk(Bytes::from("0"))));
+}
@@ -0,0 +1,162 @@
+mod common;
+
+use std::io::Cursor;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use common::{socket_pair, start_server};
+use my_redis::frame::{Frame, Limits};
+use my_redis::server::Config;
+use my_redis::{connection, connection_without_buf_trait};
+
+fn limits() -> Limits {
+    Limits {
+        max_bulk_len: 16,
+        max_array_len: 8,
+        max_depth: 2,
+        max_buffer: 1024,
+    }
+}
+
+// 上限を超えるフレーム（の先頭）
+fn oversized() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("bulk length 17", b"*2\r\n$3\r\nGET\r\n$17\r\n".to_vec()),
+        ("array length 9", b"*9\r\n".to_vec()),
+        ("nesting depth", b"*1\r\n*1\r\n*1\r\n".to_vec()),
+        ("bulk length 100", b"=100\r\ntxt:".to_vec()),
+        ("array length 9", b"%9\r\n".to_vec()),
+        // 長さの上限内でも、まだフレームになっていないデータが溜まりすぎれば切る
+        ("bytes buffered", [&b"+"[..], &[b'x'; 1100]].concat()),
+    ]
+}
+
+// 上限を超えるフレームを読み込むと、データが揃うのを待たずにエラーになることを確かめる
+macro_rules! rejects_oversized {
+    ($conn:ty) => {{
+        for (message, bytes) in oversized() {
+            let (mut a, b) = socket_pair().await;
+            let mut reader = <$conn>::new(b);
+            reader.set_limits(limits());
+
+            a.write_all(&bytes).await.unwrap();
+            let err = reader.read_frame().await.unwrap_err().to_string();
+            assert!(
+                err.contains(message),
+                "{} does not mention {}",
+                err,
+                message
+            );
+        }
+    }};
+}
+
+#[tokio::test]
+async fn bytes_mut_connection_rejects_oversized_frames() {
+    rejects_oversized!(connection::Connection);
+}
+
+#[tokio::test]
+async fn vec_connection_rejects_oversized_frames() {
+    rejects_oversized!(connection_without_buf_trait::Connection);
+}
+
+#[tokio::test]
+async fn frames_within_the_limits_are_accepted() {
+    let (mut a, b) = socket_pair().await;
+    let mut reader = connection::Connection::new(b);
+    reader.set_limits(limits());
+
+    a.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$16\r\nvvvvvvvvvvvvvvvv\r\n")
+        .await
+        .unwrap();
+    assert_eq!(
+        reader.read_frame().await.unwrap(),
+        Some(Frame::command(["SET", "key", "vvvvvvvvvvvvvvvv"]))
+    );
+
+    a.write_all(b"*1\r\n*8\r\n:1\r\n:1\r\n:1\r\n:1\r\n:1\r\n:1\r\n:1\r\n:1\r\n")
+        .await
+        .unwrap();
+    assert_eq!(
+        reader.read_frame().await.unwrap(),
+        Some(Frame::Array(vec![Frame::Array(vec![Frame::Integer(1); 8])]))
+    );
+
+    // 上限内のフレームが分かれて届いても、揃うまで待つ
+    a.write_all(b"*1\r\n$4\r\nPI").await.unwrap();
+    a.write_all(b"NG\r\n").await.unwrap();
+    assert_eq!(
+        reader.read_frame().await.unwrap(),
+        Some(Frame::command(["PING"]))
+    );
+}
+
+#[tokio::test]
+async fn server_replies_with_a_protocol_error_and_closes() {
+    let addr = start_server(Config {
+        limits: limits(),
+        ..Config::default()
+    })
+    .await;
+    for (message, bytes) in oversized() {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&bytes).await.unwrap();
+
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        let reply = String::from_utf8(reply).unwrap();
+        assert!(
+            reply.starts_with("-ERR protocol error; ") && reply.contains(message),
+            "{:?} does not mention {}",
+            reply,
+            message
+        );
+    }
+
+    // 上限内のコマンドはこれまでどおり実行できる
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$16\r\nvvvvvvvvvvvvvvvv\r\n")
+        .await
+        .unwrap();
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"+OK\r\n");
+}
+
+// 属性（|）だけが連なるフレームも入れ子として数え、深すぎればスタックを使い切る前に拒否する
+#[tokio::test]
+async fn long_attribute_chains_are_rejected() {
+    let chain = |len: usize| [b"|0\r\n".repeat(len), b":1\r\n".to_vec()].concat();
+
+    let long = chain(200_000);
+    let err =
+        Frame::check_with_limits(&mut Cursor::new(&long[..]), &Limits::default()).unwrap_err();
+    assert!(err.to_string().contains("nesting depth"), "{}", err);
+    // 上限の 2 までなら受け付ける
+    assert!(Frame::check_with_limits(&mut Cursor::new(&chain(2)[..]), &limits()).is_ok());
+    assert!(Frame::check_with_limits(&mut Cursor::new(&chain(3)[..]), &limits()).is_err());
+
+    // サーバは読み終えていないデータを残して切断すると応答が届かないことがあるので、
+    // 一度に読み込める長さで上限を超える
+    let addr = start_server(Config::default()).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&chain(100)).await.unwrap();
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await.unwrap();
+    let reply = String::from_utf8(reply).unwrap();
+    assert!(
+        reply.starts_with("-ERR protocol error; nesting depth exceeds the limit"),
+        "{:?}",
+        reply
+    );
+
+    // サーバは動き続けている
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+    let mut reply = [0; 7];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"+PONG\r\n");
+}
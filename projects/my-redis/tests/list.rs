@@ -0,0 +1,90 @@
+mod common;
+
+use common::{bulk, call};
+use my_redis::db::new_sharded_db;
+use my_redis::frame::Frame;
+
+fn array(items: &[&str]) -> Frame {
+    Frame::Array(items.iter().map(|s| bulk(s)).collect())
+}
+
+fn wrong_type() -> Frame {
+    Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())
+}
+
+#[tokio::test]
+async fn push_pop_and_range() {
+    let db = new_sharded_db(4);
+
+    assert_eq!(call(&db, &["RPUSH", "q", "a", "b", "c"]), Frame::Integer(3));
+    assert_eq!(call(&db, &["LPUSH", "q", "y", "z"]), Frame::Integer(5));
+    assert_eq!(
+        call(&db, &["LRANGE", "q", "0", "-1"]),
+        array(&["z", "y", "a", "b", "c"])
+    );
+    assert_eq!(call(&db, &["LRANGE", "q", "-2", "100"]), array(&["b", "c"]));
+    assert_eq!(call(&db, &["LRANGE", "q", "3", "1"]), array(&[]));
+    assert_eq!(call(&db, &["LLEN", "q"]), Frame::Integer(5));
+
+    assert_eq!(call(&db, &["LPOP", "q"]), bulk("z"));
+    assert_eq!(call(&db, &["RPOP", "q", "2"]), array(&["c", "b"]));
+    assert_eq!(call(&db, &["LPOP", "q", "10"]), array(&["y", "a"]));
+
+    // 空になったリストはキーごと削除される
+    assert_eq!(call(&db, &["EXISTS", "q"]), Frame::Integer(0));
+    assert_eq!(call(&db, &["LPOP", "q"]), Frame::Null);
+    assert_eq!(call(&db, &["LPOP", "q", "2"]), Frame::Null);
+    assert_eq!(call(&db, &["LPUSHX", "q", "a"]), Frame::Integer(0));
+    assert_eq!(call(&db, &["EXISTS", "q"]), Frame::Integer(0));
+}
+
+#[tokio::test]
+async fn index_set_and_trim() {
+    let db = new_sharded_db(4);
+    call(&db, &["RPUSH", "l", "a", "b", "c", "d", "e"]);
+
+    assert_eq!(call(&db, &["LINDEX", "l", "1"]), bulk("b"));
+    assert_eq!(call(&db, &["LINDEX", "l", "-1"]), bulk("e"));
+    assert_eq!(call(&db, &["LINDEX", "l", "5"]), Frame::Null);
+
+    assert_eq!(
+        call(&db, &["LSET", "l", "-2", "D"]),
+        Frame::Simple("OK".into())
+    );
+    assert_eq!(
+        call(&db, &["LSET", "l", "9", "x"]),
+        Frame::Error("ERR index out of range".into())
+    );
+    assert_eq!(
+        call(&db, &["LSET", "nope", "0", "x"]),
+        Frame::Error("ERR no such key".into())
+    );
+
+    assert_eq!(
+        call(&db, &["LTRIM", "l", "1", "-2"]),
+        Frame::Simple("OK".into())
+    );
+    assert_eq!(
+        call(&db, &["LRANGE", "l", "0", "-1"]),
+        array(&["b", "c", "D"])
+    );
+    call(&db, &["LTRIM", "l", "5", "10"]);
+    assert_eq!(call(&db, &["EXISTS", "l"]), Frame::Integer(0));
+}
+
+#[tokio::test]
+async fn wrong_type_errors() {
+    let db = new_sharded_db(4);
+    call(&db, &["SET", "s", "v"]);
+    call(&db, &["RPUSH", "l", "a"]);
+
+    assert_eq!(call(&db, &["LPUSH", "s", "a"]), wrong_type());
+    assert_eq!(call(&db, &["LRANGE", "s", "0", "-1"]), wrong_type());
+    assert_eq!(call(&db, &["GET", "l"]), wrong_type());
+    assert_eq!(call(&db, &["INCR", "l"]), wrong_type());
+    assert_eq!(call(&db, &["TYPE", "l"]), Frame::Simple("list".into()));
+
+    // SET は型に関係なく上書きする
+    assert_eq!(call(&db, &["SET", "l", "v"]), Frame::Simple("OK".into()));
+    assert_eq!(call(&db, &["GET", "l"]), bulk("v"));
+}
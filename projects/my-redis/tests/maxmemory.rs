@@ -0,0 +1,175 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::time;
+
+use common::{connect, info_field, ok, request, start_server};
+use my_redis::evict::Policy;
+use my_redis::frame::Frame;
+use my_redis::server::Config;
+use my_redis::Connection;
+
+// 1 つのキーが 170 バイト前後になるので、キーは 11 個ほどしか入らない
+const MAXMEMORY: usize = 2000;
+
+// maxmemory を小さくし、指定した追い出し方針でサーバを起動する
+async fn start(policy: Policy) -> SocketAddr {
+    start_server(Config {
+        maxmemory: MAXMEMORY,
+        maxmemory_policy: policy,
+        ..Config::default()
+    })
+    .await
+}
+
+async fn used_memory(conn: &mut Connection) -> usize {
+    info_field(conn, "memory", "used_memory")
+        .await
+        .parse()
+        .unwrap()
+}
+
+async fn exists(conn: &mut Connection, key: &str) -> bool {
+    request(conn, &["EXISTS", key]).await == Frame::Integer(1)
+}
+
+fn value() -> String {
+    "v".repeat(100)
+}
+
+#[tokio::test]
+async fn noeviction_rejects_writes_that_need_memory() {
+    let mut conn = connect(start(Policy::NoEviction).await).await;
+    let oom = Frame::Error("OOM command not allowed when used memory > 'maxmemory'.".into());
+
+    let mut written = 0;
+    loop {
+        let key = format!("key:{}", written);
+        match request(&mut conn, &["SET", &key, &value()]).await {
+            reply if reply == ok() => written += 1,
+            reply => {
+                assert_eq!(reply, oom);
+                break;
+            }
+        }
+        assert!(written < 100, "never ran out of memory");
+    }
+    assert!(written > 5);
+    assert_eq!(request(&mut conn, &["RPUSH", "list", "x"]).await, oom);
+    // 読み込みとメモリを空けるコマンドは実行できる
+    assert!(exists(&mut conn, "key:0").await);
+    assert_eq!(
+        request(&mut conn, &["DEL", "key:0", "key:1"]).await,
+        Frame::Integer(2)
+    );
+    assert_eq!(request(&mut conn, &["SET", "again", "v"]).await, ok());
+    assert_eq!(info_field(&mut conn, "memory", "evicted_keys").await, "0");
+    assert_eq!(
+        info_field(&mut conn, "memory", "maxmemory").await,
+        MAXMEMORY.to_string()
+    );
+    assert_eq!(
+        info_field(&mut conn, "memory", "maxmemory_policy").await,
+        "noeviction"
+    );
+}
+
+#[tokio::test]
+async fn allkeys_lru_keeps_recently_used_keys() {
+    let mut conn = connect(start(Policy::AllKeysLru).await).await;
+    request(&mut conn, &["SET", "hot", &value()]).await;
+    for i in 0..40 {
+        let key = format!("key:{}", i);
+        assert_eq!(request(&mut conn, &["SET", &key, &value()]).await, ok());
+        // hot は書き込みのたびに読まれるので、最後に使われてから最も時間が経つことはない
+        assert!(exists(&mut conn, "hot").await, "hot was evicted at {}", i);
+        request(&mut conn, &["GET", "hot"]).await;
+        time::sleep(Duration::from_millis(2)).await;
+    }
+
+    assert!(used_memory(&mut conn).await <= MAXMEMORY + 200);
+    assert!(
+        info_field(&mut conn, "memory", "evicted_keys")
+            .await
+            .parse::<u64>()
+            .unwrap()
+            >= 20
+    );
+    assert!(!exists(&mut conn, "key:0").await);
+    assert!(exists(&mut conn, "key:39").await);
+}
+
+#[tokio::test]
+async fn allkeys_lfu_keeps_frequently_used_keys() {
+    let mut conn = connect(start(Policy::AllKeysLfu).await).await;
+    request(&mut conn, &["SET", "hot", &value()]).await;
+    // カウンタは確率的に増えるので、新しいキーより確実に大きくなるだけアクセスしておく
+    for _ in 0..200 {
+        request(&mut conn, &["GET", "hot"]).await;
+    }
+    for i in 0..40 {
+        let key = format!("key:{}", i);
+        assert_eq!(request(&mut conn, &["SET", &key, &value()]).await, ok());
+    }
+
+    assert!(exists(&mut conn, "hot").await);
+    assert!(used_memory(&mut conn).await <= MAXMEMORY + 200);
+}
+
+#[tokio::test]
+async fn volatile_ttl_evicts_the_keys_expiring_soonest() {
+    let mut conn = connect(start(Policy::VolatileTtl).await).await;
+    request(&mut conn, &["SET", "persistent", &value()]).await;
+    for i in 0..8 {
+        let key = format!("ttl:{}", i);
+        let ttl = (1000 + i * 100).to_string();
+        request(&mut conn, &["SET", &key, &value(), "EX", &ttl]).await;
+    }
+    for i in 0..5 {
+        let key = format!("key:{}", i);
+        assert_eq!(request(&mut conn, &["SET", &key, &value()]).await, ok());
+    }
+
+    // 有効期限の早いキーから追い出し、期限のないキーは追い出さない
+    assert!(!exists(&mut conn, "ttl:0").await);
+    assert!(exists(&mut conn, "ttl:7").await);
+    assert!(exists(&mut conn, "persistent").await);
+    for i in 0..5 {
+        assert!(exists(&mut conn, &format!("key:{}", i)).await);
+    }
+
+    // 追い出せるキーがなくなれば、noeviction と同じく拒否する
+    for i in 5..20 {
+        let key = format!("key:{}", i);
+        if request(&mut conn, &["SET", &key, &value()]).await != ok() {
+            assert!(!exists(&mut conn, "ttl:7").await);
+            return;
+        }
+    }
+    panic!("never ran out of keys with a TTL");
+}
+
+#[tokio::test]
+async fn allkeys_random_stays_under_the_limit() {
+    let mut conn = connect(start(Policy::AllKeysRandom).await).await;
+    for i in 0..100 {
+        let key = format!("key:{}", i);
+        assert_eq!(request(&mut conn, &["SET", &key, &value()]).await, ok());
+        assert!(used_memory(&mut conn).await <= MAXMEMORY + 200);
+    }
+    // リストなどへの追加で値が大きくなった分も数える
+    for _ in 0..20 {
+        request(&mut conn, &["RPUSH", "list", &value()]).await;
+    }
+    request(&mut conn, &["SET", "last", "v"]).await;
+    assert!(used_memory(&mut conn).await <= MAXMEMORY + 200);
+    assert!(
+        info_field(&mut conn, "memory", "evicted_keys")
+            .await
+            .parse::<u64>()
+            .unwrap()
+            > 80
+    );
+}
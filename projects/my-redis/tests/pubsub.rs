@@ -0,0 +1,175 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::net::TcpListener;
+
+use common::{bulks, connect, start_server};
+use my_redis::frame::Frame;
+use my_redis::pubsub::{Command, Hub, Subscriber};
+use my_redis::server::Config;
+use my_redis::Connection;
+
+async fn send(conn: &mut Connection, args: &[&'static str]) {
+    conn.write_frame(&Frame::command(args.iter().copied()))
+        .await
+        .unwrap();
+}
+
+async fn read(conn: &mut Connection) -> Frame {
+    conn.read_frame().await.unwrap().unwrap()
+}
+
+async fn call(conn: &mut Connection, args: &[&'static str]) -> Frame {
+    send(conn, args).await;
+    read(conn).await
+}
+
+fn reply(kind: &str, name: &str, count: i64) -> Frame {
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::from(kind.to_string())),
+        Frame::Bulk(Bytes::from(name.to_string())),
+        Frame::Integer(count),
+    ])
+}
+
+#[tokio::test]
+async fn publish_reaches_channel_and_pattern_subscribers() {
+    let addr = start_server(Config::default()).await;
+    let mut sub = connect(addr).await;
+    let mut publisher = connect(addr).await;
+
+    send(&mut sub, &["SUBSCRIBE", "news", "sport"]).await;
+    assert_eq!(read(&mut sub).await, reply("subscribe", "news", 1));
+    assert_eq!(read(&mut sub).await, reply("subscribe", "sport", 2));
+    assert_eq!(
+        call(&mut sub, &["PSUBSCRIBE", "n*"]).await,
+        reply("psubscribe", "n*", 3)
+    );
+
+    // news には通常の購読とパターン購読の 2 つが届く
+    assert_eq!(
+        call(&mut publisher, &["PUBLISH", "news", "hello"]).await,
+        Frame::Integer(2)
+    );
+    assert_eq!(read(&mut sub).await, bulks(&["message", "news", "hello"]));
+    assert_eq!(
+        read(&mut sub).await,
+        bulks(&["pmessage", "n*", "news", "hello"])
+    );
+
+    assert_eq!(
+        call(&mut publisher, &["PUBLISH", "weather", "rain"]).await,
+        Frame::Integer(0)
+    );
+    assert_eq!(
+        call(&mut publisher, &["PUBLISH", "sport", "goal"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(read(&mut sub).await, bulks(&["message", "sport", "goal"]));
+}
+
+#[tokio::test]
+async fn subscribed_mode_and_unsubscribe() {
+    let addr = start_server(Config::default()).await;
+    let mut sub = connect(addr).await;
+
+    send(&mut sub, &["SUBSCRIBE", "a", "b"]).await;
+    read(&mut sub).await;
+    read(&mut sub).await;
+
+    assert_eq!(call(&mut sub, &["PING"]).await, bulks(&["pong", ""]));
+    assert_eq!(
+        call(&mut sub, &["GET", "k"]).await,
+        Frame::Error(
+            "ERR Can't execute 'get': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in this context"
+                .into()
+        )
+    );
+
+    assert_eq!(
+        call(&mut sub, &["UNSUBSCRIBE", "a"]).await,
+        reply("unsubscribe", "a", 1)
+    );
+    assert_eq!(
+        call(&mut sub, &["UNSUBSCRIBE"]).await,
+        reply("unsubscribe", "b", 0)
+    );
+
+    // すべての購読を解除すると、通常のコマンドを受け付ける
+    assert_eq!(
+        call(&mut sub, &["PING"]).await,
+        Frame::Simple("PONG".into())
+    );
+    assert_eq!(
+        call(&mut sub, &["UNSUBSCRIBE"]).await,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("unsubscribe")),
+            Frame::Null,
+            Frame::Integer(0),
+        ])
+    );
+}
+
+#[tokio::test]
+async fn hub_counts_receivers_and_drops_idle_channels() {
+    let hub = Hub::new();
+    assert_eq!(hub.publish(&Bytes::from("c"), Bytes::from("m")), 0);
+
+    let mut a = hub.subscribe(&Bytes::from("c"));
+    let b = hub.subscribe(&Bytes::from("c"));
+    let mut p = hub.psubscribe(&Bytes::from("[cd]"));
+    assert_eq!(hub.publish(&Bytes::from("c"), Bytes::from("m")), 3);
+    assert_eq!(a.recv().await.unwrap(), Bytes::from("m"));
+    assert_eq!(
+        p.recv().await.unwrap(),
+        (Bytes::from("c"), Bytes::from("m"))
+    );
+
+    drop(b);
+    assert_eq!(hub.publish(&Bytes::from("c"), Bytes::from("m")), 2);
+    assert_eq!(hub.publish(&Bytes::from("e"), Bytes::from("m")), 0);
+}
+
+// 購読を解除したり接続を切ったりしたら、購読者のいなくなったチャンネルとパターンを取り除く
+#[tokio::test]
+async fn hub_forgets_channels_without_subscribers() {
+    let hub = Arc::new(Hub::new());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = {
+        let hub = hub.clone();
+        tokio::spawn(async move {
+            let mut conn = Connection::new(listener.accept().await.unwrap().0);
+            let frame = conn.read_frame().await.unwrap().unwrap();
+            let command = Command::parse(&frame).unwrap().unwrap();
+            Subscriber::new(hub).run(&mut conn, command).await.unwrap();
+        })
+    };
+    // 中断したタスクの後始末は少し遅れて行われるので、変わるまで待つ
+    async fn wait_for(hub: &Hub, counts: (usize, usize)) {
+        for _ in 0..100 {
+            if hub.counts() == counts {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(hub.counts(), counts);
+    }
+
+    let mut client = connect(addr).await;
+    send(&mut client, &["SUBSCRIBE", "news", "sport"]).await;
+    read(&mut client).await;
+    read(&mut client).await;
+    call(&mut client, &["PSUBSCRIBE", "n*"]).await;
+    assert_eq!(hub.counts(), (2, 1));
+
+    call(&mut client, &["UNSUBSCRIBE", "news"]).await;
+    wait_for(&hub, (1, 1)).await;
+
+    drop(client);
+    server.await.unwrap();
+    wait_for(&hub, (0, 0)).await;
+}
@@ -0,0 +1,227 @@
+mod common;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+use common::{bulk, bulks, call, command};
+use my_redis::db::{new_sharded_db, ShardedDb, Value};
+use my_redis::frame::Frame;
+use my_redis::rdb::{self, Record, SaveRule, Snapshots};
+use my_redis::server::{self, Config};
+use my_redis::Connection;
+
+// テストごとに別の一時ファイルを使う
+fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("my-redis-{}-{}.rdb", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+// スナップショットの保存先を設定した db を作る
+fn db_with_snapshots(path: &PathBuf, rules: Vec<SaveRule>) -> ShardedDb {
+    let db = new_sharded_db(4);
+    db.enable_snapshots(Snapshots::new(path, rules, 0));
+    db
+}
+
+#[tokio::test]
+async fn save_and_load_every_type() {
+    let path = temp_path("every-type");
+    let db = db_with_snapshots(&path, vec![]);
+
+    call(&db, &["SET", "str", "hello"]);
+    call(&db, &["SET", "ttl", "soon", "EX", "100"]);
+    call(&db, &["SET", "gone", "x", "PX", "1"]);
+    call(&db, &["RPUSH", "list", "a", "b", "c"]);
+    call(&db, &["HSET", "hash", "f", "v"]);
+    call(&db, &["SADD", "set", "m"]);
+    call(&db, &["ZADD", "zset", "1.5", "a", "-2", "b"]);
+    call(&db, &["XADD", "stream", "1-1", "k", "v"]);
+    call(&db, &["XADD", "stream", "2-0", "k", "w"]);
+    call(&db, &["XGROUP", "CREATE", "stream", "g", "0"]);
+    call(
+        &db,
+        &[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "c",
+            "COUNT",
+            "1",
+            "STREAMS",
+            "stream",
+            ">",
+        ],
+    );
+    call(&db, &["XADD", "stream", "MAXLEN", "1", "3-0", "k", "x"]);
+    time::sleep(Duration::from_millis(5)).await;
+
+    assert_eq!(call(&db, &["SAVE"]), Frame::Simple("OK".into()));
+    assert!(matches!(call(&db, &["LASTSAVE"]), Frame::Integer(n) if n > 0));
+
+    let loaded = new_sharded_db(4);
+    assert_eq!(rdb::load(&loaded, &path).unwrap(), 7);
+
+    assert_eq!(call(&loaded, &["GET", "str"]), bulk("hello"));
+    assert!(matches!(call(&loaded, &["TTL", "ttl"]), Frame::Integer(n) if n > 90 && n <= 100));
+    assert_eq!(call(&loaded, &["EXISTS", "gone"]), Frame::Integer(0));
+    assert_eq!(
+        call(&loaded, &["LRANGE", "list", "0", "-1"]),
+        bulks(&["a", "b", "c"])
+    );
+    assert_eq!(call(&loaded, &["HGET", "hash", "f"]), bulk("v"));
+    assert_eq!(call(&loaded, &["SISMEMBER", "set", "m"]), Frame::Integer(1));
+    assert_eq!(
+        call(&loaded, &["ZRANGE", "zset", "0", "-1", "WITHSCORES"]),
+        Frame::Array(vec![
+            bulk("b"),
+            Frame::Double(-2.0),
+            bulk("a"),
+            Frame::Double(1.5)
+        ])
+    );
+
+    // 削除済みのエントリの ID、グループ、PEL も復元される
+    assert_eq!(call(&loaded, &["XLEN", "stream"]), Frame::Integer(1));
+    assert!(matches!(
+        call(&loaded, &["XADD", "stream", "3-0", "k", "y"]),
+        Frame::Error(_)
+    ));
+    assert_eq!(
+        call(&loaded, &["XPENDING", "stream", "g"]),
+        Frame::Array(vec![
+            Frame::Integer(1),
+            bulk("1-1"),
+            bulk("1-1"),
+            Frame::Array(vec![bulks(&["c", "1"])]),
+        ])
+    );
+    assert_eq!(
+        call(
+            &loaded,
+            &["XREADGROUP", "GROUP", "g", "c", "STREAMS", "stream", ">"]
+        ),
+        Frame::Array(vec![Frame::Array(vec![
+            bulk("stream"),
+            Frame::Array(vec![Frame::Array(vec![bulk("3-0"), bulks(&["k", "x"])])]),
+        ])])
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn decode_rejects_damaged_files() {
+    let records = vec![Record {
+        key: "k".to_string(),
+        value: Value::String(Bytes::from("v")),
+        expires_at_ms: Some(1234),
+    }];
+    let buf = rdb::encode(&records);
+    assert_eq!(rdb::decode(&buf).unwrap(), records);
+
+    // 途中で切れたファイル
+    assert!(rdb::decode(&buf[..buf.len() - 3]).is_err());
+
+    // 中身が書き換わったファイル
+    let mut corrupted = buf.clone();
+    corrupted[8] ^= 0xff;
+    assert!(rdb::decode(&corrupted).is_err());
+
+    assert!(rdb::decode(b"hello world, not a snapshot").is_err());
+}
+
+#[tokio::test]
+async fn bgsave_writes_in_the_background() {
+    let path = temp_path("bgsave");
+    let db = db_with_snapshots(&path, vec![]);
+    call(&db, &["SET", "k", "v"]);
+
+    assert_eq!(
+        call(&db, &["BGSAVE"]),
+        Frame::Simple("Background saving started".into())
+    );
+    while db.snapshots().unwrap().is_saving() {
+        time::sleep(Duration::from_millis(5)).await;
+    }
+
+    // BGSAVE の後の変更はスナップショットに含まれない
+    call(&db, &["SET", "k", "changed"]);
+    let loaded = new_sharded_db(4);
+    rdb::load(&loaded, &path).unwrap();
+    assert_eq!(call(&loaded, &["GET", "k"]), bulk("v"));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn save_without_a_file_is_an_error() {
+    let db = new_sharded_db(4);
+    assert_eq!(
+        call(&db, &["SAVE"]),
+        Frame::Error("ERR no snapshot file is configured".into())
+    );
+    assert_eq!(
+        call(&db, &["BGSAVE"]),
+        Frame::Error("ERR no snapshot file is configured".into())
+    );
+}
+
+#[tokio::test]
+async fn save_rules_trigger_a_background_save() {
+    let path = temp_path("rules");
+    let db = db_with_snapshots(
+        &path,
+        vec![SaveRule {
+            seconds: 0,
+            changes: 2,
+        }],
+    );
+    rdb::spawn_save_task(&db);
+
+    // 変更が 1 回だけでは保存しない
+    call(&db, &["SET", "a", "1"]);
+    time::sleep(Duration::from_millis(300)).await;
+    assert!(!path.exists());
+
+    call(&db, &["SET", "b", "2"]);
+    for _ in 0..100 {
+        if path.exists() && !db.snapshots().unwrap().is_saving() {
+            break;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    let loaded = new_sharded_db(4);
+    assert_eq!(rdb::load(&loaded, &path).unwrap(), 2);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn server_loads_the_snapshot_on_startup() {
+    let path = temp_path("startup");
+    let db = db_with_snapshots(&path, vec![]);
+    call(&db, &["SET", "greeting", "hello"]);
+    call(&db, &["SAVE"]);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = Config {
+        dbfilename: Some(path.clone()),
+        save_rules: vec![],
+        ..Config::default()
+    };
+    tokio::spawn(server::run_with_config(listener, config));
+
+    let mut conn = Connection::new(TcpStream::connect(addr).await.unwrap());
+    conn.write_frame(&command(&["GET", "greeting"]))
+        .await
+        .unwrap();
+    assert_eq!(conn.read_frame().await.unwrap().unwrap(), bulk("hello"));
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use bytes::Bytes;
+
+use common::{command, connect, start_server};
+use my_redis::cmd::Registry;
+use my_redis::db::new_sharded_db;
+use my_redis::frame::Frame;
+use my_redis::server::Config;
+
+#[test]
+fn unknown_and_wrong_arity_commands_are_errors() {
+    let db = new_sharded_db(4);
+    let registry = Registry::new();
+
+    assert_eq!(
+        registry.dispatch(&db, command(&["frobnicate"])),
+        Frame::Error("ERR unknown command 'frobnicate', with args beginning with:".into()),
+    );
+    assert_eq!(
+        registry.dispatch(&db, command(&["frobnicate", "x", "y"])),
+        Frame::Error("ERR unknown command 'frobnicate', with args beginning with: 'x' 'y'".into()),
+    );
+    assert_eq!(
+        registry.dispatch(&db, command(&["GET", "k", "extra"])),
+        Frame::Error("ERR wrong number of arguments for 'get' command".into()),
+    );
+    assert_eq!(
+        registry.dispatch(&db, command(&["set", "k"])),
+        Frame::Error("ERR wrong number of arguments for 'set' command".into()),
+    );
+
+    // コマンド名は大文字小文字を区別しない
+    assert_eq!(
+        registry.dispatch(&db, command(&["sEt", "k", "v"])),
+        Frame::Simple("OK".into()),
+    );
+    assert_eq!(
+        registry.dispatch(&db, command(&["get", "k"])),
+        Frame::Bulk(Bytes::from("v")),
+    );
+}
+
+// エラーを返したあとも同じ接続でコマンドを受け付ける
+#[tokio::test]
+async fn errors_keep_the_connection_open() {
+    let mut conn = connect(start_server(Config::default()).await).await;
+    let requests = [
+        (
+            command(&["frobnicate", "x"]),
+            Frame::Error("ERR unknown command 'frobnicate', with args beginning with: 'x'".into()),
+        ),
+        (command(&["SET", "k", "v"]), Frame::Simple("OK".into())),
+        (
+            command(&["GET"]),
+            Frame::Error("ERR wrong number of arguments for 'get' command".into()),
+        ),
+        (command(&["GET", "k"]), Frame::Bulk(Bytes::from("v"))),
+    ];
+    for (request, expected) in requests {
+        conn.write_frame(&request).await.unwrap();
+        assert_eq!(conn.read_frame().await.unwrap(), Some(expected));
+    }
+}
+
+// 未知のコマンドの引数は、改行を空白に置き換え、長さを切り詰めてから返す
+// 引数に CRLF が含まれていても、応答の区切りが崩れて後続の応答とずれることはない
+#[tokio::test]
+async fn unknown_command_errors_do_not_echo_raw_arguments() {
+    let mut conn = connect(start_server(Config::default()).await).await;
+
+    let long = "y".repeat(10_000);
+    conn.write_frame(&command(&["frobnicate", "x\r\n+OK\r\n", &long, "z"]))
+        .await
+        .unwrap();
+    conn.write_frame(&command(&["PING"])).await.unwrap();
+
+    let expected = format!(
+        "ERR unknown command 'frobnicate', with args beginning with: 'x  +OK  ' '{}'",
+        "y".repeat(128 - 11)
+    );
+    assert_eq!(
+        conn.read_frame().await.unwrap(),
+        Some(Frame::Error(expected))
+    );
+    assert_eq!(
+        conn.read_frame().await.unwrap(),
+        Some(Frame::Simple("PONG".into()))
+    );
+}
@@ -0,0 +1,219 @@
+#[macro_use]
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use common::{bulk, bulks, connect, info_field, request, start_server};
+use my_redis::frame::Frame;
+use my_redis::server::Config;
+
+// フォロワーとリーダーの間に挟み、接続を切れるようにするプロキシ
+struct Proxy {
+    addr: SocketAddr,
+    links: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Proxy {
+    async fn start(target: SocketAddr) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let links = Arc::new(Mutex::new(Vec::new()));
+        let handles = links.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut inbound, _) = listener.accept().await.unwrap();
+                let link = tokio::spawn(async move {
+                    let mut outbound = TcpStream::connect(target).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                });
+                handles.lock().unwrap().push(link);
+            }
+        });
+        Self { addr, links }
+    }
+
+    // 中継している接続をすべて切る
+    fn cut(&self) {
+        for link in self.links.lock().unwrap().drain(..) {
+            link.abort();
+        }
+    }
+}
+
+#[tokio::test]
+async fn follower_receives_a_snapshot_and_then_the_stream() {
+    let leader = start_server(Config::default()).await;
+    let follower = start_server(Config::default()).await;
+    let mut l = connect(leader).await;
+    let mut f = connect(follower).await;
+
+    request(&mut l, &["SET", "a", "1"]).await;
+    request(&mut l, &["RPUSH", "list", "x", "y"]).await;
+    request(&mut l, &["SET", "ttl", "v", "EX", "100"]).await;
+    // 完全再同期すると、フォロワーが持っていたキーは消える
+    request(&mut f, &["SET", "stale", "v"]).await;
+
+    let port = leader.port().to_string();
+    assert_eq!(
+        request(&mut f, &["REPLICAOF", "127.0.0.1", &port]).await,
+        Frame::Simple("OK".into())
+    );
+    wait_until!(request(&mut f, &["GET", "a"]).await == bulk("1"));
+    assert_eq!(
+        request(&mut f, &["LRANGE", "list", "0", "-1"]).await,
+        bulks(&["x", "y"])
+    );
+    assert!(matches!(
+        request(&mut f, &["TTL", "ttl"]).await,
+        Frame::Integer(n) if n > 90 && n <= 100
+    ));
+    assert_eq!(
+        request(&mut f, &["EXISTS", "stale"]).await,
+        Frame::Integer(0)
+    );
+
+    // 同期した後の書き込みも届く
+    request(&mut l, &["INCR", "a"]).await;
+    request(&mut l, &["MULTI"]).await;
+    request(&mut l, &["SET", "b", "2"]).await;
+    request(&mut l, &["INCR", "a"]).await;
+    request(&mut l, &["EXEC"]).await;
+    request(&mut l, &["XADD", "stream", "*", "k", "v"]).await;
+    wait_until!(request(&mut f, &["EXISTS", "stream"]).await == Frame::Integer(1));
+    assert_eq!(request(&mut f, &["GET", "a"]).await, bulk("3"));
+    assert_eq!(request(&mut f, &["GET", "b"]).await, bulk("2"));
+    assert_eq!(
+        request(&mut f, &["XRANGE", "stream", "-", "+"]).await,
+        request(&mut l, &["XRANGE", "stream", "-", "+"]).await
+    );
+
+    // フォロワーはクライアントからの書き込みを受け付けない
+    assert_eq!(
+        request(&mut f, &["SET", "a", "x"]).await,
+        Frame::Error("READONLY You can't write against a read only replica.".into())
+    );
+    request(&mut f, &["MULTI"]).await;
+    assert!(matches!(
+        request(&mut f, &["SET", "a", "x"]).await,
+        Frame::Error(err) if err.starts_with("READONLY")
+    ));
+    request(&mut f, &["DISCARD"]).await;
+
+    // オフセットはリーダーとフォロワーで一致し、フォロワーは処理済みのオフセットを知らせる
+    let offset = match request(&mut l, &["ROLE"]).await {
+        Frame::Array(role) => match role[1] {
+            Frame::Integer(offset) => offset,
+            _ => panic!("unexpected ROLE reply {:?}", role),
+        },
+        other => panic!("unexpected ROLE reply {:?}", other),
+    };
+    assert!(offset > 0);
+    assert_eq!(
+        request(&mut f, &["ROLE"]).await,
+        Frame::Array(vec![
+            bulk("slave"),
+            bulk("127.0.0.1"),
+            Frame::Integer(leader.port() as i64),
+            bulk("connected"),
+            Frame::Integer(offset),
+        ])
+    );
+    wait_until!(info_field(&mut l, "replication", "slave0")
+        .await
+        .ends_with(&format!("offset={}", offset)));
+    assert_eq!(
+        info_field(&mut l, "replication", "connected_slaves").await,
+        "1"
+    );
+}
+
+#[tokio::test]
+async fn follower_resumes_from_the_backlog_after_the_link_drops() {
+    let leader = start_server(Config::default()).await;
+    let follower = start_server(Config::default()).await;
+    let proxy = Proxy::start(leader).await;
+    let mut l = connect(leader).await;
+    let mut f = connect(follower).await;
+
+    let port = proxy.addr.port().to_string();
+    request(&mut f, &["REPLICAOF", "127.0.0.1", &port]).await;
+    request(&mut l, &["SET", "a", "1"]).await;
+    wait_until!(request(&mut f, &["GET", "a"]).await == bulk("1"));
+
+    // 接続が切れている間の書き込みは、再接続したときにバックログから送られる
+    proxy.cut();
+    request(&mut l, &["SET", "b", "2"]).await;
+    request(&mut l, &["RPUSH", "list", "x"]).await;
+    wait_until!(request(&mut f, &["GET", "b"]).await == bulk("2"));
+    assert_eq!(
+        request(&mut f, &["LRANGE", "list", "0", "-1"]).await,
+        bulks(&["x"])
+    );
+
+    assert_eq!(info_field(&mut l, "replication", "sync_full").await, "1");
+    assert_eq!(
+        info_field(&mut l, "replication", "sync_partial_ok").await,
+        "1"
+    );
+    assert_eq!(
+        info_field(&mut f, "replication", "master_link_status").await,
+        "up"
+    );
+}
+
+#[tokio::test]
+async fn replicaof_no_one_stops_following() {
+    let leader = start_server(Config::default()).await;
+    let follower = start_server(Config::default()).await;
+    let mut l = connect(leader).await;
+    let mut f = connect(follower).await;
+
+    let port = leader.port().to_string();
+    request(&mut f, &["REPLICAOF", "127.0.0.1", &port]).await;
+    request(&mut l, &["SET", "a", "1"]).await;
+    wait_until!(request(&mut f, &["GET", "a"]).await == bulk("1"));
+
+    assert_eq!(
+        request(&mut f, &["REPLICAOF", "NO", "ONE"]).await,
+        Frame::Simple("OK".into())
+    );
+    // 複製したキーは残り、書き込みも受け付けるようになる
+    assert_eq!(request(&mut f, &["GET", "a"]).await, bulk("1"));
+    assert_eq!(
+        request(&mut f, &["SET", "own", "v"]).await,
+        Frame::Simple("OK".into())
+    );
+    assert!(matches!(
+        request(&mut f, &["ROLE"]).await,
+        Frame::Array(role) if role[0] == bulk("master")
+    ));
+
+    // リーダーへの書き込みはもう届かない
+    request(&mut l, &["SET", "a", "2"]).await;
+    time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(request(&mut f, &["GET", "a"]).await, bulk("1"));
+    wait_until!(info_field(&mut l, "replication", "connected_slaves").await == "0");
+}
+
+#[tokio::test]
+async fn replicaof_rejects_an_invalid_port() {
+    let mut conn = connect(start_server(Config::default()).await).await;
+    assert_eq!(
+        request(&mut conn, &["REPLICAOF", "127.0.0.1", "http"]).await,
+        Frame::Error("ERR Invalid master port".into())
+    );
+    assert_eq!(
+        request(&mut conn, &["ROLE"]).await,
+        Frame::Array(vec![
+            bulk("master"),
+            Frame::Integer(0),
+            Frame::Array(vec![])
+        ])
+    );
+}
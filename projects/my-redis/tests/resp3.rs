@@ -0,0 +1,143 @@
+mod common;
+
+use common::{bulk, connect, request, start_server};
+use my_redis::frame::Frame;
+use my_redis::server::Config;
+
+// HELLO の応答のマップから、指定したキーの値を取り出す
+fn field<'a>(reply: &'a Frame, name: &str) -> &'a Frame {
+    match reply {
+        Frame::Map(pairs) => pairs
+            .iter()
+            .find(|(key, _)| *key == bulk(name))
+            .map(|(_, value)| value)
+            .unwrap_or_else(|| panic!("no {} in {:?}", name, reply)),
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn hello_switches_protocol() {
+    let mut conn = connect(start_server(Config::default()).await).await;
+
+    let reply = request(&mut conn, &["HELLO", "3"]).await;
+    assert_eq!(field(&reply, "server"), &bulk("redis"));
+    assert_eq!(field(&reply, "proto"), &Frame::Integer(3));
+    assert_eq!(field(&reply, "mode"), &bulk("standalone"));
+    assert_eq!(field(&reply, "role"), &bulk("master"));
+    assert!(matches!(field(&reply, "id"), Frame::Integer(id) if *id > 0));
+    assert_eq!(request(&mut conn, &["GET", "missing"]).await, Frame::Null);
+
+    // バージョンを省略すると、プロトコルを変えずに情報だけを返す
+    let reply = request(&mut conn, &["HELLO"]).await;
+    assert_eq!(field(&reply, "proto"), &Frame::Integer(3));
+
+    // RESP2 に戻すと、応答のマップは配列になる
+    match request(&mut conn, &["HELLO", "2", "SETNAME", "me"]).await {
+        Frame::Array(items) => {
+            assert_eq!(items.len(), 14);
+            assert_eq!(items[4], bulk("proto"));
+            assert_eq!(items[5], Frame::Integer(2));
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn hello_rejects_unknown_versions_and_options() {
+    let mut conn = connect(start_server(Config::default()).await).await;
+
+    assert_eq!(
+        request(&mut conn, &["HELLO", "4"]).await,
+        Frame::Error("NOPROTO unsupported protocol version".into())
+    );
+    assert_eq!(
+        request(&mut conn, &["HELLO", "three"]).await,
+        Frame::Error("ERR Protocol version is not an integer or out of range".into())
+    );
+    assert_eq!(
+        request(&mut conn, &["HELLO", "3", "AUTH", "user"]).await,
+        Frame::Error("ERR Syntax error in HELLO option 'AUTH'".into())
+    );
+    // 失敗した HELLO ではプロトコルは変わらない
+    assert_eq!(
+        request(&mut conn, &["SADD", "s", "a"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        request(&mut conn, &["SMEMBERS", "s"]).await,
+        Frame::Array(vec![bulk("a")])
+    );
+}
+
+#[tokio::test]
+async fn replies_adapt_to_the_negotiated_protocol() {
+    let addr = start_server(Config::default()).await;
+    let mut resp2 = connect(addr).await;
+    let mut resp3 = connect(addr).await;
+    request(&mut resp3, &["HELLO", "3"]).await;
+
+    request(&mut resp2, &["HSET", "h", "f", "v"]).await;
+    request(&mut resp2, &["SADD", "s", "m"]).await;
+    request(&mut resp2, &["ZADD", "z", "1.5", "a"]).await;
+
+    assert_eq!(
+        request(&mut resp2, &["HGETALL", "h"]).await,
+        Frame::Array(vec![bulk("f"), bulk("v")])
+    );
+    assert_eq!(
+        request(&mut resp3, &["HGETALL", "h"]).await,
+        Frame::Map(vec![(bulk("f"), bulk("v"))])
+    );
+    assert_eq!(
+        request(&mut resp3, &["HGETALL", "missing"]).await,
+        Frame::Map(vec![])
+    );
+
+    assert_eq!(
+        request(&mut resp3, &["SMEMBERS", "s"]).await,
+        Frame::Set(vec![bulk("m")])
+    );
+    assert_eq!(
+        request(&mut resp3, &["SUNION", "s", "missing"]).await,
+        Frame::Set(vec![bulk("m")])
+    );
+
+    assert_eq!(
+        request(&mut resp2, &["ZSCORE", "z", "a"]).await,
+        bulk("1.5")
+    );
+    assert_eq!(
+        request(&mut resp3, &["ZSCORE", "z", "a"]).await,
+        Frame::Double(1.5)
+    );
+    assert_eq!(
+        request(&mut resp3, &["ZINCRBY", "z", "1", "a"]).await,
+        Frame::Double(2.5)
+    );
+    assert_eq!(
+        request(&mut resp3, &["ZSCORE", "z", "b"]).await,
+        Frame::Null
+    );
+}
+
+#[tokio::test]
+async fn pubsub_messages_are_pushed_in_resp3() {
+    let addr = start_server(Config::default()).await;
+    let mut subscriber = connect(addr).await;
+    let mut publisher = connect(addr).await;
+    request(&mut subscriber, &["HELLO", "3"]).await;
+
+    assert_eq!(
+        request(&mut subscriber, &["SUBSCRIBE", "news"]).await,
+        Frame::Push(vec![bulk("subscribe"), bulk("news"), Frame::Integer(1)])
+    );
+    assert_eq!(
+        request(&mut publisher, &["PUBLISH", "news", "hello"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        subscriber.read_frame().await.unwrap().unwrap(),
+        Frame::Push(vec![bulk("message"), bulk("news"), bulk("hello")])
+    );
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use bytes::Bytes;
+
+use common::{request, start_server};
+use my_redis::frame::Frame;
+use my_redis::server::Config;
+use my_redis::Connection;
+
+// ループバックの空きポートでサーバを起動し、接続済みの Connection を返す
+async fn connect() -> Connection {
+    common::connect(start_server(Config::default()).await).await
+}
+
+#[tokio::test]
+async fn set_then_get() {
+    let mut conn = connect().await;
+
+    assert_eq!(
+        request(&mut conn, &["SET", "hello", "world"]).await,
+        Frame::Simple("OK".into())
+    );
+    assert_eq!(
+        request(&mut conn, &["GET", "hello"]).await,
+        Frame::Bulk(Bytes::from("world"))
+    );
+    assert_eq!(request(&mut conn, &["GET", "missing"]).await, Frame::Null);
+}
+
+#[tokio::test]
+async fn ping_and_echo() {
+    let mut conn = connect().await;
+
+    assert_eq!(
+        request(&mut conn, &["PING"]).await,
+        Frame::Simple("PONG".into())
+    );
+    assert_eq!(
+        request(&mut conn, &["echo", "hi"]).await,
+        Frame::Bulk(Bytes::from("hi"))
+    );
+}
+
+#[tokio::test]
+async fn errors_keep_the_connection_open() {
+    let mut conn = connect().await;
+
+    assert_eq!(
+        request(&mut conn, &["NOPE", "a"]).await,
+        Frame::Error("ERR unknown command 'NOPE', with args beginning with: 'a'".into())
+    );
+    assert_eq!(
+        request(&mut conn, &["GET"]).await,
+        Frame::Error("ERR wrong number of arguments for 'get' command".into())
+    );
+    assert_eq!(
+        request(&mut conn, &["PING"]).await,
+        Frame::Simple("PONG".into())
+    );
+}
@@ -0,0 +1,135 @@
+mod common;
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use common::call;
+use my_redis::db::new_sharded_db;
+use my_redis::frame::Frame;
+
+fn members(frame: Frame) -> HashSet<String> {
+    match frame {
+        Frame::Array(items) | Frame::Set(items) => items
+            .into_iter()
+            .map(|item| match item {
+                Frame::Bulk(b) => String::from_utf8(b.to_vec()).unwrap(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect(),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+fn set(items: &[&str]) -> HashSet<String> {
+    items.iter().map(|s| s.to_string()).collect()
+}
+
+#[tokio::test]
+async fn add_remove_and_query() {
+    let db = new_sharded_db(4);
+
+    assert_eq!(
+        call(&db, &["SADD", "tags", "a", "b", "a"]),
+        Frame::Integer(2)
+    );
+    assert_eq!(call(&db, &["SADD", "tags", "b", "c"]), Frame::Integer(1));
+    assert_eq!(call(&db, &["SCARD", "tags"]), Frame::Integer(3));
+    assert_eq!(call(&db, &["SISMEMBER", "tags", "c"]), Frame::Integer(1));
+    assert_eq!(call(&db, &["SISMEMBER", "tags", "z"]), Frame::Integer(0));
+    assert_eq!(
+        members(call(&db, &["SMEMBERS", "tags"])),
+        set(&["a", "b", "c"])
+    );
+    assert_eq!(call(&db, &["TYPE", "tags"]), Frame::Simple("set".into()));
+
+    assert_eq!(
+        call(&db, &["SREM", "tags", "a", "b", "c", "d"]),
+        Frame::Integer(3)
+    );
+    assert_eq!(call(&db, &["EXISTS", "tags"]), Frame::Integer(0));
+    assert_eq!(members(call(&db, &["SMEMBERS", "tags"])), set(&[]));
+}
+
+#[tokio::test]
+async fn algebra_across_shards() {
+    let db = new_sharded_db(8);
+    call(&db, &["SADD", "s1", "a", "b", "c", "d"]);
+    call(&db, &["SADD", "s2", "c", "d", "e"]);
+    call(&db, &["SADD", "s3", "a", "c", "f"]);
+
+    assert_eq!(
+        members(call(&db, &["SINTER", "s1", "s2", "s3"])),
+        set(&["c"])
+    );
+    assert_eq!(
+        members(call(&db, &["SUNION", "s1", "s2", "s3"])),
+        set(&["a", "b", "c", "d", "e", "f"])
+    );
+    assert_eq!(
+        members(call(&db, &["SDIFF", "s1", "s2", "s3"])),
+        set(&["b"])
+    );
+    assert_eq!(members(call(&db, &["SINTER", "s1", "missing"])), set(&[]));
+
+    assert_eq!(
+        call(&db, &["SINTERSTORE", "out", "s1", "s2"]),
+        Frame::Integer(2)
+    );
+    assert_eq!(members(call(&db, &["SMEMBERS", "out"])), set(&["c", "d"]));
+    assert_eq!(
+        call(&db, &["SUNIONSTORE", "s1", "s1", "s3"]),
+        Frame::Integer(5)
+    );
+    assert_eq!(
+        call(&db, &["SDIFFSTORE", "out", "s2", "s2"]),
+        Frame::Integer(0)
+    );
+    assert_eq!(call(&db, &["EXISTS", "out"]), Frame::Integer(0));
+
+    call(&db, &["SET", "str", "v"]);
+    assert_eq!(
+        call(&db, &["SUNION", "s1", "str"]),
+        Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())
+    );
+    // 書き込み先は型に関係なく上書きされる
+    assert_eq!(call(&db, &["SUNIONSTORE", "str", "s2"]), Frame::Integer(3));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn multi_key_commands_do_not_deadlock() {
+    let db = new_sharded_db(8);
+    let keys: Vec<String> = (0..8).map(|i| format!("set{}", i)).collect();
+    for key in &keys {
+        call(&db, &["SADD", key, "x", key]);
+    }
+
+    // 逆の順序でキーを並べたコマンドを並行して実行しても、すべて完了する
+    let mut handles = Vec::new();
+    for t in 0..8 {
+        let db = db.clone();
+        let mut keys = keys.clone();
+        if t % 2 == 1 {
+            keys.reverse();
+        }
+        handles.push(tokio::spawn(async move {
+            for i in 0..200 {
+                let dst = keys[i % keys.len()].clone();
+                let mut args = vec!["SUNIONSTORE".to_string(), dst];
+                args.extend(keys.iter().cloned());
+                let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+                call(&db, &args);
+                call(&db, &["RENAME", &keys[0], &keys[keys.len() - 1]]);
+                call(&db, &["SADD", &keys[0], "x"]);
+            }
+        }));
+    }
+
+    let all = async {
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(30), all)
+        .await
+        .expect("multi-key commands deadlocked");
+}
@@ -0,0 +1,408 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use common::{bulk, call, command, error};
+use my_redis::cmd::Registry;
+use my_redis::db::{new_sharded_db, ShardedDb};
+use my_redis::frame::Frame;
+
+// コマンドを別タスクで実行し、XADD を待つところまで進める
+async fn spawn_blocking(db: &ShardedDb, args: &[&str]) -> JoinHandle<Frame> {
+    let db = db.clone();
+    let frame = command(args);
+    let handle = tokio::spawn(async move { Arc::new(Registry::new()).execute(&db, frame).await });
+    time::sleep(Duration::from_millis(10)).await;
+    handle
+}
+
+// [id, [field, value, ...]]
+fn entry(id: &str, fields: &[&str]) -> Frame {
+    Frame::Array(vec![
+        bulk(id),
+        Frame::Array(fields.iter().map(|s| bulk(s)).collect()),
+    ])
+}
+
+// XREAD の応答の 1 キー分
+fn from_key(key: &str, entries: Vec<Frame>) -> Frame {
+    Frame::Array(vec![bulk(key), Frame::Array(entries)])
+}
+
+#[tokio::test]
+async fn xadd_assigns_increasing_ids() {
+    let db = new_sharded_db(4);
+
+    assert_eq!(call(&db, &["XADD", "s", "1-1", "a", "1"]), bulk("1-1"));
+    assert_eq!(call(&db, &["XADD", "s", "1-*", "b", "2"]), bulk("1-2"));
+    assert_eq!(call(&db, &["XADD", "s", "5", "c", "3"]), bulk("5-0"));
+    assert_eq!(
+        call(&db, &["XADD", "s", "5-0", "d", "4"]),
+        error("ERR The ID specified in XADD is equal or smaller than the target stream top item")
+    );
+    assert_eq!(
+        call(&db, &["XADD", "t", "0-0", "d", "4"]),
+        error("ERR The ID specified in XADD must be greater than 0-0")
+    );
+    assert_eq!(
+        call(&db, &["XADD", "s", "x-1", "d", "4"]),
+        error("ERR Invalid stream ID specified as stream command argument")
+    );
+    assert_eq!(
+        call(&db, &["XADD", "s", "*", "odd"]),
+        error("ERR wrong number of arguments for 'xadd' command")
+    );
+
+    // * は現在時刻から ID を作るので、これまでの ID より大きい
+    let id = match call(&db, &["XADD", "s", "*", "e", "5"]) {
+        Frame::Bulk(id) => String::from_utf8(id.to_vec()).unwrap(),
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    let (ms, seq) = id.split_once('-').unwrap();
+    assert!(ms.parse::<u64>().unwrap() > 5);
+    assert_eq!(seq, "0");
+    assert_eq!(call(&db, &["XLEN", "s"]), Frame::Integer(4));
+
+    assert_eq!(
+        call(&db, &["XADD", "missing", "NOMKSTREAM", "*", "a", "1"]),
+        Frame::Null
+    );
+    assert_eq!(call(&db, &["XLEN", "missing"]), Frame::Integer(0));
+
+    call(&db, &["SET", "str", "x"]);
+    assert_eq!(
+        call(&db, &["XADD", "str", "*", "a", "1"]),
+        error("WRONGTYPE Operation against a key holding the wrong kind of value")
+    );
+}
+
+#[tokio::test]
+async fn xadd_maxlen_trims_oldest_entries() {
+    let db = new_sharded_db(4);
+    for id in ["1", "2", "3", "4"] {
+        call(&db, &["XADD", "s", "MAXLEN", "~", "2", id, "n", id]);
+    }
+    assert_eq!(call(&db, &["XLEN", "s"]), Frame::Integer(2));
+    assert_eq!(
+        call(&db, &["XRANGE", "s", "-", "+"]),
+        Frame::Array(vec![entry("3-0", &["n", "3"]), entry("4-0", &["n", "4"])])
+    );
+    // 削除しても最後の ID は残るので、小さい ID は使えない
+    assert!(matches!(
+        call(&db, &["XADD", "s", "2", "n", "2"]),
+        Frame::Error(_)
+    ));
+}
+
+#[tokio::test]
+async fn xrange_and_xrevrange() {
+    let db = new_sharded_db(4);
+    for id in ["1-0", "1-1", "2-0", "3-0"] {
+        call(&db, &["XADD", "s", id, "id", id]);
+    }
+
+    assert_eq!(
+        call(&db, &["XRANGE", "s", "1", "2"]),
+        Frame::Array(vec![
+            entry("1-0", &["id", "1-0"]),
+            entry("1-1", &["id", "1-1"]),
+            entry("2-0", &["id", "2-0"]),
+        ])
+    );
+    assert_eq!(
+        call(&db, &["XRANGE", "s", "(1-0", "+", "COUNT", "2"]),
+        Frame::Array(vec![
+            entry("1-1", &["id", "1-1"]),
+            entry("2-0", &["id", "2-0"]),
+        ])
+    );
+    assert_eq!(
+        call(&db, &["XREVRANGE", "s", "+", "-", "COUNT", "2"]),
+        Frame::Array(vec![
+            entry("3-0", &["id", "3-0"]),
+            entry("2-0", &["id", "2-0"]),
+        ])
+    );
+    assert_eq!(call(&db, &["XRANGE", "s", "3", "1"]), Frame::Array(vec![]));
+    assert_eq!(
+        call(&db, &["XRANGE", "missing", "-", "+"]),
+        Frame::Array(vec![])
+    );
+}
+
+#[tokio::test]
+async fn xread_returns_entries_after_the_given_ids() {
+    let db = new_sharded_db(4);
+    call(&db, &["XADD", "a", "1", "x", "1"]);
+    call(&db, &["XADD", "a", "2", "x", "2"]);
+    call(&db, &["XADD", "b", "1", "y", "1"]);
+
+    assert_eq!(
+        call(&db, &["XREAD", "COUNT", "1", "STREAMS", "a", "b", "0", "1"]),
+        Frame::Array(vec![from_key("a", vec![entry("1-0", &["x", "1"])])])
+    );
+    assert_eq!(call(&db, &["XREAD", "STREAMS", "a", "$"]), Frame::NullArray);
+    assert_eq!(
+        call(&db, &["XREAD", "STREAMS", "a", "b", "0"]),
+        error("ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.")
+    );
+    assert_eq!(
+        call(&db, &["XREAD", "BLOCK", "-1", "STREAMS", "a", "0"]),
+        error("ERR timeout is negative")
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn blocking_xread_wakes_on_xadd() {
+    let db = new_sharded_db(4);
+    call(&db, &["XADD", "s", "1", "old", "1"]);
+
+    let waiting = spawn_blocking(
+        &db,
+        &["XREAD", "BLOCK", "0", "STREAMS", "other", "s", "$", "$"],
+    )
+    .await;
+    assert!(!waiting.is_finished());
+
+    call(&db, &["XADD", "s", "2", "new", "1"]);
+    assert_eq!(
+        waiting.await.unwrap(),
+        Frame::Array(vec![from_key("s", vec![entry("2-0", &["new", "1"])])])
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn blocking_xread_times_out() {
+    let db = new_sharded_db(4);
+    let waiting = spawn_blocking(&db, &["XREAD", "BLOCK", "100", "STREAMS", "s", "$"]).await;
+
+    time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(waiting.await.unwrap(), Frame::NullArray);
+    // 待ち行列から外れているので、その後の XADD は誰にも知らせない
+    call(&db, &["XADD", "s", "1", "a", "1"]);
+}
+
+#[tokio::test]
+async fn consumer_groups_deliver_each_entry_once() {
+    let db = new_sharded_db(4);
+    let ok = Frame::Simple("OK".into());
+
+    assert!(matches!(
+        call(&db, &["XGROUP", "CREATE", "s", "g", "$"]),
+        Frame::Error(msg) if msg.starts_with("ERR The XGROUP subcommand requires the key to exist")
+    ));
+    assert_eq!(
+        call(&db, &["XGROUP", "CREATE", "s", "g", "$", "MKSTREAM"]),
+        ok
+    );
+    assert_eq!(
+        call(&db, &["XGROUP", "CREATE", "s", "g", "0"]),
+        error("BUSYGROUP Consumer Group name already exists")
+    );
+    for id in ["1", "2", "3"] {
+        call(&db, &["XADD", "s", id, "n", id]);
+    }
+
+    assert_eq!(
+        call(
+            &db,
+            &[
+                "XREADGROUP",
+                "GROUP",
+                "g",
+                "alice",
+                "COUNT",
+                "2",
+                "STREAMS",
+                "s",
+                ">"
+            ]
+        ),
+        Frame::Array(vec![from_key(
+            "s",
+            vec![entry("1-0", &["n", "1"]), entry("2-0", &["n", "2"])]
+        )])
+    );
+    assert_eq!(
+        call(
+            &db,
+            &["XREADGROUP", "GROUP", "g", "bob", "STREAMS", "s", ">"]
+        ),
+        Frame::Array(vec![from_key("s", vec![entry("3-0", &["n", "3"])])])
+    );
+    assert_eq!(
+        call(
+            &db,
+            &["XREADGROUP", "GROUP", "g", "bob", "STREAMS", "s", ">"]
+        ),
+        Frame::NullArray
+    );
+
+    // ID を指定すると、そのコンシューマの未確認のエントリを読み直す
+    assert_eq!(
+        call(
+            &db,
+            &["XREADGROUP", "GROUP", "g", "alice", "STREAMS", "s", "0"]
+        ),
+        Frame::Array(vec![from_key(
+            "s",
+            vec![entry("1-0", &["n", "1"]), entry("2-0", &["n", "2"])]
+        )])
+    );
+
+    assert_eq!(
+        call(&db, &["XPENDING", "s", "g"]),
+        Frame::Array(vec![
+            Frame::Integer(3),
+            bulk("1-0"),
+            bulk("3-0"),
+            Frame::Array(vec![
+                Frame::Array(vec![bulk("alice"), bulk("2")]),
+                Frame::Array(vec![bulk("bob"), bulk("1")]),
+            ]),
+        ])
+    );
+
+    assert_eq!(
+        call(&db, &["XACK", "s", "g", "1", "1", "9"]),
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        call(
+            &db,
+            &["XREADGROUP", "GROUP", "g", "alice", "STREAMS", "s", "0"]
+        ),
+        Frame::Array(vec![from_key("s", vec![entry("2-0", &["n", "2"])])])
+    );
+
+    match call(&db, &["XPENDING", "s", "g", "-", "+", "10", "bob"]) {
+        Frame::Array(items) => {
+            assert_eq!(items.len(), 1);
+            match &items[0] {
+                Frame::Array(item) => {
+                    assert_eq!(item[0], bulk("3-0"));
+                    assert_eq!(item[1], bulk("bob"));
+                    assert_eq!(item[3], Frame::Integer(1));
+                }
+                other => panic!("unexpected item: {:?}", other),
+            }
+        }
+        other => panic!("unexpected reply: {:?}", other),
+    }
+
+    assert_eq!(
+        call(
+            &db,
+            &["XREADGROUP", "GROUP", "nope", "c", "STREAMS", "s", ">"]
+        ),
+        error("NOGROUP No such key 's' or consumer group 'nope' in XREADGROUP with GROUP option")
+    );
+    assert_eq!(
+        call(&db, &["XGROUP", "DESTROY", "s", "g"]),
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        call(&db, &["XPENDING", "s", "g"]),
+        error("NOGROUP No such key 's' or consumer group 'g'")
+    );
+}
+
+#[tokio::test]
+async fn noack_and_setid() {
+    let db = new_sharded_db(4);
+    call(&db, &["XADD", "s", "1", "n", "1"]);
+    call(&db, &["XADD", "s", "2", "n", "2"]);
+    call(&db, &["XGROUP", "CREATE", "s", "g", "0"]);
+
+    call(
+        &db,
+        &[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "c",
+            "NOACK",
+            "STREAMS",
+            "s",
+            ">",
+        ],
+    );
+    assert_eq!(
+        call(&db, &["XPENDING", "s", "g"]),
+        Frame::Array(vec![
+            Frame::Integer(0),
+            Frame::Null,
+            Frame::Null,
+            Frame::NullArray
+        ])
+    );
+
+    assert_eq!(
+        call(&db, &["XGROUP", "SETID", "s", "g", "1"]),
+        Frame::Simple("OK".into())
+    );
+    assert_eq!(
+        call(&db, &["XREADGROUP", "GROUP", "g", "c", "STREAMS", "s", ">"]),
+        Frame::Array(vec![from_key("s", vec![entry("2-0", &["n", "2"])])])
+    );
+    assert_eq!(
+        call(&db, &["XGROUP", "DELCONSUMER", "s", "g", "c"]),
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        call(&db, &["XGROUP", "FOO", "s"]),
+        error("ERR unknown subcommand 'FOO'. Try XGROUP HELP.")
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn blocking_xreadgroup_hands_each_entry_to_one_consumer() {
+    let db = new_sharded_db(4);
+    call(&db, &["XGROUP", "CREATE", "s", "g", "$", "MKSTREAM"]);
+
+    let first = spawn_blocking(
+        &db,
+        &[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "a",
+            "BLOCK",
+            "0",
+            "STREAMS",
+            "s",
+            ">",
+        ],
+    )
+    .await;
+    let second = spawn_blocking(
+        &db,
+        &[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "b",
+            "BLOCK",
+            "500",
+            "STREAMS",
+            "s",
+            ">",
+        ],
+    )
+    .await;
+
+    call(&db, &["XADD", "s", "1", "job", "1"]);
+    let first = first.await.unwrap();
+    assert_eq!(
+        first,
+        Frame::Array(vec![from_key("s", vec![entry("1-0", &["job", "1"])])])
+    );
+
+    // 先に読まれたエントリは、もう一方のコンシューマには渡らない
+    time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(second.await.unwrap(), Frame::NullArray);
+}
@@ -0,0 +1,269 @@
+mod common;
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use common::{command, connect, ok, request, start_server};
+use my_redis::cmd::Registry;
+use my_redis::db::{new_sharded_db, ShardedDb};
+use my_redis::frame::Frame;
+use my_redis::pubsub::Hub;
+use my_redis::server::Config;
+use my_redis::transaction::{Outcome, Transaction};
+
+// トランザクションの状態を通してコマンドを実行する（サーバの process と同じ順序）
+fn call_in(tx: &mut Transaction, registry: &Registry, db: &ShardedDb, args: &[&str]) -> Frame {
+    match tx.handle(registry, command(args)) {
+        Outcome::Reply(reply) => reply,
+        Outcome::Execute(frame) => registry.dispatch(db, frame),
+    }
+}
+
+fn queued() -> Frame {
+    Frame::Simple("QUEUED".into())
+}
+
+#[tokio::test]
+async fn multi_queues_and_exec_runs_in_order() {
+    let db = new_sharded_db(4);
+    let registry = Registry::new();
+    let mut tx = Transaction::new(db.clone(), Arc::new(Hub::new()));
+
+    assert_eq!(call_in(&mut tx, &registry, &db, &["MULTI"]), ok());
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["SET", "a", "1"]),
+        queued()
+    );
+    assert_eq!(call_in(&mut tx, &registry, &db, &["INCR", "a"]), queued());
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["LPUSH", "a", "x"]),
+        queued()
+    );
+    // キューに積んだだけなので、まだ実行されていない
+    assert_eq!(registry.dispatch(&db, command(&["GET", "a"])), Frame::Null);
+
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["EXEC"]),
+        Frame::Array(vec![
+            ok(),
+            Frame::Integer(2),
+            Frame::Error(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".into()
+            ),
+        ])
+    );
+    assert!(!tx.in_multi());
+    assert_eq!(
+        registry.dispatch(&db, command(&["GET", "a"])),
+        Frame::Bulk("2".into())
+    );
+}
+
+#[tokio::test]
+async fn queueing_errors_abort_exec() {
+    let db = new_sharded_db(4);
+    let registry = Registry::new();
+    let mut tx = Transaction::new(db.clone(), Arc::new(Hub::new()));
+
+    call_in(&mut tx, &registry, &db, &["MULTI"]);
+    call_in(&mut tx, &registry, &db, &["SET", "a", "1"]);
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["GET"]),
+        Frame::Error("ERR wrong number of arguments for 'get' command".into())
+    );
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["MULTI"]),
+        Frame::Error("ERR MULTI calls can not be nested".into())
+    );
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["EXEC"]),
+        Frame::Error("EXECABORT Transaction discarded because of previous errors.".into())
+    );
+    assert_eq!(
+        registry.dispatch(&db, command(&["EXISTS", "a"])),
+        Frame::Integer(0)
+    );
+
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["EXEC"]),
+        Frame::Error("ERR EXEC without MULTI".into())
+    );
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["DISCARD"]),
+        Frame::Error("ERR DISCARD without MULTI".into())
+    );
+
+    call_in(&mut tx, &registry, &db, &["MULTI"]);
+    call_in(&mut tx, &registry, &db, &["SET", "a", "1"]);
+    assert_eq!(call_in(&mut tx, &registry, &db, &["DISCARD"]), ok());
+    assert_eq!(
+        registry.dispatch(&db, command(&["EXISTS", "a"])),
+        Frame::Integer(0)
+    );
+}
+
+#[tokio::test]
+async fn watch_aborts_when_another_client_writes() {
+    let db = new_sharded_db(4);
+    let registry = Registry::new();
+    let mut tx = Transaction::new(db.clone(), Arc::new(Hub::new()));
+
+    registry.dispatch(&db, command(&["SET", "balance", "10"]));
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["WATCH", "balance"]),
+        ok()
+    );
+    // 読むだけのコマンドはバージョンを進めない
+    registry.dispatch(&db, command(&["GET", "balance"]));
+    call_in(&mut tx, &registry, &db, &["MULTI"]);
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["WATCH", "x"]),
+        Frame::Error("ERR WATCH inside MULTI is not allowed".into())
+    );
+    call_in(&mut tx, &registry, &db, &["INCRBY", "balance", "5"]);
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["EXEC"]),
+        Frame::Array(vec![Frame::Integer(15)])
+    );
+
+    // WATCH の後に他のクライアントが書き込むと、EXEC は Null 配列を返して何も実行しない
+    call_in(&mut tx, &registry, &db, &["WATCH", "balance", "list"]);
+    registry.dispatch(&db, command(&["RPUSH", "list", "x"]));
+    call_in(&mut tx, &registry, &db, &["MULTI"]);
+    call_in(&mut tx, &registry, &db, &["INCRBY", "balance", "5"]);
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["EXEC"]),
+        Frame::NullArray
+    );
+    assert_eq!(
+        registry.dispatch(&db, command(&["GET", "balance"])),
+        Frame::Bulk("15".into())
+    );
+
+    // EXEC の後は WATCH が解除されている
+    registry.dispatch(&db, command(&["DEL", "list"]));
+    call_in(&mut tx, &registry, &db, &["MULTI"]);
+    call_in(&mut tx, &registry, &db, &["INCRBY", "balance", "5"]);
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["EXEC"]),
+        Frame::Array(vec![Frame::Integer(20)])
+    );
+
+    // UNWATCH すれば、その後の書き込みで中止されない
+    call_in(&mut tx, &registry, &db, &["WATCH", "balance"]);
+    assert_eq!(call_in(&mut tx, &registry, &db, &["UNWATCH"]), ok());
+    registry.dispatch(&db, command(&["SET", "balance", "0"]));
+    call_in(&mut tx, &registry, &db, &["MULTI"]);
+    call_in(&mut tx, &registry, &db, &["INCR", "balance"]);
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["EXEC"]),
+        Frame::Array(vec![Frame::Integer(1)])
+    );
+}
+
+#[tokio::test]
+async fn watching_a_missing_key_detects_creation() {
+    let db = new_sharded_db(4);
+    let registry = Registry::new();
+    let mut tx = Transaction::new(db.clone(), Arc::new(Hub::new()));
+    let mut other = Transaction::new(db.clone(), Arc::new(Hub::new()));
+
+    call_in(&mut tx, &registry, &db, &["WATCH", "lock"]);
+    call_in(&mut other, &registry, &db, &["WATCH", "lock"]);
+    registry.dispatch(&db, command(&["SET", "lock", "1"]));
+    // 他のコネクションの WATCH が外れても、このコネクションの WATCH は残る
+    drop(other);
+
+    call_in(&mut tx, &registry, &db, &["MULTI"]);
+    call_in(&mut tx, &registry, &db, &["SET", "lock", "2"]);
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["EXEC"]),
+        Frame::NullArray
+    );
+}
+
+#[tokio::test]
+async fn exec_over_the_wire() {
+    let addr = start_server(Config::default()).await;
+
+    let mut a = connect(addr).await;
+    let mut b = connect(addr).await;
+
+    assert_eq!(request(&mut a, &["WATCH", "k"]).await, ok());
+    assert_eq!(request(&mut a, &["MULTI"]).await, ok());
+    assert_eq!(request(&mut a, &["SET", "k", "a"]).await, queued());
+    assert_eq!(request(&mut b, &["SET", "k", "b"]).await, ok());
+    assert_eq!(request(&mut a, &["EXEC"]).await, Frame::NullArray);
+
+    assert_eq!(request(&mut a, &["MULTI"]).await, ok());
+    assert_eq!(request(&mut a, &["SET", "k", "a"]).await, queued());
+    assert_eq!(request(&mut a, &["GET", "k"]).await, queued());
+    assert_eq!(
+        request(&mut a, &["EXEC"]).await,
+        Frame::Array(vec![ok(), Frame::Bulk("a".into())])
+    );
+}
+
+// PUBLISH も他のコマンドと同じようにキューに積み、EXEC で実行する
+#[tokio::test]
+async fn publish_inside_multi() {
+    let addr = start_server(Config::default()).await;
+
+    let mut sub = connect(addr).await;
+    let mut a = connect(addr).await;
+    request(&mut sub, &["SUBSCRIBE", "news"]).await;
+
+    assert_eq!(request(&mut a, &["MULTI"]).await, ok());
+    assert_eq!(
+        request(&mut a, &["PUBLISH", "news", "hello"]).await,
+        queued()
+    );
+    assert_eq!(request(&mut a, &["SET", "k", "v"]).await, queued());
+    assert_eq!(
+        request(&mut a, &["EXEC"]).await,
+        Frame::Array(vec![Frame::Integer(1), ok()])
+    );
+    assert_eq!(
+        sub.read_frame().await.unwrap().unwrap(),
+        Frame::command(["message", "news", "hello"])
+    );
+
+    // 引数の個数が誤っていれば、他のコマンドと同じく EXEC で中止する
+    assert_eq!(request(&mut a, &["MULTI"]).await, ok());
+    assert_eq!(
+        request(&mut a, &["PUBLISH", "news"]).await,
+        Frame::Error("ERR wrong number of arguments for 'publish' command".into())
+    );
+    assert_eq!(
+        request(&mut a, &["EXEC"]).await,
+        Frame::Error("EXECABORT Transaction discarded because of previous errors.".into())
+    );
+}
+
+// UTF-8 でないキーは WATCH できない
+#[tokio::test]
+async fn watch_rejects_keys_that_are_not_utf8() {
+    let db = new_sharded_db(4);
+    let registry = Registry::new();
+    let mut tx = Transaction::new(db.clone(), Arc::new(Hub::new()));
+
+    let watch = Frame::command([
+        Bytes::from_static(b"WATCH"),
+        Bytes::from_static(b"k"),
+        Bytes::from_static(b"\xff"),
+    ]);
+    assert!(matches!(
+        tx.handle(&registry, watch),
+        Outcome::Reply(Frame::Error(msg)) if msg == "ERR invalid key: keys must be valid UTF-8"
+    ));
+
+    // どのキーも監視していないので、k を変更しても EXEC は実行される
+    assert_eq!(call_in(&mut tx, &registry, &db, &["MULTI"]), ok());
+    assert_eq!(call_in(&mut tx, &registry, &db, &["GET", "k"]), queued());
+    registry.dispatch(&db, command(&["SET", "k", "v"]));
+    assert_eq!(
+        call_in(&mut tx, &registry, &db, &["EXEC"]),
+        Frame::Array(vec![Frame::Bulk("v".into())])
+    );
+}
@@ -0,0 +1,242 @@
+mod common;
+
+use std::io::Cursor;
+
+use bytes::Bytes;
+
+use common::bulks;
+use my_redis::cmd::Registry;
+use my_redis::db::{new_sharded_db, ShardedDb, SortedSet};
+use my_redis::frame::{encode, Frame, Protocol};
+
+// RESP2 のクライアントが受け取る形に変換して返す（スコアは文字列になる）
+fn call(db: &ShardedDb, args: &[&str]) -> Frame {
+    let args: Vec<Bytes> = args.iter().map(|s| Bytes::from(s.to_string())).collect();
+    let reply = Registry::new().dispatch(db, Frame::command(args));
+    let mut buf = Vec::new();
+    encode(&reply, Protocol::Resp2, &mut buf).unwrap();
+    Frame::parse(&mut Cursor::new(&buf[..])).unwrap()
+}
+
+#[tokio::test]
+async fn add_score_and_rank() {
+    let db = new_sharded_db(4);
+
+    assert_eq!(
+        call(&db, &["ZADD", "board", "3", "c", "1", "a", "2", "b"]),
+        Frame::Integer(3)
+    );
+    assert_eq!(call(&db, &["ZADD", "board", "5", "a"]), Frame::Integer(0));
+    assert_eq!(
+        call(&db, &["ZADD", "board", "CH", "6", "a", "4", "d"]),
+        Frame::Integer(2)
+    );
+    assert_eq!(call(&db, &["ZCARD", "board"]), Frame::Integer(4));
+    assert_eq!(
+        call(&db, &["ZSCORE", "board", "a"]),
+        Frame::Bulk("6".into())
+    );
+    assert_eq!(call(&db, &["ZRANK", "board", "b"]), Frame::Integer(0));
+    assert_eq!(call(&db, &["ZRANK", "board", "a"]), Frame::Integer(3));
+    assert_eq!(call(&db, &["ZREVRANK", "board", "a"]), Frame::Integer(0));
+    assert_eq!(call(&db, &["ZRANK", "board", "zz"]), Frame::Null);
+    assert_eq!(call(&db, &["TYPE", "board"]), Frame::Simple("zset".into()));
+
+    assert_eq!(
+        call(&db, &["ZINCRBY", "board", "-5.5", "a"]),
+        Frame::Bulk("0.5".into())
+    );
+    assert_eq!(
+        call(&db, &["ZRANGE", "board", "0", "-1"]),
+        bulks(&["a", "b", "c", "d"])
+    );
+
+    assert_eq!(call(&db, &["ZREM", "board", "a", "zz"]), Frame::Integer(1));
+    assert_eq!(
+        call(&db, &["ZRANGE", "board", "0", "-1", "REV", "WITHSCORES"]),
+        bulks(&["d", "4", "c", "3", "b", "2"])
+    );
+    assert_eq!(
+        call(&db, &["ZREM", "board", "b", "c", "d"]),
+        Frame::Integer(3)
+    );
+    assert_eq!(call(&db, &["EXISTS", "board"]), Frame::Integer(0));
+}
+
+#[tokio::test]
+async fn add_options() {
+    let db = new_sharded_db(4);
+
+    assert_eq!(call(&db, &["ZADD", "z", "XX", "1", "a"]), Frame::Integer(0));
+    assert_eq!(call(&db, &["EXISTS", "z"]), Frame::Integer(0));
+    assert_eq!(call(&db, &["ZADD", "z", "NX", "1", "a"]), Frame::Integer(1));
+    assert_eq!(call(&db, &["ZADD", "z", "NX", "9", "a"]), Frame::Integer(0));
+    assert_eq!(
+        call(&db, &["ZADD", "z", "GT", "CH", "0", "a"]),
+        Frame::Integer(0)
+    );
+    assert_eq!(
+        call(&db, &["ZADD", "z", "GT", "CH", "2", "a"]),
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        call(&db, &["ZADD", "z", "LT", "CH", "3", "a"]),
+        Frame::Integer(0)
+    );
+    assert_eq!(
+        call(&db, &["ZADD", "z", "INCR", "1.5", "a"]),
+        Frame::Bulk("3.5".into())
+    );
+    assert_eq!(
+        call(&db, &["ZADD", "z", "NX", "INCR", "1", "a"]),
+        Frame::Null
+    );
+
+    assert_eq!(
+        call(&db, &["ZADD", "z", "NX", "XX", "1", "a"]),
+        Frame::Error("ERR XX and NX options at the same time are not compatible".into())
+    );
+    assert_eq!(
+        call(&db, &["ZADD", "z", "1", "a", "x", "b"]),
+        Frame::Error("ERR value is not a valid float".into())
+    );
+    assert_eq!(call(&db, &["ZCARD", "z"]), Frame::Integer(1));
+    assert_eq!(
+        call(&db, &["ZADD", "z", "1", "a", "2"]),
+        Frame::Error("ERR syntax error".into())
+    );
+
+    call(&db, &["SET", "str", "v"]);
+    assert_eq!(
+        call(&db, &["ZADD", "str", "1", "a"]),
+        Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())
+    );
+}
+
+#[tokio::test]
+async fn score_ranges() {
+    let db = new_sharded_db(4);
+    call(
+        &db,
+        &[
+            "ZADD", "z", "-inf", "lo", "1", "a", "2", "b", "2", "c", "3", "d", "+inf", "hi",
+        ],
+    );
+
+    assert_eq!(
+        call(&db, &["ZRANGEBYSCORE", "z", "-inf", "+inf"]),
+        bulks(&["lo", "a", "b", "c", "d", "hi"])
+    );
+    assert_eq!(
+        call(&db, &["ZRANGEBYSCORE", "z", "(1", "3"]),
+        bulks(&["b", "c", "d"])
+    );
+    assert_eq!(
+        call(&db, &["ZRANGEBYSCORE", "z", "1", "(3", "WITHSCORES"]),
+        bulks(&["a", "1", "b", "2", "c", "2"])
+    );
+    assert_eq!(
+        call(&db, &["ZRANGEBYSCORE", "z", "(-inf", "(inf"]),
+        bulks(&["a", "b", "c", "d"])
+    );
+    assert_eq!(
+        call(&db, &["ZRANGEBYSCORE", "z", "0", "+inf", "LIMIT", "1", "2"]),
+        bulks(&["b", "c"])
+    );
+    assert_eq!(
+        call(&db, &["ZREVRANGEBYSCORE", "z", "3", "(1"]),
+        bulks(&["d", "c", "b"])
+    );
+    assert_eq!(
+        call(
+            &db,
+            &["ZRANGE", "z", "+inf", "2", "BYSCORE", "REV", "LIMIT", "1", "-1"]
+        ),
+        bulks(&["d", "c", "b"])
+    );
+    assert_eq!(call(&db, &["ZRANGEBYSCORE", "z", "(2", "(2"]), bulks(&[]));
+    assert_eq!(
+        call(&db, &["ZRANGEBYSCORE", "z", "abc", "1"]),
+        Frame::Error("ERR min or max is not a float".into())
+    );
+    assert_eq!(
+        call(&db, &["ZRANGE", "z", "0", "1", "LIMIT", "0", "1"]),
+        Frame::Error(
+            "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                .into()
+        )
+    );
+    assert_eq!(
+        call(&db, &["ZREVRANGE", "z", "1", "2", "WITHSCORES"]),
+        bulks(&["d", "3", "c", "2"])
+    );
+}
+
+#[tokio::test]
+async fn pop_min_and_max() {
+    let db = new_sharded_db(4);
+    call(&db, &["ZADD", "z", "1", "a", "2", "b", "3", "c"]);
+
+    assert_eq!(call(&db, &["ZPOPMIN", "z"]), bulks(&["a", "1"]));
+    assert_eq!(
+        call(&db, &["ZPOPMAX", "z", "5"]),
+        bulks(&["c", "3", "b", "2"])
+    );
+    assert_eq!(call(&db, &["EXISTS", "z"]), Frame::Integer(0));
+    assert_eq!(call(&db, &["ZPOPMIN", "z"]), bulks(&[]));
+}
+
+// 多数の挿入・削除の後も、順位とスコア範囲の問い合わせが
+// 単純なソートによる結果と一致することを確かめる
+#[test]
+fn sorted_set_matches_naive_model() {
+    let mut zset = SortedSet::new();
+    let mut model: Vec<(i64, String)> = Vec::new();
+
+    let mut seed: u64 = 42;
+    let mut next = || {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        seed >> 33
+    };
+
+    for _ in 0..5000 {
+        let member = format!("m{}", next() % 300);
+        let score = (next() % 50) as i64;
+        if next() % 3 == 0 {
+            zset.remove(member.as_bytes());
+            model.retain(|(_, m)| *m != member);
+        } else {
+            zset.insert(Bytes::from(member.clone()), score as f64);
+            model.retain(|(_, m)| *m != member);
+            model.push((score, member));
+        }
+    }
+    model.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+
+    assert_eq!(zset.len(), model.len());
+    for (rank, (_, member)) in model.iter().enumerate() {
+        assert_eq!(zset.rank(member.as_bytes()), Some(rank));
+    }
+    let all: Vec<_> = zset
+        .range_by_rank(0, model.len() - 1)
+        .into_iter()
+        .map(|(m, s)| (s as i64, String::from_utf8(m.to_vec()).unwrap()))
+        .collect();
+    assert_eq!(all, model);
+
+    use my_redis::db::ScoreBound;
+    let ranged = zset.range_by_score(
+        ScoreBound::Exclusive(10.0),
+        ScoreBound::Inclusive(20.0),
+        0,
+        None,
+    );
+    let expected: Vec<_> = model.iter().filter(|(s, _)| *s > 10 && *s <= 20).collect();
+    assert_eq!(ranged.len(), expected.len());
+    assert_eq!(
+        ranged.first().map(|(m, _)| m.as_ref()),
+        expected.first().map(|(_, m)| m.as_bytes())
+    );
+}